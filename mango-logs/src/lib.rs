@@ -105,6 +105,14 @@ pub struct CachePricesLog {
     pub oracle_indexes: Vec<u64>,
     pub oracle_prices: Vec<i128>, // I80F48 format
 }
+#[event]
+pub struct PriceHaltLog {
+    pub mango_group: Pubkey,
+    pub oracle_index: u64,
+    pub cached_price: i128, // I80F48
+    pub halted_price: i128, // I80F48
+}
+
 #[event]
 pub struct CacheRootBanksLog {
     pub mango_group: Pubkey,
@@ -192,6 +200,18 @@ pub struct PerpBankruptcyLog {
     pub cache_short_funding: i128, // I80F48
 }
 
+/// base_transfer and bankruptcy_price are 0 when ResolvePerpBankruptcy only takes unrealized pnl
+#[event]
+pub struct AutoDeleverageLog {
+    pub mango_group: Pubkey,
+    pub liqee: Pubkey,
+    pub counterparty: Pubkey,
+    pub market_index: u64,
+    pub bankruptcy_price: i128, // I80F48
+    pub base_transfer: i64,
+    pub quote_transfer: i128, // I80F48
+}
+
 #[event]
 pub struct TokenBankruptcyLog {
     pub mango_group: Pubkey,
@@ -219,6 +239,14 @@ pub struct UpdateFundingLog {
     pub market_index: u64,
     pub long_funding: i128,  // I80F48
     pub short_funding: i128, // I80F48
+    pub funding_period: u64,
+}
+
+#[event]
+pub struct SettleExpiredPerpMarketLog {
+    pub mango_group: Pubkey,
+    pub market_index: u64,
+    pub settlement_price: i128, // I80F48
 }
 
 #[event]
@@ -278,6 +306,15 @@ pub struct CancelAllPerpOrdersLog {
     pub canceled_order_ids: Vec<i128>,
 }
 
+#[event]
+pub struct BatchPerpOrdersLog {
+    pub mango_group: Pubkey,
+    pub mango_account: Pubkey,
+    pub market_index: u64,
+    pub canceled_order_ids: Vec<i128>,
+    pub placed_client_order_ids: Vec<u64>,
+}
+
 #[event]
 pub struct PerpBalanceLog {
     pub mango_group: Pubkey,
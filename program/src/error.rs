@@ -143,6 +143,38 @@ pub enum MangoErrorCode {
         "MangoErrorCode::InvalidAllowBorrow This market requires allow-borrow flag to be false"
     )]
     InvalidAllowBorrow,
+    #[error("MangoErrorCode::PerpMarketExpired This perp market has expired")]
+    PerpMarketExpired,
+    #[error("MangoErrorCode::PerpMarketNotSettled This perp market has no settlement price yet")]
+    PerpMarketNotSettled,
+    #[error("MangoErrorCode::PriceHalted The oracle price moved too far and awaits confirmation")]
+    PriceHalted,
+    #[error("MangoErrorCode::WouldSelfTrade Order would match an order of the same account")]
+    WouldSelfTrade,
+    #[error("MangoErrorCode::FillOrKillNotFilled The book can't fill the whole FillOrKill order")]
+    FillOrKillNotFilled,
+    #[error("MangoErrorCode::HeartbeatNotExpired The heartbeat deadline has not passed yet")]
+    HeartbeatNotExpired,
+    #[error("MangoErrorCode::PriceOutsideBand Order price is too far from the oracle price")]
+    PriceOutsideBand,
+    #[error(
+        "MangoErrorCode::MaxPositionExceeded Position would exceed the market's position limit"
+    )]
+    MaxPositionExceeded,
+    #[error(
+        "MangoErrorCode::MaxOpenInterestExceeded Open interest would exceed the market's limit"
+    )]
+    MaxOpenInterestExceeded,
+    #[error("MangoErrorCode::InsuranceVaultNotEmpty The insurance fund can still cover losses")]
+    InsuranceVaultNotEmpty,
+    #[error(
+        "MangoErrorCode::InvalidAdlCounterparty Counterparty is not next in the deleveraging queue"
+    )]
+    InvalidAdlCounterparty,
+    #[error(
+        "MangoErrorCode::NotEnoughSettlementSamples The settlement window had too few index samples"
+    )]
+    NotEnoughSettlementSamples,
     #[error("MangoErrorCode::Default Check the source code for more info")] // 40
     Default = u32::MAX_VALUE,
 }
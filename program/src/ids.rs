@@ -21,3 +21,21 @@ pub mod mngo_token {
     #[cfg(not(feature = "devnet"))]
     declare_id!("MangoCzJ36AjZyKwVj3VnYU4GTonjfVEnJmvvWaxLac");
 }
+
+pub mod pyth_receiver_program {
+    use solana_program::declare_id;
+    declare_id!("rec5EKMGg6MxZYaMdyBfgwp4d5rB9T1VQH5pJv5LtFJ");
+}
+
+pub mod pyth_push_oracle_program {
+    use solana_program::declare_id;
+    declare_id!("pythWSnswVUd12oZpeFP8e9CVaEqJg25g1Vtc2biRsT");
+}
+
+pub mod switchboard_v2_program {
+    use solana_program::declare_id;
+    #[cfg(feature = "devnet")]
+    declare_id!("2TfB33aLaneQb5TNVwyDz3jSZXS6jdW2ARw1Dgf84XCG");
+    #[cfg(not(feature = "devnet"))]
+    declare_id!("SW1TCH7qEPTdLsDHRgPuMQjbQxKdH2aBStViMFnt64f");
+}
@@ -1,4 +1,5 @@
-use crate::matching::{ExpiryType, OrderType, Side};
+use crate::matching::{ExpiryType, OrderType, SelfTradeBehavior, Side};
+use crate::oracle::MAX_COMPOSITE_SOURCES;
 use crate::state::{AssetType, INFO_LEN};
 use crate::state::{TriggerCondition, MAX_PAIRS};
 use crate::utils;
@@ -125,7 +126,11 @@ pub enum MangoInstruction {
     /// Accounts expected: 3 + Oracles
     /// 0. `[]` mango_group_ai -
     /// 1. `[writable]` mango_cache_ai -
-    /// 2+... `[]` oracle_ais - flux aggregator feed accounts
+    /// 2+... `[]` oracle_ais - flux aggregator feed accounts; perp markets that derive their
+    ///         index from another oracle (PerpMarketInfo::underlying_oracle_index) also need
+    ///         that underlying oracle passed in. RealizedVolOracles must be writable and passed
+    ///         after their source oracle; they are only sampled if the source was cached by the
+    ///         same instruction. The sources of CompositeOracles must be passed in as well
     CachePrices,
 
     /// DEPRECATED - caching of root banks now happens in update index
@@ -236,22 +241,56 @@ pub enum MangoInstruction {
         quantity: i64,
         client_order_id: u64,
         side: Side,
-        /// Can be 0 -> LIMIT, 1 -> IOC, 2 -> PostOnly, 3 -> Market, 4 -> PostOnlySlide
+        /// Can be 0 -> LIMIT, 1 -> IOC, 2 -> PostOnly, 3 -> Market, 4 -> PostOnlySlide,
+        /// 5 -> FillOrKill
         order_type: OrderType,
         /// Optional to be backward compatible; default false
         reduce_only: bool,
     },
 
+    /// Cancel a perp order by its client_order_id; an iceberg order loses its hidden rest
+    ///
+    /// Accounts expected: 6 (+1)
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_account_ai - MangoAccount
+    /// 2. `[signer]` owner_ai - Owner of Mango Account
+    /// 3. `[writable]` perp_market_ai - PerpMarket
+    /// 4. `[writable]` bids_ai - Bids acc
+    /// 5. `[writable]` asks_ai - Asks acc
+    /// 6. `[writable]` iceberg_orders_ai - IcebergOrders PDA of the MangoAccount; optional,
+    ///                 without it canceled iceberg orders keep their hidden rest
     CancelPerpOrderByClientId {
         client_order_id: u64,
         invalid_id_ok: bool,
     },
 
+    /// Cancel a perp order by its order_id; an iceberg order loses its hidden rest
+    ///
+    /// Accounts expected: 6 (+1)
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_account_ai - MangoAccount
+    /// 2. `[signer]` owner_ai - Owner of Mango Account
+    /// 3. `[writable]` perp_market_ai - PerpMarket
+    /// 4. `[writable]` bids_ai - Bids acc
+    /// 5. `[writable]` asks_ai - Asks acc
+    /// 6. `[writable]` iceberg_orders_ai - IcebergOrders PDA of the MangoAccount; optional,
+    ///                 without it canceled iceberg orders keep their hidden rest
     CancelPerpOrder {
         order_id: i128,
         invalid_id_ok: bool,
     },
 
+    /// Process up to `limit` events of the EventQueue
+    ///
+    /// Accounts expected: 4 + N
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[]` mango_cache_ai - MangoCache
+    /// 2. `[writable]` perp_market_ai - PerpMarket
+    /// 3. `[writable]` event_queue_ai - EventQueue
+    /// 4+ `[writable]` mango_account_ais - MangoAccounts of the events, in any order. Passing
+    ///                 the bids, asks and a maker's IcebergOrders PDA as well re-posts the next
+    ///                 slice of the maker's iceberg orders whose slice was filled or dropped,
+    ///                 as a PostOnly order if it can rest on the book
     ConsumeEvents {
         limit: usize,
     },
@@ -357,7 +396,7 @@ pub enum MangoInstruction {
 
     /// Force cancellation of open orders for a user being liquidated
     ///
-    /// Accounts expected: 6 + Liqee open orders accounts (MAX_PAIRS)
+    /// Accounts expected: 6 + Liqee open orders accounts (MAX_PAIRS) (+1)
     /// 0. `[]` mango_group_ai - MangoGroup
     /// 1. `[]` mango_cache_ai - MangoCache
     /// 2. `[]` perp_market_ai - PerpMarket
@@ -365,6 +404,8 @@ pub enum MangoInstruction {
     /// 4. `[writable]` asks_ai - Asks acc
     /// 5. `[writable]` liqee_mango_account_ai - Liqee MangoAccount
     /// 6+... `[]` liqor_open_orders_ais - Liqee open orders accs
+    /// 6 + MAX_PAIRS `[writable]` iceberg_orders_ai - IcebergOrders PDA of the liqee;
+    ///                 optional, without it canceled iceberg orders keep their hidden rest
     ForceCancelPerpOrders {
         limit: u8,
     },
@@ -439,9 +480,14 @@ pub enum MangoInstruction {
     /// 9. `[]` token_prog_ai - Token Program Account
     SettleFees,
 
-    /// Claim insurance fund and then socialize loss
+    /// Claim insurance fund and then socialize loss. Once the insurance fund is empty, the
+    /// admin may sign and pass profitable accounts on one side in ADL queue order (see
+    /// AutoDeleverage). Their positions are force-closed at the bankruptcy price, as far as their
+    /// unrealized pnl and maintenance health allow, and the liqor takes the closed lots over at
+    /// the oracle price (the settlement price once the market settled). Whatever is left, or all
+    /// of it without the admin's signature, is socialized.
     ///
-    /// Accounts expected: 12 + Liqor open orders accounts (MAX_PAIRS)
+    /// Accounts expected: 12 + Liqor open orders accounts (MAX_PAIRS) (+ ADL accounts)
     /// 0. `[]` mango_group_ai - MangoGroup
     /// 1. `[writable]` mango_cache_ai - MangoCache
     /// 2. `[writable]` liqee_mango_account_ai - Liqee MangoAccount
@@ -455,6 +501,10 @@ pub enum MangoInstruction {
     /// 10. `[]` perp_market_ai - PerpMarket
     /// 11. `[]` token_prog_ai - Token Program Account
     /// 12+... `[]` liqor_open_orders_ais - Liqor open orders accs
+    /// 12+MAX_PAIRS `[signer]` admin_ai - MangoGroup admin; optional, only used once the
+    ///     insurance fund is empty
+    /// 13+MAX_PAIRS... `[writable]` counterparty_mango_account_ai, each followed by `[]` its
+    ///     open orders accounts for the markets in its margin basket
     ResolvePerpBankruptcy {
         // 30
         liab_index: usize,
@@ -605,13 +655,15 @@ pub enum MangoInstruction {
 
     /// Cancel all perp open orders (batch cancel)
     ///
-    /// Accounts expected: 6
+    /// Accounts expected: 6 (+1)
     /// 0. `[]` mango_group_ai - MangoGroup
     /// 1. `[writable]` mango_account_ai - MangoAccount
     /// 2. `[signer]` owner_ai - Owner of Mango Account
     /// 3. `[writable]` perp_market_ai - PerpMarket
     /// 4. `[writable]` bids_ai - Bids acc
     /// 5. `[writable]` asks_ai - Asks acc
+    /// 6. `[writable]` iceberg_orders_ai - IcebergOrders PDA of the MangoAccount; optional,
+    ///                 without it canceled iceberg orders keep their hidden rest
     CancelAllPerpOrders {
         limit: u8,
     },
@@ -718,6 +770,14 @@ pub enum MangoInstruction {
         lm_size_shift: u8,
         /// define base decimals in case spot market has not yet been listed
         base_decimals: u8,
+        /// index price = underlying oracle price ^ index_exponent / index_divisor
+        /// e.g. 2 for BTC^2-PERP; 1 for a linear perp
+        index_exponent: u8,
+        /// oracle index of the underlying; may be the market's own oracle index
+        underlying_oracle_index: u8,
+        index_divisor: I80F48,
+        /// seconds over which the book premium is paid out as funding, e.g. 86400 for one day
+        funding_period: u64,
     },
 
     /// Change the params for perp market.
@@ -764,6 +824,40 @@ pub enum MangoInstruction {
         version: Option<u8>,
         #[serde(serialize_with = "serialize_option_fixed_width")]
         lm_size_shift: Option<u8>,
+
+        /// Optional: see CreatePerpMarket
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        index_exponent: Option<u8>,
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        underlying_oracle_index: Option<u8>,
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        index_divisor: Option<I80F48>,
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        funding_period: Option<u64>,
+
+        /// Optional: revalue positions at the underlying price +/- this many bps through the
+        /// index exponent for maint/init health instead of using the linear weights; 0 disables
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        maint_shock_bps: Option<u16>,
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        init_shock_bps: Option<u16>,
+
+        /// Optional: time constant in seconds of the EMA kept in the PriceCache; 0 disables
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        ema_period: Option<u16>,
+        /// Optional: value perp positions at the EMA price when checking liquidations
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        liquidation_use_ema: Option<bool>,
+        /// Optional: compute the funding premium against the EMA price
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        funding_use_ema: Option<bool>,
+
+        /// Optional: max bps a bid may be priced above or an ask below the oracle price; 0
+        /// disables. Orders outside are rejected unless price_band_clamp is set
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        price_band_bps: Option<u16>,
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        price_band_clamp: Option<bool>,
     },
 
     /// Change the params for perp market.
@@ -863,13 +957,15 @@ pub enum MangoInstruction {
 
     /// Cancel all perp open orders for one side of the book
     ///
-    /// Accounts expected: 6
+    /// Accounts expected: 6 (+1)
     /// 0. `[]` mango_group_ai - MangoGroup
     /// 1. `[writable]` mango_account_ai - MangoAccount
     /// 2. `[signer]` owner_ai - Owner of Mango Account
     /// 3. `[writable]` perp_market_ai - PerpMarket
     /// 4. `[writable]` bids_ai - Bids acc
     /// 5. `[writable]` asks_ai - Asks acc
+    /// 6. `[writable]` iceberg_orders_ai - IcebergOrders PDA of the MangoAccount; optional,
+    ///                 without it canceled iceberg orders keep their hidden rest
     CancelPerpOrdersSide {
         side: Side,
         limit: u8,
@@ -994,6 +1090,7 @@ pub enum MangoInstruction {
     /// 8. `[writable]` referrer_mango_account_ai - referrer's mango account;
     ///                 pass in mango_account_ai as duplicate if you don't have a referrer
     /// 9..9 + NUM_IN_MARGIN_BASKET `[]` open_orders_ais - pass in open orders in margin basket
+    /// 9 + NUM_IN_MARGIN_BASKET `[writable]` iceberg_orders_ai - only if display_quantity > 0
     PlacePerpOrder2 {
         /// Price in quote lots per base lots.
         ///
@@ -1024,11 +1121,17 @@ pub enum MangoInstruction {
         /// - Must be between 1 and 255.
         /// - The order will expire when the block timestamp has reached or exceeded
         ///   the current block timestamp plus that number of seconds.
+        ///
+        /// If expiry_type is GoodTillDate:
+        /// - Timestamps in the past mean the instruction is skipped.
+        /// - Timestamps in the future are kept as they are; such orders earn no liquidity
+        ///   mining incentives and can't have a display_quantity.
         expiry_timestamp: u64,
 
         side: Side,
 
-        /// Can be 0 -> LIMIT, 1 -> IOC, 2 -> PostOnly, 3 -> Market, 4 -> PostOnlySlide
+        /// Can be 0 -> LIMIT, 1 -> IOC, 2 -> PostOnly, 3 -> Market, 4 -> PostOnlySlide,
+        /// 5 -> FillOrKill
         order_type: OrderType,
 
         reduce_only: bool,
@@ -1039,8 +1142,20 @@ pub enum MangoInstruction {
         /// When the limit is reached, processing stops and the instruction succeeds.
         limit: u8,
 
-        /// Can be 0 -> Absolute or 1 -> Relative; see expiry_timestamp
+        /// Can be 0 -> Absolute, 1 -> Relative or 2 -> GoodTillDate; see expiry_timestamp
         expiry_type: ExpiryType,
+
+        /// What to do when the order would match an order of the same MangoAccount.
+        /// Can be 0 -> DecrementTake, 1 -> CancelProvide, 2 -> AbortTransaction or
+        /// 3 -> AllowSelfTrade, which is used when it's left out of the instruction data
+        self_trade_behavior: SelfTradeBehavior,
+
+        /// Base lots shown on the book at a time; 0 places a normal order.
+        ///
+        /// Only for Limit and PostOnly orders. Whatever rests beyond this is kept in the
+        /// IcebergOrders PDA, which must be passed after the open orders, and is posted slice
+        /// by slice: by ConsumeEvents once a slice is filled, else by RefillIcebergOrders.
+        display_quantity: i64,
     },
 
     /// Cancels all the spot orders pending for a mango account
@@ -1070,6 +1185,9 @@ pub enum MangoInstruction {
         limit: u8,
     },
 
+    /// DEPRECATED - the index of each perp market is configured with index_exponent,
+    /// underlying_oracle_index and index_divisor in CreatePerpMarket / ChangePerpMarketParams2.
+    /// Always fails; UpgradeMangoGroup carries the flag over into the index params
     DontSquare {
         dont_square: bool,
     },
@@ -1172,6 +1290,398 @@ pub enum MangoInstruction {
         order_id: usize,
         open_orders_count: usize,
     },
+
+    /// Change the params used to compute funding on a perp market
+    ///
+    /// Accounts expected by this instruction (3):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` perp_market_ai - PerpMarket
+    /// 2. `[signer]` admin_ai - MangoGroup admin
+    ChangePerpMarketFundingParams {
+        /// base lots used to compute the impact bid/ask
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        impact_quantity: Option<i64>,
+
+        /// max (book price / index price - 1) paid per funding period
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        max_funding: Option<I80F48>,
+
+        /// min (book price / index price - 1) paid per funding period
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        min_funding: Option<I80F48>,
+    },
+
+    /// Initialize a RealizedVolOracle tracking the log returns of an existing oracle and add it
+    /// to the MangoGroup oracles. The cached price is the annualized volatility in percent, so
+    /// it can be used as the index of a volatility perp market
+    ///
+    /// Accounts expected by this instruction (3):
+    /// 0. `[writable]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` oracle_ai - RealizedVolOracle, owned by the mango program
+    /// 2. `[signer]` admin_ai - MangoGroup admin
+    AddRealizedVolOracle {
+        /// oracle index of the price whose volatility is tracked
+        source_index: usize,
+        /// seconds; weight of a new sample is min(seconds since last sample / window, 1)
+        window: u64,
+        /// seconds; samples closer together than this are ignored
+        min_sample_interval: u64,
+    },
+
+    /// Set the expiry of a perp market, turning it into a dated future. No orders can be placed
+    /// at or after expiry. An expiry of 0 turns it back into a perpetual market. Possible until
+    /// the market settles, and clears the settlement samples
+    ///
+    /// Accounts expected by this instruction (3):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` perp_market_ai - PerpMarket
+    /// 2. `[signer]` admin_ai - MangoGroup admin
+    SetPerpMarketExpiry {
+        /// unix timestamp; 0 for no expiry
+        expiry: u64,
+        /// seconds before expiry over which the settlement price is averaged; at least
+        /// MIN_SETTLEMENT_SAMPLES
+        settlement_window: u64,
+    },
+
+    /// Sample the cached index price into the settlement TWAP of a dated perp market. This is a
+    /// permissionless crank that can be called during the settlement window, at most once every
+    /// settlement_window / MIN_SETTLEMENT_SAMPLES seconds. Each sample holds until the next one.
+    /// The first call at or after expiry sets the settlement price if there were at least
+    /// MIN_SETTLEMENT_SAMPLES samples. UpdateFunding must have run after expiry before that.
+    /// From then on health and SettlePnl value positions at the settlement price
+    ///
+    /// Accounts expected by this instruction (3):
+    /// 0. `[writable]` mango_group_ai - MangoGroup
+    /// 1. `[]` mango_cache_ai - MangoCache
+    /// 2. `[writable]` perp_market_ai - PerpMarket
+    SettleExpiredPerpMarket,
+
+    /// Close the position of a MangoAccount in a settled perp market, moving base_position into
+    /// quote_position at the settlement price. Open orders and unprocessed fills on the market
+    /// must be cleared first
+    ///
+    /// Accounts expected by this instruction (3):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_account_ai - MangoAccount
+    /// 2. `[writable]` perp_market_ai - PerpMarket
+    SettleExpiredPosition,
+
+    /// Initialize a CompositeOracle and add it to the MangoGroup oracles. It resolves to the
+    /// weighted median of its valid sources; CachePrices needs the sources passed in as well
+    ///
+    /// Accounts expected by this instruction (3 + up to 5):
+    /// 0. `[writable]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` oracle_ai - CompositeOracle, owned by the mango program
+    /// 2. `[signer]` admin_ai - MangoGroup admin
+    /// 3+... `[]` source_ais - Pyth, Switchboard or stub oracles
+    AddCompositeOracle {
+        /// min number of valid sources for a price
+        min_quorum: u8,
+        /// weight of each source, in the order they are passed in
+        weights: [u16; MAX_COMPOSITE_SOURCES],
+    },
+
+    /// Change the limits for the price of an oracle. Setting a limit to 0 restores the default
+    /// or, for max_price_move_bps, disables the circuit breaker
+    ///
+    /// Accounts expected by this instruction (3):
+    /// 0. `[writable]` mango_group_ai - MangoGroup
+    /// 1. `[]` oracle_ai - oracle in the MangoGroup
+    /// 2. `[signer]` admin_ai - MangoGroup admin
+    ChangeOracleLimits {
+        /// max confidence interval (or std deviation) over price, in bps; default 10%. For perps
+        /// with a derived index this applies to the index, e.g. twice the underlying's for squared
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        max_conf_bps: Option<u16>,
+        /// max slots since the oracle price was published; default no limit for Pyth and
+        /// 5 minutes for Switchboard V2
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        max_oracle_age_slots: Option<u16>,
+        /// seconds a cached price stays valid; default twice the group valid_interval
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        price_valid_interval: Option<u16>,
+        /// max move of the cached price per valid_interval in bps before CachePrices halts it.
+        /// While halted, PlacePerpOrder2, Withdraw and liquidations involving it fail
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        max_price_move_bps: Option<u16>,
+    },
+
+    /// Accept the price that tripped the circuit breaker and clear the halt. A later oracle
+    /// price close to either the cached or the halted price also clears it in CachePrices
+    ///
+    /// Accounts expected by this instruction (4):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_cache_ai - MangoCache
+    /// 2. `[]` oracle_ai - halted oracle in the MangoGroup
+    /// 3. `[signer]` admin_ai - MangoGroup admin
+    ClearPriceHalt,
+
+    /// Initialize a DerivedOracle and add it to the MangoGroup oracles. CachePrices computes its
+    /// price as base^base_exponent / quote^quote_exponent * multiplier from the cached prices of
+    /// the two oracles, so they should be passed in before it
+    ///
+    /// Accounts expected by this instruction (3):
+    /// 0. `[writable]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` oracle_ai - DerivedOracle, owned by the mango program
+    /// 2. `[signer]` admin_ai - MangoGroup admin
+    AddDerivedOracle {
+        base_index: usize,
+        base_exponent: u8,
+        quote_index: usize,
+        quote_exponent: u8,
+        /// constant factor; also converts between the native units of the tokens
+        multiplier: I80F48,
+    },
+
+    /// Change the price and/or quantity of a resting perp order in a single health check.
+    /// Reducing the quantity at the same price keeps the order's place in the book. Otherwise
+    /// the order is canceled and placed again with the same type, expiry and client_order_id,
+    /// and may be matched like a new order
+    ///
+    /// Accounts expected by this instruction (9 + `NUM_IN_MARGIN_BASKET`):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_account_ai - the MangoAccount of owner
+    /// 2. `[signer]` owner_ai - owner of MangoAccount
+    /// 3. `[]` mango_cache_ai - MangoCache for this MangoGroup
+    /// 4. `[writable]` perp_market_ai
+    /// 5. `[writable]` bids_ai - bids account for this PerpMarket
+    /// 6. `[writable]` asks_ai - asks account for this PerpMarket
+    /// 7. `[writable]` event_queue_ai - EventQueue for this PerpMarket
+    /// 8. `[writable]` referrer_mango_account_ai - referrer's mango account;
+    ///                 pass in mango_account_ai as duplicate if you don't have a referrer
+    /// 9..9 + NUM_IN_MARGIN_BASKET `[]` open_orders_ais - pass in open orders in margin basket
+    /// 9 + NUM_IN_MARGIN_BASKET `[writable]` iceberg_orders_ai - optional, to drop the hidden
+    ///                 rest of an edited iceberg order
+    EditPerpOrder {
+        order_id: i128,
+        new_price: i64,
+        new_quantity: i64,
+        /// maximum number of FillEvents before terminating if the order is re-inserted
+        limit: u8,
+    },
+
+    /// Cancel and place several perp orders on one market with a single health check.
+    /// Cancels are applied first; ids that don't belong to an open order are ignored.
+    /// Each list holds at most MAX_PERP_OPEN_ORDERS entries
+    ///
+    /// Accounts expected by this instruction (9 + `NUM_IN_MARGIN_BASKET`):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_account_ai - the MangoAccount of owner
+    /// 2. `[signer]` owner_ai - owner of MangoAccount
+    /// 3. `[]` mango_cache_ai - MangoCache for this MangoGroup
+    /// 4. `[writable]` perp_market_ai
+    /// 5. `[writable]` bids_ai - bids account for this PerpMarket
+    /// 6. `[writable]` asks_ai - asks account for this PerpMarket
+    /// 7. `[writable]` event_queue_ai - EventQueue for this PerpMarket
+    /// 8. `[writable]` referrer_mango_account_ai - referrer's mango account;
+    ///                 pass in mango_account_ai as duplicate if you don't have a referrer
+    /// 9..9 + NUM_IN_MARGIN_BASKET `[]` open_orders_ais - pass in open orders in margin basket
+    /// 9 + NUM_IN_MARGIN_BASKET `[writable]` iceberg_orders_ai - optional, to drop the hidden
+    ///                 rest of canceled iceberg orders
+    BatchPerpOrders {
+        /// maximum number of FillEvents before terminating, per placed order
+        limit: u8,
+        cancel_order_ids: Vec<i128>,
+        cancel_client_order_ids: Vec<u64>,
+        orders: Vec<BatchPerpOrder>,
+    },
+
+    /// Initialize the IcebergOrders PDA of a MangoAccount (`state::IcebergOrders`).
+    ///
+    /// Accounts expected by this instruction (6):
+    /// 0. `[writable]` iceberg_orders_ai - PDA: `["iceberg_orders", mango_account]`
+    /// 1. `[]` mango_group_ai - MangoGroup
+    /// 2. `[]` mango_account_ai - the MangoAccount of owner
+    /// 3. `[signer, writable]` owner_ai - owner of MangoAccount, pays for the PDA
+    /// 4. `[]` rent_ai - Rent sysvar
+    /// 5. `[]` system_prog_ai - System program
+    InitIcebergOrders,
+
+    /// Post the next slice of every iceberg order on this market whose visible slice has
+    /// left the book. ConsumeEvents re-posts slices by itself, but only as PostOnly orders
+    /// that fit on the book; this posts the rest with the order's own type. The owner or
+    /// delegate must sign and the account must stay healthy afterwards.
+    ///
+    /// Accounts expected by this instruction (9 + `NUM_IN_MARGIN_BASKET`):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_account_ai - the MangoAccount owning the iceberg orders
+    /// 2. `[signer]` owner_ai - owner or delegate of MangoAccount
+    /// 3. `[]` mango_cache_ai - MangoCache for this MangoGroup
+    /// 4. `[writable]` perp_market_ai
+    /// 5. `[writable]` bids_ai - bids account for this PerpMarket
+    /// 6. `[writable]` asks_ai - asks account for this PerpMarket
+    /// 7. `[writable]` event_queue_ai - EventQueue for this PerpMarket
+    /// 8. `[writable]` iceberg_orders_ai - IcebergOrders PDA of the MangoAccount
+    /// 9..9 + NUM_IN_MARGIN_BASKET `[]` open_orders_ais - pass in open orders in margin basket
+    RefillIcebergOrders {
+        /// maximum number of FillEvents before terminating, per slice
+        limit: u8,
+    },
+
+    /// Cancel an iceberg order: its visible slice, if still on the book, and the hidden rest.
+    /// Every other instruction that cancels the visible slice drops the hidden rest too.
+    ///
+    /// Accounts expected by this instruction (7):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_account_ai - the MangoAccount of owner
+    /// 2. `[signer]` owner_ai - owner of MangoAccount
+    /// 3. `[writable]` perp_market_ai
+    /// 4. `[writable]` bids_ai - bids account for this PerpMarket
+    /// 5. `[writable]` asks_ai - asks account for this PerpMarket
+    /// 6. `[writable]` iceberg_orders_ai - IcebergOrders PDA of the MangoAccount
+    CancelIcebergOrder {
+        index: u8,
+    },
+
+    /// Place a perp order whose price follows the oracle: oracle price + price_offset.
+    /// It is matched like a Limit or PostOnly order at that price and whatever remains
+    /// rests in the oracle pegged tree of the book side, so it doesn't need to be re-placed
+    /// when the oracle moves. Oracle pegged orders earn no liquidity mining incentives and
+    /// can't be changed with EditPerpOrder.
+    ///
+    /// Accounts expected by this instruction (9 + `NUM_IN_MARGIN_BASKET`):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_account_ai - the MangoAccount of owner
+    /// 2. `[signer]` owner_ai - owner of MangoAccount
+    /// 3. `[]` mango_cache_ai - MangoCache for this MangoGroup
+    /// 4. `[writable]` perp_market_ai
+    /// 5. `[writable]` bids_ai - bids account for this PerpMarket
+    /// 6. `[writable]` asks_ai - asks account for this PerpMarket
+    /// 7. `[writable]` event_queue_ai - EventQueue for this PerpMarket
+    /// 8. `[writable]` referrer_mango_account_ai - referrer's mango account;
+    ///                 pass in mango_account_ai as duplicate if you don't have a referrer
+    /// 9..9 + NUM_IN_MARGIN_BASKET `[]` open_orders_ais - pass in open orders in margin basket
+    PlacePerpPeggedOrder {
+        /// Offset to the oracle price in quote lots per base lot; may be negative
+        price_offset: i64,
+
+        /// Highest price for a bid or lowest price for an ask, -1 for none.
+        /// While the pegged price is beyond it the order can't be matched.
+        peg_limit: i64,
+
+        max_base_quantity: i64,
+        max_quote_quantity: i64,
+        client_order_id: u64,
+
+        /// Same as for PlacePerpOrder2, except that GoodTillDate is not supported
+        expiry_timestamp: u64,
+        side: Side,
+
+        /// Can be 0 -> LIMIT or 2 -> PostOnly
+        order_type: OrderType,
+        reduce_only: bool,
+        limit: u8,
+        expiry_type: ExpiryType,
+        self_trade_behavior: SelfTradeBehavior,
+    },
+
+    /// Initialize the Heartbeat PDA of a MangoAccount (`state::Heartbeat`). It starts disarmed.
+    ///
+    /// Accounts expected by this instruction (6):
+    /// 0. `[writable]` heartbeat_ai - PDA: `["heartbeat", mango_account]`
+    /// 1. `[]` mango_group_ai - MangoGroup
+    /// 2. `[]` mango_account_ai - the MangoAccount of owner
+    /// 3. `[signer, writable]` owner_ai - owner of MangoAccount, pays for the PDA
+    /// 4. `[]` rent_ai - Rent sysvar
+    /// 5. `[]` system_prog_ai - System program
+    InitHeartbeat,
+
+    /// Set the heartbeat deadline. Once it has passed anyone can cancel all perp orders of the
+    /// MangoAccount with ExpireHeartbeatOrders, until the deadline is moved again.
+    ///
+    /// Accounts expected by this instruction (4):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[]` mango_account_ai - the MangoAccount of owner
+    /// 2. `[signer]` owner_ai - owner or delegate of MangoAccount
+    /// 3. `[writable]` heartbeat_ai - Heartbeat PDA of the MangoAccount
+    SetHeartbeat {
+        /// Unix timestamp, 0 to disarm
+        deadline: u64,
+    },
+
+    /// Cancel the perp orders of a MangoAccount whose heartbeat deadline has passed, on each
+    /// of the given perp markets, and drop the hidden rest of its iceberg orders there.
+    /// Anyone can call this.
+    ///
+    /// Accounts expected by this instruction (4 + 3 * number of markets):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_account_ai - the MangoAccount whose orders are cancelled
+    /// 2. `[]` heartbeat_ai - Heartbeat PDA of the MangoAccount
+    /// 3. `[writable]` iceberg_orders_ai - PDA: `["iceberg_orders", mango_account]`; it only
+    ///                 needs to exist while the MangoAccount has active iceberg orders
+    /// 4.. `[writable]` perp_market_ai, bids_ai, asks_ai - for each perp market
+    ExpireHeartbeatOrders {
+        /// max number of orders to cancel per perp market
+        limit: u8,
+    },
+
+    /// Change the hard position caps of a perp market, enforced when placing perp orders,
+    /// taking perp OTC orders and liquidating perp positions regardless of health
+    ///
+    /// Accounts expected by this instruction (3):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` perp_market_ai - PerpMarket
+    /// 2. `[signer]` admin_ai - MangoGroup admin
+    ChangePerpMarketPositionLimits {
+        /// max base lots of a position, including open orders when placing orders; 0 disables
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        max_position_lots: Option<i64>,
+
+        /// max PerpMarket::open_interest in base lots, counting longs and shorts; 0 disables
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        max_open_interest: Option<i64>,
+    },
+
+    /// Close the perp position of an account with negative equity against profitable accounts
+    /// on the other side, at the price that makes up the loss on this market, instead of
+    /// leaving it for ResolvePerpBankruptcy. Only allowed if the insurance fund can't cover that
+    /// loss. Counterparties must be passed in descending order of unrealized pnl times leverage
+    /// (PerpAccount::adl_score); since that ranking is computed off chain, the admin must sign.
+    /// Each one gives up at most its unrealized pnl.
+    ///
+    /// Accounts expected: 6 + Liqee open orders accounts (MAX_PAIRS) + counterparty accounts
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[]` mango_cache_ai - MangoCache
+    /// 2. `[writable]` perp_market_ai - PerpMarket
+    /// 3. `[]` insurance_vault_ai - MangoGroup insurance vault
+    /// 4. `[writable]` liqee_mango_account_ai - MangoAccount
+    /// 5. `[signer]` admin_ai - MangoGroup admin
+    /// 6+... `[]` liqee_open_orders_ais - Liqee open orders accs
+    /// 6+MAX_PAIRS... `[writable]` counterparty_mango_account_ai, each followed by `[]` its
+    ///     open orders accounts for the markets in its margin basket
+    AutoDeleverage,
+
+    /// Grow a MangoGroup created before PerpMarketInfo had the index, risk and price band params
+    /// and move its data into the current layout. Oracle 0 keeps being cached as oracle 1 squared
+    /// unless DontSquare was set; the other new params start disabled.
+    ///
+    /// Accounts expected by this instruction (3):
+    /// 0. `[writable]` mango_group_ai - MangoGroup
+    /// 1. `[writable, signer]` admin_ai - MangoGroup admin, pays for the extra rent
+    /// 2. `[]` system_prog_ai - System program
+    UpgradeMangoGroup,
+
+    /// Grow a PerpMarket created before the funding, premium, expiry and limit fields were added.
+    /// The funding period becomes one week for market index 0 and one day otherwise, as before,
+    /// with the default impact quantity and funding clamps. Expiry and limits stay disabled.
+    ///
+    /// Accounts expected by this instruction (4):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` perp_market_ai - PerpMarket
+    /// 2. `[writable, signer]` admin_ai - MangoGroup admin, pays for the extra rent
+    /// 3. `[]` system_prog_ai - System program
+    UpgradePerpMarket,
+
+    /// Grow a MangoCache created before PriceCache had the EMA and circuit breaker state. The EMA
+    /// starts at the cached price and no price is halted.
+    ///
+    /// Accounts expected by this instruction (4):
+    /// 0. `[]` mango_group_ai - MangoGroup
+    /// 1. `[writable]` mango_cache_ai - MangoCache
+    /// 2. `[writable, signer]` admin_ai - MangoGroup admin, pays for the extra rent
+    /// 3. `[]` system_prog_ai - System program
+    UpgradeMangoCache,
 }
 
 impl MangoInstruction {
@@ -1498,7 +2008,7 @@ impl MangoInstruction {
                 MangoInstruction::ExecutePerpTriggerOrder { order_index }
             }
             46 => {
-                let data_arr = array_ref![data, 0, 148];
+                let data_arr = array_ref![data, 0, 174];
                 let (
                     maint_leverage,
                     init_leverage,
@@ -1515,7 +2025,13 @@ impl MangoInstruction {
                     version,
                     lm_size_shift,
                     base_decimals,
-                ) = array_refs![data_arr, 16, 16, 16, 16, 16, 8, 8, 16, 16, 8, 8, 1, 1, 1, 1];
+                    index_exponent,
+                    underlying_oracle_index,
+                    index_divisor,
+                    funding_period,
+                ) = array_refs![
+                    data_arr, 16, 16, 16, 16, 16, 8, 8, 16, 16, 8, 8, 1, 1, 1, 1, 1, 1, 16, 8
+                ];
                 MangoInstruction::CreatePerpMarket {
                     maint_leverage: I80F48::from_le_bytes(*maint_leverage),
                     init_leverage: I80F48::from_le_bytes(*init_leverage),
@@ -1532,9 +2048,61 @@ impl MangoInstruction {
                     version: version[0],
                     lm_size_shift: lm_size_shift[0],
                     base_decimals: base_decimals[0],
+                    index_exponent: index_exponent[0],
+                    underlying_oracle_index: underlying_oracle_index[0],
+                    index_divisor: I80F48::from_le_bytes(*index_divisor),
+                    funding_period: u64::from_le_bytes(*funding_period),
                 }
             }
             47 => {
+                // Optional to be backward compatible; defaults to None
+                let (index_exponent, underlying_oracle_index, index_divisor, funding_period) =
+                    if data.len() >= 173 {
+                        let ext_arr = array_ref![data, 143, 30];
+                        let (
+                            index_exponent,
+                            underlying_oracle_index,
+                            index_divisor,
+                            funding_period,
+                        ) = array_refs![ext_arr, 2, 2, 17, 9];
+                        (
+                            unpack_u8_opt(index_exponent),
+                            unpack_u8_opt(underlying_oracle_index),
+                            unpack_i80f48_opt(index_divisor),
+                            unpack_u64_opt(funding_period),
+                        )
+                    } else {
+                        (None, None, None, None)
+                    };
+                let (maint_shock_bps, init_shock_bps) = if data.len() >= 179 {
+                    let ext_arr = array_ref![data, 173, 6];
+                    let (maint_shock_bps, init_shock_bps) = array_refs![ext_arr, 3, 3];
+                    (unpack_u16_opt(maint_shock_bps), unpack_u16_opt(init_shock_bps))
+                } else {
+                    (None, None)
+                };
+                let (ema_period, liquidation_use_ema, funding_use_ema) = if data.len() >= 186 {
+                    let ext_arr = array_ref![data, 179, 7];
+                    let (ema_period, liquidation_use_ema, funding_use_ema) =
+                        array_refs![ext_arr, 3, 2, 2];
+                    (
+                        unpack_u16_opt(ema_period),
+                        unpack_u8_opt(liquidation_use_ema).map(|x| x != 0),
+                        unpack_u8_opt(funding_use_ema).map(|x| x != 0),
+                    )
+                } else {
+                    (None, None, None)
+                };
+                let (price_band_bps, price_band_clamp) = if data.len() >= 191 {
+                    let ext_arr = array_ref![data, 186, 5];
+                    let (price_band_bps, price_band_clamp) = array_refs![ext_arr, 3, 2];
+                    (
+                        unpack_u16_opt(price_band_bps),
+                        unpack_u8_opt(price_band_clamp).map(|x| x != 0),
+                    )
+                } else {
+                    (None, None)
+                };
                 let data_arr = array_ref![data, 0, 143];
                 let (
                     maint_leverage,
@@ -1564,6 +2132,17 @@ impl MangoInstruction {
                     exp: unpack_u8_opt(exp),
                     version: unpack_u8_opt(version),
                     lm_size_shift: unpack_u8_opt(lm_size_shift),
+                    index_exponent,
+                    underlying_oracle_index,
+                    index_divisor,
+                    funding_period,
+                    maint_shock_bps,
+                    init_shock_bps,
+                    ema_period,
+                    liquidation_use_ema,
+                    funding_use_ema,
+                    price_band_bps,
+                    price_band_clamp,
                 }
             }
             48 => MangoInstruction::UpdateMarginBasket,
@@ -1647,6 +2226,13 @@ impl MangoInstruction {
                     limit,
                 ) = array_refs![data_arr, 8, 8, 8, 8, 8, 1, 1, 1, 1];
                 let expiry_type_byte = if data.len() > 44 { data[44] } else { 0 };
+                let self_trade_behavior_byte = if data.len() > 45 {
+                    data[45]
+                } else {
+                    SelfTradeBehavior::AllowSelfTrade.into()
+                };
+                let display_quantity =
+                    if data.len() >= 54 { i64::from_le_bytes(*array_ref![data, 46, 8]) } else { 0 };
                 MangoInstruction::PlacePerpOrder2 {
                     price: i64::from_le_bytes(*price),
                     max_base_quantity: i64::from_le_bytes(*max_base_quantity),
@@ -1658,6 +2244,11 @@ impl MangoInstruction {
                     reduce_only: reduce_only[0] != 0,
                     limit: u8::from_le_bytes(*limit),
                     expiry_type: ExpiryType::try_from_primitive(expiry_type_byte).ok()?,
+                    self_trade_behavior: SelfTradeBehavior::try_from_primitive(
+                        self_trade_behavior_byte,
+                    )
+                    .ok()?,
+                    display_quantity,
                 }
             }
             65 => {
@@ -1698,32 +2289,238 @@ impl MangoInstruction {
                     open_orders_count: usize::from_le_bytes(*open_orders_count),
                 }
             }
-            _ => {
-                return None;
+            74 => {
+                let data_arr = array_ref![data, 0, 43];
+                let (impact_quantity, max_funding, min_funding) = array_refs![data_arr, 9, 17, 17];
+                MangoInstruction::ChangePerpMarketFundingParams {
+                    impact_quantity: unpack_i64_opt(impact_quantity),
+                    max_funding: unpack_i80f48_opt(max_funding),
+                    min_funding: unpack_i80f48_opt(min_funding),
+                }
             }
-        })
-    }
-    pub fn pack(&self) -> Vec<u8> {
-        bincode::serialize(self).unwrap()
-    }
-}
-
-fn unpack_u8_opt(data: &[u8; 2]) -> Option<u8> {
-    if data[0] == 0 {
-        None
-    } else {
-        Some(data[1])
-    }
-}
-
-fn unpack_i80f48_opt(data: &[u8; 17]) -> Option<I80F48> {
-    let (opt, val) = array_refs![data, 1, 16];
-    if opt[0] == 0 {
-        None
+            75 => {
+                let data_arr = array_ref![data, 0, 24];
+                let (source_index, window, min_sample_interval) = array_refs![data_arr, 8, 8, 8];
+                MangoInstruction::AddRealizedVolOracle {
+                    source_index: usize::from_le_bytes(*source_index),
+                    window: u64::from_le_bytes(*window),
+                    min_sample_interval: u64::from_le_bytes(*min_sample_interval),
+                }
+            }
+            76 => {
+                let data_arr = array_ref![data, 0, 16];
+                let (expiry, settlement_window) = array_refs![data_arr, 8, 8];
+                MangoInstruction::SetPerpMarketExpiry {
+                    expiry: u64::from_le_bytes(*expiry),
+                    settlement_window: u64::from_le_bytes(*settlement_window),
+                }
+            }
+            77 => MangoInstruction::SettleExpiredPerpMarket,
+            78 => MangoInstruction::SettleExpiredPosition,
+            79 => {
+                let data_arr = array_ref![data, 0, 11];
+                let (min_quorum, weights) = array_refs![data_arr, 1, 10];
+                let mut weights_out = [0u16; MAX_COMPOSITE_SOURCES];
+                for (i, w) in weights.chunks_exact(2).enumerate() {
+                    weights_out[i] = u16::from_le_bytes([w[0], w[1]]);
+                }
+                MangoInstruction::AddCompositeOracle {
+                    min_quorum: min_quorum[0],
+                    weights: weights_out,
+                }
+            }
+            80 => {
+                let data_arr = array_ref![data, 0, 12];
+                let (max_conf_bps, max_oracle_age_slots, price_valid_interval, max_price_move_bps) =
+                    array_refs![data_arr, 3, 3, 3, 3];
+                MangoInstruction::ChangeOracleLimits {
+                    max_conf_bps: unpack_u16_opt(max_conf_bps),
+                    max_oracle_age_slots: unpack_u16_opt(max_oracle_age_slots),
+                    price_valid_interval: unpack_u16_opt(price_valid_interval),
+                    max_price_move_bps: unpack_u16_opt(max_price_move_bps),
+                }
+            }
+            81 => MangoInstruction::ClearPriceHalt,
+            82 => {
+                let data_arr = array_ref![data, 0, 34];
+                let (base_index, base_exponent, quote_index, quote_exponent, multiplier) =
+                    array_refs![data_arr, 8, 1, 8, 1, 16];
+                MangoInstruction::AddDerivedOracle {
+                    base_index: usize::from_le_bytes(*base_index),
+                    base_exponent: base_exponent[0],
+                    quote_index: usize::from_le_bytes(*quote_index),
+                    quote_exponent: quote_exponent[0],
+                    multiplier: I80F48::from_le_bytes(*multiplier),
+                }
+            }
+            83 => {
+                let data_arr = array_ref![data, 0, 33];
+                let (order_id, new_price, new_quantity, limit) = array_refs![data_arr, 16, 8, 8, 1];
+                MangoInstruction::EditPerpOrder {
+                    order_id: i128::from_le_bytes(*order_id),
+                    new_price: i64::from_le_bytes(*new_price),
+                    new_quantity: i64::from_le_bytes(*new_quantity),
+                    limit: limit[0],
+                }
+            }
+            84 => {
+                let (limit, data) = array_refs![data, 1; ..;];
+                let (cancel_order_ids, data) =
+                    unpack_vec(data, 16, |b| Some(i128::from_le_bytes(*array_ref![b, 0, 16])))?;
+                let (cancel_client_order_ids, data) =
+                    unpack_vec(data, 8, |b| Some(u64::from_le_bytes(*array_ref![b, 0, 8])))?;
+                let (orders, _) = unpack_vec(data, 28, |b| {
+                    let (price, max_base_quantity, client_order_id, side, order_type, tif, stb) =
+                        array_refs![array_ref![b, 0, 28], 8, 8, 8, 1, 1, 1, 1];
+                    Some(BatchPerpOrder {
+                        price: i64::from_le_bytes(*price),
+                        max_base_quantity: i64::from_le_bytes(*max_base_quantity),
+                        client_order_id: u64::from_le_bytes(*client_order_id),
+                        side: Side::try_from_primitive(side[0]).ok()?,
+                        order_type: OrderType::try_from_primitive(order_type[0]).ok()?,
+                        time_in_force: tif[0],
+                        self_trade_behavior: SelfTradeBehavior::try_from_primitive(stb[0]).ok()?,
+                    })
+                })?;
+                MangoInstruction::BatchPerpOrders {
+                    limit: limit[0],
+                    cancel_order_ids,
+                    cancel_client_order_ids,
+                    orders,
+                }
+            }
+            85 => MangoInstruction::InitIcebergOrders,
+            86 => {
+                let data_arr = array_ref![data, 0, 1];
+                MangoInstruction::RefillIcebergOrders { limit: data_arr[0] }
+            }
+            87 => {
+                let data_arr = array_ref![data, 0, 1];
+                MangoInstruction::CancelIcebergOrder { index: data_arr[0] }
+            }
+            88 => {
+                let data_arr = array_ref![data, 0, 54];
+                let (
+                    price_offset,
+                    peg_limit,
+                    max_base_quantity,
+                    max_quote_quantity,
+                    client_order_id,
+                    expiry_timestamp,
+                    side,
+                    order_type,
+                    reduce_only,
+                    limit,
+                    expiry_type,
+                    self_trade_behavior,
+                ) = array_refs![data_arr, 8, 8, 8, 8, 8, 8, 1, 1, 1, 1, 1, 1];
+                MangoInstruction::PlacePerpPeggedOrder {
+                    price_offset: i64::from_le_bytes(*price_offset),
+                    peg_limit: i64::from_le_bytes(*peg_limit),
+                    max_base_quantity: i64::from_le_bytes(*max_base_quantity),
+                    max_quote_quantity: i64::from_le_bytes(*max_quote_quantity),
+                    client_order_id: u64::from_le_bytes(*client_order_id),
+                    expiry_timestamp: u64::from_le_bytes(*expiry_timestamp),
+                    side: Side::try_from_primitive(side[0]).ok()?,
+                    order_type: OrderType::try_from_primitive(order_type[0]).ok()?,
+                    reduce_only: reduce_only[0] != 0,
+                    limit: limit[0],
+                    expiry_type: ExpiryType::try_from_primitive(expiry_type[0]).ok()?,
+                    self_trade_behavior: SelfTradeBehavior::try_from_primitive(
+                        self_trade_behavior[0],
+                    )
+                    .ok()?,
+                }
+            }
+            89 => MangoInstruction::InitHeartbeat,
+            90 => {
+                let data_arr = array_ref![data, 0, 8];
+                MangoInstruction::SetHeartbeat { deadline: u64::from_le_bytes(*data_arr) }
+            }
+            91 => {
+                let data_arr = array_ref![data, 0, 1];
+                MangoInstruction::ExpireHeartbeatOrders { limit: data_arr[0] }
+            }
+            92 => {
+                let data_arr = array_ref![data, 0, 18];
+                let (max_position_lots, max_open_interest) = array_refs![data_arr, 9, 9];
+                MangoInstruction::ChangePerpMarketPositionLimits {
+                    max_position_lots: unpack_i64_opt(max_position_lots),
+                    max_open_interest: unpack_i64_opt(max_open_interest),
+                }
+            }
+            93 => MangoInstruction::AutoDeleverage,
+            94 => MangoInstruction::UpgradeMangoGroup,
+            95 => MangoInstruction::UpgradePerpMarket,
+            96 => MangoInstruction::UpgradeMangoCache,
+            _ => {
+                return None;
+            }
+        })
+    }
+    pub fn pack(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+}
+
+/// A new order of BatchPerpOrders
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BatchPerpOrder {
+    /// price in quote lots per base lot
+    pub price: i64,
+    pub max_base_quantity: i64,
+    pub client_order_id: u64,
+    pub side: Side,
+    pub order_type: OrderType,
+    /// seconds until the order expires; 0 to never expire
+    pub time_in_force: u8,
+    /// same as for PlacePerpOrder2
+    pub self_trade_behavior: SelfTradeBehavior,
+}
+
+/// Unpack a bincode Vec of fixed width elements, returning it and the remaining data
+fn unpack_vec<T>(
+    data: &[u8],
+    width: usize,
+    unpack_one: impl Fn(&[u8]) -> Option<T>,
+) -> Option<(Vec<T>, &[u8])> {
+    if data.len() < 8 {
+        return None;
+    }
+    let (len, data) = data.split_at(8);
+    let size = usize::from_le_bytes(len.try_into().ok()?).checked_mul(width)?;
+    if data.len() < size {
+        return None;
+    }
+    let (items, rest) = data.split_at(size);
+    Some((items.chunks_exact(width).map(unpack_one).collect::<Option<Vec<T>>>()?, rest))
+}
+
+fn unpack_u8_opt(data: &[u8; 2]) -> Option<u8> {
+    if data[0] == 0 {
+        None
+    } else {
+        Some(data[1])
+    }
+}
+
+fn unpack_i80f48_opt(data: &[u8; 17]) -> Option<I80F48> {
+    let (opt, val) = array_refs![data, 1, 16];
+    if opt[0] == 0 {
+        None
     } else {
         Some(I80F48::from_le_bytes(*val))
     }
 }
+fn unpack_u16_opt(data: &[u8; 3]) -> Option<u16> {
+    let (opt, val) = array_refs![data, 1, 2];
+    if opt[0] == 0 {
+        None
+    } else {
+        Some(u16::from_le_bytes(*val))
+    }
+}
+
 fn unpack_u64_opt(data: &[u8; 9]) -> Option<u64> {
     let (opt, val) = array_refs![data, 1, 8];
     if opt[0] == 0 {
@@ -1733,6 +2530,15 @@ fn unpack_u64_opt(data: &[u8; 9]) -> Option<u64> {
     }
 }
 
+fn unpack_i64_opt(data: &[u8; 9]) -> Option<i64> {
+    let (opt, val) = array_refs![data, 1, 8];
+    if opt[0] == 0 {
+        None
+    } else {
+        Some(i64::from_le_bytes(*val))
+    }
+}
+
 fn unpack_dex_new_order_v3(data: &[u8]) -> Option<serum_dex::instruction::NewOrderInstructionV3> {
     let max_ts =
         if data.len() == 54 { i64::from_le_bytes(*array_ref![data, 46, 8]) } else { i64::MAX };
@@ -2115,6 +2921,8 @@ pub fn place_perp_order2(
     expiry_timestamp: Option<u64>, // Send 0 if you want to ignore time in force
     limit: u8,                     // maximum number of FillEvents before terminating
     expiry_type: ExpiryType,
+    self_trade_behavior: SelfTradeBehavior,
+    display_quantity: i64, // 0 for a normal order
 ) -> Result<Instruction, ProgramError> {
     let mut accounts = vec![
         AccountMeta::new_readonly(*mango_group_pk, false),
@@ -2129,6 +2937,13 @@ pub fn place_perp_order2(
     ];
 
     accounts.extend(open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+    if display_quantity > 0 {
+        let (iceberg_orders_pk, _) = Pubkey::find_program_address(
+            &[utils::ICEBERG_ORDERS_PREFIX.as_bytes(), mango_account_pk.as_ref()],
+            program_id,
+        );
+        accounts.push(AccountMeta::new(iceberg_orders_pk, false));
+    }
 
     let instr = MangoInstruction::PlacePerpOrder2 {
         side,
@@ -2141,6 +2956,8 @@ pub fn place_perp_order2(
         expiry_timestamp: expiry_timestamp.unwrap_or(0),
         limit,
         expiry_type,
+        self_trade_behavior,
+        display_quantity,
     };
     let data = instr.pack();
 
@@ -3285,6 +4102,595 @@ pub fn take_perp_otc_order(
     Ok(Instruction { program_id: *program_id, accounts, data })
 }
 
+pub fn change_perp_market_funding_params(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    impact_quantity: Option<i64>,
+    max_funding: Option<I80F48>,
+    min_funding: Option<I80F48>,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
+    ];
+
+    let instr = MangoInstruction::ChangePerpMarketFundingParams {
+        impact_quantity,
+        max_funding,
+        min_funding,
+    };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn add_realized_vol_oracle(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    oracle_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    source_index: usize,
+    window: u64,
+    min_sample_interval: u64,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*mango_group_pk, false),
+        AccountMeta::new(*oracle_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
+    ];
+
+    let instr =
+        MangoInstruction::AddRealizedVolOracle { source_index, window, min_sample_interval };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn set_perp_market_expiry(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    expiry: u64,
+    settlement_window: u64,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
+    ];
+
+    let instr = MangoInstruction::SetPerpMarketExpiry { expiry, settlement_window };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn settle_expired_perp_market(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*mango_group_pk, false),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+    ];
+
+    let instr = MangoInstruction::SettleExpiredPerpMarket;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn settle_expired_position(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+    ];
+
+    let instr = MangoInstruction::SettleExpiredPosition;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn add_composite_oracle(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    oracle_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    source_pks: &[Pubkey],
+    min_quorum: u8,
+    weights: [u16; MAX_COMPOSITE_SOURCES],
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new(*mango_group_pk, false),
+        AccountMeta::new(*oracle_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
+    ];
+    accounts.extend(source_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+
+    let instr = MangoInstruction::AddCompositeOracle { min_quorum, weights };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn change_oracle_limits(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    oracle_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    max_conf_bps: Option<u16>,
+    max_oracle_age_slots: Option<u16>,
+    price_valid_interval: Option<u16>,
+    max_price_move_bps: Option<u16>,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*mango_group_pk, false),
+        AccountMeta::new_readonly(*oracle_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
+    ];
+
+    let instr = MangoInstruction::ChangeOracleLimits {
+        max_conf_bps,
+        max_oracle_age_slots,
+        price_valid_interval,
+        max_price_move_bps,
+    };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn clear_price_halt(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    oracle_pk: &Pubkey,
+    admin_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_cache_pk, false),
+        AccountMeta::new_readonly(*oracle_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
+    ];
+
+    let instr = MangoInstruction::ClearPriceHalt;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn add_derived_oracle(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    oracle_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    base_index: usize,
+    base_exponent: u8,
+    quote_index: usize,
+    quote_exponent: u8,
+    multiplier: I80F48,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*mango_group_pk, false),
+        AccountMeta::new(*oracle_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
+    ];
+
+    let instr = MangoInstruction::AddDerivedOracle {
+        base_index,
+        base_exponent,
+        quote_index,
+        quote_exponent,
+        multiplier,
+    };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn edit_perp_order(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    bids_pk: &Pubkey,
+    asks_pk: &Pubkey,
+    event_queue_pk: &Pubkey,
+    referrer_mango_account_pk: Option<&Pubkey>,
+    open_orders_pks: &[Pubkey],
+    order_id: i128,
+    new_price: i64,
+    new_quantity: i64,
+    limit: u8,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new(*event_queue_pk, false),
+        AccountMeta::new(*referrer_mango_account_pk.unwrap_or(mango_account_pk), false),
+    ];
+
+    accounts.extend(open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+
+    let instr = MangoInstruction::EditPerpOrder { order_id, new_price, new_quantity, limit };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn batch_perp_orders(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    bids_pk: &Pubkey,
+    asks_pk: &Pubkey,
+    event_queue_pk: &Pubkey,
+    referrer_mango_account_pk: Option<&Pubkey>,
+    open_orders_pks: &[Pubkey],
+    limit: u8,
+    cancel_order_ids: Vec<i128>,
+    cancel_client_order_ids: Vec<u64>,
+    orders: Vec<BatchPerpOrder>,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new(*event_queue_pk, false),
+        AccountMeta::new(*referrer_mango_account_pk.unwrap_or(mango_account_pk), false),
+    ];
+
+    accounts.extend(open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+
+    let instr = MangoInstruction::BatchPerpOrders {
+        limit,
+        cancel_order_ids,
+        cancel_client_order_ids,
+        orders,
+    };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn init_iceberg_orders(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let (iceberg_orders_pk, _) = Pubkey::find_program_address(
+        &[utils::ICEBERG_ORDERS_PREFIX.as_bytes(), mango_account_pk.as_ref()],
+        program_id,
+    );
+
+    let accounts = vec![
+        AccountMeta::new(iceberg_orders_pk, false),
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new_readonly(*mango_account_pk, false),
+        AccountMeta::new(*owner_pk, true),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+
+    let instr = MangoInstruction::InitIcebergOrders;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn refill_iceberg_orders(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    bids_pk: &Pubkey,
+    asks_pk: &Pubkey,
+    event_queue_pk: &Pubkey,
+    iceberg_orders_pk: &Pubkey,
+    open_orders_pks: &[Pubkey],
+    limit: u8,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new(*event_queue_pk, false),
+        AccountMeta::new(*iceberg_orders_pk, false),
+    ];
+
+    accounts.extend(open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+
+    let instr = MangoInstruction::RefillIcebergOrders { limit };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn cancel_iceberg_order(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    bids_pk: &Pubkey,
+    asks_pk: &Pubkey,
+    iceberg_orders_pk: &Pubkey,
+    index: u8,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new(*iceberg_orders_pk, false),
+    ];
+
+    let instr = MangoInstruction::CancelIcebergOrder { index };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn place_perp_pegged_order(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    bids_pk: &Pubkey,
+    asks_pk: &Pubkey,
+    event_queue_pk: &Pubkey,
+    referrer_mango_account_pk: Option<&Pubkey>,
+    open_orders_pks: &[Pubkey],
+    side: Side,
+    price_offset: i64,
+    peg_limit: i64, // -1 for no limit
+    max_base_quantity: i64,
+    max_quote_quantity: i64,
+    client_order_id: u64,
+    order_type: OrderType,
+    reduce_only: bool,
+    expiry_timestamp: Option<u64>, // Send 0 if you want to ignore time in force
+    limit: u8,                     // maximum number of FillEvents before terminating
+    expiry_type: ExpiryType,
+    self_trade_behavior: SelfTradeBehavior,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new(*event_queue_pk, false),
+        AccountMeta::new(*referrer_mango_account_pk.unwrap_or(mango_account_pk), false),
+    ];
+
+    accounts.extend(open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+
+    let instr = MangoInstruction::PlacePerpPeggedOrder {
+        price_offset,
+        peg_limit,
+        max_base_quantity,
+        max_quote_quantity,
+        client_order_id,
+        expiry_timestamp: expiry_timestamp.unwrap_or(0),
+        side,
+        order_type,
+        reduce_only,
+        limit,
+        expiry_type,
+        self_trade_behavior,
+    };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn init_heartbeat(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let (heartbeat_pk, _) = Pubkey::find_program_address(
+        &[utils::HEARTBEAT_PREFIX.as_bytes(), mango_account_pk.as_ref()],
+        program_id,
+    );
+
+    let accounts = vec![
+        AccountMeta::new(heartbeat_pk, false),
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new_readonly(*mango_account_pk, false),
+        AccountMeta::new(*owner_pk, true),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+
+    let instr = MangoInstruction::InitHeartbeat;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn set_heartbeat(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    heartbeat_pk: &Pubkey,
+    deadline: u64,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new_readonly(*mango_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new(*heartbeat_pk, false),
+    ];
+
+    let instr = MangoInstruction::SetHeartbeat { deadline };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn expire_heartbeat_orders(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_account_pk: &Pubkey,
+    heartbeat_pk: &Pubkey,
+    perp_market_pks: &[(Pubkey, Pubkey, Pubkey)], // (perp_market, bids, asks)
+    limit: u8,
+) -> Result<Instruction, ProgramError> {
+    let (iceberg_orders_pk, _) = Pubkey::find_program_address(
+        &[utils::ICEBERG_ORDERS_PREFIX.as_bytes(), mango_account_pk.as_ref()],
+        program_id,
+    );
+
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_account_pk, false),
+        AccountMeta::new_readonly(*heartbeat_pk, false),
+        AccountMeta::new(iceberg_orders_pk, false),
+    ];
+
+    for (perp_market_pk, bids_pk, asks_pk) in perp_market_pks {
+        accounts.push(AccountMeta::new(*perp_market_pk, false));
+        accounts.push(AccountMeta::new(*bids_pk, false));
+        accounts.push(AccountMeta::new(*asks_pk, false));
+    }
+
+    let instr = MangoInstruction::ExpireHeartbeatOrders { limit };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn change_perp_market_position_limits(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    max_position_lots: Option<i64>,
+    max_open_interest: Option<i64>,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
+    ];
+
+    let instr =
+        MangoInstruction::ChangePerpMarketPositionLimits { max_position_lots, max_open_interest };
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn auto_deleverage(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    insurance_vault_pk: &Pubkey,
+    liqee_mango_account_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    liqee_open_orders_pks: &[Pubkey],
+    counterparties: &[(Pubkey, Vec<Pubkey>)], // (mango account, packed open orders)
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new_readonly(*mango_cache_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new_readonly(*insurance_vault_pk, false),
+        AccountMeta::new(*liqee_mango_account_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
+    ];
+
+    accounts.extend(liqee_open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+    for (mango_account_pk, open_orders_pks) in counterparties {
+        accounts.push(AccountMeta::new(*mango_account_pk, false));
+        accounts.extend(open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+    }
+
+    let instr = MangoInstruction::AutoDeleverage;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn upgrade_mango_group(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    admin_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*mango_group_pk, false),
+        AccountMeta::new(*admin_pk, true),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+
+    let instr = MangoInstruction::UpgradeMangoGroup;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn upgrade_perp_market(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    admin_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*admin_pk, true),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+
+    let instr = MangoInstruction::UpgradePerpMarket;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
+pub fn upgrade_mango_cache(
+    program_id: &Pubkey,
+    mango_group_pk: &Pubkey,
+    mango_cache_pk: &Pubkey,
+    admin_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*mango_group_pk, false),
+        AccountMeta::new(*mango_cache_pk, false),
+        AccountMeta::new(*admin_pk, true),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+
+    let instr = MangoInstruction::UpgradeMangoCache;
+    let data = instr.pack();
+    Ok(Instruction { program_id: *program_id, accounts, data })
+}
+
 /// Serialize Option<T> as (bool, T). This gives the binary representation
 /// a fixed width, instead of it becoming one byte for None.
 fn serialize_option_fixed_width<S: serde::Serializer, T: Sized + Default + Serialize>(
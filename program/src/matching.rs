@@ -1,8 +1,9 @@
 use std::cell::RefMut;
+use std::cmp::Reverse;
 use std::convert::TryFrom;
 use std::mem::size_of;
 
-use bytemuck::{cast, cast_mut, cast_ref};
+use bytemuck::{bytes_of, cast, cast_mut, cast_ref, from_bytes};
 use fixed::types::I80F48;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use serde::{Deserialize, Serialize};
@@ -36,6 +37,10 @@ const NODE_SIZE: usize = 88;
 /// This exists as a guard against excessive compute use.
 const DROP_EXPIRED_ORDER_LIMIT: usize = 5;
 
+/// Most OutEvents posting a PostOnly order pushes: the expired orders it drops on the other
+/// side, plus an expired and an evicted order on its own side
+pub const MAX_POST_ONLY_EVENTS: usize = DROP_EXPIRED_ORDER_LIMIT + 2;
+
 #[derive(IntoPrimitive, TryFromPrimitive)]
 #[repr(u32)]
 pub enum NodeTag {
@@ -108,6 +113,10 @@ pub struct LeafNode {
     pub tag: u32,
     pub owner_slot: u8,
     pub order_type: OrderType, // this was added for TradingView move order
+
+    /// PerpMarket version at the time the order was placed, with GOOD_TILL_DATE_FLAG set for
+    /// GoodTillDate orders. Since it then never equals the market's version, those orders earn
+    /// no liquidity incentives.
     pub version: u8,
 
     /// Time in seconds after `timestamp` at which the order expires.
@@ -123,16 +132,29 @@ pub struct LeafNode {
 
     // Liquidity incentive related parameters
     // Either the best bid or best ask at the time the order was placed
+    // For oracle pegged orders this is the peg limit instead (-1 for none) and for GoodTillDate
+    // orders the absolute expiry; neither earns liquidity incentives
     pub best_initial: i64,
 
     // The time the order was placed
     pub timestamp: u64,
 }
 
+/// Set in `LeafNode::version` for orders with an absolute expiry stored in `best_initial`
+pub const GOOD_TILL_DATE_FLAG: u8 = 1 << 7;
+
 #[inline(always)]
 fn key_to_price(key: i128) -> i64 {
     (key >> 64) as i64
 }
+
+/// Oracle pegged order keys have bit 62 flipped so that bits 62 and 63 differ. For fixed price
+/// orders both bits are equal since the sequence number stays far below 2^62.
+#[inline(always)]
+pub fn is_oracle_pegged_key(key: i128) -> bool {
+    let low = key as u64;
+    (low >> 63) != ((low >> 62) & 1)
+}
 impl LeafNode {
     pub fn new(
         version: u8,
@@ -161,15 +183,51 @@ impl LeafNode {
         }
     }
 
+    /// Price in quote lots per base lot; meaningless for oracle pegged orders
     #[inline(always)]
     pub fn price(&self) -> i64 {
         key_to_price(self.key)
     }
 
+    #[inline(always)]
+    pub fn is_oracle_pegged(&self) -> bool {
+        is_oracle_pegged_key(self.key)
+    }
+
+    /// Offset to the oracle price in quote lots of an oracle pegged order. The key stores it
+    /// with the sign bit flipped so keys sort by offset.
+    #[inline(always)]
+    pub fn price_offset(&self) -> i64 {
+        (((self.key as u128) >> 64) as u64 ^ (1 << 63)) as i64
+    }
+
+    /// Current price of an oracle pegged order, None while it would cross its peg limit
+    pub fn oracle_pegged_price(&self, side: Side, oracle_price_lots: i64) -> Option<i64> {
+        let price = oracle_price_lots.saturating_add(self.price_offset());
+        let peg_limit = self.best_initial;
+        let crosses_limit = peg_limit != -1
+            && match side {
+                Side::Bid => price > peg_limit,
+                Side::Ask => price < peg_limit,
+            };
+        if price < 1 || crosses_limit {
+            None
+        } else {
+            Some(price)
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_good_till_date(&self) -> bool {
+        self.version & GOOD_TILL_DATE_FLAG != 0
+    }
+
     /// Time at which this order will expire, u64::MAX if never
     #[inline(always)]
     pub fn expiry(&self) -> u64 {
-        if self.time_in_force == 0 {
+        if self.is_good_till_date() {
+            self.best_initial as u64
+        } else if self.time_in_force == 0 {
             u64::MAX
         } else {
             self.timestamp + self.time_in_force as u64
@@ -178,7 +236,12 @@ impl LeafNode {
 
     #[inline(always)]
     pub fn is_valid(&self, now_ts: u64) -> bool {
-        self.time_in_force == 0 || now_ts < self.timestamp + self.time_in_force as u64
+        now_ts < self.expiry()
+    }
+
+    /// Whether Book::edit_order modifies this order in place, keeping its time priority
+    pub fn edits_in_place(&self, new_price: i64, new_quantity: i64) -> bool {
+        new_price == self.price() && new_quantity <= self.quantity
     }
 }
 
@@ -323,6 +386,11 @@ pub enum OrderType {
     /// If existing orders match with this order, adjust the price to just barely
     /// not match. Always places an order on the book.
     PostOnlySlide = 4,
+
+    /// Take existing orders up to price, max_base_quantity and max_quote_quantity only if
+    /// all of max_base_quantity can be filled, otherwise fail without touching the book.
+    /// Never place an order on the book.
+    FillOrKill = 5,
 }
 
 #[derive(
@@ -351,13 +419,64 @@ pub enum ExpiryType {
     ///
     /// Must be between 1 and 255.
     Relative,
+
+    /// Expire at exactly the given block time, however far in the future.
+    ///
+    /// Orders with an expiry in the past are ignored. These orders earn no liquidity mining
+    /// incentives and can't be oracle pegged or iceberg orders.
+    GoodTillDate,
+}
+
+/// What to do when an order would match against a resting order of the same MangoAccount
+#[derive(
+    Eq, PartialEq, Copy, Clone, TryFromPrimitive, IntoPrimitive, Debug, Serialize, Deserialize,
+)]
+#[repr(u8)]
+#[serde(into = "u8", try_from = "u8")]
+pub enum SelfTradeBehavior {
+    /// Reduce both orders by the overlapping quantity without a fill or fees
+    DecrementTake = 0,
+
+    /// Cancel the resting order and continue matching against the rest of the book
+    CancelProvide = 1,
+
+    /// Fail the instruction
+    AbortTransaction = 2,
+
+    /// Match it like any other order, with a FillEvent that has the MangoAccount on both sides.
+    /// This is what orders did before self-trade prevention and what PlacePerpOrder and trigger
+    /// orders still do
+    AllowSelfTrade = 3,
 }
 
 pub const MAX_BOOK_NODES: usize = 1024; // NOTE: this cannot be larger than u32::MAX
 
+/// The two binary trees of a BookSide. They share the node storage.
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum BookSideOrderTree {
+    /// Keyed on the price
+    Fixed,
+
+    /// Keyed on the offset to the oracle price
+    OraclePegged,
+}
+
+impl BookSideOrderTree {
+    #[inline(always)]
+    pub fn of_key(key: i128) -> Self {
+        if is_oracle_pegged_key(key) {
+            Self::OraclePegged
+        } else {
+            Self::Fixed
+        }
+    }
+}
+
 /// A binary tree on AnyNode::key()
 ///
-/// The key encodes the price in the top 64 bits.
+/// The key encodes the price in the top 64 bits. Oracle pegged orders live in a second tree
+/// whose root is kept in the space of the last node; the key encodes the offset to the
+/// oracle price instead.
 #[derive(Copy, Clone, Pod, Loadable)]
 #[repr(C)]
 pub struct BookSide {
@@ -368,8 +487,14 @@ pub struct BookSide {
     free_list_head: NodeHandle,
     root_node: NodeHandle,
     leaf_count: usize,
-    nodes: [AnyNode; MAX_BOOK_NODES],
+    nodes: [AnyNode; MAX_BOOK_NODES - 1],
+
+    oracle_pegged_root_node: NodeHandle,
+    padding0: [u8; 4],
+    oracle_pegged_leaf_count: usize,
+    padding1: [u8; NODE_SIZE - 16],
 }
+const_assert_eq!(size_of::<BookSide>(), 40 + MAX_BOOK_NODES * NODE_SIZE);
 
 /// Iterate over orders in order (bids=descending, asks=ascending)
 pub struct BookSideIter<'a> {
@@ -388,13 +513,17 @@ pub struct BookSideIter<'a> {
 
 impl<'a> BookSideIter<'a> {
     pub fn new(book_side: &'a BookSide, now_ts: u64) -> Self {
+        Self::new_in(book_side, BookSideOrderTree::Fixed, now_ts)
+    }
+
+    pub fn new_in(book_side: &'a BookSide, tree: BookSideOrderTree, now_ts: u64) -> Self {
         let (left, right) =
             if book_side.meta_data.data_type == DataType::Bids as u8 { (1, 0) } else { (0, 1) };
         let stack = vec![];
 
         let mut iter = Self { book_side, stack, next_leaf: None, left, right, now_ts };
-        if book_side.leaf_count != 0 {
-            iter.next_leaf = iter.find_leftmost_valid_leaf(book_side.root_node);
+        if let Some(root) = book_side.root_in(tree) {
+            iter.next_leaf = iter.find_leftmost_valid_leaf(root);
         }
         iter
     }
@@ -453,6 +582,72 @@ impl<'a> Iterator for BookSideIter<'a> {
     }
 }
 
+/// Iterate over the orders of both trees in matching order, together with the price they
+/// currently match at. Oracle pegged orders that would cross their peg limit are skipped.
+/// On equal prices fixed price orders come first.
+pub struct BookSideOrdersIter<'a> {
+    fixed: BookSideIter<'a>,
+    oracle_pegged: BookSideIter<'a>,
+    next_fixed: Option<(NodeHandle, &'a LeafNode, i64)>,
+    next_oracle_pegged: Option<(NodeHandle, &'a LeafNode, i64)>,
+    side: Side,
+    oracle_price_lots: i64,
+}
+
+impl<'a> BookSideOrdersIter<'a> {
+    pub fn new(book_side: &'a BookSide, oracle_price_lots: i64, now_ts: u64) -> Self {
+        let side = if book_side.meta_data.data_type == DataType::Bids as u8 {
+            Side::Bid
+        } else {
+            Side::Ask
+        };
+        let mut iter = Self {
+            fixed: BookSideIter::new_in(book_side, BookSideOrderTree::Fixed, now_ts),
+            oracle_pegged: BookSideIter::new_in(book_side, BookSideOrderTree::OraclePegged, now_ts),
+            next_fixed: None,
+            next_oracle_pegged: None,
+            side,
+            oracle_price_lots,
+        };
+        iter.next_fixed = iter.fixed.next().map(|(h, leaf)| (h, leaf, leaf.price()));
+        iter.next_oracle_pegged = iter.find_next_oracle_pegged();
+        iter
+    }
+
+    fn find_next_oracle_pegged(&mut self) -> Option<(NodeHandle, &'a LeafNode, i64)> {
+        let (side, oracle_price_lots) = (self.side, self.oracle_price_lots);
+        self.oracle_pegged.find_map(|(h, leaf)| {
+            leaf.oracle_pegged_price(side, oracle_price_lots).map(|price| (h, leaf, price))
+        })
+    }
+}
+
+impl<'a> Iterator for BookSideOrdersIter<'a> {
+    type Item = (NodeHandle, &'a LeafNode, i64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let take_fixed = match (self.next_fixed, self.next_oracle_pegged) {
+            (None, None) => return None,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (Some((_, _, fixed_price)), Some((_, _, pegged_price))) => match self.side {
+                Side::Bid => fixed_price >= pegged_price,
+                Side::Ask => fixed_price <= pegged_price,
+            },
+        };
+
+        if take_fixed {
+            let current = self.next_fixed;
+            self.next_fixed = self.fixed.next().map(|(h, leaf)| (h, leaf, leaf.price()));
+            current
+        } else {
+            let current = self.next_oracle_pegged;
+            self.next_oracle_pegged = self.find_next_oracle_pegged();
+            current
+        }
+    }
+}
+
 impl BookSide {
     #[deprecated(
         since = "3.4.0",
@@ -475,14 +670,33 @@ impl BookSide {
         BookSideIter::new(self, 0)
     }
 
+    /// Iterate over the valid orders of both trees with their current price
+    pub fn iter_valid_with_price(&self, oracle_price_lots: i64, now_ts: u64) -> BookSideOrdersIter {
+        BookSideOrdersIter::new(self, oracle_price_lots, now_ts)
+    }
+
+    /// Iterate over the orders of both trees with their current price, including invalid orders
+    pub fn iter_all_including_invalid_with_price(
+        &self,
+        oracle_price_lots: i64,
+    ) -> BookSideOrdersIter {
+        BookSideOrdersIter::new(self, oracle_price_lots, 0)
+    }
+
     pub fn load_mut_checked<'a>(
         account: &'a AccountInfo,
         program_id: &Pubkey,
         perp_market: &PerpMarket,
     ) -> MangoResult<RefMut<'a, Self>> {
         check!(account.owner == program_id, MangoErrorCode::InvalidOwner)?;
-        let state = Self::load_mut(account)?;
+        let mut state = Self::load_mut(account)?;
         check!(state.meta_data.is_initialized, MangoErrorCode::Default)?;
+        // Books created before oracle pegged orders may use the last node slot, which now holds
+        // the oracle pegged tree root. Free it the first time the book is writable.
+        // Read only loads read that slot in place, see `get`.
+        if !state.has_oracle_pegged_tree() && account.is_writable {
+            state.free_last_node()?;
+        }
 
         match DataType::try_from(state.meta_data.data_type).unwrap() {
             DataType::Bids => check!(account.key == &perp_market.bids, MangoErrorCode::Default)?,
@@ -512,6 +726,70 @@ impl BookSide {
         Ok(state)
     }
 
+    /// False for books created before oracle pegged orders that used the last node slot. Their
+    /// oracle pegged tree is empty until `free_last_node` ran.
+    fn has_oracle_pegged_tree(&self) -> bool {
+        self.bump_index <= self.nodes.len()
+    }
+
+    /// Move the node in the last slot into a free slot, so the slot can hold the oracle pegged
+    /// tree root
+    fn free_last_node(&mut self) -> MangoResult {
+        let last = self.nodes.len() as NodeHandle;
+        let node = *self.legacy_last_node();
+
+        match NodeTag::try_from(node.tag) {
+            Ok(NodeTag::InnerNode) | Ok(NodeTag::LeafNode) => {
+                // A single tree with n leaves uses 2n - 1 slots, so another slot is free
+                let handle = self.insert(&node)?;
+                if self.root_node == last {
+                    self.root_node = handle;
+                } else {
+                    for other in self.nodes.iter_mut() {
+                        if let Some(inner) = other.as_inner_mut() {
+                            for child in inner.children.iter_mut().filter(|c| **c == last) {
+                                *child = handle;
+                            }
+                        }
+                    }
+                }
+            }
+            Ok(NodeTag::FreeNode) | Ok(NodeTag::LastFreeNode) => {
+                let next = cast_ref::<AnyNode, FreeNode>(&node).next;
+                let was_tail = node.tag == u32::from(NodeTag::LastFreeNode);
+                if self.free_list_head == last {
+                    self.free_list_head = next;
+                } else {
+                    let mut handle = self.free_list_head;
+                    loop {
+                        let free: &mut FreeNode = cast_mut(&mut self.nodes[handle as usize]);
+                        if free.next == last {
+                            free.next = next;
+                            if was_tail {
+                                free.tag = NodeTag::LastFreeNode.into();
+                            }
+                            break;
+                        }
+                        check!(
+                            free.tag == u32::from(NodeTag::FreeNode),
+                            MangoErrorCode::InvalidAccountState
+                        )?;
+                        handle = free.next;
+                    }
+                }
+                self.free_list_len -= 1;
+            }
+            _ => {}
+        }
+
+        self.bump_index = self.nodes.len();
+        self.oracle_pegged_root_node = 0;
+        self.padding0 = [0; 4];
+        self.oracle_pegged_leaf_count = 0;
+        self.padding1 = [0; NODE_SIZE - 16];
+        Ok(())
+    }
+
     fn get_mut(&mut self, key: NodeHandle) -> Option<&mut AnyNode> {
         let node = &mut self.nodes[key as usize];
         let tag = NodeTag::try_from(node.tag);
@@ -520,8 +798,18 @@ impl BookSide {
             _ => None,
         }
     }
+    /// The node in the last slot of a book that `free_last_node` didn't migrate yet
+    fn legacy_last_node(&self) -> &AnyNode {
+        let offset = size_of::<BookSide>() - NODE_SIZE;
+        from_bytes(&bytes_of(self)[offset..])
+    }
+
     fn get(&self, key: NodeHandle) -> Option<&AnyNode> {
-        let node = &self.nodes[key as usize];
+        let node = if key as usize == self.nodes.len() && !self.has_oracle_pegged_tree() {
+            self.legacy_last_node()
+        } else {
+            &self.nodes[key as usize]
+        };
         let tag = NodeTag::try_from(node.tag);
         match tag {
             Ok(NodeTag::InnerNode) | Ok(NodeTag::LeafNode) => Some(node),
@@ -529,15 +817,55 @@ impl BookSide {
         }
     }
 
+    /// Remove the lowest price order of the fixed price tree
     pub fn remove_min(&mut self) -> Option<LeafNode> {
         self.remove_by_key(self.get(self.find_min()?)?.key()?)
     }
 
+    /// Remove the highest price order of the fixed price tree
     pub fn remove_max(&mut self) -> Option<LeafNode> {
         self.remove_by_key(self.get(self.find_max()?)?.key()?)
     }
 
-    /// Remove the order with the lowest expiry timestamp, if that's < now_ts.
+    /// Remove the order of either tree that would be matched last, the lowest bid or the highest
+    /// ask, with oracle pegged orders priced at `oracle_price_lots`. Oracle pegged orders that
+    /// can't be matched at that price go first. Returns the order and its current price.
+    pub fn remove_worst(&mut self, oracle_price_lots: i64) -> Option<(LeafNode, Option<i64>)> {
+        let (key, price) = self.find_worst(oracle_price_lots)?;
+        Some((self.remove_by_key(key)?, price))
+    }
+
+    /// Key and current price of the order `remove_worst` would remove
+    pub fn find_worst(&self, oracle_price_lots: i64) -> Option<(i128, Option<i64>)> {
+        let side =
+            if self.meta_data.data_type == DataType::Bids as u8 { Side::Bid } else { Side::Ask };
+        // None sorts first, so the worst order has the lowest rank
+        let rank = |price: Option<i64>| match side {
+            Side::Bid => price,
+            Side::Ask => price.map(|p| -p),
+        };
+
+        let fixed_h = match side {
+            Side::Bid => self.find_min(),
+            Side::Ask => self.find_max(),
+        };
+        let mut worst =
+            fixed_h.and_then(|h| self.get(h)?.as_leaf()).map(|leaf| (leaf.key, Some(leaf.price())));
+
+        // The oracle pegged tree is sorted by offset, but each order has its own peg limit.
+        // Of equally priced orders the last in matching order goes.
+        let oracle_pegged = BookSideIter::new_in(self, BookSideOrderTree::OraclePegged, 0)
+            .map(|(_, leaf)| (leaf.key, leaf.oracle_pegged_price(side, oracle_price_lots)))
+            .max_by_key(|&(_, price)| Reverse(rank(price)));
+        if let Some((key, price)) = oracle_pegged {
+            if worst.map_or(true, |(_, worst_price)| rank(price) < rank(worst_price)) {
+                worst = Some((key, price));
+            }
+        }
+        worst
+    }
+
+    /// Remove the order with the lowest expiry timestamp of either tree, if that's < now_ts.
     pub fn remove_one_expired(&mut self, now_ts: u64) -> Option<LeafNode> {
         let (expired_h, expires_at) = self.find_earliest_expiry()?;
         if expires_at < now_ts {
@@ -551,10 +879,50 @@ impl BookSide {
         self.find_min_max(true)
     }
     fn root(&self) -> Option<NodeHandle> {
-        if self.leaf_count == 0 {
+        self.root_in(BookSideOrderTree::Fixed)
+    }
+
+    fn root_in(&self, tree: BookSideOrderTree) -> Option<NodeHandle> {
+        let (root_node, leaf_count) = match tree {
+            BookSideOrderTree::Fixed => (self.root_node, self.leaf_count),
+            BookSideOrderTree::OraclePegged if !self.has_oracle_pegged_tree() => (0, 0),
+            BookSideOrderTree::OraclePegged => {
+                (self.oracle_pegged_root_node, self.oracle_pegged_leaf_count)
+            }
+        };
+        if leaf_count == 0 {
             None
         } else {
-            Some(self.root_node)
+            Some(root_node)
+        }
+    }
+
+    fn set_root_in(&mut self, tree: BookSideOrderTree, root_node: NodeHandle, leaf_count: usize) {
+        match tree {
+            BookSideOrderTree::Fixed => {
+                self.root_node = root_node;
+                self.leaf_count = leaf_count;
+            }
+            BookSideOrderTree::OraclePegged => {
+                self.oracle_pegged_root_node = root_node;
+                self.oracle_pegged_leaf_count = leaf_count;
+            }
+        }
+    }
+
+    fn leaf_count_in_mut(&mut self, tree: BookSideOrderTree) -> &mut usize {
+        match tree {
+            BookSideOrderTree::Fixed => &mut self.leaf_count,
+            BookSideOrderTree::OraclePegged => &mut self.oracle_pegged_leaf_count,
+        }
+    }
+
+    /// Number of orders in the tree, including invalid orders
+    pub fn leaf_count_in(&self, tree: BookSideOrderTree) -> usize {
+        match tree {
+            BookSideOrderTree::Fixed => self.leaf_count,
+            BookSideOrderTree::OraclePegged if !self.has_oracle_pegged_tree() => 0,
+            BookSideOrderTree::OraclePegged => self.oracle_pegged_leaf_count,
         }
     }
 
@@ -634,17 +1002,45 @@ impl BookSide {
         }
     }
 
+    fn find_handle_by_key(&self, search_key: i128) -> Option<NodeHandle> {
+        let mut node_handle = self.root_in(BookSideOrderTree::of_key(search_key))?;
+        loop {
+            match self.get(node_handle).unwrap().case().unwrap() {
+                NodeRef::Inner(inner) => node_handle = inner.walk_down(search_key).0,
+                NodeRef::Leaf(leaf) => {
+                    return if leaf.key == search_key { Some(node_handle) } else { None };
+                }
+            }
+        }
+    }
+
+    /// Find the order with this key, including invalid orders
+    pub fn find_by_key(&self, search_key: i128) -> Option<&LeafNode> {
+        self.get(self.find_handle_by_key(search_key)?)?.as_leaf()
+    }
+
+    /// Change the quantity of an order in place so it keeps its time priority.
+    /// Returns the order as it was before the change.
+    pub fn set_quantity(&mut self, search_key: i128, quantity: i64) -> Option<LeafNode> {
+        let handle = self.find_handle_by_key(search_key)?;
+        let leaf = self.get_mut(handle)?.as_leaf_mut()?;
+        let old_leaf = *leaf;
+        leaf.quantity = quantity;
+        Some(old_leaf)
+    }
+
     fn remove_by_key(&mut self, search_key: i128) -> Option<LeafNode> {
+        let tree = BookSideOrderTree::of_key(search_key);
+
         // path of InnerNode handles that lead to the removed leaf
         let mut stack: Vec<(NodeHandle, bool)> = vec![];
 
         // special case potentially removing the root
-        let mut parent_h = self.root()?;
+        let mut parent_h = self.root_in(tree)?;
         let (mut child_h, mut crit_bit) = match self.get(parent_h).unwrap().case().unwrap() {
             NodeRef::Leaf(&leaf) if leaf.key == search_key => {
-                assert_eq!(self.leaf_count, 1);
-                self.root_node = 0;
-                self.leaf_count = 0;
+                assert_eq!(self.leaf_count_in(tree), 1);
+                self.set_root_in(tree, 0, 0);
                 let _old_root = self.remove(parent_h).unwrap();
                 return Some(leaf);
             }
@@ -677,7 +1073,7 @@ impl BookSide {
         let other_child_node_contents = self.remove(other_child_h).unwrap();
         let new_expiry = other_child_node_contents.earliest_expiry();
         *self.get_mut(parent_h).unwrap() = other_child_node_contents;
-        self.leaf_count -= 1;
+        *self.leaf_count_in_mut(tree) -= 1;
         let removed_leaf: LeafNode = cast(self.remove(child_h).unwrap());
 
         // update child min expiry back up to the root
@@ -740,21 +1136,27 @@ impl BookSide {
         *node = *val;
         Ok(key)
     }
+    /// Insert into the fixed price or the oracle pegged tree, depending on the key
     pub fn insert_leaf(
         &mut self,
         new_leaf: &LeafNode,
     ) -> MangoResult<(NodeHandle, Option<LeafNode>)> {
+        let tree = BookSideOrderTree::of_key(new_leaf.key);
+        check!(
+            tree == BookSideOrderTree::Fixed || self.has_oracle_pegged_tree(),
+            MangoErrorCode::InvalidAccountState
+        )?;
+
         // path of InnerNode handles that lead to the new leaf
         let mut stack: Vec<(NodeHandle, bool)> = vec![];
 
         // deal with inserts into an empty tree
-        let mut root: NodeHandle = match self.root() {
+        let mut root: NodeHandle = match self.root_in(tree) {
             Some(h) => h,
             None => {
                 // create a new root if none exists
                 let handle = self.insert(new_leaf.as_ref())?;
-                self.root_node = handle;
-                self.leaf_count = 1;
+                self.set_root_in(tree, handle, 1);
                 return Ok((handle, None));
             }
         };
@@ -825,7 +1227,7 @@ impl BookSide {
                 self.update_parent_earliest_expiry(&stack, old_root_expiry, new_leaf_expiry);
             }
 
-            self.leaf_count += 1;
+            *self.leaf_count_in_mut(tree) += 1;
             return Ok((new_leaf_handle, None));
         }
     }
@@ -858,8 +1260,16 @@ impl BookSide {
     }
 
     /// Returns the handle of the node with the lowest expiry timestamp, and this timestamp
+    /// The order with the earliest expiry of either tree and its expiry
     pub fn find_earliest_expiry(&self) -> Option<(NodeHandle, u64)> {
-        let mut current: NodeHandle = match self.root() {
+        [BookSideOrderTree::Fixed, BookSideOrderTree::OraclePegged]
+            .into_iter()
+            .filter_map(|tree| self.find_earliest_expiry_in(tree))
+            .min_by_key(|&(_, expiry)| expiry)
+    }
+
+    fn find_earliest_expiry_in(&self, tree: BookSideOrderTree) -> Option<(NodeHandle, u64)> {
+        let mut current: NodeHandle = match self.root_in(tree) {
             Some(h) => h,
             None => return None,
         };
@@ -901,21 +1311,83 @@ impl<'a> Book<'a> {
         })
     }
 
-    /// returns best valid bid
-    pub fn get_best_bid_price(&self, now_ts: u64) -> Option<i64> {
-        Some(self.bids.iter_valid(now_ts).next()?.1.price())
+    /// returns best valid bid, including oracle pegged bids
+    pub fn get_best_bid_price(&self, oracle_price_lots: i64, now_ts: u64) -> Option<i64> {
+        Some(self.bids.iter_valid_with_price(oracle_price_lots, now_ts).next()?.2)
+    }
+
+    /// returns best valid ask, including oracle pegged asks
+    pub fn get_best_ask_price(&self, oracle_price_lots: i64, now_ts: u64) -> Option<i64> {
+        Some(self.asks.iter_valid_with_price(oracle_price_lots, now_ts).next()?.2)
     }
 
-    /// returns best valid ask
-    pub fn get_best_ask_price(&self, now_ts: u64) -> Option<i64> {
-        Some(self.asks.iter_valid(now_ts).next()?.1.price())
+    /// Whether a PostOnly order of `quantity` at `price` would rest on the book in full instead
+    /// of failing or being rejected: the market hasn't expired, the price is within the oracle
+    /// limits and price band and doesn't cross, the BookSide has room, and the MangoAccount has
+    /// a free order slot and stays within max_position_lots
+    pub fn can_post(
+        &self,
+        market: &PerpMarket,
+        info: &PerpMarketInfo,
+        mango_account: &MangoAccount,
+        market_index: usize,
+        side: Side,
+        price: i64,
+        quantity: i64,
+        oracle_price: I80F48,
+        now_ts: u64,
+    ) -> bool {
+        let oracle_price_lots = market.native_price_to_lot(oracle_price);
+        let price_ratio = market.lot_to_native_price(price).checked_div(oracle_price).unwrap();
+        let (book_side, within_limits, crosses) = match side {
+            Side::Bid => (
+                &self.bids,
+                price_ratio <= info.maint_liab_weight
+                    && info
+                        .price_band_limit(side, oracle_price_lots)
+                        .map_or(true, |max| price <= max),
+                self.get_best_ask_price(oracle_price_lots, now_ts).map_or(false, |p| p <= price),
+            ),
+            Side::Ask => (
+                &self.asks,
+                price_ratio >= info.maint_asset_weight
+                    && info
+                        .price_band_limit(side, oracle_price_lots)
+                        .map_or(true, |min| price >= min),
+                self.get_best_bid_price(oracle_price_lots, now_ts).map_or(false, |p| p >= price),
+            ),
+        };
+        let has_room = !book_side.is_full()
+            || book_side.find_earliest_expiry().map_or(false, |(_, expiry)| expiry < now_ts)
+            || match book_side.find_worst(oracle_price_lots) {
+                Some((_, None)) => true,
+                Some((_, Some(worst_price))) => match side {
+                    Side::Bid => price > worst_price,
+                    Side::Ask => price < worst_price,
+                },
+                None => false,
+            };
+        let exposure = mango_account.perp_accounts[market_index].exposure(side);
+
+        !market.is_expired(now_ts)
+            && within_limits
+            && !crosses
+            && has_room
+            && mango_account.next_order_slot().is_some()
+            && market.check_max_position(exposure.max(0), (exposure + quantity).max(0)).is_ok()
     }
 
     /// Get the quantity of valid bids above and including the price
-    pub fn get_bids_size_above(&self, price: i64, max_depth: i64, now_ts: u64) -> i64 {
+    pub fn get_bids_size_above(
+        &self,
+        price: i64,
+        max_depth: i64,
+        oracle_price_lots: i64,
+        now_ts: u64,
+    ) -> i64 {
         let mut s = 0;
-        for (_, bid) in self.bids.iter_valid(now_ts) {
-            if price > bid.price() || s >= max_depth {
+        for (_, bid, bid_price) in self.bids.iter_valid_with_price(oracle_price_lots, now_ts) {
+            if price > bid_price || s >= max_depth {
                 break;
             }
             s += bid.quantity;
@@ -925,26 +1397,38 @@ impl<'a> Book<'a> {
 
     /// Walk up the book `quantity` units and return the price at that level. If `quantity` units
     /// not on book, return None
-    pub fn get_impact_price(&self, side: Side, quantity: i64, now_ts: u64) -> Option<i64> {
+    pub fn get_impact_price(
+        &self,
+        side: Side,
+        quantity: i64,
+        oracle_price_lots: i64,
+        now_ts: u64,
+    ) -> Option<i64> {
         let mut s = 0;
         let book_side = match side {
-            Side::Bid => self.bids.iter_valid(now_ts),
-            Side::Ask => self.asks.iter_valid(now_ts),
+            Side::Bid => self.bids.iter_valid_with_price(oracle_price_lots, now_ts),
+            Side::Ask => self.asks.iter_valid_with_price(oracle_price_lots, now_ts),
         };
-        for (_, order) in book_side {
+        for (_, order, price) in book_side {
             s += order.quantity;
             if s >= quantity {
-                return Some(order.price());
+                return Some(price);
             }
         }
         None
     }
 
     /// Get the quantity of valid asks below and including the price
-    pub fn get_asks_size_below(&self, price: i64, max_depth: i64, now_ts: u64) -> i64 {
+    pub fn get_asks_size_below(
+        &self,
+        price: i64,
+        max_depth: i64,
+        oracle_price_lots: i64,
+        now_ts: u64,
+    ) -> i64 {
         let mut s = 0;
-        for (_, ask) in self.asks.iter_valid(now_ts) {
-            if price < ask.price() || s >= max_depth {
+        for (_, ask, ask_price) in self.asks.iter_valid_with_price(oracle_price_lots, now_ts) {
+            if price < ask_price || s >= max_depth {
                 break;
             }
             s += ask.quantity;
@@ -952,9 +1436,15 @@ impl<'a> Book<'a> {
         s.min(max_depth)
     }
     /// Get the quantity of valid bids above this order id. Will return full size of book if order id not found
-    pub fn get_bids_size_above_order(&self, order_id: i128, max_depth: i64, now_ts: u64) -> i64 {
+    pub fn get_bids_size_above_order(
+        &self,
+        order_id: i128,
+        max_depth: i64,
+        oracle_price_lots: i64,
+        now_ts: u64,
+    ) -> i64 {
         let mut s = 0;
-        for (_, bid) in self.bids.iter_valid(now_ts) {
+        for (_, bid, _) in self.bids.iter_valid_with_price(oracle_price_lots, now_ts) {
             if bid.key == order_id || s >= max_depth {
                 break;
             }
@@ -964,9 +1454,15 @@ impl<'a> Book<'a> {
     }
 
     /// Get the quantity of valid asks above this order id. Will return full size of book if order id not found
-    pub fn get_asks_size_below_order(&self, order_id: i128, max_depth: i64, now_ts: u64) -> i64 {
+    pub fn get_asks_size_below_order(
+        &self,
+        order_id: i128,
+        max_depth: i64,
+        oracle_price_lots: i64,
+        now_ts: u64,
+    ) -> i64 {
         let mut s = 0;
-        for (_, ask) in self.asks.iter_valid(now_ts) {
+        for (_, ask, _) in self.asks.iter_valid_with_price(oracle_price_lots, now_ts) {
             if ask.key == order_id || s >= max_depth {
                 break;
             }
@@ -974,6 +1470,8 @@ impl<'a> Book<'a> {
         }
         s.min(max_depth)
     }
+    /// Match a new order against the book and post the rest. Returns the id and price of the
+    /// posted order, if any
     #[inline(never)]
     pub fn new_order(
         &mut self,
@@ -992,13 +1490,46 @@ impl<'a> Book<'a> {
         max_base_quantity: i64, // guaranteed to be greater than zero due to initial check
         max_quote_quantity: i64, // guaranteed to be greater than zero due to initial check
         order_type: OrderType,
+        self_trade_behavior: SelfTradeBehavior,
         time_in_force: u8,
+        good_till_date: u64, // absolute expiry of a GoodTillDate order, 0 for none
         client_order_id: u64,
         now_ts: u64,
         referrer_mango_account_ai: Option<&AccountInfo>,
         limit: u8,
-    ) -> MangoResult {
-        match side {
+    ) -> MangoResult<Option<(i128, i64)>> {
+        check!(!market.is_expired(now_ts), MangoErrorCode::PerpMarketExpired)?;
+
+        if order_type == OrderType::FillOrKill {
+            let info = &mango_group.perp_markets[market_index];
+            let (taker_base, _, _, _) = match side {
+                Side::Bid => self.sim_new_bid(
+                    market,
+                    info,
+                    oracle_price,
+                    price,
+                    max_base_quantity,
+                    max_quote_quantity,
+                    order_type,
+                    now_ts,
+                )?,
+                Side::Ask => self.sim_new_ask(
+                    market,
+                    info,
+                    oracle_price,
+                    price,
+                    max_base_quantity,
+                    max_quote_quantity,
+                    order_type,
+                    now_ts,
+                )?,
+            };
+            check_eq!(taker_base.abs(), max_base_quantity, MangoErrorCode::FillOrKillNotFilled)?;
+        }
+        let taker_base_start = mango_account.perp_accounts[market_index].taker_base;
+        let exposure_start = mango_account.perp_accounts[market_index].exposure(side);
+
+        let posted = match side {
             Side::Bid => self.new_bid(
                 program_id,
                 mango_group,
@@ -1011,15 +1542,18 @@ impl<'a> Book<'a> {
                 mango_account_pk,
                 market_index,
                 price,
+                None,
                 max_base_quantity,
                 max_quote_quantity,
                 order_type,
+                self_trade_behavior,
                 time_in_force,
+                good_till_date,
                 client_order_id,
                 now_ts,
                 referrer_mango_account_ai,
                 limit,
-            ),
+            )?,
             Side::Ask => self.new_ask(
                 program_id,
                 mango_group,
@@ -1032,65 +1566,211 @@ impl<'a> Book<'a> {
                 mango_account_pk,
                 market_index,
                 price,
+                None,
                 max_base_quantity,
                 max_quote_quantity,
                 order_type,
+                self_trade_behavior,
                 time_in_force,
+                good_till_date,
                 client_order_id,
                 now_ts,
                 referrer_mango_account_ai,
                 limit,
-            ),
+            )?,
+        };
+
+        // The simulation doesn't know about the fill limit or self-trades, so check the result too
+        if order_type == OrderType::FillOrKill {
+            let taker_base = mango_account.perp_accounts[market_index].taker_base;
+            check_eq!(
+                (taker_base - taker_base_start).abs(),
+                max_base_quantity,
+                MangoErrorCode::FillOrKillNotFilled
+            )?;
         }
+        market.check_order_limits(
+            &mango_account.perp_accounts[market_index],
+            side,
+            exposure_start,
+            taker_base_start,
+        )?;
+
+        // The book changed; keep the time weighted premium used for funding up to date
+        market.update_premium(self, oracle_price, now_ts);
+        Ok(posted)
     }
 
-    /// Iterate over the book and return
-    /// return changes to (taker_base, taker_quote, bids_quantity, asks_quantity)
-    pub fn sim_new_bid(
-        &self,
-        market: &PerpMarket,
-        info: &PerpMarketInfo,
+    /// Place an order priced at the oracle price plus `price_offset`. It matches as a limit
+    /// order at that price, capped by `peg_limit` unless that is -1, and the rest is posted
+    /// to the oracle pegged tree where its price follows the oracle.
+    #[inline(never)]
+    pub fn new_oracle_pegged_order(
+        &mut self,
+        program_id: &Pubkey,
+        mango_group: &MangoGroup,
+        mango_group_pk: &Pubkey,
+        mango_cache: &MangoCache,
+        event_queue: &mut EventQueue,
+        market: &mut PerpMarket,
         oracle_price: I80F48,
-        price: i64,
+        mango_account: &mut MangoAccount,
+        mango_account_pk: &Pubkey,
+        market_index: usize,
+        side: Side,
+        price_offset: i64,
+        peg_limit: i64,
         max_base_quantity: i64, // guaranteed to be greater than zero due to initial check
         max_quote_quantity: i64, // guaranteed to be greater than zero due to initial check
         order_type: OrderType,
+        self_trade_behavior: SelfTradeBehavior,
+        time_in_force: u8,
+        client_order_id: u64,
         now_ts: u64,
-    ) -> MangoResult<(i64, i64, i64, i64)> {
-        let (mut taker_base, mut taker_quote, mut bids_quantity, asks_quantity) = (0, 0, 0i64, 0);
+        referrer_mango_account_ai: Option<&AccountInfo>,
+        limit: u8,
+    ) -> MangoResult<Option<(i128, i64)>> {
+        check!(!market.is_expired(now_ts), MangoErrorCode::PerpMarketExpired)?;
+        check!(
+            order_type == OrderType::Limit || order_type == OrderType::PostOnly,
+            MangoErrorCode::InvalidParam
+        )?;
 
-        let (post_only, mut post_allowed, price) = match order_type {
-            OrderType::Limit => (false, true, price),
-            OrderType::ImmediateOrCancel => (false, false, price),
-            OrderType::PostOnly => (true, true, price),
-            OrderType::Market => (false, false, i64::MAX),
-            OrderType::PostOnlySlide => {
-                let price = if let Some(best_ask_price) = self.get_best_ask_price(now_ts) {
-                    price.min(best_ask_price.checked_sub(1).ok_or(math_err!())?)
-                } else {
-                    price
-                };
-                (true, true, price)
-            }
-        };
-        if post_allowed {
-            // price limit check computed lazily to save CU on average
-            let native_price = market.lot_to_native_price(price);
-            if native_price.checked_div(oracle_price).unwrap() > info.maint_liab_weight {
-                msg!("Posting on book disallowed due to price limits");
-                post_allowed = false;
-            }
+        let oracle_price_lots = market.native_price_to_lot(oracle_price);
+        let price = oracle_price_lots.saturating_add(price_offset);
+
+        // Clamping wouldn't help here, the offset would keep the order outside the price band
+        let info = &mango_group.perp_markets[market_index];
+        if let Some(limit_price) = info.price_band_limit(side, oracle_price_lots) {
+            let in_band = match side {
+                Side::Bid => price <= limit_price,
+                Side::Ask => price >= limit_price,
+            };
+            check!(in_band, MangoErrorCode::PriceOutsideBand)?;
         }
 
-        let mut rem_base_quantity = max_base_quantity; // base lots (aka contracts)
-        let mut rem_quote_quantity = max_quote_quantity;
+        let price = match (side, peg_limit) {
+            (_, -1) => price,
+            (Side::Bid, _) => price.min(peg_limit),
+            (Side::Ask, _) => price.max(peg_limit),
+        };
+        check!(price > 0, MangoErrorCode::InvalidParam)?;
 
-        for (_, best_ask) in self.asks.iter_valid(now_ts) {
-            let best_ask_price = best_ask.price();
-            if price < best_ask_price {
-                break;
-            } else if post_only {
-                return Ok((taker_base, taker_quote, bids_quantity, asks_quantity));
+        let oracle_peg = Some((price_offset, peg_limit));
+        let taker_base_start = mango_account.perp_accounts[market_index].taker_base;
+        let exposure_start = mango_account.perp_accounts[market_index].exposure(side);
+        let posted = match side {
+            Side::Bid => self.new_bid(
+                program_id,
+                mango_group,
+                mango_group_pk,
+                mango_cache,
+                event_queue,
+                market,
+                oracle_price,
+                mango_account,
+                mango_account_pk,
+                market_index,
+                price,
+                oracle_peg,
+                max_base_quantity,
+                max_quote_quantity,
+                order_type,
+                self_trade_behavior,
+                time_in_force,
+                0,
+                client_order_id,
+                now_ts,
+                referrer_mango_account_ai,
+                limit,
+            )?,
+            Side::Ask => self.new_ask(
+                program_id,
+                mango_group,
+                mango_group_pk,
+                mango_cache,
+                event_queue,
+                market,
+                oracle_price,
+                mango_account,
+                mango_account_pk,
+                market_index,
+                price,
+                oracle_peg,
+                max_base_quantity,
+                max_quote_quantity,
+                order_type,
+                self_trade_behavior,
+                time_in_force,
+                0,
+                client_order_id,
+                now_ts,
+                referrer_mango_account_ai,
+                limit,
+            )?,
+        };
+        market.check_order_limits(
+            &mango_account.perp_accounts[market_index],
+            side,
+            exposure_start,
+            taker_base_start,
+        )?;
+
+        market.update_premium(self, oracle_price, now_ts);
+        Ok(posted)
+    }
+
+    /// Iterate over the book and return
+    /// return changes to (taker_base, taker_quote, bids_quantity, asks_quantity)
+    pub fn sim_new_bid(
+        &self,
+        market: &PerpMarket,
+        info: &PerpMarketInfo,
+        oracle_price: I80F48,
+        price: i64,
+        max_base_quantity: i64, // guaranteed to be greater than zero due to initial check
+        max_quote_quantity: i64, // guaranteed to be greater than zero due to initial check
+        order_type: OrderType,
+        now_ts: u64,
+    ) -> MangoResult<(i64, i64, i64, i64)> {
+        let (mut taker_base, mut taker_quote, mut bids_quantity, asks_quantity) = (0, 0, 0i64, 0);
+
+        let oracle_price_lots = market.native_price_to_lot(oracle_price);
+        let (post_only, mut post_allowed, price) = match order_type {
+            OrderType::Limit => (false, true, price),
+            OrderType::ImmediateOrCancel | OrderType::FillOrKill => (false, false, price),
+            OrderType::PostOnly => (true, true, price),
+            OrderType::Market => (false, false, i64::MAX),
+            OrderType::PostOnlySlide => {
+                let price = if let Some(best_ask_price) =
+                    self.get_best_ask_price(oracle_price_lots, now_ts)
+                {
+                    price.min(best_ask_price.checked_sub(1).ok_or(math_err!())?)
+                } else {
+                    price
+                };
+                (true, true, price)
+            }
+        };
+        if post_allowed {
+            // price limit check computed lazily to save CU on average
+            let native_price = market.lot_to_native_price(price);
+            if native_price.checked_div(oracle_price).unwrap() > info.maint_liab_weight {
+                msg!("Posting on book disallowed due to price limits");
+                post_allowed = false;
+            }
+        }
+
+        let mut rem_base_quantity = max_base_quantity; // base lots (aka contracts)
+        let mut rem_quote_quantity = max_quote_quantity;
+
+        for (_, best_ask, best_ask_price) in
+            self.asks.iter_valid_with_price(oracle_price_lots, now_ts)
+        {
+            if price < best_ask_price {
+                break;
+            } else if post_only {
+                return Ok((taker_base, taker_quote, bids_quantity, asks_quantity));
             }
 
             let max_match_by_quote = rem_quote_quantity / best_ask_price;
@@ -1126,13 +1806,16 @@ impl<'a> Book<'a> {
     ) -> MangoResult<(i64, i64, i64, i64)> {
         let (mut taker_base, mut taker_quote, bids_quantity, mut asks_quantity) = (0, 0, 0, 0i64);
 
+        let oracle_price_lots = market.native_price_to_lot(oracle_price);
         let (post_only, mut post_allowed, price) = match order_type {
             OrderType::Limit => (false, true, price),
-            OrderType::ImmediateOrCancel => (false, false, price),
+            OrderType::ImmediateOrCancel | OrderType::FillOrKill => (false, false, price),
             OrderType::PostOnly => (true, true, price),
             OrderType::Market => (false, false, 1),
             OrderType::PostOnlySlide => {
-                let price = if let Some(best_bid_price) = self.get_best_bid_price(now_ts) {
+                let price = if let Some(best_bid_price) =
+                    self.get_best_bid_price(oracle_price_lots, now_ts)
+                {
                     price.max(best_bid_price.checked_add(1).ok_or(math_err!())?)
                 } else {
                     price
@@ -1152,8 +1835,9 @@ impl<'a> Book<'a> {
         let mut rem_base_quantity = max_base_quantity; // base lots (aka contracts)
         let mut rem_quote_quantity = max_quote_quantity;
 
-        for (_, best_bid) in self.bids.iter_valid(now_ts) {
-            let best_bid_price = best_bid.price();
+        for (_, best_bid, best_bid_price) in
+            self.bids.iter_valid_with_price(oracle_price_lots, now_ts)
+        {
             if price > best_bid_price {
                 break;
             } else if post_only {
@@ -1195,24 +1879,30 @@ impl<'a> Book<'a> {
         mango_account_pk: &Pubkey,
         market_index: usize,
         price: i64,
-        max_base_quantity: i64, // guaranteed to be greater than zero due to initial check
-        max_quote_quantity: i64, // guaranteed to be greater than zero due to initial check
+        oracle_peg: Option<(i64, i64)>, // (price_offset, peg_limit) to post an oracle pegged order
+        max_base_quantity: i64,         // guaranteed to be greater than zero due to initial check
+        max_quote_quantity: i64,        // guaranteed to be greater than zero due to initial check
         order_type: OrderType,
+        self_trade_behavior: SelfTradeBehavior,
         time_in_force: u8,
+        good_till_date: u64,
         client_order_id: u64,
         now_ts: u64,
         referrer_mango_account_ai: Option<&AccountInfo>,
         mut limit: u8, // max number of FillEvents allowed; guaranteed to be greater than 0
-    ) -> MangoResult {
+    ) -> MangoResult<Option<(i128, i64)>> {
         // TODO proper error handling
         // TODO handle the case where we run out of compute (right now just fails)
+        let oracle_price_lots = market.native_price_to_lot(oracle_price);
         let (post_only, mut post_allowed, price) = match order_type {
             OrderType::Limit => (false, true, price),
-            OrderType::ImmediateOrCancel => (false, false, price),
+            OrderType::ImmediateOrCancel | OrderType::FillOrKill => (false, false, price),
             OrderType::PostOnly => (true, true, price),
             OrderType::Market => (false, false, i64::MAX),
             OrderType::PostOnlySlide => {
-                let price = if let Some(best_ask_price) = self.get_best_ask_price(now_ts) {
+                let price = if let Some(best_ask_price) =
+                    self.get_best_ask_price(oracle_price_lots, now_ts)
+                {
                     price.min(best_ask_price.checked_sub(1).ok_or(math_err!())?)
                 } else {
                     price
@@ -1221,6 +1911,18 @@ impl<'a> Book<'a> {
             }
         };
         let info = &mango_group.perp_markets[market_index];
+
+        // Keep the limit price within the market's price band around the oracle price
+        let price = match info.price_band_limit(Side::Bid, oracle_price_lots) {
+            Some(max_price) if price > max_price => {
+                check!(
+                    info.price_band_clamp || order_type == OrderType::Market,
+                    MangoErrorCode::PriceOutsideBand
+                )?;
+                max_price
+            }
+            _ => price,
+        };
         if post_allowed {
             // price limit check computed lazily to save CU on average
             let native_price = market.lot_to_native_price(price);
@@ -1236,7 +1938,10 @@ impl<'a> Book<'a> {
         let mut referrer_mango_account_opt = None;
 
         // generate new order id
-        let order_id = market.gen_order_id(Side::Bid, price);
+        let order_id = match oracle_peg {
+            None => market.gen_order_id(Side::Bid, price),
+            Some((price_offset, _)) => market.gen_oracle_pegged_order_id(Side::Bid, price_offset),
+        };
 
         // Iterate through book and match against this new bid
         //
@@ -1244,10 +1949,13 @@ impl<'a> Book<'a> {
         // and then applied after this loop.
         let mut rem_base_quantity = max_base_quantity; // base lots (aka contracts)
         let mut rem_quote_quantity = max_quote_quantity;
+        let mut self_trade_quote = 0; // decremented by self trades, without fills or fees
         let mut ask_changes: Vec<(NodeHandle, i64)> = vec![];
         let mut ask_deletes: Vec<i128> = vec![];
         let mut number_of_dropped_expired_orders = 0;
-        for (best_ask_h, best_ask) in self.asks.iter_all_including_invalid() {
+        for (best_ask_h, best_ask, best_ask_price) in
+            self.asks.iter_all_including_invalid_with_price(oracle_price_lots)
+        {
             if !best_ask.is_valid(now_ts) {
                 // Remove the order from the book unless we've done that enough
                 if number_of_dropped_expired_orders < DROP_EXPIRED_ORDER_LIMIT {
@@ -1266,8 +1974,6 @@ impl<'a> Book<'a> {
                 continue;
             }
 
-            let best_ask_price = best_ask.price();
-
             if price < best_ask_price {
                 break;
             } else if post_only {
@@ -1280,6 +1986,25 @@ impl<'a> Book<'a> {
                 break;
             }
 
+            let self_trade = best_ask.owner == *mango_account_pk;
+            let decrement_take =
+                self_trade && self_trade_behavior == SelfTradeBehavior::DecrementTake;
+            if self_trade {
+                match self_trade_behavior {
+                    SelfTradeBehavior::DecrementTake | SelfTradeBehavior::AllowSelfTrade => {}
+                    SelfTradeBehavior::CancelProvide => {
+                        // Cancel the resting order and keep matching against the rest of the book
+                        ask_deletes.push(best_ask.key);
+                        mango_account
+                            .remove_order(best_ask.owner_slot as usize, best_ask.quantity)?;
+                        continue;
+                    }
+                    SelfTradeBehavior::AbortTransaction => {
+                        return Err(throw_err!(MangoErrorCode::WouldSelfTrade));
+                    }
+                }
+            }
+
             let max_match_by_quote = rem_quote_quantity / best_ask_price;
             if max_match_by_quote == 0 {
                 // Done matching because we reached max quote quantity
@@ -1292,7 +2017,6 @@ impl<'a> Book<'a> {
             let match_quote = match_quantity * best_ask_price;
             rem_base_quantity -= match_quantity;
             rem_quote_quantity -= match_quote;
-            mango_account.perp_accounts[market_index].add_taker_trade(match_quantity, -match_quote);
 
             let new_best_ask_quantity = best_ask.quantity - match_quantity;
             let maker_out = new_best_ask_quantity == 0;
@@ -1302,6 +2026,21 @@ impl<'a> Book<'a> {
                 ask_changes.push((best_ask_h, new_best_ask_quantity));
             }
 
+            if decrement_take {
+                // Both orders shrink by the overlap without a FillEvent or fees
+                self_trade_quote += match_quote;
+                if maker_out {
+                    mango_account.remove_order(best_ask.owner_slot as usize, match_quantity)?;
+                } else {
+                    mango_account.perp_accounts[market_index].asks_quantity -= match_quantity;
+                }
+                if done {
+                    break;
+                }
+                continue;
+            }
+            mango_account.perp_accounts[market_index].add_taker_trade(match_quantity, -match_quote);
+
             // if ref_fee_rate is none, determine it
             // if ref_valid, then pay into referrer, else pay to perp market
             if ref_fee_rate.is_none() {
@@ -1345,7 +2084,7 @@ impl<'a> Book<'a> {
                 break;
             }
         }
-        let total_quote_taken = max_quote_quantity - rem_quote_quantity;
+        let total_quote_taken = max_quote_quantity - rem_quote_quantity - self_trade_quote;
 
         // Apply changes to matched asks (handles invalidate on delete!)
         for (handle, new_quantity) in ask_changes {
@@ -1357,6 +2096,7 @@ impl<'a> Book<'a> {
 
         // If there are still quantity unmatched, place on the book
         let book_base_quantity = rem_base_quantity.min(rem_quote_quantity / price);
+        let mut posted = None;
         if post_allowed && book_base_quantity > 0 {
             // Drop an expired order if possible
             if let Some(expired_bid) = self.bids.remove_one_expired(now_ts) {
@@ -1373,8 +2113,11 @@ impl<'a> Book<'a> {
 
             if self.bids.is_full() {
                 // If this bid is higher than lowest bid, boot that bid and insert this one
-                let min_bid = self.bids.remove_min().unwrap();
-                check!(price > min_bid.price(), MangoErrorCode::OutOfSpace)?;
+                let (min_bid, min_bid_price) = self
+                    .bids
+                    .remove_worst(oracle_price_lots)
+                    .ok_or(throw_err!(MangoErrorCode::OutOfSpace))?;
+                check!(min_bid_price.map_or(true, |p| price > p), MangoErrorCode::OutOfSpace)?;
                 let event = OutEvent::new(
                     Side::Bid,
                     min_bid.owner_slot,
@@ -1387,21 +2130,30 @@ impl<'a> Book<'a> {
             }
 
             // iterate through book on the bid side
-            let best_initial = if market.meta_data.version == 0 {
-                match self.get_best_bid_price(now_ts) {
+            let best_initial = if let Some((_, peg_limit)) = oracle_peg {
+                peg_limit
+            } else if good_till_date != 0 {
+                good_till_date as i64
+            } else if market.meta_data.version == 0 {
+                match self.get_best_bid_price(oracle_price_lots, now_ts) {
                     None => price,
                     Some(p) => p,
                 }
             } else {
                 let max_depth: i64 = market.liquidity_mining_info.max_depth_bps.to_num();
-                self.get_bids_size_above(price, max_depth, now_ts)
+                self.get_bids_size_above(price, max_depth, oracle_price_lots, now_ts)
             };
 
             let owner_slot = mango_account
                 .next_order_slot()
                 .ok_or(throw_err!(MangoErrorCode::TooManyOpenOrders))?;
+            let version = if good_till_date != 0 {
+                market.meta_data.version | GOOD_TILL_DATE_FLAG
+            } else {
+                market.meta_data.version
+            };
             let new_bid = LeafNode::new(
-                market.meta_data.version,
+                version,
                 owner_slot as u8,
                 order_id,
                 *mango_account_pk,
@@ -1422,6 +2174,7 @@ impl<'a> Book<'a> {
                 price
             );
             mango_account.add_order(market_index, Side::Bid, &new_bid)?;
+            posted = Some((order_id, price));
         }
 
         // if there were matched taker quote apply ref fees
@@ -1441,7 +2194,7 @@ impl<'a> Book<'a> {
             );
         }
 
-        Ok(())
+        Ok(posted)
     }
 
     #[inline(never)]
@@ -1458,22 +2211,28 @@ impl<'a> Book<'a> {
         mango_account_pk: &Pubkey,
         market_index: usize,
         price: i64,
-        max_base_quantity: i64, // guaranteed to be greater than zero due to initial check
-        max_quote_quantity: i64, // guaranteed to be greater than zero due to initial check
+        oracle_peg: Option<(i64, i64)>, // (price_offset, peg_limit) to post an oracle pegged order
+        max_base_quantity: i64,         // guaranteed to be greater than zero due to initial check
+        max_quote_quantity: i64,        // guaranteed to be greater than zero due to initial check
         order_type: OrderType,
+        self_trade_behavior: SelfTradeBehavior,
         time_in_force: u8,
+        good_till_date: u64,
         client_order_id: u64,
         now_ts: u64,
         referrer_mango_account_ai: Option<&AccountInfo>,
         mut limit: u8, // max number of FillEvents allowed; guaranteed to be greater than 0
-    ) -> MangoResult {
+    ) -> MangoResult<Option<(i128, i64)>> {
+        let oracle_price_lots = market.native_price_to_lot(oracle_price);
         let (post_only, mut post_allowed, price) = match order_type {
             OrderType::Limit => (false, true, price),
-            OrderType::ImmediateOrCancel => (false, false, price),
+            OrderType::ImmediateOrCancel | OrderType::FillOrKill => (false, false, price),
             OrderType::PostOnly => (true, true, price),
             OrderType::Market => (false, false, 1),
             OrderType::PostOnlySlide => {
-                let price = if let Some(best_bid_price) = self.get_best_bid_price(now_ts) {
+                let price = if let Some(best_bid_price) =
+                    self.get_best_bid_price(oracle_price_lots, now_ts)
+                {
                     price.max(best_bid_price.checked_add(1).ok_or(math_err!())?)
                 } else {
                     price
@@ -1482,6 +2241,18 @@ impl<'a> Book<'a> {
             }
         };
         let info = &mango_group.perp_markets[market_index];
+
+        // Keep the limit price within the market's price band around the oracle price
+        let price = match info.price_band_limit(Side::Ask, oracle_price_lots) {
+            Some(min_price) if price < min_price => {
+                check!(
+                    info.price_band_clamp || order_type == OrderType::Market,
+                    MangoErrorCode::PriceOutsideBand
+                )?;
+                min_price
+            }
+            _ => price,
+        };
         if post_allowed {
             // price limit check computed lazily to save CU on average
             let native_price = market.lot_to_native_price(price);
@@ -1496,7 +2267,10 @@ impl<'a> Book<'a> {
         let mut referrer_mango_account_opt = None;
 
         // generate new order id
-        let order_id = market.gen_order_id(Side::Ask, price);
+        let order_id = match oracle_peg {
+            None => market.gen_order_id(Side::Ask, price),
+            Some((price_offset, _)) => market.gen_oracle_pegged_order_id(Side::Ask, price_offset),
+        };
 
         // Iterate through book and match against this new ask
         //
@@ -1504,10 +2278,13 @@ impl<'a> Book<'a> {
         // and then applied after this loop.
         let mut rem_base_quantity = max_base_quantity; // base lots (aka contracts)
         let mut rem_quote_quantity = max_quote_quantity;
+        let mut self_trade_quote = 0; // decremented by self trades, without fills or fees
         let mut bid_changes: Vec<(NodeHandle, i64)> = vec![];
         let mut bid_deletes: Vec<i128> = vec![];
         let mut number_of_dropped_expired_orders = 0;
-        for (best_bid_h, best_bid) in self.bids.iter_all_including_invalid() {
+        for (best_bid_h, best_bid, best_bid_price) in
+            self.bids.iter_all_including_invalid_with_price(oracle_price_lots)
+        {
             if !best_bid.is_valid(now_ts) {
                 // Remove the order from the book unless we've done that enough
                 if number_of_dropped_expired_orders < DROP_EXPIRED_ORDER_LIMIT {
@@ -1526,8 +2303,6 @@ impl<'a> Book<'a> {
                 continue;
             }
 
-            let best_bid_price = best_bid.price();
-
             if price > best_bid_price {
                 break;
             } else if post_only {
@@ -1540,6 +2315,25 @@ impl<'a> Book<'a> {
                 break;
             }
 
+            let self_trade = best_bid.owner == *mango_account_pk;
+            let decrement_take =
+                self_trade && self_trade_behavior == SelfTradeBehavior::DecrementTake;
+            if self_trade {
+                match self_trade_behavior {
+                    SelfTradeBehavior::DecrementTake | SelfTradeBehavior::AllowSelfTrade => {}
+                    SelfTradeBehavior::CancelProvide => {
+                        // Cancel the resting order and keep matching against the rest of the book
+                        bid_deletes.push(best_bid.key);
+                        mango_account
+                            .remove_order(best_bid.owner_slot as usize, best_bid.quantity)?;
+                        continue;
+                    }
+                    SelfTradeBehavior::AbortTransaction => {
+                        return Err(throw_err!(MangoErrorCode::WouldSelfTrade));
+                    }
+                }
+            }
+
             let max_match_by_quote = rem_quote_quantity / best_bid_price;
             if max_match_by_quote == 0 {
                 // Done matching because we reached max quote quantity
@@ -1552,7 +2346,6 @@ impl<'a> Book<'a> {
             let match_quote = match_quantity * best_bid_price;
             rem_base_quantity -= match_quantity;
             rem_quote_quantity -= match_quote;
-            mango_account.perp_accounts[market_index].add_taker_trade(-match_quantity, match_quote);
 
             let new_best_bid_quantity = best_bid.quantity - match_quantity;
             let maker_out = new_best_bid_quantity == 0;
@@ -1562,6 +2355,21 @@ impl<'a> Book<'a> {
                 bid_changes.push((best_bid_h, new_best_bid_quantity));
             }
 
+            if decrement_take {
+                // Both orders shrink by the overlap without a FillEvent or fees
+                self_trade_quote += match_quote;
+                if maker_out {
+                    mango_account.remove_order(best_bid.owner_slot as usize, match_quantity)?;
+                } else {
+                    mango_account.perp_accounts[market_index].bids_quantity -= match_quantity;
+                }
+                if done {
+                    break;
+                }
+                continue;
+            }
+            mango_account.perp_accounts[market_index].add_taker_trade(-match_quantity, match_quote);
+
             // if ref_fee_rate is none, determine it
             // if ref_valid, then pay into referrer, else pay to perp market
             if ref_fee_rate.is_none() {
@@ -1606,7 +2414,7 @@ impl<'a> Book<'a> {
                 break;
             }
         }
-        let total_quote_taken = max_quote_quantity - rem_quote_quantity;
+        let total_quote_taken = max_quote_quantity - rem_quote_quantity - self_trade_quote;
 
         // Apply changes to matched bids (handles invalidate on delete!)
         for (handle, new_quantity) in bid_changes {
@@ -1618,6 +2426,7 @@ impl<'a> Book<'a> {
 
         // If there are still quantity unmatched, place on the book
         let book_base_quantity = rem_base_quantity.min(rem_quote_quantity / price);
+        let mut posted = None;
         if book_base_quantity > 0 && post_allowed {
             // Drop an expired order if possible
             if let Some(expired_ask) = self.asks.remove_one_expired(now_ts) {
@@ -1634,8 +2443,11 @@ impl<'a> Book<'a> {
 
             if self.asks.is_full() {
                 // If this asks is lower than highest ask, boot that ask and insert this one
-                let max_ask = self.asks.remove_max().unwrap();
-                check!(price < max_ask.price(), MangoErrorCode::OutOfSpace)?;
+                let (max_ask, max_ask_price) = self
+                    .asks
+                    .remove_worst(oracle_price_lots)
+                    .ok_or(throw_err!(MangoErrorCode::OutOfSpace))?;
+                check!(max_ask_price.map_or(true, |p| price < p), MangoErrorCode::OutOfSpace)?;
                 let event = OutEvent::new(
                     Side::Ask,
                     max_ask.owner_slot,
@@ -1647,21 +2459,30 @@ impl<'a> Book<'a> {
                 event_queue.push_back(cast(event)).unwrap();
             }
 
-            let best_initial = if market.meta_data.version == 0 {
-                match self.get_best_ask_price(now_ts) {
+            let best_initial = if let Some((_, peg_limit)) = oracle_peg {
+                peg_limit
+            } else if good_till_date != 0 {
+                good_till_date as i64
+            } else if market.meta_data.version == 0 {
+                match self.get_best_ask_price(oracle_price_lots, now_ts) {
                     None => price,
                     Some(p) => p,
                 }
             } else {
                 let max_depth: i64 = market.liquidity_mining_info.max_depth_bps.to_num();
-                self.get_asks_size_below(price, max_depth, now_ts)
+                self.get_asks_size_below(price, max_depth, oracle_price_lots, now_ts)
             };
 
             let owner_slot = mango_account
                 .next_order_slot()
                 .ok_or(throw_err!(MangoErrorCode::TooManyOpenOrders))?;
+            let version = if good_till_date != 0 {
+                market.meta_data.version | GOOD_TILL_DATE_FLAG
+            } else {
+                market.meta_data.version
+            };
             let new_ask = LeafNode::new(
-                market.meta_data.version,
+                version,
                 owner_slot as u8,
                 order_id,
                 *mango_account_pk,
@@ -1683,6 +2504,7 @@ impl<'a> Book<'a> {
             );
 
             mango_account.add_order(market_index, Side::Ask, &new_ask)?;
+            posted = Some((order_id, price));
         }
 
         // if there were matched taker quote apply ref fees
@@ -1702,7 +2524,7 @@ impl<'a> Book<'a> {
             );
         }
 
-        Ok(())
+        Ok(posted)
     }
 
     pub fn cancel_order(&mut self, order_id: i128, side: Side) -> MangoResult<LeafNode> {
@@ -1716,6 +2538,91 @@ impl<'a> Book<'a> {
         }
     }
 
+    /// Amend a resting order of this MangoAccount. If the price is unchanged and the quantity goes
+    /// down, the order is modified in place and keeps its time priority. Otherwise it is canceled
+    /// and placed again at the new price, which may match against the book.
+    /// Returns the order as it was before the edit and whether it was re-inserted.
+    #[inline(never)]
+    pub fn edit_order(
+        &mut self,
+        program_id: &Pubkey,
+        mango_group: &MangoGroup,
+        mango_group_pk: &Pubkey,
+        mango_cache: &MangoCache,
+        event_queue: &mut EventQueue,
+        market: &mut PerpMarket,
+        oracle_price: I80F48,
+        mango_account: &mut MangoAccount,
+        mango_account_pk: &Pubkey,
+        market_index: usize,
+        order_id: i128,
+        side: Side,
+        new_price: i64,
+        new_quantity: i64,
+        now_ts: u64,
+        referrer_mango_account_ai: Option<&AccountInfo>,
+        limit: u8,
+    ) -> MangoResult<(LeafNode, bool)> {
+        let book_side = match side {
+            Side::Bid => &mut self.bids,
+            Side::Ask => &mut self.asks,
+        };
+        let order =
+            *book_side.find_by_key(order_id).ok_or(throw_err!(MangoErrorCode::InvalidOrderId))?;
+        check_eq!(&order.owner, mango_account_pk, MangoErrorCode::InvalidOrderId)?;
+        check!(!order.is_oracle_pegged(), MangoErrorCode::InvalidParam)?;
+        check!(order.is_valid(now_ts), MangoErrorCode::InvalidOrderId)?;
+
+        if order.edits_in_place(new_price, new_quantity) {
+            book_side.set_quantity(order_id, new_quantity).unwrap();
+            let pa = &mut mango_account.perp_accounts[market_index];
+            let reduction = order.quantity - new_quantity;
+            match side {
+                Side::Bid => pa.bids_quantity -= reduction,
+                Side::Ask => pa.asks_quantity -= reduction,
+            }
+            market.update_premium(self, oracle_price, now_ts);
+            return Ok((order, false));
+        }
+
+        let order = self.cancel_order(order_id, side)?;
+        mango_account.remove_order(order.owner_slot as usize, order.quantity)?;
+
+        // Keep the original expiry; is_valid above guarantees it is still in the future
+        let (time_in_force, good_till_date) = if order.is_good_till_date() {
+            (0, order.expiry())
+        } else if order.time_in_force == 0 {
+            (0, 0)
+        } else {
+            ((order.expiry() - now_ts).min(u8::MAX as u64) as u8, 0)
+        };
+        self.new_order(
+            program_id,
+            mango_group,
+            mango_group_pk,
+            mango_cache,
+            event_queue,
+            market,
+            oracle_price,
+            mango_account,
+            mango_account_pk,
+            market_index,
+            side,
+            new_price,
+            new_quantity,
+            i64::MAX,
+            order.order_type,
+            SelfTradeBehavior::DecrementTake,
+            time_in_force,
+            good_till_date,
+            order.client_order_id,
+            now_ts,
+            referrer_mango_account_ai,
+            limit,
+        )?;
+        Ok((order, true))
+    }
+
     /// Used by force cancel so does not need to give liquidity incentives
     pub fn cancel_all(
         &mut self,
@@ -1854,42 +2761,42 @@ impl<'a> Book<'a> {
         max_depth: i64,
         now_ts: u64,
         limit: &mut u8,
-        mut my_bids: Vec<i128>,
+        my_bids: Vec<i128>,
         canceled_order_ids: &mut Vec<i128>,
     ) -> MangoResult {
+        // Oracle pegged orders aren't in the fixed price tree walked below
+        let (my_pegged_bids, mut my_bids): (Vec<i128>, Vec<i128>) =
+            my_bids.into_iter().partition(|&key| is_oracle_pegged_key(key));
+        self.cancel_oracle_pegged_orders(
+            mango_account,
+            Side::Bid,
+            my_pegged_bids,
+            limit,
+            canceled_order_ids,
+        )?;
+
         my_bids.sort_unstable();
         let mut bids_and_sizes = vec![];
         let mut cuml_bids = 0;
 
-        let mut iter = self.bids.iter_all_including_invalid();
-        let mut curr = iter.next();
-        while let Some((_, bid)) = curr {
-            match my_bids.last() {
-                None => break,
-                Some(&my_highest_bid) => {
-                    if bid.key > my_highest_bid {
-                        if bid.is_valid(now_ts) {
-                            // if bid is not valid, it doesn't count towards book liquidity
-                            cuml_bids += bid.quantity;
-                        }
-                        curr = iter.next();
-                    } else if bid.key == my_highest_bid {
-                        bids_and_sizes.push((bid.key, cuml_bids));
-                        my_bids.pop();
-                        curr = iter.next();
-                    } else {
-                        // my_highest_bid is not on the book; it must be on EventQueue waiting to be processed
-                        // check the next my_highest_bid against bid
-                        my_bids.pop();
-                    }
-
-                    if cuml_bids >= max_depth {
-                        for bid_key in my_bids {
-                            bids_and_sizes.push((bid_key, max_depth));
-                        }
-                        break;
-                    }
+        // Oracle pegged bids ahead of mine count towards book liquidity as well. Bids that aren't
+        // found on the book must be on EventQueue waiting to be processed
+        let oracle_price_lots = perp_market.native_price_to_lot(perp_market.last_index_price);
+        for (_, bid, _) in self.bids.iter_all_including_invalid_with_price(oracle_price_lots) {
+            if my_bids.is_empty() {
+                break;
+            }
+            if cuml_bids >= max_depth {
+                for bid_key in my_bids.drain(..) {
+                    bids_and_sizes.push((bid_key, max_depth));
                 }
+                break;
+            }
+            match my_bids.binary_search(&bid.key) {
+                Ok(i) => bids_and_sizes.push((my_bids.remove(i), cuml_bids)),
+                // if bid is not valid, it doesn't count towards book liquidity
+                Err(_) if bid.is_valid(now_ts) => cuml_bids += bid.quantity,
+                Err(_) => {}
             }
         }
 
@@ -1935,41 +2842,42 @@ impl<'a> Book<'a> {
         max_depth: i64,
         now_ts: u64,
         limit: &mut u8,
-        mut my_asks: Vec<i128>,
+        my_asks: Vec<i128>,
         canceled_order_ids: &mut Vec<i128>,
     ) -> MangoResult {
-        my_asks.sort_unstable_by(|a, b| b.cmp(a));
+        // Oracle pegged orders aren't in the fixed price tree walked below
+        let (my_pegged_asks, mut my_asks): (Vec<i128>, Vec<i128>) =
+            my_asks.into_iter().partition(|&key| is_oracle_pegged_key(key));
+        self.cancel_oracle_pegged_orders(
+            mango_account,
+            Side::Ask,
+            my_pegged_asks,
+            limit,
+            canceled_order_ids,
+        )?;
+
+        my_asks.sort_unstable();
         let mut asks_and_sizes = vec![];
         let mut cuml_asks = 0;
 
-        let mut iter = self.asks.iter_all_including_invalid();
-        let mut curr = iter.next();
-        while let Some((_, ask)) = curr {
-            match my_asks.last() {
-                None => break,
-                Some(&my_lowest_ask) => {
-                    if ask.key < my_lowest_ask {
-                        if ask.is_valid(now_ts) {
-                            // if ask is not valid, it doesn't count towards book liquidity
-                            cuml_asks += ask.quantity;
-                        }
-                        curr = iter.next();
-                    } else if ask.key == my_lowest_ask {
-                        asks_and_sizes.push((ask.key, cuml_asks));
-                        my_asks.pop();
-                        curr = iter.next();
-                    } else {
-                        // my_lowest_ask is not on the book; it must be on EventQueue waiting to be processed
-                        // check the next my_lowest_ask against ask
-                        my_asks.pop();
-                    }
-                    if cuml_asks >= max_depth {
-                        for key in my_asks {
-                            asks_and_sizes.push((key, max_depth))
-                        }
-                        break;
-                    }
+        // Oracle pegged asks ahead of mine count towards book liquidity as well. Asks that aren't
+        // found on the book must be on EventQueue waiting to be processed
+        let oracle_price_lots = perp_market.native_price_to_lot(perp_market.last_index_price);
+        for (_, ask, _) in self.asks.iter_all_including_invalid_with_price(oracle_price_lots) {
+            if my_asks.is_empty() {
+                break;
+            }
+            if cuml_asks >= max_depth {
+                for key in my_asks.drain(..) {
+                    asks_and_sizes.push((key, max_depth))
                 }
+                break;
+            }
+            match my_asks.binary_search(&ask.key) {
+                Ok(i) => asks_and_sizes.push((my_asks.remove(i), cuml_asks)),
+                // if ask is not valid, it doesn't count towards book liquidity
+                Err(_) if ask.is_valid(now_ts) => cuml_asks += ask.quantity,
+                Err(_) => {}
             }
         }
 
@@ -2005,6 +2913,34 @@ impl<'a> Book<'a> {
 
         Ok(())
     }
+    /// Internal; oracle pegged orders earn no liquidity incentives
+    fn cancel_oracle_pegged_orders(
+        &mut self,
+        mango_account: &mut MangoAccount,
+        side: Side,
+        keys: Vec<i128>,
+        limit: &mut u8,
+        canceled_order_ids: &mut Vec<i128>,
+    ) -> MangoResult {
+        for key in keys {
+            if *limit == 0 {
+                return Ok(());
+            } else {
+                *limit -= 1;
+            }
+            match self.cancel_order(key, side) {
+                Ok(order) => {
+                    mango_account.remove_order(order.owner_slot as usize, order.quantity)?;
+                    canceled_order_ids.push(key);
+                }
+                Err(_) => {
+                    msg!("Failed to cancel oid: {}; it may be on EventQueue unprocessed", key)
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Cancel all the orders for MangoAccount for this PerpMarket up to `limit`
     /// Only used when PerpMarket version == 0
     pub fn cancel_all_with_price_incentives(
@@ -2015,6 +2951,7 @@ impl<'a> Book<'a> {
         mut limit: u8,
     ) -> MangoResult {
         let now_ts = Clock::get()?.unix_timestamp as u64;
+        let oracle_price_lots = perp_market.native_price_to_lot(perp_market.last_index_price);
 
         for i in 0..MAX_PERP_OPEN_ORDERS {
             if mango_account.order_market[i] != market_index as u8 {
@@ -2025,8 +2962,9 @@ impl<'a> Book<'a> {
             let order_side = mango_account.order_side[i];
 
             let best_final = match order_side {
-                Side::Bid => self.get_best_bid_price(now_ts).unwrap(),
-                Side::Ask => self.get_best_ask_price(now_ts).unwrap(),
+                _ if is_oracle_pegged_key(order_id) => 0, // no incentives, see below
+                Side::Bid => self.get_best_bid_price(oracle_price_lots, now_ts).unwrap(),
+                Side::Ask => self.get_best_ask_price(oracle_price_lots, now_ts).unwrap(),
             };
 
             match self.cancel_order(order_id, order_side) {
@@ -2034,7 +2972,7 @@ impl<'a> Book<'a> {
                     // technically these should be the same. Can enable this check to be extra sure
                     // check!(i == order.owner_slot as usize, MathError)?;
                     mango_account.remove_order(order.owner_slot as usize, order.quantity)?;
-                    if order.version != perp_market.meta_data.version {
+                    if order.version != perp_market.meta_data.version || order.is_oracle_pegged() {
                         continue;
                     }
                     mango_account.perp_accounts[market_index].apply_price_incentives(
@@ -2060,37 +2998,105 @@ impl<'a> Book<'a> {
         }
         Ok(())
     }
-}
-
-fn determine_ref_vars<'a>(
-    program_id: &Pubkey,
-    mango_group: &MangoGroup,
-    mango_group_pk: &Pubkey,
-    mango_cache: &MangoCache,
-    mango_account: &MangoAccount,
-    referrer_mango_account_ai: Option<&'a AccountInfo>,
-    now_ts: u64,
-) -> MangoResult<(I80F48, Option<RefMut<'a, MangoAccount>>)> {
-    let mngo_index = match mango_group.find_token_index(&mngo_token::id()) {
-        None => return Ok((ZERO_I80F48, None)),
-        Some(i) => i,
-    };
-
-    let mngo_cache = &mango_cache.root_bank_cache[mngo_index];
 
-    // If the user's MNGO deposit is non-zero then the rootbank cache will be checked already in `place_perp_order`.
-    // If it's zero then cache may be out of date, but it doesn't matter because 0 * index = 0
-    let mngo_deposits = mango_account.get_native_deposit(mngo_cache, mngo_index)?;
-    let ref_mngo_req = I80F48::from_num(mango_group.ref_mngo_required);
-    if mngo_deposits >= ref_mngo_req {
-        return Ok((ZERO_I80F48, None));
-    } else if let Some(referrer_mango_account_ai) = referrer_mango_account_ai {
-        // If referrer_mango_account is invalid, just treat it as if it doesn't exist
-        if let Ok(referrer_mango_account) =
-            MangoAccount::load_mut_checked(referrer_mango_account_ai, program_id, mango_group_pk)
-        {
-            // Need to check if it's valid because user may not have mngo in active assets
-            mngo_cache.check_valid(mango_group, now_ts)?;
+    /// Cancel the given orders of MangoAccount for this PerpMarket, giving the same liquidity
+    /// incentives as cancelling them one by one. Returns the order ids that were canceled
+    pub fn cancel_orders_with_incentives(
+        &mut self,
+        mango_account: &mut MangoAccount,
+        perp_market: &mut PerpMarket,
+        market_index: usize,
+        orders: Vec<(i128, Side)>,
+        now_ts: u64,
+    ) -> MangoResult<Vec<i128>> {
+        let mut canceled_order_ids = vec![];
+        if perp_market.meta_data.version != 0 {
+            let max_depth: i64 = perp_market.liquidity_mining_info.max_depth_bps.to_num();
+            let mut limit = u8::MAX;
+            let (bids, asks): (Vec<_>, Vec<_>) =
+                orders.into_iter().partition(|&(_, side)| side == Side::Bid);
+            self.cancel_all_bids_with_size_incentives(
+                mango_account,
+                perp_market,
+                market_index,
+                max_depth,
+                now_ts,
+                &mut limit,
+                bids.into_iter().map(|(key, _)| key).collect(),
+                &mut canceled_order_ids,
+            )?;
+            self.cancel_all_asks_with_size_incentives(
+                mango_account,
+                perp_market,
+                market_index,
+                max_depth,
+                now_ts,
+                &mut limit,
+                asks.into_iter().map(|(key, _)| key).collect(),
+                &mut canceled_order_ids,
+            )?;
+            return Ok(canceled_order_ids);
+        }
+
+        let oracle_price_lots = perp_market.native_price_to_lot(perp_market.last_index_price);
+        for (order_id, side) in orders {
+            let best_final = match side {
+                Side::Bid => self.get_best_bid_price(oracle_price_lots, now_ts),
+                Side::Ask => self.get_best_ask_price(oracle_price_lots, now_ts),
+            };
+            if let Ok(order) = self.cancel_order(order_id, side) {
+                mango_account.remove_order(order.owner_slot as usize, order.quantity)?;
+                canceled_order_ids.push(order_id);
+                if order.version == perp_market.meta_data.version
+                    && order.is_valid(now_ts)
+                    && !order.is_oracle_pegged()
+                {
+                    mango_account.perp_accounts[market_index].apply_price_incentives(
+                        perp_market,
+                        side,
+                        order.price(),
+                        order.best_initial,
+                        best_final.unwrap(),
+                        order.timestamp,
+                        now_ts,
+                        order.quantity,
+                    )?;
+                }
+            }
+        }
+        Ok(canceled_order_ids)
+    }
+}
+
+fn determine_ref_vars<'a>(
+    program_id: &Pubkey,
+    mango_group: &MangoGroup,
+    mango_group_pk: &Pubkey,
+    mango_cache: &MangoCache,
+    mango_account: &MangoAccount,
+    referrer_mango_account_ai: Option<&'a AccountInfo>,
+    now_ts: u64,
+) -> MangoResult<(I80F48, Option<RefMut<'a, MangoAccount>>)> {
+    let mngo_index = match mango_group.find_token_index(&mngo_token::id()) {
+        None => return Ok((ZERO_I80F48, None)),
+        Some(i) => i,
+    };
+
+    let mngo_cache = &mango_cache.root_bank_cache[mngo_index];
+
+    // If the user's MNGO deposit is non-zero then the rootbank cache will be checked already in `place_perp_order`.
+    // If it's zero then cache may be out of date, but it doesn't matter because 0 * index = 0
+    let mngo_deposits = mango_account.get_native_deposit(mngo_cache, mngo_index)?;
+    let ref_mngo_req = I80F48::from_num(mango_group.ref_mngo_required);
+    if mngo_deposits >= ref_mngo_req {
+        return Ok((ZERO_I80F48, None));
+    } else if let Some(referrer_mango_account_ai) = referrer_mango_account_ai {
+        // If referrer_mango_account is invalid, just treat it as if it doesn't exist
+        if let Ok(referrer_mango_account) =
+            MangoAccount::load_mut_checked(referrer_mango_account_ai, program_id, mango_group_pk)
+        {
+            // Need to check if it's valid because user may not have mngo in active assets
+            mngo_cache.check_valid(mango_group, now_ts)?;
             let ref_mngo_deposits =
                 referrer_mango_account.get_native_deposit(mngo_cache, mngo_index)?;
 
@@ -2186,7 +3192,11 @@ mod tests {
             free_list_head: 0,
             root_node: 0,
             leaf_count: 0,
-            nodes: [AnyNode { tag: 0, data: [0u8; NODE_SIZE - 4] }; MAX_BOOK_NODES],
+            nodes: [AnyNode { tag: 0, data: [0u8; NODE_SIZE - 4] }; MAX_BOOK_NODES - 1],
+            oracle_pegged_root_node: 0,
+            padding0: [0; 4],
+            oracle_pegged_leaf_count: 0,
+            padding1: [0; NODE_SIZE - 16],
         }
     }
 
@@ -2326,6 +3336,171 @@ mod tests {
         assert!(bids.find_earliest_expiry().is_none());
     }
 
+    #[test]
+    fn bookside_set_quantity_keeps_priority() {
+        let mut asks = new_bookside(DataType::Asks);
+        let ask_key = |price: i64, seq_num: u64| ((price as i128) << 64) | (seq_num as i128);
+        let new_leaf = |price: i64, seq_num: u64, quantity: i64| {
+            let key = ask_key(price, seq_num);
+            LeafNode::new(0, 0, key, Pubkey::default(), quantity, 0, 0, 0, OrderType::Limit, 0)
+        };
+        let first = new_leaf(100, 0, 10);
+        asks.insert_leaf(&first).unwrap();
+        asks.insert_leaf(&new_leaf(100, 1, 10)).unwrap();
+        asks.insert_leaf(&new_leaf(101, 2, 10)).unwrap();
+
+        let old_leaf = asks.set_quantity(first.key, 4).unwrap();
+        assert_eq!(old_leaf.quantity, 10);
+        assert_eq!(asks.find_by_key(first.key).unwrap().quantity, 4);
+        assert_eq!(asks.get_min().unwrap().key, first.key);
+        verify_bookside(&asks);
+
+        assert!(asks.set_quantity(ask_key(100, 3), 1).is_none());
+        assert!(asks.find_by_key(ask_key(102, 0)).is_none());
+    }
+
+    // Simulate a book created before oracle pegged orders that used the last node slot
+    fn write_last_slot(bookside: &mut BookSide, node: &AnyNode) {
+        let offset = size_of::<BookSide>() - NODE_SIZE;
+        bytemuck::bytes_of_mut(bookside)[offset..].copy_from_slice(bytes_of(node));
+        bookside.bump_index = MAX_BOOK_NODES;
+    }
+
+    #[test]
+    fn bookside_free_last_node_in_use() {
+        let new_leaf = |key: i128| {
+            LeafNode::new(0, 0, key, Pubkey::default(), 1, 0, 0, 0, OrderType::Limit, 0)
+        };
+        let last = (MAX_BOOK_NODES - 1) as NodeHandle;
+
+        // 3 leaves and 2 inner nodes; move each of them, including the root, to the last slot
+        for moved in 0..5 {
+            let mut bids = new_bookside(DataType::Bids);
+            for key in 0..3 {
+                bids.insert_leaf(&new_leaf(key)).unwrap();
+            }
+            let node = bids.remove(moved).unwrap();
+            if bids.root_node == moved {
+                bids.root_node = last;
+            }
+            for other in bids.nodes.iter_mut() {
+                if let Some(inner) = other.as_inner_mut() {
+                    for child in inner.children.iter_mut().filter(|c| **c == moved) {
+                        *child = last;
+                    }
+                }
+            }
+            write_last_slot(&mut bids, &node);
+            assert!(bids.root_in(BookSideOrderTree::OraclePegged).is_none());
+
+            // read only loads don't migrate the book but can still read the last slot
+            let keys: Vec<i128> = bids.iter_all_including_invalid().map(|(_, l)| l.key).collect();
+            assert_eq!(keys, vec![2, 1, 0]);
+            assert_eq!(bids.get_min().unwrap().key, 0);
+            assert_eq!(bids.find_by_key(1).unwrap().key, 1);
+
+            bids.free_last_node().unwrap();
+            assert!(bids.has_oracle_pegged_tree());
+            assert_eq!(bids.free_list_len, 0);
+            assert_eq!(bids.oracle_pegged_leaf_count, 0);
+            verify_bookside(&bids);
+            let keys: Vec<i128> = bids.iter_all_including_invalid().map(|(_, l)| l.key).collect();
+            assert_eq!(keys, vec![2, 1, 0]);
+        }
+    }
+
+    #[test]
+    fn bookside_free_last_node_free() {
+        let new_leaf = |key: i128| {
+            LeafNode::new(0, 0, key, Pubkey::default(), 1, 0, 0, 0, OrderType::Limit, 0)
+        };
+        let last = (MAX_BOOK_NODES - 1) as NodeHandle;
+
+        // the last slot is the head or the tail of the free list
+        for at_head in [true, false] {
+            let mut asks = new_bookside(DataType::Asks);
+            for key in 0..3 {
+                asks.insert_leaf(&new_leaf(key)).unwrap();
+            }
+            asks.remove_by_key(1).unwrap();
+            assert_eq!(asks.free_list_len, 2);
+
+            let mut free = FreeNode { tag: 0, next: 0, padding: [0; NODE_SIZE - 8] };
+            if at_head {
+                free.tag = NodeTag::FreeNode.into();
+                free.next = asks.free_list_head;
+                asks.free_list_head = last;
+            } else {
+                free.tag = NodeTag::LastFreeNode.into();
+                let tail = cast_ref::<AnyNode, FreeNode>(&asks.nodes[asks.free_list_head as usize])
+                    .next as usize;
+                let tail_node: &mut FreeNode = cast_mut(&mut asks.nodes[tail]);
+                assert_eq!(tail_node.tag, u32::from(NodeTag::LastFreeNode));
+                tail_node.tag = NodeTag::FreeNode.into();
+                tail_node.next = last;
+            }
+            asks.free_list_len += 1;
+            write_last_slot(&mut asks, cast_ref(&free));
+
+            asks.free_last_node().unwrap();
+            assert!(asks.has_oracle_pegged_tree());
+            assert_eq!(asks.free_list_len, 2);
+            verify_bookside(&asks);
+
+            // the two free slots take one more order, the last slot isn't reused
+            asks.insert_leaf(&new_leaf(3)).unwrap();
+            verify_bookside(&asks);
+            assert!(asks.insert_leaf(&new_leaf(4)).is_err());
+        }
+    }
+
+    #[test]
+    fn bookside_expiry_and_eviction_in_both_trees() {
+        use bytemuck::Zeroable;
+
+        let mut market = PerpMarket::zeroed();
+        let new_leaf = |key: i128, peg_limit: i64, time_in_force: u8| {
+            let owner = Pubkey::default();
+            LeafNode::new(1, 0, key, owner, 1, 0, 1000, peg_limit, OrderType::Limit, time_in_force)
+        };
+        let oracle_price_lots = 1000;
+
+        let mut bids = new_bookside(DataType::Bids);
+        let fixed = new_leaf(market.gen_order_id(Side::Bid, 990), 0, 0);
+        let expiring = new_leaf(market.gen_oracle_pegged_order_id(Side::Bid, -5), -1, 10);
+        let far = new_leaf(market.gen_oracle_pegged_order_id(Side::Bid, -20), -1, 0);
+        // pegged at 1005 but limited to bids of at most 1002
+        let limited = new_leaf(market.gen_oracle_pegged_order_id(Side::Bid, 5), 1002, 0);
+        for leaf in [&fixed, &expiring, &far, &limited] {
+            bids.insert_leaf(leaf).unwrap();
+        }
+
+        // expired oracle pegged orders are found like fixed price ones
+        assert_eq!(bids.find_earliest_expiry().unwrap().1, 1010);
+        assert!(bids.remove_one_expired(1010).is_none());
+        assert_eq!(bids.remove_one_expired(1011).unwrap().key, expiring.key);
+
+        // the bid that can't match goes first, then the lowest priced one of either tree
+        let (worst, price) = bids.remove_worst(oracle_price_lots).unwrap();
+        assert_eq!((worst.key, price), (limited.key, None));
+        let (worst, price) = bids.remove_worst(oracle_price_lots).unwrap();
+        assert_eq!((worst.key, price), (far.key, Some(980)));
+        let (worst, price) = bids.remove_worst(oracle_price_lots).unwrap();
+        assert_eq!((worst.key, price), (fixed.key, Some(990)));
+        assert!(bids.remove_worst(oracle_price_lots).is_none());
+        verify_bookside(&bids);
+
+        let mut asks = new_bookside(DataType::Asks);
+        let fixed = new_leaf(market.gen_order_id(Side::Ask, 1010), 0, 0);
+        let far = new_leaf(market.gen_oracle_pegged_order_id(Side::Ask, 20), -1, 0);
+        asks.insert_leaf(&fixed).unwrap();
+        asks.insert_leaf(&far).unwrap();
+        let (worst, price) = asks.remove_worst(oracle_price_lots).unwrap();
+        assert_eq!((worst.key, price), (far.key, Some(1020)));
+        let (worst, price) = asks.remove_worst(oracle_price_lots).unwrap();
+        assert_eq!((worst.key, price), (fixed.key, Some(1010)));
+    }
+
     #[test]
     fn bookside_expiry_random() {
         use rand::Rng;
@@ -2379,17 +3554,8 @@ mod tests {
         false
     }
 
-    #[test]
-    fn book_bids_full() {
-        use crate::queue::{AnyEvent, EventQueueHeader};
-        use crate::state::FREE_ORDER_SLOT;
+    fn new_mango_group() -> MangoGroup {
         use bytemuck::Zeroable;
-        use std::cell::RefCell;
-
-        let bids = RefCell::new(new_bookside(DataType::Bids));
-        let asks = RefCell::new(new_bookside(DataType::Asks));
-        let mut book = Book { bids: bids.borrow_mut(), asks: asks.borrow_mut() };
-
         let mut mango_group = MangoGroup::zeroed();
         mango_group.perp_markets[0] = PerpMarketInfo {
             perp_market: Pubkey::default(),
@@ -2402,7 +3568,35 @@ mod tests {
             taker_fee: I80F48::ZERO,
             base_lot_size: 1,
             quote_lot_size: 1,
+            index_divisor: I80F48::ONE,
+            underlying_oracle_index: 0,
+            index_exponent: 1,
+            maint_shock_bps: 0,
+            init_shock_bps: 0,
+            ema_period: 0,
+            liquidation_use_ema: false,
+            funding_use_ema: false,
+            max_price_move_bps: 0,
+            price_band_bps: 0,
+            price_band_clamp: false,
+            padding: [0; 1],
+            settlement_price: I80F48::ZERO,
         };
+        mango_group
+    }
+
+    #[test]
+    fn book_bids_full() {
+        use crate::queue::{AnyEvent, EventQueueHeader};
+        use crate::state::FREE_ORDER_SLOT;
+        use bytemuck::Zeroable;
+        use std::cell::RefCell;
+
+        let bids = RefCell::new(new_bookside(DataType::Bids));
+        let asks = RefCell::new(new_bookside(DataType::Asks));
+        let mut book = Book { bids: bids.borrow_mut(), asks: asks.borrow_mut() };
+
+        let mango_group = new_mango_group();
 
         let mango_cache = MangoCache::zeroed();
 
@@ -2444,8 +3638,10 @@ mod tests {
                     quantity,
                     i64::MAX,
                     OrderType::Limit,
+                    SelfTradeBehavior::DecrementTake,
                     tif,
                     0,
+                    0,
                     now_ts,
                     None,
                     u8::MAX,
@@ -2493,4 +3689,1114 @@ mod tests {
         assert!(!bookside_contains_price(&book.bids, bids_max - 4));
         assert!(bookside_contains_price(&book.bids, bids_max - 5));
     }
+
+    #[test]
+    fn book_self_trade() {
+        use crate::queue::{AnyEvent, EventQueueHeader};
+        use crate::state::FREE_ORDER_SLOT;
+        use bytemuck::Zeroable;
+        use std::cell::RefCell;
+
+        let bids = RefCell::new(new_bookside(DataType::Bids));
+        let asks = RefCell::new(new_bookside(DataType::Asks));
+        let mut book = Book { bids: bids.borrow_mut(), asks: asks.borrow_mut() };
+
+        let mango_group = new_mango_group();
+        let mango_cache = MangoCache::zeroed();
+
+        let event_queue_header = RefCell::new(EventQueueHeader::zeroed());
+        let event_queue_buf = RefCell::new([AnyEvent::zeroed(); 100]);
+        let mut event_queue =
+            EventQueue::new(event_queue_header.borrow_mut(), event_queue_buf.borrow_mut());
+
+        let mut perp_market = PerpMarket::zeroed();
+        perp_market.quote_lot_size = 1;
+        perp_market.base_lot_size = 1;
+
+        let mango_account_pk = Pubkey::new_unique();
+        let mut mango_account = MangoAccount::zeroed();
+        mango_account.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
+
+        let mut new_order = |book: &mut Book,
+                             mango_account: &mut MangoAccount,
+                             side,
+                             quantity,
+                             self_trade_behavior|
+         -> MangoResult<Option<(i128, i64)>> {
+            book.new_order(
+                &Pubkey::default(),
+                &mango_group,
+                &Pubkey::default(),
+                &mango_cache,
+                &mut event_queue,
+                &mut perp_market,
+                I80F48::from_num(1000),
+                mango_account,
+                &mango_account_pk,
+                0,
+                side,
+                1000,
+                quantity,
+                i64::MAX,
+                OrderType::Limit,
+                self_trade_behavior,
+                0,
+                0,
+                0,
+                1000000,
+                None,
+                u8::MAX,
+            )
+        };
+
+        // DecrementTake shrinks both orders without filling
+        new_order(&mut book, &mut mango_account, Side::Ask, 5, SelfTradeBehavior::DecrementTake)
+            .unwrap();
+        new_order(&mut book, &mut mango_account, Side::Bid, 3, SelfTradeBehavior::DecrementTake)
+            .unwrap();
+        assert_eq!(book.asks.get_min().unwrap().quantity, 2);
+        assert_eq!(book.bids.leaf_count, 0);
+        assert_eq!(mango_account.perp_accounts[0].asks_quantity, 2);
+        assert_eq!(mango_account.perp_accounts[0].bids_quantity, 0);
+        assert_eq!(mango_account.perp_accounts[0].taker_base, 0);
+        // no taker fees on the decremented quantity
+        assert!(mango_account.perp_accounts[0].quote_position.is_zero());
+
+        // CancelProvide removes the resting ask and posts the bid
+        new_order(&mut book, &mut mango_account, Side::Bid, 1, SelfTradeBehavior::CancelProvide)
+            .unwrap();
+        assert_eq!(book.asks.leaf_count, 0);
+        assert_eq!(book.bids.get_max().unwrap().quantity, 1);
+        assert_eq!(mango_account.perp_accounts[0].asks_quantity, 0);
+        assert_eq!(mango_account.perp_accounts[0].bids_quantity, 1);
+        assert_eq!(mango_account.order_market.iter().filter(|&&m| m == 0).count(), 1);
+
+        // AbortTransaction fails
+        assert!(new_order(
+            &mut book,
+            &mut mango_account,
+            Side::Ask,
+            1,
+            SelfTradeBehavior::AbortTransaction
+        )
+        .is_err());
+        assert_eq!(event_queue.len(), 0);
+
+        // AllowSelfTrade fills against the own order like against any other
+        new_order(&mut book, &mut mango_account, Side::Ask, 1, SelfTradeBehavior::AllowSelfTrade)
+            .unwrap();
+        assert_eq!(book.bids.leaf_count, 0);
+        assert_eq!(book.asks.leaf_count, 0);
+        assert_eq!(mango_account.perp_accounts[0].taker_base, -1);
+        assert_eq!(event_queue.len(), 1);
+        let fill: &FillEvent = cast_ref(event_queue.peek_front().unwrap());
+        assert_eq!(fill.maker, mango_account_pk);
+        assert_eq!(fill.taker, mango_account_pk);
+    }
+
+    #[test]
+    fn book_can_post() {
+        use crate::queue::{AnyEvent, EventQueueHeader};
+        use crate::state::FREE_ORDER_SLOT;
+        use bytemuck::Zeroable;
+        use std::cell::RefCell;
+
+        let bids = RefCell::new(new_bookside(DataType::Bids));
+        let asks = RefCell::new(new_bookside(DataType::Asks));
+        let mut book = Book { bids: bids.borrow_mut(), asks: asks.borrow_mut() };
+
+        let mango_group = new_mango_group();
+        let info = &mango_group.perp_markets[0];
+        let mango_cache = MangoCache::zeroed();
+
+        let event_queue_header = RefCell::new(EventQueueHeader::zeroed());
+        let event_queue_buf = RefCell::new([AnyEvent::zeroed(); 100]);
+        let mut event_queue =
+            EventQueue::new(event_queue_header.borrow_mut(), event_queue_buf.borrow_mut());
+
+        let oracle_price = I80F48::from_num(1000);
+        let now_ts = 1000000;
+        let mut perp_market = PerpMarket::zeroed();
+        perp_market.quote_lot_size = 1;
+        perp_market.base_lot_size = 1;
+        perp_market.max_position_lots = 5;
+
+        let mut mango_account = MangoAccount::zeroed();
+        mango_account.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
+
+        let can_post =
+            |book: &Book, perp_market: &PerpMarket, ma: &MangoAccount, side, price, qty| {
+                book.can_post(perp_market, info, ma, 0, side, price, qty, oracle_price, now_ts)
+            };
+        assert!(can_post(&book, &perp_market, &mango_account, Side::Bid, 1000, 5));
+        assert!(can_post(&book, &perp_market, &mango_account, Side::Ask, 1000, 5));
+        // beyond the oracle limits of maint weights of 1
+        assert!(!can_post(&book, &perp_market, &mango_account, Side::Bid, 1001, 1));
+        assert!(!can_post(&book, &perp_market, &mango_account, Side::Ask, 999, 1));
+        // beyond max_position_lots
+        assert!(!can_post(&book, &perp_market, &mango_account, Side::Bid, 1000, 6));
+
+        // a bid crossing a resting ask would be rejected
+        let mut maker = MangoAccount::zeroed();
+        maker.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
+        book.new_order(
+            &Pubkey::default(),
+            &mango_group,
+            &Pubkey::default(),
+            &mango_cache,
+            &mut event_queue,
+            &mut perp_market,
+            oracle_price,
+            &mut maker,
+            &Pubkey::new_unique(),
+            0,
+            Side::Ask,
+            1000,
+            1,
+            i64::MAX,
+            OrderType::Limit,
+            SelfTradeBehavior::DecrementTake,
+            0,
+            0,
+            0,
+            now_ts,
+            None,
+            u8::MAX,
+        )
+        .unwrap();
+        assert!(!can_post(&book, &perp_market, &mango_account, Side::Bid, 1000, 1));
+        assert!(can_post(&book, &perp_market, &mango_account, Side::Bid, 999, 1));
+
+        // no free order slot
+        let mut full_account = mango_account;
+        full_account.order_market = [0; MAX_PERP_OPEN_ORDERS];
+        assert!(!can_post(&book, &perp_market, &full_account, Side::Bid, 999, 1));
+
+        // expired market
+        perp_market.expiry = now_ts;
+        assert!(!can_post(&book, &perp_market, &mango_account, Side::Bid, 999, 1));
+    }
+
+    #[test]
+    fn book_fill_or_kill() {
+        use crate::queue::{AnyEvent, EventQueueHeader};
+        use crate::state::FREE_ORDER_SLOT;
+        use bytemuck::Zeroable;
+        use std::cell::RefCell;
+
+        let bids = RefCell::new(new_bookside(DataType::Bids));
+        let asks = RefCell::new(new_bookside(DataType::Asks));
+        let mut book = Book { bids: bids.borrow_mut(), asks: asks.borrow_mut() };
+
+        let mango_group = new_mango_group();
+        let mango_cache = MangoCache::zeroed();
+
+        let event_queue_header = RefCell::new(EventQueueHeader::zeroed());
+        let event_queue_buf = RefCell::new([AnyEvent::zeroed(); 100]);
+        let mut event_queue =
+            EventQueue::new(event_queue_header.borrow_mut(), event_queue_buf.borrow_mut());
+
+        let mut perp_market = PerpMarket::zeroed();
+        perp_market.quote_lot_size = 1;
+        perp_market.base_lot_size = 1;
+
+        let maker_pk = Pubkey::new_unique();
+        let mut maker = MangoAccount::zeroed();
+        maker.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
+        let taker_pk = Pubkey::new_unique();
+        let mut taker = MangoAccount::zeroed();
+        taker.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
+
+        let mut new_order = |book: &mut Book,
+                             event_queue: &mut EventQueue,
+                             mango_account: &mut MangoAccount,
+                             mango_account_pk: &Pubkey,
+                             side,
+                             price,
+                             quantity,
+                             order_type|
+         -> MangoResult<Option<(i128, i64)>> {
+            book.new_order(
+                &Pubkey::default(),
+                &mango_group,
+                &Pubkey::default(),
+                &mango_cache,
+                event_queue,
+                &mut perp_market,
+                I80F48::from_num(1000),
+                mango_account,
+                mango_account_pk,
+                0,
+                side,
+                price,
+                quantity,
+                i64::MAX,
+                order_type,
+                SelfTradeBehavior::DecrementTake,
+                0,
+                0,
+                0,
+                1000000,
+                None,
+                u8::MAX,
+            )
+        };
+
+        for price in [1000, 1010] {
+            new_order(
+                &mut book,
+                &mut event_queue,
+                &mut maker,
+                &maker_pk,
+                Side::Ask,
+                price,
+                5,
+                OrderType::Limit,
+            )
+            .unwrap();
+        }
+
+        // only 5 lots are available up to 1005, so the order fails and the book is untouched
+        assert!(new_order(
+            &mut book,
+            &mut event_queue,
+            &mut taker,
+            &taker_pk,
+            Side::Bid,
+            1005,
+            8,
+            OrderType::FillOrKill,
+        )
+        .is_err());
+        assert_eq!(book.asks.leaf_count, 2);
+        assert_eq!(event_queue.len(), 0);
+        assert_eq!(taker.perp_accounts[0].taker_base, 0);
+
+        new_order(
+            &mut book,
+            &mut event_queue,
+            &mut taker,
+            &taker_pk,
+            Side::Bid,
+            1010,
+            8,
+            OrderType::FillOrKill,
+        )
+        .unwrap();
+        assert_eq!(book.asks.leaf_count, 1);
+        assert_eq!(book.asks.get_min().unwrap().quantity, 2);
+        assert_eq!(book.bids.leaf_count, 0);
+        assert_eq!(event_queue.len(), 2);
+        assert_eq!(taker.perp_accounts[0].taker_base, 8);
+    }
+
+    #[test]
+    fn book_price_band() {
+        use crate::queue::{AnyEvent, EventQueueHeader};
+        use crate::state::FREE_ORDER_SLOT;
+        use bytemuck::Zeroable;
+        use std::cell::RefCell;
+
+        let bids = RefCell::new(new_bookside(DataType::Bids));
+        let asks = RefCell::new(new_bookside(DataType::Asks));
+        let mut book = Book { bids: bids.borrow_mut(), asks: asks.borrow_mut() };
+
+        let mut mango_group = new_mango_group();
+        mango_group.perp_markets[0].price_band_bps = 2000;
+        // allow bids up to the band edge to rest on the book
+        mango_group.perp_markets[0].maint_liab_weight = I80F48::from_num(1.5);
+        let mango_cache = MangoCache::zeroed();
+
+        let event_queue_header = RefCell::new(EventQueueHeader::zeroed());
+        let event_queue_buf = RefCell::new([AnyEvent::zeroed(); 100]);
+        let mut event_queue =
+            EventQueue::new(event_queue_header.borrow_mut(), event_queue_buf.borrow_mut());
+
+        let mut perp_market = PerpMarket::zeroed();
+        perp_market.quote_lot_size = 1;
+        perp_market.base_lot_size = 1;
+
+        let maker_pk = Pubkey::new_unique();
+        let mut maker = MangoAccount::zeroed();
+        maker.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
+        let taker_pk = Pubkey::new_unique();
+        let mut taker = MangoAccount::zeroed();
+        taker.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
+
+        let mut new_order = |book: &mut Book,
+                             mango_group: &MangoGroup,
+                             mango_account: &mut MangoAccount,
+                             mango_account_pk: &Pubkey,
+                             side,
+                             price,
+                             order_type|
+         -> MangoResult<Option<(i128, i64)>> {
+            book.new_order(
+                &Pubkey::default(),
+                mango_group,
+                &Pubkey::default(),
+                &mango_cache,
+                &mut event_queue,
+                &mut perp_market,
+                I80F48::from_num(1000),
+                mango_account,
+                mango_account_pk,
+                0,
+                side,
+                price,
+                5,
+                i64::MAX,
+                order_type,
+                SelfTradeBehavior::DecrementTake,
+                0,
+                0,
+                0,
+                1000000,
+                None,
+                u8::MAX,
+            )
+        };
+
+        for price in [1000, 1300] {
+            new_order(
+                &mut book,
+                &mango_group,
+                &mut maker,
+                &maker_pk,
+                Side::Ask,
+                price,
+                OrderType::Limit,
+            )
+            .unwrap();
+        }
+
+        // a bid above oracle + 20% is rejected
+        assert!(new_order(
+            &mut book,
+            &mango_group,
+            &mut taker,
+            &taker_pk,
+            Side::Bid,
+            1500,
+            OrderType::Limit
+        )
+        .is_err());
+        assert_eq!(book.asks.leaf_count, 2);
+
+        // market orders are clamped regardless of the flag
+        new_order(&mut book, &mango_group, &mut taker, &taker_pk, Side::Bid, 0, OrderType::Market)
+            .unwrap();
+        assert_eq!(taker.perp_accounts[0].taker_base, 5);
+        assert_eq!(book.asks.get_min().unwrap().price(), 1300);
+
+        // with clamping the bid can't reach the ask at 1300 and rests at the band edge
+        mango_group.perp_markets[0].price_band_clamp = true;
+        new_order(
+            &mut book,
+            &mango_group,
+            &mut taker,
+            &taker_pk,
+            Side::Bid,
+            1500,
+            OrderType::Limit,
+        )
+        .unwrap();
+        assert_eq!(taker.perp_accounts[0].taker_base, 5);
+        assert_eq!(book.bids.get_max().unwrap().price(), 1200);
+    }
+
+    #[test]
+    fn book_good_till_date() {
+        use crate::queue::{AnyEvent, EventQueueHeader};
+        use crate::state::FREE_ORDER_SLOT;
+        use bytemuck::Zeroable;
+        use std::cell::RefCell;
+
+        let bids = RefCell::new(new_bookside(DataType::Bids));
+        let asks = RefCell::new(new_bookside(DataType::Asks));
+        let mut book = Book { bids: bids.borrow_mut(), asks: asks.borrow_mut() };
+
+        let mango_group = new_mango_group();
+        let mango_cache = MangoCache::zeroed();
+
+        let event_queue_header = RefCell::new(EventQueueHeader::zeroed());
+        let event_queue_buf = RefCell::new([AnyEvent::zeroed(); 100]);
+        let mut event_queue =
+            EventQueue::new(event_queue_header.borrow_mut(), event_queue_buf.borrow_mut());
+
+        let mut perp_market = PerpMarket::zeroed();
+        perp_market.quote_lot_size = 1;
+        perp_market.base_lot_size = 1;
+        perp_market.meta_data.version = 1;
+
+        let maker_pk = Pubkey::new_unique();
+        let mut maker = MangoAccount::zeroed();
+        maker.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
+        let taker_pk = Pubkey::new_unique();
+        let mut taker = MangoAccount::zeroed();
+        taker.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
+
+        let mut new_order = |book: &mut Book,
+                             event_queue: &mut EventQueue,
+                             mango_account: &mut MangoAccount,
+                             mango_account_pk: &Pubkey,
+                             side,
+                             order_type,
+                             good_till_date,
+                             now_ts|
+         -> MangoResult<Option<(i128, i64)>> {
+            book.new_order(
+                &Pubkey::default(),
+                &mango_group,
+                &Pubkey::default(),
+                &mango_cache,
+                event_queue,
+                &mut perp_market,
+                I80F48::from_num(1000),
+                mango_account,
+                mango_account_pk,
+                0,
+                side,
+                1000,
+                2,
+                i64::MAX,
+                order_type,
+                SelfTradeBehavior::DecrementTake,
+                0,
+                good_till_date,
+                0,
+                now_ts,
+                None,
+                u8::MAX,
+            )
+        };
+
+        let now_ts = 1000000;
+        let expiry = now_ts + 3600;
+        new_order(
+            &mut book,
+            &mut event_queue,
+            &mut maker,
+            &maker_pk,
+            Side::Ask,
+            OrderType::Limit,
+            expiry,
+            now_ts,
+        )
+        .unwrap();
+        let order = *book.asks.get_min().unwrap();
+        assert!(order.is_good_till_date());
+        assert_eq!(order.version, 1 | GOOD_TILL_DATE_FLAG);
+        assert_eq!(order.expiry(), expiry);
+        assert_eq!(order.timestamp, now_ts);
+        assert!(order.is_valid(expiry - 1));
+        assert!(!order.is_valid(expiry));
+
+        // still matchable long after the 255 seconds a time_in_force allows
+        new_order(
+            &mut book,
+            &mut event_queue,
+            &mut taker,
+            &taker_pk,
+            Side::Bid,
+            OrderType::ImmediateOrCancel,
+            0,
+            expiry - 1,
+        )
+        .unwrap();
+        assert_eq!(taker.perp_accounts[0].taker_base, 2);
+        assert_eq!(book.asks.leaf_count, 0);
+
+        new_order(
+            &mut book,
+            &mut event_queue,
+            &mut maker,
+            &maker_pk,
+            Side::Ask,
+            OrderType::Limit,
+            expiry,
+            now_ts,
+        )
+        .unwrap();
+        assert_eq!(book.asks.find_earliest_expiry().unwrap().1, expiry);
+
+        // once expired it is removed instead of matched
+        new_order(
+            &mut book,
+            &mut event_queue,
+            &mut taker,
+            &taker_pk,
+            Side::Bid,
+            OrderType::ImmediateOrCancel,
+            0,
+            expiry,
+        )
+        .unwrap();
+        assert_eq!(taker.perp_accounts[0].taker_base, 2);
+        assert_eq!(book.asks.leaf_count, 0);
+    }
+
+    #[test]
+    fn book_edit_order() {
+        use crate::queue::{AnyEvent, EventQueueHeader};
+        use crate::state::FREE_ORDER_SLOT;
+        use bytemuck::Zeroable;
+        use std::cell::RefCell;
+
+        let bids = RefCell::new(new_bookside(DataType::Bids));
+        let asks = RefCell::new(new_bookside(DataType::Asks));
+        let mut book = Book { bids: bids.borrow_mut(), asks: asks.borrow_mut() };
+
+        let mango_group = new_mango_group();
+        let mango_cache = MangoCache::zeroed();
+
+        let event_queue_header = RefCell::new(EventQueueHeader::zeroed());
+        let event_queue_buf = RefCell::new([AnyEvent::zeroed(); 100]);
+        let mut event_queue =
+            EventQueue::new(event_queue_header.borrow_mut(), event_queue_buf.borrow_mut());
+
+        let mut perp_market = PerpMarket::zeroed();
+        perp_market.quote_lot_size = 1;
+        perp_market.base_lot_size = 1;
+        perp_market.meta_data.version = 1;
+
+        let maker_pk = Pubkey::new_unique();
+        let mut maker = MangoAccount::zeroed();
+        maker.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
+        let other_maker_pk = Pubkey::new_unique();
+        let mut other_maker = MangoAccount::zeroed();
+        other_maker.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
+
+        let new_ask = |book: &mut Book,
+                       event_queue: &mut EventQueue,
+                       perp_market: &mut PerpMarket,
+                       mango_account: &mut MangoAccount,
+                       mango_account_pk: &Pubkey,
+                       quantity,
+                       time_in_force,
+                       good_till_date,
+                       client_order_id,
+                       now_ts| {
+            book.new_order(
+                &Pubkey::default(),
+                &mango_group,
+                &Pubkey::default(),
+                &mango_cache,
+                event_queue,
+                perp_market,
+                I80F48::from_num(1000),
+                mango_account,
+                mango_account_pk,
+                0,
+                Side::Ask,
+                1000,
+                quantity,
+                i64::MAX,
+                OrderType::Limit,
+                SelfTradeBehavior::DecrementTake,
+                time_in_force,
+                good_till_date,
+                client_order_id,
+                now_ts,
+                None,
+                u8::MAX,
+            )
+            .unwrap()
+            .unwrap()
+            .0
+        };
+        let edit_ask = |book: &mut Book,
+                        event_queue: &mut EventQueue,
+                        perp_market: &mut PerpMarket,
+                        mango_account: &mut MangoAccount,
+                        mango_account_pk: &Pubkey,
+                        order_id,
+                        new_price,
+                        new_quantity,
+                        now_ts| {
+            book.edit_order(
+                &Pubkey::default(),
+                &mango_group,
+                &Pubkey::default(),
+                &mango_cache,
+                event_queue,
+                perp_market,
+                I80F48::from_num(1000),
+                mango_account,
+                mango_account_pk,
+                0,
+                order_id,
+                Side::Ask,
+                new_price,
+                new_quantity,
+                now_ts,
+                None,
+                u8::MAX,
+            )
+            .unwrap()
+        };
+
+        let now_ts = 1000000;
+        let expiry = now_ts + 3600;
+        let order_id = new_ask(
+            &mut book,
+            &mut event_queue,
+            &mut perp_market,
+            &mut maker,
+            &maker_pk,
+            5,
+            0,
+            expiry,
+            7,
+            now_ts,
+        );
+        let other_order_id = new_ask(
+            &mut book,
+            &mut event_queue,
+            &mut perp_market,
+            &mut other_maker,
+            &other_maker_pk,
+            3,
+            100,
+            0,
+            8,
+            now_ts,
+        );
+        assert_eq!(maker.perp_accounts[0].asks_quantity, 5);
+
+        // Reducing the quantity at the same price keeps the order ahead of the other one
+        let (order, reinserted) = edit_ask(
+            &mut book,
+            &mut event_queue,
+            &mut perp_market,
+            &mut maker,
+            &maker_pk,
+            order_id,
+            1000,
+            2,
+            now_ts + 1,
+        );
+        assert!(!reinserted);
+        assert_eq!(order.quantity, 5);
+        assert_eq!(maker.perp_accounts[0].asks_quantity, 2);
+        assert_eq!(maker.perp_accounts[0].bids_quantity, 0);
+        let best = *book.asks.get_min().unwrap();
+        assert_eq!(best.key, order_id);
+        assert_eq!(best.quantity, 2);
+        assert_eq!(book.asks.leaf_count, 2);
+
+        // A new price cancels and places it again with the same client id and expiry
+        let (order, reinserted) = edit_ask(
+            &mut book,
+            &mut event_queue,
+            &mut perp_market,
+            &mut maker,
+            &maker_pk,
+            order_id,
+            1001,
+            4,
+            now_ts + 2,
+        );
+        assert!(reinserted);
+        assert_eq!(order.key, order_id);
+        assert!(book.asks.find_by_key(order_id).is_none());
+        assert_eq!(maker.perp_accounts[0].asks_quantity, 4);
+        let edited = *book.asks.get_max().unwrap();
+        assert_eq!(edited.price(), 1001);
+        assert_eq!(edited.quantity, 4);
+        assert_eq!(edited.owner, maker_pk);
+        assert_eq!(edited.client_order_id, 7);
+        assert!(edited.is_good_till_date());
+        assert_eq!(edited.expiry(), expiry);
+        assert_eq!(maker.find_order_with_client_id(0, 7).unwrap().0, edited.key);
+
+        // Growing an order with a time in force doesn't extend its life
+        let (_, reinserted) = edit_ask(
+            &mut book,
+            &mut event_queue,
+            &mut perp_market,
+            &mut other_maker,
+            &other_maker_pk,
+            other_order_id,
+            1000,
+            6,
+            now_ts + 40,
+        );
+        assert!(reinserted);
+        let edited = *book.asks.get_min().unwrap();
+        assert_ne!(edited.key, other_order_id);
+        assert_eq!(edited.quantity, 6);
+        assert_eq!(edited.client_order_id, 8);
+        assert_eq!(edited.expiry(), now_ts + 100);
+        assert_eq!(other_maker.perp_accounts[0].asks_quantity, 6);
+    }
+
+    #[test]
+    fn book_batch_cancel_then_place() {
+        use crate::queue::{AnyEvent, EventQueueHeader};
+        use crate::state::FREE_ORDER_SLOT;
+        use bytemuck::Zeroable;
+        use std::cell::RefCell;
+
+        let bids = RefCell::new(new_bookside(DataType::Bids));
+        let asks = RefCell::new(new_bookside(DataType::Asks));
+        let mut book = Book { bids: bids.borrow_mut(), asks: asks.borrow_mut() };
+
+        let mango_group = new_mango_group();
+        let mango_cache = MangoCache::zeroed();
+
+        let event_queue_header = RefCell::new(EventQueueHeader::zeroed());
+        let event_queue_buf = RefCell::new([AnyEvent::zeroed(); 100]);
+        let mut event_queue =
+            EventQueue::new(event_queue_header.borrow_mut(), event_queue_buf.borrow_mut());
+
+        let mut perp_market = PerpMarket::zeroed();
+        perp_market.quote_lot_size = 1;
+        perp_market.base_lot_size = 1;
+        perp_market.meta_data.version = 1;
+
+        let maker_pk = Pubkey::new_unique();
+        let mut maker = MangoAccount::zeroed();
+        maker.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
+
+        let mut new_order = |book: &mut Book,
+                             perp_market: &mut PerpMarket,
+                             maker: &mut MangoAccount,
+                             side,
+                             price,
+                             quantity,
+                             client_order_id,
+                             now_ts| {
+            book.new_order(
+                &Pubkey::default(),
+                &mango_group,
+                &Pubkey::default(),
+                &mango_cache,
+                &mut event_queue,
+                perp_market,
+                I80F48::from_num(1000),
+                maker,
+                &maker_pk,
+                0,
+                side,
+                price,
+                quantity,
+                i64::MAX,
+                OrderType::Limit,
+                SelfTradeBehavior::DecrementTake,
+                0,
+                0,
+                client_order_id,
+                now_ts,
+                None,
+                u8::MAX,
+            )
+            .unwrap();
+        };
+
+        let now_ts = 1000000;
+        new_order(&mut book, &mut perp_market, &mut maker, Side::Ask, 1000, 5, 1, now_ts);
+        new_order(&mut book, &mut perp_market, &mut maker, Side::Bid, 990, 2, 2, now_ts);
+        let (ask_id, ask_side) = maker.find_order_with_client_id(0, 1).unwrap();
+        assert_eq!(ask_side, Side::Ask);
+
+        // Cancels go first, so a bid at the price of the canceled ask rests instead of
+        // trading against it
+        let canceled_order_ids = book
+            .cancel_orders_with_incentives(
+                &mut maker,
+                &mut perp_market,
+                0,
+                vec![(ask_id, ask_side)],
+                now_ts + 1,
+            )
+            .unwrap();
+        assert_eq!(canceled_order_ids, vec![ask_id]);
+        new_order(&mut book, &mut perp_market, &mut maker, Side::Bid, 1000, 3, 3, now_ts + 1);
+
+        assert_eq!(event_queue.len(), 0);
+        assert!(book.asks.get_min().is_none());
+        let best_bid = *book.bids.get_max().unwrap();
+        assert_eq!(best_bid.price(), 1000);
+        assert_eq!(best_bid.quantity, 3);
+        assert_eq!(best_bid.client_order_id, 3);
+        assert_eq!(book.bids.leaf_count, 2);
+        assert_eq!(maker.perp_accounts[0].asks_quantity, 0);
+        assert_eq!(maker.perp_accounts[0].bids_quantity, 5);
+        assert!(maker.find_order_with_client_id(0, 1).is_none());
+        assert!(maker.find_order_with_client_id(0, 3).is_some());
+    }
+
+    #[test]
+    fn book_funding_premium() {
+        use crate::queue::{AnyEvent, EventQueueHeader};
+        use crate::state::FREE_ORDER_SLOT;
+        use bytemuck::Zeroable;
+        use std::cell::RefCell;
+
+        let bids = RefCell::new(new_bookside(DataType::Bids));
+        let asks = RefCell::new(new_bookside(DataType::Asks));
+        let mut book = Book { bids: bids.borrow_mut(), asks: asks.borrow_mut() };
+
+        let mango_group = new_mango_group();
+        let mut mango_cache = MangoCache::zeroed();
+
+        let event_queue_header = RefCell::new(EventQueueHeader::zeroed());
+        let event_queue_buf = RefCell::new([AnyEvent::zeroed(); 100]);
+        let mut event_queue =
+            EventQueue::new(event_queue_header.borrow_mut(), event_queue_buf.borrow_mut());
+
+        let now_ts = 1000000;
+        let index_price = I80F48::from_num(1000);
+        let mut perp_market = PerpMarket::zeroed();
+        perp_market.quote_lot_size = 1;
+        perp_market.base_lot_size = 1;
+        perp_market.meta_data.version = 1;
+        perp_market.impact_quantity = 1;
+        perp_market.max_funding = I80F48::from_num(0.05);
+        perp_market.min_funding = I80F48::from_num(-0.05);
+        perp_market.funding_period = 40;
+        perp_market.last_updated = now_ts;
+        perp_market.last_premium_update = now_ts;
+
+        let maker_pk = Pubkey::new_unique();
+        let mut maker = MangoAccount::zeroed();
+        maker.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
+
+        let mut new_order = |book: &mut Book, side, price, now_ts| {
+            book.new_order(
+                &Pubkey::default(),
+                &mango_group,
+                &Pubkey::default(),
+                &mango_cache,
+                &mut event_queue,
+                &mut perp_market,
+                index_price,
+                &mut maker,
+                &maker_pk,
+                0,
+                side,
+                price,
+                1,
+                i64::MAX,
+                OrderType::Limit,
+                SelfTradeBehavior::DecrementTake,
+                0,
+                0,
+                0,
+                now_ts,
+                None,
+                u8::MAX,
+            )
+            .unwrap();
+        };
+
+        // A book with only bids pays the max premium, then the mid price 2% above the index
+        new_order(&mut book, Side::Bid, 1010, now_ts);
+        new_order(&mut book, Side::Ask, 1030, now_ts + 10);
+        let eps = I80F48::from_num(0.000001);
+        assert!((perp_market.premium_accumulator - I80F48::from_num(0.5)).abs() < eps);
+        assert!((perp_market.last_premium - I80F48::from_num(0.02)).abs() < eps);
+        assert_eq!(perp_market.last_premium_update, now_ts + 10);
+
+        // Without a book change the last premium keeps accruing
+        perp_market.update_premium(&book, index_price, now_ts + 40);
+        assert!((perp_market.premium_accumulator - I80F48::from_num(1.1)).abs() < eps);
+
+        // Funding pays the time weighted premium of 1.1 / 40 over the whole funding period
+        mango_cache.price_cache[0].price = index_price;
+        mango_cache.price_cache[0].last_update = now_ts + 40;
+        perp_market.update_funding(&mango_group, &book, &mango_cache, 0, now_ts + 40).unwrap();
+        assert!((perp_market.long_funding - I80F48::from_num(27.5)).abs() < eps);
+        assert_eq!(perp_market.long_funding, perp_market.short_funding);
+        assert_eq!(perp_market.premium_accumulator, ZERO_I80F48);
+        assert_eq!(perp_market.last_updated, now_ts + 40);
+    }
+
+    #[test]
+    fn book_oracle_pegged_orders() {
+        use crate::queue::{AnyEvent, EventQueueHeader};
+        use crate::state::FREE_ORDER_SLOT;
+        use bytemuck::Zeroable;
+        use std::cell::RefCell;
+
+        let bids = RefCell::new(new_bookside(DataType::Bids));
+        let asks = RefCell::new(new_bookside(DataType::Asks));
+        let mut book = Book { bids: bids.borrow_mut(), asks: asks.borrow_mut() };
+
+        let mango_group = new_mango_group();
+        let mango_cache = MangoCache::zeroed();
+
+        let event_queue_header = RefCell::new(EventQueueHeader::zeroed());
+        let event_queue_buf = RefCell::new([AnyEvent::zeroed(); 100]);
+        let mut event_queue =
+            EventQueue::new(event_queue_header.borrow_mut(), event_queue_buf.borrow_mut());
+
+        let mut perp_market = PerpMarket::zeroed();
+        perp_market.quote_lot_size = 1;
+        perp_market.base_lot_size = 1;
+
+        let maker_pk = Pubkey::new_unique();
+        let mut maker = MangoAccount::zeroed();
+        maker.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
+        let taker_pk = Pubkey::new_unique();
+        let mut taker = MangoAccount::zeroed();
+        taker.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
+
+        let mut new_order = |book: &mut Book,
+                             event_queue: &mut EventQueue,
+                             mango_account: &mut MangoAccount,
+                             mango_account_pk: &Pubkey,
+                             oracle_price: i64,
+                             side,
+                             price,
+                             oracle_peg: Option<(i64, i64)>,
+                             quantity,
+                             order_type|
+         -> MangoResult<Option<(i128, i64)>> {
+            let oracle_price = I80F48::from_num(oracle_price);
+            match oracle_peg {
+                None => book.new_order(
+                    &Pubkey::default(),
+                    &mango_group,
+                    &Pubkey::default(),
+                    &mango_cache,
+                    event_queue,
+                    &mut perp_market,
+                    oracle_price,
+                    mango_account,
+                    mango_account_pk,
+                    0,
+                    side,
+                    price,
+                    quantity,
+                    i64::MAX,
+                    order_type,
+                    SelfTradeBehavior::DecrementTake,
+                    0,
+                    0,
+                    0,
+                    1000000,
+                    None,
+                    u8::MAX,
+                ),
+                Some((price_offset, peg_limit)) => book.new_oracle_pegged_order(
+                    &Pubkey::default(),
+                    &mango_group,
+                    &Pubkey::default(),
+                    &mango_cache,
+                    event_queue,
+                    &mut perp_market,
+                    oracle_price,
+                    mango_account,
+                    mango_account_pk,
+                    0,
+                    side,
+                    price_offset,
+                    peg_limit,
+                    quantity,
+                    i64::MAX,
+                    order_type,
+                    SelfTradeBehavior::DecrementTake,
+                    0,
+                    0,
+                    1000000,
+                    None,
+                    u8::MAX,
+                ),
+            }
+        };
+
+        let (eq, maker_ref) = (&mut event_queue, &mut maker);
+        new_order(
+            &mut book,
+            eq,
+            maker_ref,
+            &maker_pk,
+            1000,
+            Side::Ask,
+            1003,
+            None,
+            2,
+            OrderType::Limit,
+        )
+        .unwrap();
+        new_order(
+            &mut book,
+            eq,
+            maker_ref,
+            &maker_pk,
+            1000,
+            Side::Ask,
+            0,
+            Some((5, -1)),
+            3,
+            OrderType::PostOnly,
+        )
+        .unwrap();
+        // pegged at 990 but limited to asks of 1000 or more, so not matchable for now
+        new_order(
+            &mut book,
+            eq,
+            maker_ref,
+            &maker_pk,
+            1000,
+            Side::Ask,
+            0,
+            Some((-10, 1000)),
+            4,
+            OrderType::PostOnly,
+        )
+        .unwrap();
+        assert_eq!(book.asks.leaf_count_in(BookSideOrderTree::Fixed), 1);
+        assert_eq!(book.asks.leaf_count_in(BookSideOrderTree::OraclePegged), 2);
+        assert!(maker.orders.iter().filter(|&&id| is_oracle_pegged_key(id)).count() == 2);
+
+        // best, impact and size helpers see the ask pegged at 1005 but not the peg limited one
+        let now_ts = 1000000;
+        assert_eq!(book.get_best_ask_price(1000, now_ts), Some(1003));
+        assert_eq!(book.get_impact_price(Side::Ask, 3, 1000, now_ts), Some(1005));
+        assert_eq!(book.get_impact_price(Side::Ask, 6, 1000, now_ts), None);
+        assert_eq!(book.get_asks_size_below(1005, 100, 1000, now_ts), 5);
+        assert_eq!(book.get_best_ask_price(995, now_ts), Some(1000));
+        assert_eq!(book.get_asks_size_below_order(maker.orders[0], 100, 995, now_ts), 3);
+
+        // matches the fixed ask at 1003 first, then the ask pegged at 1005
+        new_order(
+            &mut book,
+            eq,
+            &mut taker,
+            &taker_pk,
+            1000,
+            Side::Bid,
+            1010,
+            None,
+            10,
+            OrderType::ImmediateOrCancel,
+        )
+        .unwrap();
+        assert_eq!(taker.perp_accounts[0].taker_base, 5);
+        assert_eq!(taker.perp_accounts[0].taker_quote, -(2 * 1003 + 3 * 1005));
+        assert_eq!(book.asks.leaf_count_in(BookSideOrderTree::Fixed), 0);
+        assert_eq!(book.asks.leaf_count_in(BookSideOrderTree::OraclePegged), 1);
+
+        // once the oracle moves up the remaining pegged ask is at 1010 and within its limit
+        new_order(
+            &mut book,
+            eq,
+            &mut taker,
+            &taker_pk,
+            1020,
+            Side::Bid,
+            1030,
+            None,
+            10,
+            OrderType::ImmediateOrCancel,
+        )
+        .unwrap();
+        assert_eq!(taker.perp_accounts[0].taker_base, 9);
+        assert_eq!(taker.perp_accounts[0].taker_quote, -(2 * 1003 + 3 * 1005 + 4 * 1010));
+        assert_eq!(book.asks.leaf_count_in(BookSideOrderTree::OraclePegged), 0);
+        assert_eq!(eq.len(), 3);
+    }
 }
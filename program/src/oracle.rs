@@ -1,11 +1,18 @@
-use arrayref::array_ref;
+use arrayref::{array_ref, array_refs};
 use fixed::types::I80F48;
 use mango_common::Loadable;
 use mango_macro::{Loadable, Pod};
 use solana_program::{account_info::AccountInfo, pubkey::Pubkey, rent::Rent};
-use std::{cell::RefMut, mem::size_of, str::FromStr};
+use std::{
+    cell::{Ref, RefMut},
+    mem::size_of,
+};
+use switchboard_v2::SwitchboardDecimal;
 
 use crate::error::{check_assert, MangoErrorCode, MangoResult, SourceFileId};
+use crate::ids::{pyth_push_oracle_program, pyth_receiver_program, switchboard_v2_program};
+use crate::state::{ONE_I80F48, YEAR};
+use crate::utils::{checked_pow_i80f48, ln_i80f48, sqrt_i80f48};
 
 declare_check_assert_macros!(SourceFileId::Oracle);
 
@@ -16,10 +23,38 @@ pub enum OracleType {
     Stub,
     Pyth,
     Switchboard,
+    SwitchboardV2,
+    RealizedVol,
+    Composite,
+    PythPull,
+    Derived,
     Unknown,
 }
 
+/// Per oracle limits from TokenInfo, see MangoGroup::oracle_limits
+pub struct OracleLimits {
+    pub max_conf: Option<I80F48>, // max confidence interval over price; None uses the default
+    pub max_age_slots: u64,       // 0 for no limit
+}
+
+impl OracleLimits {
+    /// Whether a price published at `published_slot` is older than max_age_slots at `slot`
+    pub fn is_stale(&self, slot: u64, published_slot: u64) -> bool {
+        self.max_age_slots > 0 && slot.saturating_sub(published_slot) > self.max_age_slots
+    }
+
+    /// Whether the confidence interval (or std deviation) over the price is above max_conf,
+    /// or above `default` if none is configured
+    pub fn is_conf_too_high(&self, conf: I80F48, default: I80F48) -> bool {
+        conf > self.max_conf.unwrap_or(default)
+    }
+}
+
 pub const STUB_MAGIC: u32 = 0x6F676E4D;
+pub const REALIZED_VOL_MAGIC: u32 = 0x6C6F564D;
+pub const COMPOSITE_MAGIC: u32 = 0x706D434D;
+pub const DERIVED_MAGIC: u32 = 0x7672444D;
+pub const MAX_COMPOSITE_SOURCES: usize = 5;
 
 #[derive(Copy, Clone, Pod, Loadable)]
 #[repr(C)]
@@ -60,6 +95,204 @@ impl StubOracle {
     }
 }
 
+/// Realized volatility of another oracle in the MangoGroup. CachePrices samples the cached price
+/// of the source oracle and keeps an exponentially weighted estimate of the variance of its log
+/// returns. The annualized volatility can then be used as the index of a perp market.
+#[derive(Copy, Clone, Pod, Loadable)]
+#[repr(C)]
+pub struct RealizedVolOracle {
+    pub magic: u32,
+    pub padding: [u8; 4],
+    pub source_index: u64,        // oracle index of the price being tracked
+    pub window: u64,              // seconds; a sample dt after the last has weight dt / window
+    pub min_sample_interval: u64, // samples closer together than this are ignored
+
+    pub last_price: I80F48, // source price at the last sample
+    pub last_update: u64,
+    pub variance_rate: I80F48, // ewma of squared log returns per second
+}
+
+impl RealizedVolOracle {
+    pub fn load_mut_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+    ) -> MangoResult<RefMut<'a, Self>> {
+        check_eq!(account.data_len(), size_of::<Self>(), MangoErrorCode::Default)?;
+        check_eq!(account.owner, program_id, MangoErrorCode::InvalidOwner)?;
+
+        let oracle = Self::load_mut(account)?;
+        check_eq!(oracle.magic, REALIZED_VOL_MAGIC, MangoErrorCode::InvalidOracleType)?;
+
+        Ok(oracle)
+    }
+
+    pub fn load_and_init<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        rent: &Rent,
+        source_index: usize,
+        window: u64,
+        min_sample_interval: u64,
+    ) -> MangoResult<RefMut<'a, Self>> {
+        check_eq!(account.data_len(), size_of::<Self>(), MangoErrorCode::Default)?;
+        check_eq!(account.owner, program_id, MangoErrorCode::InvalidOwner)?;
+        check!(
+            rent.is_exempt(account.lamports(), account.data_len()),
+            MangoErrorCode::AccountNotRentExempt
+        )?;
+
+        let mut oracle = Self::load_mut(account)?;
+        check!(oracle.magic == 0, MangoErrorCode::Default)?;
+
+        oracle.magic = REALIZED_VOL_MAGIC;
+        oracle.source_index = source_index as u64;
+        oracle.window = window;
+        oracle.min_sample_interval = min_sample_interval;
+
+        Ok(oracle)
+    }
+
+    /// Sample the source price at now_ts and update the variance estimate
+    pub fn update(&mut self, price: I80F48, now_ts: u64) {
+        if !price.is_positive() {
+            return;
+        }
+
+        if self.last_price.is_positive() {
+            if now_ts < self.last_update + self.min_sample_interval.max(1) {
+                return;
+            }
+            let dt = I80F48::from_num(now_ts - self.last_update);
+            let log_return = ln_i80f48(price / self.last_price);
+            let sample = log_return * log_return / dt;
+            let alpha = (dt / I80F48::from_num(self.window)).min(ONE_I80F48);
+            self.variance_rate = self.variance_rate * (ONE_I80F48 - alpha) + sample * alpha;
+        }
+
+        self.last_price = price;
+        self.last_update = now_ts;
+    }
+
+    /// Annualized realized volatility, e.g. 0.8 for 80%
+    pub fn annualized_vol(&self) -> I80F48 {
+        sqrt_i80f48(self.variance_rate * YEAR)
+    }
+}
+
+/// Weighted median of several Pyth, Switchboard or stub oracles. Sources that fail to read are
+/// skipped as long as at least min_quorum sources are valid.
+#[derive(Copy, Clone, Pod, Loadable)]
+#[repr(C)]
+pub struct CompositeOracle {
+    pub magic: u32,
+    pub num_sources: u8,
+    pub min_quorum: u8,
+    pub weights: [u16; MAX_COMPOSITE_SOURCES],
+    pub sources: [Pubkey; MAX_COMPOSITE_SOURCES],
+}
+
+impl CompositeOracle {
+    pub fn load_and_init<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        rent: &Rent,
+    ) -> MangoResult<RefMut<'a, Self>> {
+        check_eq!(account.data_len(), size_of::<Self>(), MangoErrorCode::Default)?;
+        check_eq!(account.owner, program_id, MangoErrorCode::InvalidOwner)?;
+        check!(
+            rent.is_exempt(account.lamports(), account.data_len()),
+            MangoErrorCode::AccountNotRentExempt
+        )?;
+
+        let mut oracle = Self::load_mut(account)?;
+        check!(oracle.magic == 0, MangoErrorCode::Default)?;
+        oracle.magic = COMPOSITE_MAGIC;
+
+        Ok(oracle)
+    }
+
+    /// Weighted median of the (price, weight) of the sources that could be read, None if fewer
+    /// than min_quorum of them could
+    pub fn aggregate(&self, prices: &mut [(I80F48, u16)]) -> Option<I80F48> {
+        if prices.len() < self.min_quorum as usize {
+            return None;
+        }
+        weighted_median(prices)
+    }
+}
+
+/// Ratio of two other oracles in the MangoGroup: base^base_exponent / quote^quote_exponent *
+/// multiplier. CachePrices computes it from the cached prices of both sources, e.g. for an
+/// ETH/BTC index or ETH^2/BTC.
+#[derive(Copy, Clone, Pod, Loadable)]
+#[repr(C)]
+pub struct DerivedOracle {
+    pub magic: u32,
+    pub base_exponent: u8,
+    pub quote_exponent: u8,
+    pub padding: [u8; 2],
+    pub base_index: u64,    // oracle index of the numerator
+    pub quote_index: u64,   // oracle index of the denominator
+    pub multiplier: I80F48, // also converts between the native units of the tokens
+}
+
+impl DerivedOracle {
+    pub fn load_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+    ) -> MangoResult<Ref<'a, Self>> {
+        check_eq!(account.data_len(), size_of::<Self>(), MangoErrorCode::Default)?;
+        check_eq!(account.owner, program_id, MangoErrorCode::InvalidOwner)?;
+
+        let oracle = Self::load(account)?;
+        check_eq!(oracle.magic, DERIVED_MAGIC, MangoErrorCode::InvalidOracleType)?;
+
+        Ok(oracle)
+    }
+
+    pub fn load_and_init<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        rent: &Rent,
+    ) -> MangoResult<RefMut<'a, Self>> {
+        check_eq!(account.data_len(), size_of::<Self>(), MangoErrorCode::Default)?;
+        check_eq!(account.owner, program_id, MangoErrorCode::InvalidOwner)?;
+        check!(
+            rent.is_exempt(account.lamports(), account.data_len()),
+            MangoErrorCode::AccountNotRentExempt
+        )?;
+
+        let mut oracle = Self::load_mut(account)?;
+        check!(oracle.magic == 0, MangoErrorCode::Default)?;
+        oracle.magic = DERIVED_MAGIC;
+
+        Ok(oracle)
+    }
+
+    pub fn compute_price(&self, base_price: I80F48, quote_price: I80F48) -> Option<I80F48> {
+        if !base_price.is_positive() || !quote_price.is_positive() {
+            return None;
+        }
+        checked_pow_i80f48(base_price, self.base_exponent)?
+            .checked_div(checked_pow_i80f48(quote_price, self.quote_exponent)?)?
+            .checked_mul(self.multiplier)
+    }
+}
+
+/// Return the price at which at least half of the total weight is at or below
+pub fn weighted_median(prices: &mut [(I80F48, u16)]) -> Option<I80F48> {
+    prices.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    let total: u32 = prices.iter().map(|(_, w)| *w as u32).sum();
+    let mut cumulative = 0u32;
+    for (price, weight) in prices.iter() {
+        cumulative += *weight as u32;
+        if 2 * cumulative >= total {
+            return Some(*price);
+        }
+    }
+    None
+}
+
 pub fn determine_oracle_type(account: &AccountInfo) -> OracleType {
     let borrowed = account.data.borrow();
     let magic = u32::from_le_bytes(*array_ref![borrowed, 0, 4]);
@@ -69,11 +302,276 @@ pub fn determine_oracle_type(account: &AccountInfo) -> OracleType {
         OracleType::Switchboard
     } else if magic == STUB_MAGIC {
         OracleType::Stub
-    }
-    //  else if account.owner == &Pubkey::from_str("2TfB33aLaneQb5TNVwyDz3jSZXS6jdW2ARw1Dgf84XCG").unwrap() {
-    //     return OracleType::SwitchboardV2;
-    // }
-    else {
+    } else if magic == REALIZED_VOL_MAGIC {
+        OracleType::RealizedVol
+    } else if magic == COMPOSITE_MAGIC {
+        OracleType::Composite
+    } else if magic == DERIVED_MAGIC {
+        OracleType::Derived
+    } else if account.owner == &switchboard_v2_program::ID {
+        OracleType::SwitchboardV2
+    } else if account.owner == &pyth_receiver_program::ID {
+        OracleType::PythPull
+    } else {
         OracleType::Unknown
     }
 }
+
+/// Anchor discriminator of the PriceUpdateV2 account of the Pyth receiver program
+pub const PYTH_PRICE_UPDATE_DISCRIMINATOR: [u8; 8] = [34, 241, 35, 99, 157, 126, 244, 205];
+const PYTH_PRICE_UPDATE_FULL_LEN: usize = 133;
+/// The push oracle keeps a price feed account per (shard, feed id). Pyth's sponsored feeds are
+/// all updated on shard 0, which is the only shard accepted.
+pub const PYTH_PUSH_ORACLE_SHARD: u16 = 0;
+
+/// Price message of a Pyth pull oracle price update account (PriceUpdateV2). It has a variable
+/// length borsh layout: discriminator, write authority, verification level, price message and
+/// posted slot. Only fully verified updates are accepted.
+pub struct PythPriceUpdate {
+    pub feed_id: [u8; 32],
+    pub price: i64,
+    pub conf: u64,
+    pub exponent: i32,
+    pub publish_time: i64,
+    pub posted_slot: u64,
+}
+
+impl PythPriceUpdate {
+    /// Load the price update and check it's the account the push oracle keeps updated for its
+    /// feed id, so the feed can't be swapped for another one. This derives a PDA, so it's only
+    /// done once in AddOracle; afterwards the oracle key stored in the MangoGroup pins the feed.
+    pub fn load_checked(account: &AccountInfo) -> MangoResult<Self> {
+        let update = Self::load(account)?;
+        check_eq!(
+            account.key,
+            &Self::feed_address(&update.feed_id, PYTH_PUSH_ORACLE_SHARD),
+            MangoErrorCode::InvalidOracleType
+        )?;
+        Ok(update)
+    }
+
+    /// Load the price update of an account that was checked with `load_checked` before
+    pub fn load(account: &AccountInfo) -> MangoResult<Self> {
+        check_eq!(account.owner, &pyth_receiver_program::ID, MangoErrorCode::InvalidOracleType)?;
+        let data = account.try_borrow_data()?;
+        check!(data.len() >= PYTH_PRICE_UPDATE_FULL_LEN, MangoErrorCode::InvalidOracleType)?;
+        let data = array_ref![data, 0, PYTH_PRICE_UPDATE_FULL_LEN];
+        let (
+            discriminator,
+            _write_authority,
+            verification_level,
+            feed_id,
+            price,
+            conf,
+            exponent,
+            publish_time,
+            _prev_publish_time,
+            _ema_price,
+            _ema_conf,
+            posted_slot,
+        ) = array_refs![data, 8, 32, 1, 32, 8, 8, 4, 8, 8, 8, 8, 8];
+        check!(
+            discriminator == &PYTH_PRICE_UPDATE_DISCRIMINATOR,
+            MangoErrorCode::InvalidOracleType
+        )?;
+
+        // VerificationLevel::Full; Partial has an extra byte and isn't accepted
+        check_eq!(verification_level[0], 1, MangoErrorCode::InvalidOraclePrice)?;
+
+        Ok(Self {
+            feed_id: *feed_id,
+            price: i64::from_le_bytes(*price),
+            conf: u64::from_le_bytes(*conf),
+            exponent: i32::from_le_bytes(*exponent),
+            publish_time: i64::from_le_bytes(*publish_time),
+            posted_slot: u64::from_le_bytes(*posted_slot),
+        })
+    }
+
+    /// Address of the price feed account the Pyth push oracle updates for `feed_id`
+    pub fn feed_address(feed_id: &[u8; 32], shard_id: u16) -> Pubkey {
+        Pubkey::find_program_address(
+            &[&shard_id.to_le_bytes(), feed_id],
+            &pyth_push_oracle_program::ID,
+        )
+        .0
+    }
+}
+
+/// Convert a switchboard v2 decimal (mantissa * 10^-scale) to I80F48, dropping digits of the
+/// mantissa that don't fit
+pub fn switchboard_decimal_to_i80f48(decimal: &SwitchboardDecimal) -> Option<I80F48> {
+    let mut mantissa = decimal.mantissa;
+    let mut scale = decimal.scale;
+    while I80F48::checked_from_num(mantissa).is_none() && scale > 0 {
+        mantissa /= 10;
+        scale -= 1;
+    }
+
+    let mut value = I80F48::checked_from_num(mantissa)?;
+    while scale > 0 {
+        let step = scale.min(18);
+        value = value.checked_div(I80F48::from_num(10u64.pow(step)))?;
+        scale -= step;
+    }
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::ZERO_I80F48;
+
+    fn new_derived_oracle(
+        base_exponent: u8,
+        quote_exponent: u8,
+        multiplier: I80F48,
+    ) -> DerivedOracle {
+        DerivedOracle {
+            magic: DERIVED_MAGIC,
+            base_exponent,
+            quote_exponent,
+            padding: [0; 2],
+            base_index: 0,
+            quote_index: 1,
+            multiplier,
+        }
+    }
+
+    fn decimal(mantissa: i128, scale: u32) -> SwitchboardDecimal {
+        SwitchboardDecimal { mantissa, scale }
+    }
+
+    #[test]
+    fn oracle_limits_checks() {
+        let default_conf = I80F48::from_num(0.1);
+        let limits = OracleLimits { max_conf: None, max_age_slots: 0 };
+        assert!(!limits.is_stale(1000, 0));
+        assert!(!limits.is_conf_too_high(default_conf, default_conf));
+        assert!(limits.is_conf_too_high(I80F48::from_num(0.11), default_conf));
+
+        let limits = OracleLimits { max_conf: Some(I80F48::from_num(0.01)), max_age_slots: 25 };
+        assert!(!limits.is_stale(1025, 1000));
+        assert!(limits.is_stale(1026, 1000));
+        // prices published after the current slot aren't stale
+        assert!(!limits.is_stale(1000, 1010));
+        assert!(!limits.is_conf_too_high(I80F48::from_num(0.01), default_conf));
+        assert!(limits.is_conf_too_high(I80F48::from_num(0.02), default_conf));
+    }
+
+    #[test]
+    fn weighted_median_prices() {
+        let p = |x: i32| I80F48::from_num(x);
+        assert_eq!(weighted_median(&mut [(p(3), 1), (p(1), 1), (p(2), 1)]), Some(p(2)));
+        // a heavy source outweighs the others
+        assert_eq!(weighted_median(&mut [(p(1), 1), (p(2), 1), (p(10), 5)]), Some(p(10)));
+        // with exactly half the weight on each side the lower price is taken
+        assert_eq!(weighted_median(&mut [(p(2), 3), (p(1), 3)]), Some(p(1)));
+        assert_eq!(weighted_median(&mut [(p(1), 1), (p(2), 2), (p(3), 1)]), Some(p(2)));
+        assert_eq!(weighted_median(&mut []), None);
+    }
+
+    #[test]
+    fn composite_oracle_quorum() {
+        let composite = CompositeOracle {
+            magic: COMPOSITE_MAGIC,
+            num_sources: 3,
+            min_quorum: 2,
+            weights: [1; MAX_COMPOSITE_SOURCES],
+            sources: [Pubkey::default(); MAX_COMPOSITE_SOURCES],
+        };
+        let p = |x: i32| I80F48::from_num(x);
+        assert_eq!(composite.aggregate(&mut [(p(5), 1)]), None);
+        assert_eq!(composite.aggregate(&mut [(p(5), 1), (p(4), 1)]), Some(p(4)));
+        assert_eq!(composite.aggregate(&mut [(p(5), 1), (p(4), 1), (p(6), 1)]), Some(p(5)));
+    }
+
+    #[test]
+    fn realized_vol_oracle_update() {
+        let mut oracle = RealizedVolOracle {
+            magic: REALIZED_VOL_MAGIC,
+            padding: [0; 4],
+            source_index: 0,
+            window: 100,
+            min_sample_interval: 10,
+            last_price: ZERO_I80F48,
+            last_update: 0,
+            variance_rate: ZERO_I80F48,
+        };
+
+        // the first sample only records the price
+        oracle.update(I80F48::from_num(100), 1000);
+        assert_eq!(oracle.last_price, I80F48::from_num(100));
+        assert_eq!(oracle.variance_rate, ZERO_I80F48);
+
+        // samples too close together and non-positive prices are ignored
+        oracle.update(I80F48::from_num(110), 1005);
+        oracle.update(ZERO_I80F48, 1010);
+        assert_eq!(oracle.last_price, I80F48::from_num(100));
+        assert_eq!(oracle.last_update, 1000);
+
+        // a 10% move over 10 seconds has weight 10 / 100
+        oracle.update(I80F48::from_num(110), 1010);
+        let variance_rate = 1.1f64.ln().powi(2) / 10.0 * 0.1;
+        let eps = I80F48::from_num(0.000000001);
+        assert!((oracle.variance_rate - I80F48::from_num(variance_rate)).abs() < eps);
+        let vol = (variance_rate * 31_536_000.0).sqrt();
+        assert!((oracle.annualized_vol() - I80F48::from_num(vol)).abs() < I80F48::from_num(0.0001));
+
+        // after a whole window without moves the estimate is back to zero
+        oracle.update(I80F48::from_num(110), 1110);
+        assert_eq!(oracle.variance_rate, ZERO_I80F48);
+        assert_eq!(oracle.annualized_vol(), ZERO_I80F48);
+    }
+
+    #[test]
+    fn switchboard_decimal_conversion() {
+        let value = switchboard_decimal_to_i80f48(&decimal(12345, 2)).unwrap();
+        assert_eq!(value, I80F48::from_num(12345) / I80F48::from_num(100));
+        assert_eq!(switchboard_decimal_to_i80f48(&decimal(42, 0)), Some(I80F48::from_num(42)));
+        assert_eq!(switchboard_decimal_to_i80f48(&decimal(-15, 1)), Some(I80F48::from_num(-1.5)));
+
+        // scales above 10^18 are divided out in steps
+        let value = switchboard_decimal_to_i80f48(&decimal(5 * 10i128.pow(20), 20));
+        assert_eq!(value, Some(I80F48::from_num(5)));
+
+        // digits of a mantissa too large for I80F48 are dropped while there is a scale
+        let value = switchboard_decimal_to_i80f48(&decimal(10i128.pow(30), 20));
+        assert_eq!(value, Some(I80F48::from_num(10_000_000_000u64)));
+        assert!(switchboard_decimal_to_i80f48(&decimal(10i128.pow(30), 0)).is_none());
+    }
+
+    #[test]
+    fn derived_oracle_compute_price() {
+        // ETH/BTC from ETH and BTC prices in USDC
+        let ratio = new_derived_oracle(1, 1, ONE_I80F48);
+        let price = ratio.compute_price(I80F48::from_num(3000), I80F48::from_num(60000)).unwrap();
+        assert_eq!(price, I80F48::from_num(3000) / I80F48::from_num(60000));
+
+        // exponents apply to each side before the multiplier
+        let squared = new_derived_oracle(2, 1, I80F48::from_num(0.5));
+        let price = squared.compute_price(I80F48::from_num(6), I80F48::from_num(4)).unwrap();
+        assert_eq!(price, I80F48::from_num(4.5));
+
+        // missing or negative source prices have no derived price
+        assert!(ratio.compute_price(ZERO_I80F48, I80F48::from_num(4)).is_none());
+        assert!(ratio.compute_price(I80F48::from_num(4), I80F48::from_num(-1)).is_none());
+    }
+
+    #[test]
+    fn derived_oracle_compute_price_overflow() {
+        // 1e12^4 doesn't fit into I80F48
+        let big = I80F48::from_num(1_000_000_000_000u64);
+        let oracle = new_derived_oracle(4, 1, ONE_I80F48);
+        assert!(oracle.compute_price(big, ONE_I80F48).is_none());
+
+        // neither does dividing by a denominator that rounds to zero
+        let tiny = I80F48::from_num(0.0001);
+        let oracle = new_derived_oracle(1, 4, ONE_I80F48);
+        assert!(oracle.compute_price(big, tiny).is_none());
+
+        // nor the multiplied result
+        let oracle = new_derived_oracle(2, 1, I80F48::from_num(1_000_000_000_000u64));
+        assert!(oracle.compute_price(I80F48::from_num(1_000_000u64), ONE_I80F48).is_none());
+    }
+}
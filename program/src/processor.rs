@@ -26,38 +26,51 @@ use solana_program::rent::Rent;
 use solana_program::sysvar::Sysvar;
 use spl_token::state::{Account, Mint};
 use switchboard_program::FastRoundResultAccountData;
+use switchboard_v2::AggregatorAccountData;
 
 use mango_common::Loadable;
 use mango_logs::{
-    mango_emit_heap, mango_emit_stack, CachePerpMarketsLog, CachePricesLog, CacheRootBanksLog,
-    CancelAllPerpOrdersLog, CloseMangoAccountLog, CloseSpotOpenOrdersLog, CreateMangoAccountLog,
-    CreateSpotOpenOrdersLog, DepositLog, LiquidatePerpMarketLog, LiquidateTokenAndPerpLog,
-    LiquidateTokenAndTokenLog, MngoAccrualLog, OpenOrdersBalanceLog, PerpBankruptcyLog,
-    RedeemMngoLog, SettleFeesLog, SettlePnlLog, TokenBalanceLog, TokenBankruptcyLog,
+    mango_emit_heap, mango_emit_stack, AutoDeleverageLog, BatchPerpOrdersLog, CachePerpMarketsLog,
+    CachePricesLog, CacheRootBanksLog, CancelAllPerpOrdersLog, CloseMangoAccountLog,
+    CloseSpotOpenOrdersLog, CreateMangoAccountLog, CreateSpotOpenOrdersLog, DepositLog,
+    LiquidatePerpMarketLog, LiquidateTokenAndPerpLog, LiquidateTokenAndTokenLog, MngoAccrualLog,
+    OpenOrdersBalanceLog, PerpBankruptcyLog, PriceHaltLog, RedeemMngoLog,
+    SettleExpiredPerpMarketLog, SettleFeesLog, SettlePnlLog, TokenBalanceLog, TokenBankruptcyLog,
     UpdateFundingLog, UpdateRootBankLog, WithdrawLog,
 };
 
 use crate::error::{check_assert, MangoError, MangoErrorCode, MangoResult, SourceFileId};
 use crate::ids::{msrm_token, srm_token};
-use crate::instruction::MangoInstruction;
-use crate::matching::{Book, BookSide, ExpiryType, OrderType, Side};
-use crate::oracle::{determine_oracle_type, OracleType, StubOracle, STUB_MAGIC};
+use crate::instruction::{BatchPerpOrder, MangoInstruction};
+use crate::matching::{
+    is_oracle_pegged_key, Book, BookSide, ExpiryType, OrderType, SelfTradeBehavior, Side,
+    MAX_POST_ONLY_EVENTS,
+};
+use crate::oracle::{
+    determine_oracle_type, switchboard_decimal_to_i80f48, CompositeOracle, DerivedOracle,
+    OracleLimits, OracleType, PythPriceUpdate, RealizedVolOracle, StubOracle,
+    MAX_COMPOSITE_SOURCES, STUB_MAGIC,
+};
 use crate::queue::{EventQueue, EventType, FillEvent, LiquidateEvent, OutEvent};
 #[cfg(not(feature = "devnet"))]
 use crate::state::PYTH_CONF_FILTER;
 use crate::state::{
     check_open_orders, load_asks_mut, load_bids_mut, load_market_state, load_open_orders,
     load_open_orders_accounts, AdvancedOrderType, AdvancedOrders, AssetType, DataType, HealthCache,
-    HealthType, MangoAccount, MangoCache, MangoGroup, MetaData, NodeBank, OtcOrderStatus,
-    OtcOrders, PerpMarket, PerpMarketCache, PerpMarketInfo, PerpOtcOrder, PerpTriggerOrder,
-    PriceCache, ReferrerIdRecord, ReferrerMemory, RootBank, RootBankCache, SpotMarketInfo,
-    TokenInfo, TriggerCondition, UserActiveAssets, ADVANCED_ORDER_FEE, FREE_ORDER_SLOT, INFO_LEN,
-    MAX_ADVANCED_ORDERS, MAX_NODE_BANKS, MAX_PAIRS, MAX_PERP_OPEN_ORDERS, MAX_TOKENS,
-    NEG_ONE_I80F48, ONE_I80F48, QUOTE_INDEX, ZERO_I80F48,
+    HealthType, Heartbeat, IcebergOrder, IcebergOrders, MangoAccount, MangoCache, MangoGroup,
+    MetaData, NodeBank, OtcOrderStatus, OtcOrders, PerpAccount, PerpMarket, PerpMarketCache,
+    PerpMarketInfo, PerpOtcOrder, PerpTriggerOrder, ReferrerIdRecord, ReferrerMemory, RootBank,
+    RootBankCache, SpotMarketInfo, TokenInfo, TriggerCondition, UserActiveAssets,
+    ADVANCED_ORDER_FEE, DAY, FREE_ORDER_SLOT, INFO_LEN, MAX_ADVANCED_ORDERS, MAX_ICEBERG_ORDERS,
+    MAX_INDEX_EXPONENT, MAX_NODE_BANKS, MAX_PAIRS, MAX_PERP_OPEN_ORDERS, MAX_TOKENS,
+    MIN_SETTLEMENT_SAMPLES, NEG_ONE_I80F48, ONE_I80F48, QUOTE_INDEX, ZERO_I80F48,
+};
+use crate::state::{
+    PYTH_PULL_MAX_STALENESS, SWITCHBOARD_V2_CONF_FILTER, SWITCHBOARD_V2_MAX_STALENESS,
 };
 use crate::utils::{
     emit_perp_balances, gen_signer_key, gen_signer_seeds, pow_i80f48, serum_fees_mod,
-    OTC_ORDERS_PREFIX,
+    HEARTBEAT_PREFIX, ICEBERG_ORDERS_PREFIX, OTC_ORDERS_PREFIX,
 };
 
 declare_check_assert_macros!(SourceFileId::Processor);
@@ -158,7 +171,10 @@ impl Processor {
             mint: *quote_mint_ai.key,
             root_bank: *quote_root_bank_ai.key,
             decimals: mint.decimals,
-            padding: [0u8; 7],
+            padding: 0,
+            max_conf_bps: 0,
+            max_oracle_age_slots: 0,
+            price_valid_interval: 0,
         };
 
         check!(admin_ai.is_signer, MangoErrorCode::Default)?;
@@ -484,11 +500,12 @@ impl Processor {
             check!(mint.decimals == token_info.decimals, MangoErrorCode::InvalidParam)?;
         }
 
+        // keep oracle limits that may have been set for a perp market on this index
         mango_group.tokens[market_index] = TokenInfo {
             mint: *mint_ai.key,
             root_bank: *root_bank_ai.key,
             decimals: mint.decimals,
-            padding: [0u8; 7],
+            ..mango_group.tokens[market_index]
         };
 
         let (maint_asset_weight, maint_liab_weight) = get_leverage_weights(maint_leverage);
@@ -548,9 +565,18 @@ impl Processor {
             OracleType::Pyth => {
                 // msg!("OracleType:Pyth"); // Do nothing really cause all that's needed is storing the pkey
             }
-            OracleType::Switchboard => {
+            OracleType::Switchboard | OracleType::SwitchboardV2 => {
                 // msg!("OracleType::Switchboard");
             }
+            OracleType::PythPull => {
+                // only price feed accounts, as ephemeral price updates are closed after use.
+                // Checked once here; read_oracle trusts the key stored in the MangoGroup
+                PythPriceUpdate::load_checked(oracle_ai)?;
+            }
+            OracleType::RealizedVol | OracleType::Composite | OracleType::Derived => {
+                // These are initialized and added by their own Add*Oracle instruction
+                return Err(throw_err!(MangoErrorCode::InvalidOracleType));
+            }
             OracleType::Stub | OracleType::Unknown => {
                 let rent = Rent::get()?;
                 let mut oracle = StubOracle::load_and_init(oracle_ai, program_id, &rent)?;
@@ -657,6 +683,11 @@ impl Processor {
             mango_group.tokens[market_index].decimals = 6;
         }
 
+        // Keep the index already cached at this oracle index, e.g. the squared index of an
+        // upgraded group
+        let (underlying_oracle_index, index_exponent, index_divisor) =
+            mango_group.perp_markets[market_index].index_params(market_index);
+
         mango_group.perp_markets[market_index] = PerpMarketInfo {
             perp_market: *perp_market_ai.key,
             maint_asset_weight,
@@ -668,6 +699,20 @@ impl Processor {
             taker_fee,
             base_lot_size,
             quote_lot_size,
+            index_divisor,
+            underlying_oracle_index: underlying_oracle_index as u8,
+            index_exponent,
+            maint_shock_bps: 0,
+            init_shock_bps: 0,
+            ema_period: 0,
+            liquidation_use_ema: false,
+            funding_use_ema: false,
+            // set per oracle by ChangeOracleLimits, possibly before the perp market was added
+            max_price_move_bps: mango_group.perp_markets[market_index].max_price_move_bps,
+            price_band_bps: 0,
+            price_band_clamp: false,
+            padding: [0; 1],
+            settlement_price: ZERO_I80F48,
         };
 
         // Initialize the Bids
@@ -700,6 +745,7 @@ impl Processor {
             exp,
             0,
             0,
+            DAY.to_num(),
         )?;
 
         Ok(())
@@ -727,6 +773,10 @@ impl Processor {
         version: u8,
         lm_size_shift: u8,
         base_decimals: u8,
+        index_exponent: u8,
+        underlying_oracle_index: u8,
+        index_divisor: I80F48,
+        funding_period: u64,
     ) -> MangoResult {
         // params check
         check!(init_leverage >= ONE_I80F48, MangoErrorCode::InvalidParam)?;
@@ -741,6 +791,12 @@ impl Processor {
         check!(!rate.is_negative(), MangoErrorCode::InvalidParam)?;
         check!(target_period_length > 0, MangoErrorCode::InvalidParam)?;
         check!(exp <= 8 && exp > 0, MangoErrorCode::InvalidParam)?;
+        check!(
+            index_exponent > 0 && index_exponent <= MAX_INDEX_EXPONENT,
+            MangoErrorCode::InvalidParam
+        )?;
+        check!(index_divisor.is_positive(), MangoErrorCode::InvalidParam)?;
+        check!(funding_period > 0, MangoErrorCode::InvalidParam)?;
 
         const NUM_FIXED: usize = 13;
         let accounts = array_ref![accounts, 0, NUM_FIXED];
@@ -781,6 +837,10 @@ impl Processor {
 
         // Make sure perp market at this index not already initialized
         check!(mango_group.perp_markets[market_index].is_empty(), MangoErrorCode::InvalidParam)?;
+        check!(
+            (underlying_oracle_index as usize) < mango_group.num_oracles,
+            MangoErrorCode::InvalidParam
+        )?;
 
         // This means there isn't already a token and spot market in Mango
         // Set the base decimals; if token not empty, ignore user input base_decimals
@@ -869,6 +929,7 @@ impl Processor {
             exp,
             version,
             lm_size_shift,
+            funding_period,
         )?;
 
         let (maint_asset_weight, maint_liab_weight) = get_leverage_weights(maint_leverage);
@@ -884,6 +945,20 @@ impl Processor {
             taker_fee,
             base_lot_size,
             quote_lot_size,
+            index_divisor,
+            underlying_oracle_index,
+            index_exponent,
+            maint_shock_bps: 0,
+            init_shock_bps: 0,
+            ema_period: 0,
+            liquidation_use_ema: false,
+            funding_use_ema: false,
+            // set per oracle by ChangeOracleLimits, possibly before the perp market was added
+            max_price_move_bps: mango_group.perp_markets[market_index].max_price_move_bps,
+            price_band_bps: 0,
+            price_band_clamp: false,
+            padding: [0; 1],
+            settlement_price: ZERO_I80F48,
         };
 
         Ok(())
@@ -1007,6 +1082,17 @@ impl Processor {
         exp: Option<u8>,
         version: Option<u8>,
         lm_size_shift: Option<u8>,
+        index_exponent: Option<u8>,
+        underlying_oracle_index: Option<u8>,
+        index_divisor: Option<I80F48>,
+        funding_period: Option<u64>,
+        maint_shock_bps: Option<u16>,
+        init_shock_bps: Option<u16>,
+        ema_period: Option<u16>,
+        liquidation_use_ema: Option<bool>,
+        funding_use_ema: Option<bool>,
+        price_band_bps: Option<u16>,
+        price_band_clamp: Option<bool>,
     ) -> MangoResult<()> {
         const NUM_FIXED: usize = 3;
         let accounts = array_ref![accounts, 0, NUM_FIXED];
@@ -1027,7 +1113,7 @@ impl Processor {
 
         let mut perp_market =
             PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
-        let mut info = &mut mango_group.perp_markets[market_index];
+        let info = &mango_group.perp_markets[market_index];
 
         // Unwrap params. Default to current state if Option is None
         let (maint_asset_weight, maint_liab_weight) = if let Some(x) = maint_leverage {
@@ -1045,6 +1131,20 @@ impl Processor {
         let maker_fee = maker_fee.unwrap_or(info.maker_fee);
         let taker_fee = taker_fee.unwrap_or(info.taker_fee);
 
+        let (current_underlying_index, current_exponent, current_divisor) =
+            info.index_params(market_index);
+        let index_exponent = index_exponent.unwrap_or(current_exponent);
+        let underlying_oracle_index =
+            underlying_oracle_index.unwrap_or(current_underlying_index as u8);
+        let index_divisor = index_divisor.unwrap_or(current_divisor);
+        let maint_shock_bps = maint_shock_bps.unwrap_or(info.maint_shock_bps);
+        let init_shock_bps = init_shock_bps.unwrap_or(info.init_shock_bps);
+        let ema_period = ema_period.unwrap_or(info.ema_period);
+        let liquidation_use_ema = liquidation_use_ema.unwrap_or(info.liquidation_use_ema);
+        let funding_use_ema = funding_use_ema.unwrap_or(info.funding_use_ema);
+        let price_band_bps = price_band_bps.unwrap_or(info.price_band_bps);
+        let price_band_clamp = price_band_clamp.unwrap_or(info.price_band_clamp);
+
         // params check
         check!(init_asset_weight > ZERO_I80F48, MangoErrorCode::InvalidParam)?;
         check!(maint_asset_weight > init_asset_weight, MangoErrorCode::InvalidParam)?;
@@ -1053,6 +1153,20 @@ impl Processor {
 
         check!(maker_fee + taker_fee >= ZERO_I80F48, MangoErrorCode::InvalidParam)?;
 
+        check!(
+            index_exponent > 0 && index_exponent <= MAX_INDEX_EXPONENT,
+            MangoErrorCode::InvalidParam
+        )?;
+        check!(
+            (underlying_oracle_index as usize) < mango_group.num_oracles,
+            MangoErrorCode::InvalidParam
+        )?;
+        check!(index_divisor.is_positive(), MangoErrorCode::InvalidParam)?;
+        check!(init_shock_bps >= maint_shock_bps, MangoErrorCode::InvalidParam)?;
+        check!(init_shock_bps < 10_000, MangoErrorCode::InvalidParam)?;
+
+        let info = &mut mango_group.perp_markets[market_index];
+
         // Set the params on MangoGroup PerpMarketInfo
         info.maker_fee = maker_fee;
         info.taker_fee = taker_fee;
@@ -1061,6 +1175,16 @@ impl Processor {
         info.init_asset_weight = init_asset_weight;
         info.maint_liab_weight = maint_liab_weight;
         info.init_liab_weight = init_liab_weight;
+        info.index_exponent = index_exponent;
+        info.underlying_oracle_index = underlying_oracle_index;
+        info.index_divisor = index_divisor;
+        info.maint_shock_bps = maint_shock_bps;
+        info.init_shock_bps = init_shock_bps;
+        info.ema_period = ema_period;
+        info.liquidation_use_ema = liquidation_use_ema;
+        info.funding_use_ema = funding_use_ema;
+        info.price_band_bps = price_band_bps;
+        info.price_band_clamp = price_band_clamp;
 
         let version = version.unwrap_or(perp_market.meta_data.version);
         check!(version == 0 || version == 1, MangoErrorCode::InvalidParam)?;
@@ -1135,6 +1259,12 @@ impl Processor {
         }
 
         perp_market.meta_data.version = version;
+
+        if let Some(funding_period) = funding_period {
+            check!(funding_period > 0, MangoErrorCode::InvalidParam)?;
+            perp_market.funding_period = funding_period;
+        }
+
         Ok(())
     }
 
@@ -1155,42 +1285,82 @@ impl Processor {
 
         let mut oracle_indexes = Vec::new();
         let mut oracle_prices = Vec::new();
-        // Substitute BTC^2-PERP oracle (Token index=0) with OracleAccountInfo corresponding to BTC-PERP (Token index=1)
-        // TODO: This is brittle and requires a specific ordering and relationship between markets inside the group
-        let mut substitute_oracle = &oracle_ais[0];
-        for oracle_ai in oracle_ais.iter() {
-            let oracle_index = mango_group.find_oracle_index(oracle_ai.key).ok_or(throw!())?;
-            if oracle_index == 1 {
-                substitute_oracle = oracle_ai;
-            }
-        }
 
         for oracle_ai in oracle_ais.iter() {
-            let oracle_index = mango_group.find_oracle_index(oracle_ai.key).ok_or(throw!())?;
-            // let oracle_info = oracle_map.get(&oracle_index);
+            // Sources of composite oracles are passed in too but aren't cached themselves
+            let oracle_index = match mango_group.find_oracle_index(oracle_ai.key) {
+                Some(i) => i,
+                None => continue,
+            };
 
-            let sub_oracle_index =
-                if oracle_index == 0 && !mango_group.dont_square { 1 } else { oracle_index };
-            let sub_oracle_ai = if oracle_index == 0 && !mango_group.dont_square {
-                substitute_oracle
-            } else {
+            // Sample the source price for volatility oracles if it was cached just before
+            if determine_oracle_type(oracle_ai) == OracleType::RealizedVol {
+                match RealizedVolOracle::load_mut_checked(oracle_ai, program_id) {
+                    Ok(mut vol_oracle) => {
+                        let source_cache =
+                            &mango_cache.price_cache[vol_oracle.source_index as usize];
+                        if source_cache.last_update == last_update {
+                            vol_oracle.update(source_cache.price, last_update);
+                        }
+                    }
+                    Err(_) => {
+                        msg!("Failed CachePrice for oracle_index: {}", oracle_index);
+                        continue;
+                    }
+                }
+            }
+
+            // Perp markets may derive their index from another oracle, e.g. BTC^2-PERP from BTC
+            let (underlying_index, exponent, divisor) =
+                mango_group.perp_markets[oracle_index].index_params(oracle_index);
+            let underlying_ai = if underlying_index == oracle_index {
                 oracle_ai
+            } else if let Some(ai) =
+                oracle_ais.iter().find(|ai| ai.key == &mango_group.oracles[underlying_index])
+            {
+                ai
+            } else {
+                msg!(
+                    "Failed CachePrice for oracle_index: {}; underlying oracle {} not passed in",
+                    oracle_index,
+                    underlying_index
+                );
+                continue;
             };
-            let should_square =
-                if oracle_index == 0 && !mango_group.dont_square { true } else { false };
 
-            if let Ok(mut price) = read_oracle(
-                &mango_group,
-                // oracle_info.unwrap().desired_token_index,
-                // &oracle_info.unwrap().desired_account_info,
-                sub_oracle_index,
-                sub_oracle_ai,
-            ) {
-                if should_square {
-                    price = pow_i80f48(price, 2).checked_div(I80F48!(1000000)).unwrap();
+            let price = if determine_oracle_type(underlying_ai) == OracleType::Derived {
+                // uses the source prices cached earlier in this or a previous CachePrices
+                read_derived_oracle(
+                    program_id,
+                    &mango_group,
+                    &mango_cache,
+                    underlying_ai,
+                    last_update,
+                )
+            } else {
+                let limits = mango_group.oracle_limits(oracle_index);
+                read_oracle(&mango_group, underlying_index, underlying_ai, oracle_ais, &limits)
+            };
+            if let Ok(price) = price {
+                let price = pow_i80f48(price, exponent).checked_div(divisor).unwrap();
+                let info = &mango_group.perp_markets[oracle_index];
+                let price_cache = &mut mango_cache.price_cache[oracle_index];
+                if !price_cache.check_price_move(
+                    price,
+                    last_update,
+                    info.max_price_move_bps,
+                    mango_group.valid_interval,
+                ) {
+                    msg!("Price halted for oracle_index: {}", oracle_index);
+                    mango_emit_heap!(PriceHaltLog {
+                        mango_group: *mango_group_ai.key,
+                        oracle_index: oracle_index as u64,
+                        cached_price: price_cache.price.to_bits(),
+                        halted_price: price.to_bits(),
+                    });
+                    continue;
                 }
-                // msg!("Oracle px: {:?}", price);
-                mango_cache.price_cache[oracle_index] = PriceCache { price, last_update };
+                price_cache.update(price, last_update, info.ema_period);
 
                 oracle_indexes.push(oracle_index as u64);
                 oracle_prices.push(price.to_bits());
@@ -1348,6 +1518,7 @@ impl Processor {
         );
         let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
         mango_cache.check_valid(&mango_group, &active_assets, now_ts)?;
+        mango_cache.check_not_halted(&mango_group, &active_assets)?;
 
         let root_bank_cache = &mango_cache.root_bank_cache[token_index];
 
@@ -2551,6 +2722,8 @@ impl Processor {
             quantity,
             i64::MAX, // no limit on quote quantity
             order_type,
+            SelfTradeBehavior::AllowSelfTrade,
+            0,
             0,
             client_order_id,
             now_ts,
@@ -2580,11 +2753,21 @@ impl Processor {
         expiry_timestamp: u64,
         limit: u8,
         expiry_type: ExpiryType,
+        self_trade_behavior: SelfTradeBehavior,
+        display_quantity: i64,
     ) -> MangoResult {
         check!(price > 0, MangoErrorCode::InvalidParam)?;
         check!(max_base_quantity > 0, MangoErrorCode::InvalidParam)?;
         check!(max_quote_quantity > 0, MangoErrorCode::InvalidParam)?;
         check!(limit > 0, MangoErrorCode::InvalidParam)?;
+        check!(display_quantity >= 0, MangoErrorCode::InvalidParam)?;
+        if display_quantity > 0 {
+            check!(
+                order_type == OrderType::Limit || order_type == OrderType::PostOnly,
+                MangoErrorCode::InvalidParam
+            )?;
+            check!(!reduce_only, MangoErrorCode::InvalidParam)?;
+        }
 
         const NUM_FIXED: usize = 9;
         let (fixed_ais, packed_open_orders_ais) = array_refs![accounts, NUM_FIXED; ..;];
@@ -2623,7 +2806,7 @@ impl Processor {
         let open_orders_accounts = load_open_orders_accounts(&open_orders_ais)?;
 
         let now_ts = Clock::get()?.unix_timestamp as u64;
-        let time_in_force = match expiry_type {
+        let (time_in_force, good_till_date) = match expiry_type {
             ExpiryType::Absolute => {
                 if expiry_timestamp != 0 {
                     // If expiry is far in the future, clamp to 255 seconds
@@ -2633,9 +2816,9 @@ impl Processor {
                         msg!("Order is already expired");
                         return Ok(());
                     }
-                    tif
+                    (tif, 0)
                 } else {
-                    0 // never expire
+                    (0, 0) // never expire
                 }
             }
             ExpiryType::Relative => {
@@ -2643,7 +2826,17 @@ impl Processor {
                     expiry_timestamp > 0 && expiry_timestamp <= 255,
                     MangoErrorCode::InvalidParam
                 )?;
-                expiry_timestamp as u8
+                (expiry_timestamp as u8, 0)
+            }
+            ExpiryType::GoodTillDate => {
+                // the expiry is kept in LeafNode::best_initial, an i64
+                check!(expiry_timestamp <= i64::MAX as u64, MangoErrorCode::InvalidParam)?;
+                check!(display_quantity == 0, MangoErrorCode::InvalidParam)?;
+                if expiry_timestamp <= now_ts {
+                    msg!("Order is already expired");
+                    return Ok(());
+                }
+                (0, expiry_timestamp)
             }
         };
 
@@ -2661,6 +2854,7 @@ impl Processor {
 
         let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
         mango_cache.check_valid(&mango_group, &active_assets, now_ts)?;
+        mango_cache.check_not_halted(&mango_group, &active_assets)?;
 
         let mut health_cache = HealthCache::new(active_assets);
         health_cache.init_vals_with_orders_vec(
@@ -2707,7 +2901,7 @@ impl Processor {
             return Ok(());
         }
 
-        book.new_order(
+        let posted = book.new_order(
             program_id,
             &mango_group,
             mango_group_ai.key,
@@ -2723,7 +2917,9 @@ impl Processor {
             max_base_quantity,
             max_quote_quantity,
             order_type,
+            self_trade_behavior,
             time_in_force,
+            good_till_date,
             client_order_id,
             now_ts,
             referrer_mango_account_ai,
@@ -2735,123 +2931,103 @@ impl Processor {
         check!(
             post_health >= ZERO_I80F48 || (health_up_only && post_health >= pre_health),
             MangoErrorCode::InsufficientFunds
-        )
-    }
-
-    #[inline(never)]
-    fn cancel_perp_order_by_client_id(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        client_order_id: u64,
-    ) -> MangoResult<()> {
-        const NUM_FIXED: usize = 6;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
-        let [
-            mango_group_ai,     // read
-            mango_account_ai,   // write
-            owner_ai,           // read, signer
-            perp_market_ai,     // write
-            bids_ai,            // write
-            asks_ai,            // write
-        ] = accounts;
-
-        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
-
-        let mut mango_account =
-            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
-        check!(!mango_account.is_bankrupt, MangoErrorCode::Bankrupt)?;
-        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
-        check!(
-            &mango_account.owner == owner_ai.key || &mango_account.delegate == owner_ai.key,
-            MangoErrorCode::InvalidOwner
         )?;
 
-        let mut perp_market =
-            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
-
-        let market_index = mango_group.find_perp_market_index(perp_market_ai.key).unwrap();
-
-        let now_ts = Clock::get()?.unix_timestamp as u64;
-        let (order_id, side) = mango_account
-            .find_order_with_client_id(market_index, client_order_id)
-            .ok_or(throw_err!(MangoErrorCode::ClientIdNotFound))?;
-
-        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
-        let best_final = if perp_market.meta_data.version == 0 {
-            match side {
-                Side::Bid => book.get_best_bid_price(now_ts).unwrap(),
-                Side::Ask => book.get_best_ask_price(now_ts).unwrap(),
-            }
-        } else {
-            let max_depth: i64 = perp_market.liquidity_mining_info.max_depth_bps.to_num();
-            match side {
-                Side::Bid => book.get_bids_size_above_order(order_id, max_depth, now_ts),
-                Side::Ask => book.get_asks_size_below_order(order_id, max_depth, now_ts),
-            }
-        };
-
-        let order = book.cancel_order(order_id, side)?;
-        check_eq!(&order.owner, mango_account_ai.key, MangoErrorCode::InvalidOrderId)?;
-        mango_account.remove_order(order.owner_slot as usize, order.quantity)?;
-
-        // If order version doesn't match the perp market version, no incentives
-        // time in force invalid orders don't get rewards
-        if order.version != perp_market.meta_data.version || !order.is_valid(now_ts) {
+        if display_quantity == 0 {
             return Ok(());
         }
 
-        let mngo_start = mango_account.perp_accounts[market_index].mngo_accrued;
-        if perp_market.meta_data.version == 0 {
-            mango_account.perp_accounts[market_index].apply_price_incentives(
-                &mut perp_market,
-                side,
-                order.price(),
-                order.best_initial,
-                best_final,
-                order.timestamp,
-                now_ts,
-                order.quantity,
-            )?;
-        } else {
-            mango_account.perp_accounts[market_index].apply_size_incentives(
-                &mut perp_market,
-                order.best_initial,
-                best_final,
-                order.timestamp,
-                Clock::get()?.unix_timestamp as u64,
-                order.quantity,
-            )?;
+        // The health check above covered the full resting size. Keep display_quantity of it
+        // on the book and move the rest into the IcebergOrders PDA.
+        let order_id = match posted {
+            Some((order_id, _)) => order_id,
+            None => return Ok(()),
+        };
+        let book_side = match side {
+            Side::Bid => &mut book.bids,
+            Side::Ask => &mut book.asks,
+        };
+        let resting_quantity = book_side.find_by_key(order_id).map_or(0, |leaf| leaf.quantity);
+        if resting_quantity <= display_quantity {
+            return Ok(());
         }
 
-        mango_emit_heap!(MngoAccrualLog {
-            mango_group: *mango_group_ai.key,
-            mango_account: *mango_account_ai.key,
-            market_index: market_index as u64,
-            mngo_accrual: mango_account.perp_accounts[market_index].mngo_accrued - mngo_start
-        });
+        let iceberg_orders_ai = packed_open_orders_ais
+            .iter()
+            .find(|ai| ai.owner == program_id)
+            .ok_or(throw_err!(MangoErrorCode::InvalidAccount))?;
+        let mut iceberg_orders =
+            IcebergOrders::load_mut_checked(iceberg_orders_ai, program_id, mango_account_ai.key)?;
 
+        book_side.set_quantity(order_id, display_quantity).unwrap();
+        let hidden_quantity = resting_quantity - display_quantity;
+        let pa = &mut mango_account.perp_accounts[market_index];
+        match side {
+            Side::Bid => pa.bids_quantity -= hidden_quantity,
+            Side::Ask => pa.asks_quantity -= hidden_quantity,
+        }
+        iceberg_orders.add_order(IcebergOrder {
+            is_active: true,
+            market_index: market_index as u8,
+            side,
+            order_type,
+            padding0: [0; 4],
+            client_order_id,
+            price,
+            display_quantity,
+            hidden_quantity,
+            padding1: [0; 8],
+            order_id,
+        })?;
+        mango_account.has_iceberg_orders = true;
+
+        perp_market.update_premium(&book, mango_cache.get_price(market_index), now_ts);
         Ok(())
     }
 
     #[inline(never)]
-    fn cancel_perp_order(
+    /// Place a perp order priced relative to the oracle; see `Book::new_oracle_pegged_order`
+    fn place_perp_pegged_order(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        order_id: i128,
-    ) -> MangoResult<()> {
-        const NUM_FIXED: usize = 6;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        side: Side,
+        price_offset: i64,
+        peg_limit: i64,
+        max_base_quantity: i64,
+        max_quote_quantity: i64,
+        client_order_id: u64,
+        order_type: OrderType,
+        reduce_only: bool,
+        expiry_timestamp: u64,
+        limit: u8,
+        expiry_type: ExpiryType,
+        self_trade_behavior: SelfTradeBehavior,
+    ) -> MangoResult {
+        check!(peg_limit == -1 || peg_limit > 0, MangoErrorCode::InvalidParam)?;
+        check!(max_base_quantity > 0, MangoErrorCode::InvalidParam)?;
+        check!(max_quote_quantity > 0, MangoErrorCode::InvalidParam)?;
+        check!(limit > 0, MangoErrorCode::InvalidParam)?;
+
+        const NUM_FIXED: usize = 9;
+        let (fixed_ais, packed_open_orders_ais) = array_refs![accounts, NUM_FIXED; ..;];
         let [
-            mango_group_ai,     // read
-            mango_account_ai,   // write
-            owner_ai,           // read, signer
-            perp_market_ai,     // write
-            bids_ai,            // write
-            asks_ai,            // write
-        ] = accounts;
+            mango_group_ai,             // read
+            mango_account_ai,           // write
+            owner_ai,                   // read, signer
+            mango_cache_ai,             // read
+            perp_market_ai,             // write
+            bids_ai,                    // write
+            asks_ai,                    // write
+            event_queue_ai,             // write
+            referrer_mango_account_ai,  // write
+        ] = fixed_ais;
 
-        // TODO OPT put the liquidity incentive stuff in the bids and asks accounts so perp market
-        //  doesn't have to be passed in as write
+        // If referrer same as user, assume no referrer
+        let referrer_mango_account_ai = if referrer_mango_account_ai.key == mango_account_ai.key {
+            None
+        } else {
+            Some(referrer_mango_account_ai)
+        };
 
         let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
 
@@ -2864,90 +3040,166 @@ impl Processor {
             MangoErrorCode::InvalidOwner
         )?;
 
-        let mut perp_market =
-            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
-
-        let market_index = mango_group.find_perp_market_index(perp_market_ai.key).unwrap();
+        let open_orders_ais =
+            mango_account.checked_unpack_open_orders(&mango_group, packed_open_orders_ais)?;
+        let open_orders_accounts = load_open_orders_accounts(&open_orders_ais)?;
 
         let now_ts = Clock::get()?.unix_timestamp as u64;
-
-        let side = mango_account
-            .find_order_side(market_index, order_id)
-            .ok_or(throw_err!(MangoErrorCode::InvalidOrderId))?;
-        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
-
-        let best_final = if perp_market.meta_data.version == 0 {
-            match side {
-                Side::Bid => book.get_best_bid_price(now_ts).unwrap(),
-                Side::Ask => book.get_best_ask_price(now_ts).unwrap(),
+        let time_in_force = match expiry_type {
+            ExpiryType::Absolute => {
+                if expiry_timestamp != 0 {
+                    // If expiry is far in the future, clamp to 255 seconds
+                    let tif = expiry_timestamp.saturating_sub(now_ts).min(255) as u8;
+                    if tif == 0 {
+                        // If expiry is in the past or now, ignore the order
+                        msg!("Order is already expired");
+                        return Ok(());
+                    }
+                    tif
+                } else {
+                    0 // never expire
+                }
             }
-        } else {
-            let max_depth: i64 = perp_market.liquidity_mining_info.max_depth_bps.to_num();
-            match side {
-                Side::Bid => book.get_bids_size_above_order(order_id, max_depth, now_ts),
-                Side::Ask => book.get_asks_size_below_order(order_id, max_depth, now_ts),
+            ExpiryType::Relative => {
+                check!(
+                    expiry_timestamp > 0 && expiry_timestamp <= 255,
+                    MangoErrorCode::InvalidParam
+                )?;
+                expiry_timestamp as u8
             }
+            // LeafNode::best_initial already holds the peg limit
+            ExpiryType::GoodTillDate => return Err(throw_err!(MangoErrorCode::InvalidParam)),
         };
 
-        let order = book.cancel_order(order_id, side)?;
-        check_eq!(&order.owner, mango_account_ai.key, MangoErrorCode::InvalidOrderId)?;
-        mango_account.remove_order(order.owner_slot as usize, order.quantity)?;
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+        let market_index = mango_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
 
-        // If order version doesn't match the perp market version, no incentives
-        // time in force invalid orders don't get rewards
-        if order.version != perp_market.meta_data.version || !order.is_valid(now_ts) {
-            return Ok(());
-        }
+        let active_assets = UserActiveAssets::new(
+            &mango_group,
+            &mango_account,
+            vec![(AssetType::Perp, market_index)],
+        );
 
-        let mngo_start = mango_account.perp_accounts[market_index].mngo_accrued;
-        if perp_market.meta_data.version == 0 {
-            mango_account.perp_accounts[market_index].apply_price_incentives(
-                &mut perp_market,
-                side,
-                order.price(),
-                order.best_initial,
-                best_final,
-                order.timestamp,
-                now_ts,
-                order.quantity,
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+        mango_cache.check_valid(&mango_group, &active_assets, now_ts)?;
+        mango_cache.check_not_halted(&mango_group, &active_assets)?;
+
+        let mut health_cache = HealthCache::new(active_assets);
+        health_cache.init_vals_with_orders_vec(
+            &mango_group,
+            &mango_cache,
+            &mango_account,
+            &open_orders_accounts,
+        )?;
+        let pre_health = health_cache.get_health(&mango_group, HealthType::Init);
+
+        // update the being_liquidated flag
+        if mango_account.being_liquidated {
+            if pre_health >= ZERO_I80F48 {
+                mango_account.being_liquidated = false;
+            } else {
+                return Err(throw_err!(MangoErrorCode::BeingLiquidated));
+            }
+        }
+
+        // This means health must only go up
+        let health_up_only = pre_health < ZERO_I80F48;
+
+        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+        let mut event_queue =
+            EventQueue::load_mut_checked(event_queue_ai, program_id, &perp_market)?;
+
+        // If reduce_only, position must only go down
+        let max_base_quantity = if reduce_only {
+            let base_pos = mango_account.get_complete_base_pos(
+                market_index,
+                &event_queue,
+                mango_account_ai.key,
             )?;
+
+            if (side == Side::Bid && base_pos > 0) || (side == Side::Ask && base_pos < 0) {
+                0
+            } else {
+                base_pos.abs().min(max_base_quantity)
+            }
         } else {
-            mango_account.perp_accounts[market_index].apply_size_incentives(
-                &mut perp_market,
-                order.best_initial,
-                best_final,
-                order.timestamp,
-                Clock::get()?.unix_timestamp as u64,
-                order.quantity,
-            )?;
+            max_base_quantity
+        };
+        if max_base_quantity == 0 {
+            return Ok(());
         }
 
-        mango_emit_heap!(MngoAccrualLog {
-            mango_group: *mango_group_ai.key,
-            mango_account: *mango_account_ai.key,
-            market_index: market_index as u64,
-            mngo_accrual: mango_account.perp_accounts[market_index].mngo_accrued - mngo_start
-        });
+        book.new_oracle_pegged_order(
+            program_id,
+            &mango_group,
+            mango_group_ai.key,
+            &mango_cache,
+            &mut event_queue,
+            &mut perp_market,
+            mango_cache.get_price(market_index),
+            &mut mango_account,
+            mango_account_ai.key,
+            market_index,
+            side,
+            price_offset,
+            peg_limit,
+            max_base_quantity,
+            max_quote_quantity,
+            order_type,
+            self_trade_behavior,
+            time_in_force,
+            client_order_id,
+            now_ts,
+            referrer_mango_account_ai,
+            limit,
+        )?;
 
-        Ok(())
+        health_cache.update_perp_val(&mango_group, &mango_cache, &mango_account, market_index)?;
+        let post_health = health_cache.get_health(&mango_group, HealthType::Init);
+        check!(
+            post_health >= ZERO_I80F48 || (health_up_only && post_health >= pre_health),
+            MangoErrorCode::InsufficientFunds
+        )
     }
 
     #[inline(never)]
-    fn cancel_all_perp_orders(
+    /// Change the price and/or quantity of a resting perp order with a single health check.
+    /// A size reduction at the same price keeps the order's place in the book.
+    fn edit_perp_order(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
+        order_id: i128,
+        new_price: i64,
+        new_quantity: i64,
         limit: u8,
     ) -> MangoResult {
-        const NUM_FIXED: usize = 6;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        check!(new_price > 0, MangoErrorCode::InvalidParam)?;
+        check!(new_quantity > 0, MangoErrorCode::InvalidParam)?;
+        check!(limit > 0, MangoErrorCode::InvalidParam)?;
+
+        const NUM_FIXED: usize = 9;
+        let (fixed_ais, packed_open_orders_ais) = array_refs![accounts, NUM_FIXED; ..;];
         let [
-            mango_group_ai,     // read
-            mango_account_ai,   // write
-            owner_ai,           // read, signer
-            perp_market_ai,     // write
-            bids_ai,            // write
-            asks_ai,            // write
-        ] = accounts;
+            mango_group_ai,             // read
+            mango_account_ai,           // write
+            owner_ai,                   // read, signer
+            mango_cache_ai,             // read
+            perp_market_ai,             // write
+            bids_ai,                    // write
+            asks_ai,                    // write
+            event_queue_ai,             // write
+            referrer_mango_account_ai,  // write
+        ] = fixed_ais;
+
+        // If referrer same as user, assume no referrer
+        let referrer_mango_account_ai = if referrer_mango_account_ai.key == mango_account_ai.key {
+            None
+        } else {
+            Some(referrer_mango_account_ai)
+        };
 
         let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
 
@@ -2960,583 +3212,1378 @@ impl Processor {
             MangoErrorCode::InvalidOwner
         )?;
 
+        let open_orders_ais =
+            mango_account.checked_unpack_open_orders(&mango_group, packed_open_orders_ais)?;
+        let open_orders_accounts = load_open_orders_accounts(&open_orders_ais)?;
+
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+
         let mut perp_market =
             PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+        let market_index = mango_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
 
-        let market_index = mango_group.find_perp_market_index(perp_market_ai.key).unwrap();
+        let side = mango_account
+            .find_order_side(market_index, order_id)
+            .ok_or(throw_err!(MangoErrorCode::InvalidOrderId))?;
+
+        let active_assets = UserActiveAssets::new(
+            &mango_group,
+            &mango_account,
+            vec![(AssetType::Perp, market_index)],
+        );
+
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+        mango_cache.check_valid(&mango_group, &active_assets, now_ts)?;
+        mango_cache.check_not_halted(&mango_group, &active_assets)?;
+
+        let mut health_cache = HealthCache::new(active_assets);
+        health_cache.init_vals_with_orders_vec(
+            &mango_group,
+            &mango_cache,
+            &mango_account,
+            &open_orders_accounts,
+        )?;
+        let pre_health = health_cache.get_health(&mango_group, HealthType::Init);
+
+        // update the being_liquidated flag
+        if mango_account.being_liquidated {
+            if pre_health >= ZERO_I80F48 {
+                mango_account.being_liquidated = false;
+            } else {
+                return Err(throw_err!(MangoErrorCode::BeingLiquidated));
+            }
+        }
+
+        // This means health must only go up
+        let health_up_only = pre_health < ZERO_I80F48;
 
         let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
-        let mngo_start = mango_account.perp_accounts[market_index].mngo_accrued;
+        let mut event_queue =
+            EventQueue::load_mut_checked(event_queue_ai, program_id, &perp_market)?;
 
-        if perp_market.meta_data.version == 0 {
-            book.cancel_all_with_price_incentives(
-                &mut mango_account,
-                &mut perp_market,
-                market_index,
-                limit,
-            )?;
+        // Only a re-inserted order is canceled and gets incentives
+        let order = match side {
+            Side::Bid => book.bids.find_by_key(order_id),
+            Side::Ask => book.asks.find_by_key(order_id),
+        };
+        let oracle_price_lots =
+            perp_market.native_price_to_lot(mango_cache.get_price(market_index));
+        let best_final = if order.map_or(true, |o| o.edits_in_place(new_price, new_quantity)) {
+            None
+        } else if perp_market.meta_data.version == 0 {
+            Some(match side {
+                Side::Bid => book.get_best_bid_price(oracle_price_lots, now_ts).unwrap(),
+                Side::Ask => book.get_best_ask_price(oracle_price_lots, now_ts).unwrap(),
+            })
         } else {
-            let (all_order_ids, canceled_order_ids) = book.cancel_all_with_size_incentives(
-                &mut mango_account,
-                &mut perp_market,
-                market_index,
-                limit,
-            )?;
-            mango_emit_heap!(CancelAllPerpOrdersLog {
+            let max_depth: i64 = perp_market.liquidity_mining_info.max_depth_bps.to_num();
+            Some(match side {
+                Side::Bid => {
+                    book.get_bids_size_above_order(order_id, max_depth, oracle_price_lots, now_ts)
+                }
+                Side::Ask => {
+                    book.get_asks_size_below_order(order_id, max_depth, oracle_price_lots, now_ts)
+                }
+            })
+        };
+
+        let (order, reinserted) = book.edit_order(
+            program_id,
+            &mango_group,
+            mango_group_ai.key,
+            &mango_cache,
+            &mut event_queue,
+            &mut perp_market,
+            mango_cache.get_price(market_index),
+            &mut mango_account,
+            mango_account_ai.key,
+            market_index,
+            order_id,
+            side,
+            new_price,
+            new_quantity,
+            now_ts,
+            referrer_mango_account_ai,
+            limit,
+        )?;
+        cancel_iceberg_orders(
+            program_id,
+            packed_open_orders_ais.iter().find(|ai| ai.owner == program_id),
+            &mut mango_account,
+            mango_account_ai.key,
+            |o| o.market_index as usize == market_index && o.order_id == order_id,
+        )?;
+
+        // A re-inserted order was canceled, so it gets the same incentives as CancelPerpOrder
+        if reinserted && order.version == perp_market.meta_data.version {
+            let mngo_start = mango_account.perp_accounts[market_index].mngo_accrued;
+            if perp_market.meta_data.version == 0 {
+                mango_account.perp_accounts[market_index].apply_price_incentives(
+                    &mut perp_market,
+                    side,
+                    order.price(),
+                    order.best_initial,
+                    best_final.unwrap(),
+                    order.timestamp,
+                    now_ts,
+                    order.quantity,
+                )?;
+            } else {
+                mango_account.perp_accounts[market_index].apply_size_incentives(
+                    &mut perp_market,
+                    order.best_initial,
+                    best_final.unwrap(),
+                    order.timestamp,
+                    now_ts,
+                    order.quantity,
+                )?;
+            }
+
+            mango_emit_heap!(MngoAccrualLog {
                 mango_group: *mango_group_ai.key,
                 mango_account: *mango_account_ai.key,
                 market_index: market_index as u64,
-                all_order_ids,
-                canceled_order_ids
+                mngo_accrual: mango_account.perp_accounts[market_index].mngo_accrued - mngo_start
             });
         }
 
-        mango_emit_heap!(MngoAccrualLog {
-            mango_group: *mango_group_ai.key,
-            mango_account: *mango_account_ai.key,
-            market_index: market_index as u64,
-            mngo_accrual: mango_account.perp_accounts[market_index].mngo_accrued - mngo_start
-        });
-        Ok(())
+        health_cache.update_perp_val(&mango_group, &mango_cache, &mango_account, market_index)?;
+        let post_health = health_cache.get_health(&mango_group, HealthType::Init);
+        check!(
+            post_health >= ZERO_I80F48 || (health_up_only && post_health >= pre_health),
+            MangoErrorCode::InsufficientFunds
+        )
     }
 
     #[inline(never)]
-    /// Take two MangoAccount and settle quote currency pnl between them
-    fn settle_pnl(
+    /// Cancel and place perp orders on one market, checking health once at the end
+    fn batch_perp_orders(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        market_index: usize,
-    ) -> MangoResult<()> {
-        // TODO - what if someone has no collateral except other perps contracts
-        //  maybe you don't allow people to withdraw if they don't have enough
-        //  when liquidating, make sure you settle their pnl first?
-        // TODO consider doing this in batches of 32 accounts that are close to zero sum
-        // TODO write unit tests for this function
+        limit: u8,
+        cancel_order_ids: Vec<i128>,
+        cancel_client_order_ids: Vec<u64>,
+        orders: Vec<BatchPerpOrder>,
+    ) -> MangoResult {
+        check!(limit > 0, MangoErrorCode::InvalidParam)?;
+        check!(cancel_order_ids.len() <= MAX_PERP_OPEN_ORDERS, MangoErrorCode::InvalidParam)?;
+        check!(
+            cancel_client_order_ids.len() <= MAX_PERP_OPEN_ORDERS,
+            MangoErrorCode::InvalidParam
+        )?;
+        check!(orders.len() <= MAX_PERP_OPEN_ORDERS, MangoErrorCode::InvalidParam)?;
+        for order in orders.iter() {
+            check!(order.price > 0, MangoErrorCode::InvalidParam)?;
+            check!(order.max_base_quantity > 0, MangoErrorCode::InvalidParam)?;
+        }
 
-        const NUM_FIXED: usize = 6;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        const NUM_FIXED: usize = 9;
+        let (fixed_ais, packed_open_orders_ais) = array_refs![accounts, NUM_FIXED; ..;];
         let [
-            mango_group_ai,     // read
-            mango_account_a_ai, // write
-            mango_account_b_ai, // write
-            mango_cache_ai,     // read
-            root_bank_ai,       // read
-            node_bank_ai,       // write
-        ] = accounts;
-        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+            mango_group_ai,             // read
+            mango_account_ai,           // write
+            owner_ai,                   // read, signer
+            mango_cache_ai,             // read
+            perp_market_ai,             // write
+            bids_ai,                    // write
+            asks_ai,                    // write
+            event_queue_ai,             // write
+            referrer_mango_account_ai,  // write
+        ] = fixed_ais;
 
-        let mut mango_account_a =
-            MangoAccount::load_mut_checked(mango_account_a_ai, program_id, mango_group_ai.key)?;
-        check!(!mango_account_a.is_bankrupt, MangoErrorCode::Bankrupt)?;
+        // If referrer same as user, assume no referrer
+        let referrer_mango_account_ai = if referrer_mango_account_ai.key == mango_account_ai.key {
+            None
+        } else {
+            Some(referrer_mango_account_ai)
+        };
 
-        let mut mango_account_b =
-            MangoAccount::load_mut_checked(mango_account_b_ai, program_id, mango_group_ai.key)?;
-        check!(!mango_account_b.is_bankrupt, MangoErrorCode::Bankrupt)?;
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
 
-        match mango_group.find_root_bank_index(root_bank_ai.key) {
-            None => return Err(throw_err!(MangoErrorCode::Default)),
-            Some(i) => check!(i == QUOTE_INDEX, MangoErrorCode::Default)?,
-        }
-        let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
-        let mut node_bank = NodeBank::load_mut_checked(node_bank_ai, program_id)?;
-        check!(root_bank.node_banks.contains(node_bank_ai.key), MangoErrorCode::Default)?;
+        let mut mango_account =
+            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(!mango_account.is_bankrupt, MangoErrorCode::Bankrupt)?;
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check!(
+            &mango_account.owner == owner_ai.key || &mango_account.delegate == owner_ai.key,
+            MangoErrorCode::InvalidOwner
+        )?;
+
+        let open_orders_ais =
+            mango_account.checked_unpack_open_orders(&mango_group, packed_open_orders_ais)?;
+        let open_orders_accounts = load_open_orders_accounts(&open_orders_ais)?;
 
-        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
         let now_ts = Clock::get()?.unix_timestamp as u64;
 
-        let root_bank_cache = &mango_cache.root_bank_cache[QUOTE_INDEX];
-        let price_cache = &mango_cache.price_cache[market_index];
-        let perp_market_cache = &mango_cache.perp_market_cache[market_index];
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+        let market_index = mango_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
 
-        root_bank_cache.check_valid(&mango_group, now_ts)?;
-        price_cache.check_valid(&mango_group, now_ts)?;
-        perp_market_cache.check_valid(&mango_group, now_ts)?;
+        let active_assets = UserActiveAssets::new(
+            &mango_group,
+            &mango_account,
+            vec![(AssetType::Perp, market_index)],
+        );
 
-        let price = price_cache.price;
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+        mango_cache.check_valid(&mango_group, &active_assets, now_ts)?;
+        mango_cache.check_not_halted(&mango_group, &active_assets)?;
 
-        let a = &mut mango_account_a.perp_accounts[market_index];
-        let b = &mut mango_account_b.perp_accounts[market_index];
+        let mut health_cache = HealthCache::new(active_assets);
+        health_cache.init_vals_with_orders_vec(
+            &mango_group,
+            &mango_cache,
+            &mango_account,
+            &open_orders_accounts,
+        )?;
+        let pre_health = health_cache.get_health(&mango_group, HealthType::Init);
 
-        // Account for unrealized funding payments before settling
-        a.settle_funding(perp_market_cache);
-        b.settle_funding(perp_market_cache);
+        // update the being_liquidated flag
+        if mango_account.being_liquidated {
+            if pre_health >= ZERO_I80F48 {
+                mango_account.being_liquidated = false;
+            } else {
+                return Err(throw_err!(MangoErrorCode::BeingLiquidated));
+            }
+        }
 
-        let contract_size = mango_group.perp_markets[market_index].base_lot_size;
-        let new_quote_pos_a = I80F48::from_num(-a.base_position * contract_size) * price;
-        let new_quote_pos_b = I80F48::from_num(-b.base_position * contract_size) * price;
-        let a_pnl: I80F48 = a.quote_position - new_quote_pos_a;
-        let b_pnl: I80F48 = b.quote_position - new_quote_pos_b;
+        // This means health must only go up
+        let health_up_only = pre_health < ZERO_I80F48;
 
-        // pnl must be opposite signs for there to be a settlement
-        if !((a_pnl.is_positive() && b_pnl.is_negative())
-            || (a_pnl.is_negative() && b_pnl.is_positive()))
-        {
-            return Ok(());
-        }
+        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+        let mut event_queue =
+            EventQueue::load_mut_checked(event_queue_ai, program_id, &perp_market)?;
 
-        let settlement = a_pnl.abs().min(b_pnl.abs());
-        let a_settle = if a_pnl > 0 { settlement } else { -settlement };
-        a.transfer_quote_position(b, a_settle);
+        let mut cancels: Vec<(i128, Side)> = cancel_order_ids
+            .iter()
+            .filter_map(|&order_id| {
+                mango_account.find_order_side(market_index, order_id).map(|side| (order_id, side))
+            })
+            .chain(cancel_client_order_ids.iter().filter_map(|&client_order_id| {
+                mango_account.find_order_with_client_id(market_index, client_order_id)
+            }))
+            .collect();
+        cancels.sort_unstable_by_key(|&(order_id, _)| order_id);
+        cancels.dedup_by_key(|&mut (order_id, _)| order_id);
 
-        transfer_token_internal(
-            &root_bank_cache,
-            &mut node_bank,
-            &mut mango_account_b,
-            &mut mango_account_a,
-            mango_account_b_ai.key,
-            mango_account_a_ai.key,
-            QUOTE_INDEX,
-            a_settle,
+        let mngo_start = mango_account.perp_accounts[market_index].mngo_accrued;
+        let canceled_order_ids = book.cancel_orders_with_incentives(
+            &mut mango_account,
+            &mut perp_market,
+            market_index,
+            cancels,
+            now_ts,
+        )?;
+        cancel_iceberg_orders(
+            program_id,
+            packed_open_orders_ais.iter().find(|ai| ai.owner == program_id),
+            &mut mango_account,
+            mango_account_ai.key,
+            |o| o.market_index as usize == market_index && canceled_order_ids.contains(&o.order_id),
         )?;
+        let index_price = perp_market.last_index_price;
+        perp_market.update_premium(&book, index_price, now_ts);
 
-        mango_emit_heap!(SettlePnlLog {
+        for order in orders.iter() {
+            book.new_order(
+                program_id,
+                &mango_group,
+                mango_group_ai.key,
+                &mango_cache,
+                &mut event_queue,
+                &mut perp_market,
+                mango_cache.get_price(market_index),
+                &mut mango_account,
+                mango_account_ai.key,
+                market_index,
+                order.side,
+                order.price,
+                order.max_base_quantity,
+                i64::MAX,
+                order.order_type,
+                order.self_trade_behavior,
+                order.time_in_force,
+                0,
+                order.client_order_id,
+                now_ts,
+                referrer_mango_account_ai,
+                limit,
+            )?;
+        }
+
+        mango_emit_heap!(BatchPerpOrdersLog {
             mango_group: *mango_group_ai.key,
-            mango_account_a: *mango_account_a_ai.key,
-            mango_account_b: *mango_account_b_ai.key,
+            mango_account: *mango_account_ai.key,
             market_index: market_index as u64,
-            settlement: a_settle.to_bits(), // Will be positive if a has positive pnl and settling with b
+            canceled_order_ids,
+            placed_client_order_ids: orders.iter().map(|order| order.client_order_id).collect()
+        });
+        mango_emit_heap!(MngoAccrualLog {
+            mango_group: *mango_group_ai.key,
+            mango_account: *mango_account_ai.key,
+            market_index: market_index as u64,
+            mngo_accrual: mango_account.perp_accounts[market_index].mngo_accrued - mngo_start
         });
-        emit_perp_balances(
-            *mango_group_ai.key,
-            *mango_account_a_ai.key,
-            market_index as u64,
-            &mango_account_a.perp_accounts[market_index],
-            perp_market_cache,
-        );
-        emit_perp_balances(
-            *mango_group_ai.key,
-            *mango_account_b_ai.key,
-            market_index as u64,
-            &mango_account_b.perp_accounts[market_index],
-            perp_market_cache,
-        );
 
-        Ok(())
+        health_cache.update_perp_val(&mango_group, &mango_cache, &mango_account, market_index)?;
+        let post_health = health_cache.get_health(&mango_group, HealthType::Init);
+        check!(
+            post_health >= ZERO_I80F48 || (health_up_only && post_health >= pre_health),
+            MangoErrorCode::InsufficientFunds
+        )
     }
 
     #[inline(never)]
-    /// Take an account that has losses in the selected perp market to account for fees_accrued
-    fn settle_fees(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
-        const NUM_FIXED: usize = 10;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
+    /// Post the next slice of the iceberg orders on a perp market whose visible slice is gone.
+    /// Only the owner or delegate may call this; the MangoAccount must afford the new slices.
+    fn refill_iceberg_orders(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        limit: u8,
+    ) -> MangoResult {
+        check!(limit > 0, MangoErrorCode::InvalidParam)?;
+
+        const NUM_FIXED: usize = 9;
+        let (fixed_ais, packed_open_orders_ais) = array_refs![accounts, NUM_FIXED; ..;];
         let [
             mango_group_ai,     // read
+            mango_account_ai,   // write
+            owner_ai,           // read, signer
             mango_cache_ai,     // read
             perp_market_ai,     // write
-            mango_account_ai,   // write
-            root_bank_ai,       // read
-            node_bank_ai,       // write
-            bank_vault_ai,      // write
-            fees_vault_ai,      // write
-            signer_ai,          // read
-            token_prog_ai,      // read
-        ] = accounts;
-        check_eq!(token_prog_ai.key, &spl_token::ID, MangoErrorCode::InvalidProgramId)?;
+            bids_ai,            // write
+            asks_ai,            // write
+            event_queue_ai,     // write
+            iceberg_orders_ai,  // write
+        ] = fixed_ais;
 
         let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
-        check!(fees_vault_ai.key == &mango_group.fees_vault, MangoErrorCode::InvalidVault)?;
-        check!(signer_ai.key == &mango_group.signer_key, MangoErrorCode::InvalidSignerKey)?;
-
-        let mut perp_market =
-            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
-        let market_index = mango_group.find_perp_market_index(perp_market_ai.key).unwrap();
 
         let mut mango_account =
             MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
         check!(!mango_account.is_bankrupt, MangoErrorCode::Bankrupt)?;
-
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
         check!(
-            &mango_group.tokens[QUOTE_INDEX].root_bank == root_bank_ai.key,
-            MangoErrorCode::InvalidRootBank
+            &mango_account.owner == owner_ai.key || &mango_account.delegate == owner_ai.key,
+            MangoErrorCode::InvalidOwner
         )?;
-        let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
-        let mut node_bank = NodeBank::load_mut_checked(node_bank_ai, program_id)?;
-        check!(root_bank.node_banks.contains(node_bank_ai.key), MangoErrorCode::InvalidNodeBank)?;
-        check!(bank_vault_ai.key == &node_bank.vault, MangoErrorCode::InvalidVault)?;
-
-        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
-        let now_ts = Clock::get()?.unix_timestamp as u64;
 
-        let root_bank_cache = &mango_cache.root_bank_cache[QUOTE_INDEX];
-        let price_cache = &mango_cache.price_cache[market_index];
-        let perp_market_cache = &mango_cache.perp_market_cache[market_index];
+        let open_orders_ais =
+            mango_account.checked_unpack_open_orders(&mango_group, packed_open_orders_ais)?;
+        let open_orders_accounts = load_open_orders_accounts(&open_orders_ais)?;
 
-        root_bank_cache.check_valid(&mango_group, now_ts)?;
-        price_cache.check_valid(&mango_group, now_ts)?;
-        perp_market_cache.check_valid(&mango_group, now_ts)?;
+        let mut iceberg_orders =
+            IcebergOrders::load_mut_checked(iceberg_orders_ai, program_id, mango_account_ai.key)?;
 
-        let price = price_cache.price;
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+        let market_index = mango_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
 
-        let pa = &mut mango_account.perp_accounts[market_index];
-        pa.settle_funding(&perp_market_cache);
-        let contract_size = mango_group.perp_markets[market_index].base_lot_size;
-        let new_quote_pos = I80F48::from_num(-pa.base_position * contract_size) * price;
-        let pnl: I80F48 = pa.quote_position - new_quote_pos;
-        // ignore these cases and fail silently so transactions can continue
-        if !(pnl.is_negative() && perp_market.fees_accrued.is_positive()) {
-            msg!("ignore settle_fees instruction: pnl.is_negative()={} perp_market.fees_accrued.is_positive()={}", pnl.is_negative(), perp_market.fees_accrued.is_positive());
-            return Ok(());
-        }
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let active_assets = UserActiveAssets::new(
+            &mango_group,
+            &mango_account,
+            vec![(AssetType::Perp, market_index)],
+        );
 
-        let settlement = pnl.abs().min(perp_market.fees_accrued).checked_floor().unwrap();
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+        mango_cache.check_valid(&mango_group, &active_assets, now_ts)?;
+        mango_cache.check_not_halted(&mango_group, &active_assets)?;
 
-        perp_market.fees_accrued -= settlement;
-        pa.quote_position += settlement;
+        let mut health_cache = HealthCache::new(active_assets);
+        health_cache.init_vals_with_orders_vec(
+            &mango_group,
+            &mango_cache,
+            &mango_account,
+            &open_orders_accounts,
+        )?;
+        let pre_health = health_cache.get_health(&mango_group, HealthType::Init);
 
-        // Transfer quote token from bank vault to fees vault owned by Mango DAO
-        let signers_seeds = gen_signer_seeds(&mango_group.signer_nonce, mango_group_ai.key);
-        invoke_transfer(
-            token_prog_ai,
-            bank_vault_ai,
-            fees_vault_ai,
-            signer_ai,
-            &[&signers_seeds],
-            settlement.to_num(),
-        )?;
+        // update the being_liquidated flag
+        if mango_account.being_liquidated {
+            if pre_health >= ZERO_I80F48 {
+                mango_account.being_liquidated = false;
+            } else {
+                return Err(throw_err!(MangoErrorCode::BeingLiquidated));
+            }
+        }
 
-        // Decrement deposits on mango account
-        checked_change_net(
-            root_bank_cache,
-            &mut node_bank,
+        // This means health must only go up
+        let health_up_only = pre_health < ZERO_I80F48;
+
+        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+        let mut event_queue =
+            EventQueue::load_mut_checked(event_queue_ai, program_id, &perp_market)?;
+
+        post_iceberg_slices(
+            program_id,
+            &mango_group,
+            mango_group_ai.key,
+            &mango_cache,
+            &mut book,
+            &mut event_queue,
+            &mut perp_market,
             &mut mango_account,
             mango_account_ai.key,
-            QUOTE_INDEX,
-            -settlement,
+            &mut iceberg_orders,
+            market_index,
+            false,
+            now_ts,
+            limit,
         )?;
 
-        mango_emit_heap!(SettleFeesLog {
-            mango_group: *mango_group_ai.key,
-            mango_account: *mango_account_ai.key,
-            market_index: market_index as u64,
-            settlement: settlement.to_bits()
-        });
-
-        emit_perp_balances(
-            *mango_group_ai.key,
-            *mango_account_ai.key,
-            market_index as u64,
-            &mango_account.perp_accounts[market_index],
-            perp_market_cache,
-        );
-
-        Ok(())
+        health_cache.update_perp_val(&mango_group, &mango_cache, &mango_account, market_index)?;
+        let post_health = health_cache.get_health(&mango_group, HealthType::Init);
+        check!(
+            post_health >= ZERO_I80F48 || (health_up_only && post_health >= pre_health),
+            MangoErrorCode::InsufficientFunds
+        )
     }
 
     #[inline(never)]
-    fn force_cancel_spot_orders(
+    /// Cancel the visible slice of an iceberg order, if it is still on the book,
+    /// and drop its hidden remainder
+    fn cancel_iceberg_order(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        limit: u8,
-    ) -> MangoResult<()> {
-        const NUM_FIXED: usize = 19;
-        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
-        let (fixed_ais, liqee_open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
+        index: u8,
+    ) -> MangoResult {
+        let index = index as usize;
+        check!(index < MAX_ICEBERG_ORDERS, MangoErrorCode::InvalidParam)?;
 
+        const NUM_FIXED: usize = 7;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
         let [
-            mango_group_ai,         // read
-            mango_cache_ai,         // read
-            liqee_mango_account_ai, // write
-            base_root_bank_ai,      // read
-            base_node_bank_ai,      // write
-            base_vault_ai,          // write
-            quote_root_bank_ai,     // read
-            quote_node_bank_ai,     // write
-            quote_vault_ai,         // write
-
-            spot_market_ai,         // write
-            bids_ai,                // write
-            asks_ai,                // write
-            signer_ai,              // read
-            dex_event_queue_ai,     // write
-            dex_base_ai,            // write
-            dex_quote_ai,           // write
-            dex_signer_ai,          // read
-            dex_prog_ai,            // read
-            token_prog_ai,          // read
-        ] = fixed_ais;
-
-        // Check token program id
-        check_eq!(token_prog_ai.key, &spl_token::ID, MangoErrorCode::InvalidProgramId)?;
+            mango_group_ai,     // read
+            mango_account_ai,   // write
+            owner_ai,           // read, signer
+            perp_market_ai,     // write
+            bids_ai,            // write
+            asks_ai,            // write
+            iceberg_orders_ai,  // write
+        ] = accounts;
 
         let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
-        check_eq!(dex_prog_ai.key, &mango_group.dex_program_id, MangoErrorCode::InvalidProgramId)?;
-        check!(signer_ai.key == &mango_group.signer_key, MangoErrorCode::InvalidSignerKey)?;
-
-        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
-        let mut liqee_ma =
-            MangoAccount::load_mut_checked(liqee_mango_account_ai, program_id, mango_group_ai.key)?;
-        check!(!liqee_ma.is_bankrupt, MangoErrorCode::Bankrupt)?;
-        liqee_ma.check_open_orders(&mango_group, liqee_open_orders_ais)?;
-
-        let market_index = mango_group.find_spot_market_index(spot_market_ai.key).unwrap();
-        check!(liqee_ma.in_margin_basket[market_index], MangoErrorCode::Default)?;
 
-        check_eq!(
-            &mango_group.tokens[market_index].root_bank,
-            base_root_bank_ai.key,
-            MangoErrorCode::InvalidRootBank
+        let mut mango_account =
+            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(!mango_account.is_bankrupt, MangoErrorCode::Bankrupt)?;
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check!(
+            &mango_account.owner == owner_ai.key || &mango_account.delegate == owner_ai.key,
+            MangoErrorCode::InvalidOwner
         )?;
-        let base_root_bank = RootBank::load_checked(base_root_bank_ai, program_id)?;
 
+        let mut iceberg_orders =
+            IcebergOrders::load_mut_checked(iceberg_orders_ai, program_id, mango_account_ai.key)?;
+        let order = &mut iceberg_orders.orders[index];
+        check!(order.is_active, MangoErrorCode::InvalidParam)?;
+        let market_index = order.market_index as usize;
         check!(
-            base_root_bank.node_banks.contains(base_node_bank_ai.key),
-            MangoErrorCode::InvalidNodeBank
+            &mango_group.perp_markets[market_index].perp_market == perp_market_ai.key,
+            MangoErrorCode::InvalidMarket
         )?;
-        let mut base_node_bank = NodeBank::load_mut_checked(base_node_bank_ai, program_id)?;
-        check_eq!(&base_node_bank.vault, base_vault_ai.key, MangoErrorCode::InvalidVault)?;
 
-        check_eq!(
-            &mango_group.tokens[QUOTE_INDEX].root_bank,
-            quote_root_bank_ai.key,
-            MangoErrorCode::InvalidRootBank
-        )?;
-        let quote_root_bank = RootBank::load_checked(quote_root_bank_ai, program_id)?;
+        if let Some(side) = mango_account.find_order_side(market_index, order.order_id) {
+            let mut perp_market =
+                PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+            let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+            let now_ts = Clock::get()?.unix_timestamp as u64;
+
+            let mngo_start = mango_account.perp_accounts[market_index].mngo_accrued;
+            book.cancel_orders_with_incentives(
+                &mut mango_account,
+                &mut perp_market,
+                market_index,
+                vec![(order.order_id, side)],
+                now_ts,
+            )?;
+            let index_price = perp_market.last_index_price;
+            perp_market.update_premium(&book, index_price, now_ts);
+
+            mango_emit_heap!(MngoAccrualLog {
+                mango_group: *mango_group_ai.key,
+                mango_account: *mango_account_ai.key,
+                market_index: market_index as u64,
+                mngo_accrual: mango_account.perp_accounts[market_index].mngo_accrued - mngo_start
+            });
+        }
+
+        order.is_active = false;
+        order.hidden_quantity = 0;
+        mango_account.has_iceberg_orders = iceberg_orders.has_active_orders();
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn cancel_perp_order_by_client_id(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        client_order_id: u64,
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 6;
+        let (fixed_ais, iceberg_orders_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            mango_group_ai,     // read
+            mango_account_ai,   // write
+            owner_ai,           // read, signer
+            perp_market_ai,     // write
+            bids_ai,            // write
+            asks_ai,            // write
+        ] = fixed_ais;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
 
+        let mut mango_account =
+            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(!mango_account.is_bankrupt, MangoErrorCode::Bankrupt)?;
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
         check!(
-            quote_root_bank.node_banks.contains(quote_node_bank_ai.key),
-            MangoErrorCode::InvalidNodeBank
+            &mango_account.owner == owner_ai.key || &mango_account.delegate == owner_ai.key,
+            MangoErrorCode::InvalidOwner
         )?;
-        let mut quote_node_bank = NodeBank::load_mut_checked(quote_node_bank_ai, program_id)?;
-        check_eq!(&quote_node_bank.vault, quote_vault_ai.key, MangoErrorCode::InvalidVault)?;
-
-        let now_ts = Clock::get()?.unix_timestamp as u64;
 
-        let liqee_active_assets = UserActiveAssets::new(&mango_group, &liqee_ma, vec![]);
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
 
-        mango_cache.check_valid(&mango_group, &liqee_active_assets, now_ts)?;
+        let market_index = mango_group.find_perp_market_index(perp_market_ai.key).unwrap();
 
-        let mut health_cache = HealthCache::new(liqee_active_assets);
-        health_cache.init_vals(&mango_group, &mango_cache, &liqee_ma, liqee_open_orders_ais)?;
-        let init_health = health_cache.get_health(&mango_group, HealthType::Init);
-        let maint_health = health_cache.get_health(&mango_group, HealthType::Maint);
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let (order_id, side) = mango_account
+            .find_order_with_client_id(market_index, client_order_id)
+            .ok_or(throw_err!(MangoErrorCode::ClientIdNotFound))?;
 
-        // Can only force cancel on an account already being liquidated
-        if liqee_ma.being_liquidated {
-            if init_health > ZERO_I80F48 {
-                liqee_ma.being_liquidated = false;
-                msg!("Account init_health above zero.");
-                return Ok(());
+        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+        let oracle_price_lots = perp_market.native_price_to_lot(perp_market.last_index_price);
+        let best_final = if is_oracle_pegged_key(order_id) {
+            0 // oracle pegged orders earn no liquidity incentives
+        } else if perp_market.meta_data.version == 0 {
+            match side {
+                Side::Bid => book.get_best_bid_price(oracle_price_lots, now_ts).unwrap(),
+                Side::Ask => book.get_best_ask_price(oracle_price_lots, now_ts).unwrap(),
             }
-        } else if maint_health >= ZERO_I80F48 {
-            return Err(throw_err!(MangoErrorCode::NotLiquidatable));
         } else {
-            liqee_ma.being_liquidated = true;
-        }
+            let max_depth: i64 = perp_market.liquidity_mining_info.max_depth_bps.to_num();
+            match side {
+                Side::Bid => {
+                    book.get_bids_size_above_order(order_id, max_depth, oracle_price_lots, now_ts)
+                }
+                Side::Ask => {
+                    book.get_asks_size_below_order(order_id, max_depth, oracle_price_lots, now_ts)
+                }
+            }
+        };
 
-        // Cancel orders up to the limit
-        let open_orders_ai = &liqee_open_orders_ais[market_index];
-        let signers_seeds = gen_signer_seeds(&mango_group.signer_nonce, mango_group_ai.key);
-        invoke_cancel_orders(
-            open_orders_ai,
-            dex_prog_ai,
-            spot_market_ai,
-            bids_ai,
-            asks_ai,
-            signer_ai,
-            dex_event_queue_ai,
-            &[&signers_seeds],
-            limit,
+        let order = book.cancel_order(order_id, side)?;
+        check_eq!(&order.owner, mango_account_ai.key, MangoErrorCode::InvalidOrderId)?;
+        mango_account.remove_order(order.owner_slot as usize, order.quantity)?;
+        cancel_iceberg_orders(
+            program_id,
+            iceberg_orders_ais.first(),
+            &mut mango_account,
+            mango_account_ai.key,
+            |o| o.market_index as usize == market_index && o.order_id == order_id,
         )?;
 
-        let (pre_base, pre_quote) = {
-            let open_orders = load_open_orders(open_orders_ai)?;
-            (
-                open_orders.native_coin_free,
-                open_orders.native_pc_free + open_orders.referrer_rebates_accrued,
-            )
-        };
+        let index_price = perp_market.last_index_price;
+        perp_market.update_premium(&book, index_price, now_ts);
 
-        if pre_base == 0 && pre_quote == 0 {
-            // margin basket may be in an invalid state; correct it before returning
-            let open_orders = load_open_orders(open_orders_ai)?;
-            liqee_ma.update_basket(market_index, &open_orders)?;
+        // If order version doesn't match the perp market version, no incentives
+        // time in force invalid orders and oracle pegged orders don't get rewards
+        if order.version != perp_market.meta_data.version
+            || !order.is_valid(now_ts)
+            || order.is_oracle_pegged()
+        {
             return Ok(());
         }
 
-        // Settle funds released by canceling open orders
-        // TODO OPT add a new ForceSettleFunds to save compute in this instruction
-        invoke_settle_funds(
-            dex_prog_ai,
-            spot_market_ai,
-            open_orders_ai,
-            signer_ai,
-            dex_base_ai,
-            dex_quote_ai,
-            base_vault_ai,
-            quote_vault_ai,
-            dex_signer_ai,
-            token_prog_ai,
-            &[&signers_seeds],
-        )?;
-
-        let (post_base, post_quote) = {
-            let open_orders = load_open_orders(open_orders_ai)?;
-            liqee_ma.update_basket(market_index, &open_orders)?;
-            mango_emit_stack::<_, 256>(OpenOrdersBalanceLog {
-                mango_group: *mango_group_ai.key,
-                mango_account: *liqee_mango_account_ai.key,
-                market_index: market_index as u64,
-                base_total: open_orders.native_coin_total,
-                base_free: open_orders.native_coin_free,
-                quote_total: open_orders.native_pc_total,
-                quote_free: open_orders.native_pc_free,
-                referrer_rebates_accrued: open_orders.referrer_rebates_accrued,
-            });
-
-            (
-                open_orders.native_coin_free,
-                open_orders.native_pc_free + open_orders.referrer_rebates_accrued,
-            )
-        };
-
-        check!(post_base <= pre_base, MangoErrorCode::Default)?;
-        check!(post_quote <= pre_quote, MangoErrorCode::Default)?;
+        let mngo_start = mango_account.perp_accounts[market_index].mngo_accrued;
+        if perp_market.meta_data.version == 0 {
+            mango_account.perp_accounts[market_index].apply_price_incentives(
+                &mut perp_market,
+                side,
+                order.price(),
+                order.best_initial,
+                best_final,
+                order.timestamp,
+                now_ts,
+                order.quantity,
+            )?;
+        } else {
+            mango_account.perp_accounts[market_index].apply_size_incentives(
+                &mut perp_market,
+                order.best_initial,
+                best_final,
+                order.timestamp,
+                Clock::get()?.unix_timestamp as u64,
+                order.quantity,
+            )?;
+        }
 
-        // Update balances from settling funds
-        let base_change = I80F48::from_num(pre_base - post_base);
-        let quote_change = I80F48::from_num(pre_quote - post_quote);
+        mango_emit_heap!(MngoAccrualLog {
+            mango_group: *mango_group_ai.key,
+            mango_account: *mango_account_ai.key,
+            market_index: market_index as u64,
+            mngo_accrual: mango_account.perp_accounts[market_index].mngo_accrued - mngo_start
+        });
 
-        checked_change_net(
-            &mango_cache.root_bank_cache[market_index],
-            &mut base_node_bank,
-            &mut liqee_ma,
-            liqee_mango_account_ai.key,
-            market_index,
-            base_change,
-        )?;
-        checked_change_net(
-            &mango_cache.root_bank_cache[QUOTE_INDEX],
-            &mut quote_node_bank,
-            &mut liqee_ma,
-            liqee_mango_account_ai.key,
-            QUOTE_INDEX,
-            quote_change,
-        )
+        Ok(())
     }
 
     #[inline(never)]
-    fn force_cancel_perp_orders(
+    fn cancel_perp_order(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        limit: u8,
+        order_id: i128,
     ) -> MangoResult<()> {
         const NUM_FIXED: usize = 6;
-        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
-        let (fixed_ais, liqee_open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
-
+        let (fixed_ais, iceberg_orders_ais) = array_refs![accounts, NUM_FIXED; ..;];
         let [
-            mango_group_ai,         // read
-            mango_cache_ai,         // read
-            perp_market_ai,         // read
-            bids_ai,                // write
-            asks_ai,                // write
-            liqee_mango_account_ai, // write
+            mango_group_ai,     // read
+            mango_account_ai,   // write
+            owner_ai,           // read, signer
+            perp_market_ai,     // write
+            bids_ai,            // write
+            asks_ai,            // write
         ] = fixed_ais;
 
+        // TODO OPT put the liquidity incentive stuff in the bids and asks accounts so perp market
+        //  doesn't have to be passed in as write
+
         let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
-        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
 
-        let mut liqee_ma =
-            MangoAccount::load_mut_checked(liqee_mango_account_ai, program_id, mango_group_ai.key)?;
-        check!(!liqee_ma.is_bankrupt, MangoErrorCode::Bankrupt)?;
-        liqee_ma.check_open_orders(&mango_group, liqee_open_orders_ais)?;
+        let mut mango_account =
+            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(!mango_account.is_bankrupt, MangoErrorCode::Bankrupt)?;
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check!(
+            &mango_account.owner == owner_ai.key || &mango_account.delegate == owner_ai.key,
+            MangoErrorCode::InvalidOwner
+        )?;
+
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
 
-        let perp_market = PerpMarket::load_checked(perp_market_ai, program_id, mango_group_ai.key)?;
         let market_index = mango_group.find_perp_market_index(perp_market_ai.key).unwrap();
-        let perp_market_info = &mango_group.perp_markets[market_index];
-        check!(!perp_market_info.is_empty(), MangoErrorCode::InvalidMarket)?;
 
         let now_ts = Clock::get()?.unix_timestamp as u64;
 
-        let liqee_active_assets = UserActiveAssets::new(&mango_group, &liqee_ma, vec![]);
+        let side = mango_account
+            .find_order_side(market_index, order_id)
+            .ok_or(throw_err!(MangoErrorCode::InvalidOrderId))?;
+        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
 
-        mango_cache.check_valid(&mango_group, &liqee_active_assets, now_ts)?;
+        let oracle_price_lots = perp_market.native_price_to_lot(perp_market.last_index_price);
+        let best_final = if is_oracle_pegged_key(order_id) {
+            0 // oracle pegged orders earn no liquidity incentives
+        } else if perp_market.meta_data.version == 0 {
+            match side {
+                Side::Bid => book.get_best_bid_price(oracle_price_lots, now_ts).unwrap(),
+                Side::Ask => book.get_best_ask_price(oracle_price_lots, now_ts).unwrap(),
+            }
+        } else {
+            let max_depth: i64 = perp_market.liquidity_mining_info.max_depth_bps.to_num();
+            match side {
+                Side::Bid => {
+                    book.get_bids_size_above_order(order_id, max_depth, oracle_price_lots, now_ts)
+                }
+                Side::Ask => {
+                    book.get_asks_size_below_order(order_id, max_depth, oracle_price_lots, now_ts)
+                }
+            }
+        };
 
-        let mut health_cache = HealthCache::new(liqee_active_assets);
-        health_cache.init_vals(&mango_group, &mango_cache, &liqee_ma, liqee_open_orders_ais)?;
-        let init_health = health_cache.get_health(&mango_group, HealthType::Init);
-        let maint_health = health_cache.get_health(&mango_group, HealthType::Maint);
+        let order = book.cancel_order(order_id, side)?;
+        check_eq!(&order.owner, mango_account_ai.key, MangoErrorCode::InvalidOrderId)?;
+        mango_account.remove_order(order.owner_slot as usize, order.quantity)?;
+        cancel_iceberg_orders(
+            program_id,
+            iceberg_orders_ais.first(),
+            &mut mango_account,
+            mango_account_ai.key,
+            |o| o.market_index as usize == market_index && o.order_id == order_id,
+        )?;
 
-        if liqee_ma.being_liquidated {
-            if init_health > ZERO_I80F48 {
-                liqee_ma.being_liquidated = false;
-                msg!("Account init_health above zero.");
-                return Ok(());
-            }
-        } else if maint_health >= ZERO_I80F48 {
-            msg!(
-                "maint health {} init health {}",
-                maint_health.to_num::<f64>(),
-                init_health.to_num::<f64>()
-            );
-            return Err(throw_err!(MangoErrorCode::NotLiquidatable));
+        let index_price = perp_market.last_index_price;
+        perp_market.update_premium(&book, index_price, now_ts);
+
+        // If order version doesn't match the perp market version, no incentives
+        // time in force invalid orders and oracle pegged orders don't get rewards
+        if order.version != perp_market.meta_data.version
+            || !order.is_valid(now_ts)
+            || order.is_oracle_pegged()
+        {
+            return Ok(());
+        }
+
+        let mngo_start = mango_account.perp_accounts[market_index].mngo_accrued;
+        if perp_market.meta_data.version == 0 {
+            mango_account.perp_accounts[market_index].apply_price_incentives(
+                &mut perp_market,
+                side,
+                order.price(),
+                order.best_initial,
+                best_final,
+                order.timestamp,
+                now_ts,
+                order.quantity,
+            )?;
         } else {
-            liqee_ma.being_liquidated = true;
+            mango_account.perp_accounts[market_index].apply_size_incentives(
+                &mut perp_market,
+                order.best_initial,
+                best_final,
+                order.timestamp,
+                Clock::get()?.unix_timestamp as u64,
+                order.quantity,
+            )?;
         }
 
-        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
-        book.cancel_all(&mut liqee_ma, market_index, limit)
+        mango_emit_heap!(MngoAccrualLog {
+            mango_group: *mango_group_ai.key,
+            mango_account: *mango_account_ai.key,
+            market_index: market_index as u64,
+            mngo_accrual: mango_account.perp_accounts[market_index].mngo_accrued - mngo_start
+        });
+
+        Ok(())
     }
 
     #[inline(never)]
-    /// Liquidator takes some of borrows at token at `liab_index` and receives some deposits from
-    /// the token at `asset_index`
-    /// Requires: `liab_index != asset_index`
-    fn liquidate_token_and_token(
+    fn cancel_all_perp_orders(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        max_liab_transfer: I80F48,
-    ) -> MangoResult<()> {
-        // parameter checks
-        check!(max_liab_transfer.is_positive(), MangoErrorCode::InvalidParam)?;
-
-        const NUM_FIXED: usize = 9;
-        let accounts = array_ref![accounts, 0, NUM_FIXED + 2 * MAX_PAIRS];
-        let (fixed_ais, liqee_open_orders_ais, liqor_open_orders_ais) =
-            array_refs![accounts, NUM_FIXED, MAX_PAIRS, MAX_PAIRS];
-
+        limit: u8,
+    ) -> MangoResult {
+        const NUM_FIXED: usize = 6;
+        let (fixed_ais, iceberg_orders_ais) = array_refs![accounts, NUM_FIXED; ..;];
         let [
-            mango_group_ai,         // read
-            mango_cache_ai,         // read
-            liqee_mango_account_ai, // write
-            liqor_mango_account_ai, // write
-            liqor_ai,               // read, signer
-            asset_root_bank_ai,     // read
-            asset_node_bank_ai,     // write
-            liab_root_bank_ai,      // read
-            liab_node_bank_ai,      // write
+            mango_group_ai,     // read
+            mango_account_ai,   // write
+            owner_ai,           // read, signer
+            perp_market_ai,     // write
+            bids_ai,            // write
+            asks_ai,            // write
         ] = fixed_ais;
 
         let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
-        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
-        let mut liqee_ma =
-            MangoAccount::load_mut_checked(liqee_mango_account_ai, program_id, mango_group_ai.key)?;
-        check!(!liqee_ma.is_bankrupt, MangoErrorCode::Bankrupt)?;
-        liqee_ma.check_open_orders(&mango_group, liqee_open_orders_ais)?;
 
-        let mut liqor_ma =
-            MangoAccount::load_mut_checked(liqor_mango_account_ai, program_id, mango_group_ai.key)?;
+        let mut mango_account =
+            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(!mango_account.is_bankrupt, MangoErrorCode::Bankrupt)?;
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
         check!(
-            &liqor_ma.owner == liqor_ai.key || &liqor_ma.delegate == liqor_ai.key,
+            &mango_account.owner == owner_ai.key || &mango_account.delegate == owner_ai.key,
             MangoErrorCode::InvalidOwner
         )?;
-        check!(liqor_ai.is_signer, MangoErrorCode::InvalidSignerKey)?;
-        check!(!liqor_ma.is_bankrupt, MangoErrorCode::Bankrupt)?;
-        liqor_ma.check_open_orders(&mango_group, liqor_open_orders_ais)?;
 
-        let asset_root_bank = RootBank::load_checked(asset_root_bank_ai, program_id)?;
-        let asset_index = mango_group.find_root_bank_index(asset_root_bank_ai.key).unwrap();
-        let mut asset_node_bank = NodeBank::load_mut_checked(asset_node_bank_ai, program_id)?;
-        check!(
-            asset_root_bank.node_banks.contains(asset_node_bank_ai.key),
-            MangoErrorCode::InvalidNodeBank
-        )?;
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
 
-        let liab_root_bank = RootBank::load_checked(liab_root_bank_ai, program_id)?;
-        let liab_index = mango_group.find_root_bank_index(liab_root_bank_ai.key).unwrap();
-        let mut liab_node_bank = NodeBank::load_mut_checked(liab_node_bank_ai, program_id)?;
-        check!(liab_root_bank.node_banks.contains(liab_node_bank_ai.key), MangoErrorCode::Default)?;
-        check!(asset_index != liab_index, MangoErrorCode::InvalidParam)?;
+        let market_index = mango_group.find_perp_market_index(perp_market_ai.key).unwrap();
+
+        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+        let mngo_start = mango_account.perp_accounts[market_index].mngo_accrued;
+        let orders_before = mango_account.orders;
+
+        if perp_market.meta_data.version == 0 {
+            book.cancel_all_with_price_incentives(
+                &mut mango_account,
+                &mut perp_market,
+                market_index,
+                limit,
+            )?;
+        } else {
+            let (all_order_ids, canceled_order_ids) = book.cancel_all_with_size_incentives(
+                &mut mango_account,
+                &mut perp_market,
+                market_index,
+                limit,
+            )?;
+            mango_emit_heap!(CancelAllPerpOrdersLog {
+                mango_group: *mango_group_ai.key,
+                mango_account: *mango_account_ai.key,
+                market_index: market_index as u64,
+                all_order_ids,
+                canceled_order_ids
+            });
+        }
+        let canceled_order_ids = mango_account.removed_order_ids(&orders_before);
+        cancel_iceberg_orders(
+            program_id,
+            iceberg_orders_ais.first(),
+            &mut mango_account,
+            mango_account_ai.key,
+            |o| o.market_index as usize == market_index && canceled_order_ids.contains(&o.order_id),
+        )?;
+
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let index_price = perp_market.last_index_price;
+        perp_market.update_premium(&book, index_price, now_ts);
+
+        mango_emit_heap!(MngoAccrualLog {
+            mango_group: *mango_group_ai.key,
+            mango_account: *mango_account_ai.key,
+            market_index: market_index as u64,
+            mngo_accrual: mango_account.perp_accounts[market_index].mngo_accrued - mngo_start
+        });
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Take two MangoAccount and settle quote currency pnl between them
+    fn settle_pnl(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        market_index: usize,
+    ) -> MangoResult<()> {
+        // TODO - what if someone has no collateral except other perps contracts
+        //  maybe you don't allow people to withdraw if they don't have enough
+        //  when liquidating, make sure you settle their pnl first?
+        // TODO consider doing this in batches of 32 accounts that are close to zero sum
+        // TODO write unit tests for this function
+
+        const NUM_FIXED: usize = 6;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            mango_account_a_ai, // write
+            mango_account_b_ai, // write
+            mango_cache_ai,     // read
+            root_bank_ai,       // read
+            node_bank_ai,       // write
+        ] = accounts;
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+
+        let mut mango_account_a =
+            MangoAccount::load_mut_checked(mango_account_a_ai, program_id, mango_group_ai.key)?;
+        check!(!mango_account_a.is_bankrupt, MangoErrorCode::Bankrupt)?;
+
+        let mut mango_account_b =
+            MangoAccount::load_mut_checked(mango_account_b_ai, program_id, mango_group_ai.key)?;
+        check!(!mango_account_b.is_bankrupt, MangoErrorCode::Bankrupt)?;
+
+        match mango_group.find_root_bank_index(root_bank_ai.key) {
+            None => return Err(throw_err!(MangoErrorCode::Default)),
+            Some(i) => check!(i == QUOTE_INDEX, MangoErrorCode::Default)?,
+        }
+        let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
+        let mut node_bank = NodeBank::load_mut_checked(node_bank_ai, program_id)?;
+        check!(root_bank.node_banks.contains(node_bank_ai.key), MangoErrorCode::Default)?;
+
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+
+        let root_bank_cache = &mango_cache.root_bank_cache[QUOTE_INDEX];
+        let price_cache = &mango_cache.price_cache[market_index];
+        let perp_market_cache = &mango_cache.perp_market_cache[market_index];
+
+        root_bank_cache.check_valid(&mango_group, now_ts)?;
+        price_cache.check_valid(&mango_group, market_index, now_ts)?;
+        perp_market_cache.check_valid(&mango_group, now_ts)?;
+
+        let price = mango_group.perp_markets[market_index].settled_or(price_cache.price);
+
+        let a = &mut mango_account_a.perp_accounts[market_index];
+        let b = &mut mango_account_b.perp_accounts[market_index];
+
+        // Account for unrealized funding payments before settling
+        a.settle_funding(perp_market_cache);
+        b.settle_funding(perp_market_cache);
+
+        let contract_size = mango_group.perp_markets[market_index].base_lot_size;
+        let new_quote_pos_a = I80F48::from_num(-a.base_position * contract_size) * price;
+        let new_quote_pos_b = I80F48::from_num(-b.base_position * contract_size) * price;
+        let a_pnl: I80F48 = a.quote_position - new_quote_pos_a;
+        let b_pnl: I80F48 = b.quote_position - new_quote_pos_b;
+
+        // pnl must be opposite signs for there to be a settlement
+        if !((a_pnl.is_positive() && b_pnl.is_negative())
+            || (a_pnl.is_negative() && b_pnl.is_positive()))
+        {
+            return Ok(());
+        }
+
+        let settlement = a_pnl.abs().min(b_pnl.abs());
+        let a_settle = if a_pnl > 0 { settlement } else { -settlement };
+        a.transfer_quote_position(b, a_settle);
+
+        transfer_token_internal(
+            &root_bank_cache,
+            &mut node_bank,
+            &mut mango_account_b,
+            &mut mango_account_a,
+            mango_account_b_ai.key,
+            mango_account_a_ai.key,
+            QUOTE_INDEX,
+            a_settle,
+        )?;
+
+        mango_emit_heap!(SettlePnlLog {
+            mango_group: *mango_group_ai.key,
+            mango_account_a: *mango_account_a_ai.key,
+            mango_account_b: *mango_account_b_ai.key,
+            market_index: market_index as u64,
+            settlement: a_settle.to_bits(), // Will be positive if a has positive pnl and settling with b
+        });
+        emit_perp_balances(
+            *mango_group_ai.key,
+            *mango_account_a_ai.key,
+            market_index as u64,
+            &mango_account_a.perp_accounts[market_index],
+            perp_market_cache,
+        );
+        emit_perp_balances(
+            *mango_group_ai.key,
+            *mango_account_b_ai.key,
+            market_index as u64,
+            &mango_account_b.perp_accounts[market_index],
+            perp_market_cache,
+        );
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Take an account that has losses in the selected perp market to account for fees_accrued
+    fn settle_fees(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
+        const NUM_FIXED: usize = 10;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            mango_cache_ai,     // read
+            perp_market_ai,     // write
+            mango_account_ai,   // write
+            root_bank_ai,       // read
+            node_bank_ai,       // write
+            bank_vault_ai,      // write
+            fees_vault_ai,      // write
+            signer_ai,          // read
+            token_prog_ai,      // read
+        ] = accounts;
+        check_eq!(token_prog_ai.key, &spl_token::ID, MangoErrorCode::InvalidProgramId)?;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        check!(fees_vault_ai.key == &mango_group.fees_vault, MangoErrorCode::InvalidVault)?;
+        check!(signer_ai.key == &mango_group.signer_key, MangoErrorCode::InvalidSignerKey)?;
+
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+        let market_index = mango_group.find_perp_market_index(perp_market_ai.key).unwrap();
+
+        let mut mango_account =
+            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(!mango_account.is_bankrupt, MangoErrorCode::Bankrupt)?;
+
+        check!(
+            &mango_group.tokens[QUOTE_INDEX].root_bank == root_bank_ai.key,
+            MangoErrorCode::InvalidRootBank
+        )?;
+        let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
+        let mut node_bank = NodeBank::load_mut_checked(node_bank_ai, program_id)?;
+        check!(root_bank.node_banks.contains(node_bank_ai.key), MangoErrorCode::InvalidNodeBank)?;
+        check!(bank_vault_ai.key == &node_bank.vault, MangoErrorCode::InvalidVault)?;
+
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+
+        let root_bank_cache = &mango_cache.root_bank_cache[QUOTE_INDEX];
+        let price_cache = &mango_cache.price_cache[market_index];
+        let perp_market_cache = &mango_cache.perp_market_cache[market_index];
+
+        root_bank_cache.check_valid(&mango_group, now_ts)?;
+        price_cache.check_valid(&mango_group, market_index, now_ts)?;
+        perp_market_cache.check_valid(&mango_group, now_ts)?;
+
+        let price = mango_group.perp_markets[market_index].settled_or(price_cache.price);
+
+        let pa = &mut mango_account.perp_accounts[market_index];
+        pa.settle_funding(&perp_market_cache);
+        let contract_size = mango_group.perp_markets[market_index].base_lot_size;
+        let new_quote_pos = I80F48::from_num(-pa.base_position * contract_size) * price;
+        let pnl: I80F48 = pa.quote_position - new_quote_pos;
+        // ignore these cases and fail silently so transactions can continue
+        if !(pnl.is_negative() && perp_market.fees_accrued.is_positive()) {
+            msg!("ignore settle_fees instruction: pnl.is_negative()={} perp_market.fees_accrued.is_positive()={}", pnl.is_negative(), perp_market.fees_accrued.is_positive());
+            return Ok(());
+        }
+
+        let settlement = pnl.abs().min(perp_market.fees_accrued).checked_floor().unwrap();
+
+        perp_market.fees_accrued -= settlement;
+        pa.quote_position += settlement;
+
+        // Transfer quote token from bank vault to fees vault owned by Mango DAO
+        let signers_seeds = gen_signer_seeds(&mango_group.signer_nonce, mango_group_ai.key);
+        invoke_transfer(
+            token_prog_ai,
+            bank_vault_ai,
+            fees_vault_ai,
+            signer_ai,
+            &[&signers_seeds],
+            settlement.to_num(),
+        )?;
+
+        // Decrement deposits on mango account
+        checked_change_net(
+            root_bank_cache,
+            &mut node_bank,
+            &mut mango_account,
+            mango_account_ai.key,
+            QUOTE_INDEX,
+            -settlement,
+        )?;
+
+        mango_emit_heap!(SettleFeesLog {
+            mango_group: *mango_group_ai.key,
+            mango_account: *mango_account_ai.key,
+            market_index: market_index as u64,
+            settlement: settlement.to_bits()
+        });
+
+        emit_perp_balances(
+            *mango_group_ai.key,
+            *mango_account_ai.key,
+            market_index as u64,
+            &mango_account.perp_accounts[market_index],
+            perp_market_cache,
+        );
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn force_cancel_spot_orders(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        limit: u8,
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 19;
+        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
+        let (fixed_ais, liqee_open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
+
+        let [
+            mango_group_ai,         // read
+            mango_cache_ai,         // read
+            liqee_mango_account_ai, // write
+            base_root_bank_ai,      // read
+            base_node_bank_ai,      // write
+            base_vault_ai,          // write
+            quote_root_bank_ai,     // read
+            quote_node_bank_ai,     // write
+            quote_vault_ai,         // write
+
+            spot_market_ai,         // write
+            bids_ai,                // write
+            asks_ai,                // write
+            signer_ai,              // read
+            dex_event_queue_ai,     // write
+            dex_base_ai,            // write
+            dex_quote_ai,           // write
+            dex_signer_ai,          // read
+            dex_prog_ai,            // read
+            token_prog_ai,          // read
+        ] = fixed_ais;
+
+        // Check token program id
+        check_eq!(token_prog_ai.key, &spl_token::ID, MangoErrorCode::InvalidProgramId)?;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        check_eq!(dex_prog_ai.key, &mango_group.dex_program_id, MangoErrorCode::InvalidProgramId)?;
+        check!(signer_ai.key == &mango_group.signer_key, MangoErrorCode::InvalidSignerKey)?;
+
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+        let mut liqee_ma =
+            MangoAccount::load_mut_checked(liqee_mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(!liqee_ma.is_bankrupt, MangoErrorCode::Bankrupt)?;
+        liqee_ma.check_open_orders(&mango_group, liqee_open_orders_ais)?;
+
+        let market_index = mango_group.find_spot_market_index(spot_market_ai.key).unwrap();
+        check!(liqee_ma.in_margin_basket[market_index], MangoErrorCode::Default)?;
+
+        check_eq!(
+            &mango_group.tokens[market_index].root_bank,
+            base_root_bank_ai.key,
+            MangoErrorCode::InvalidRootBank
+        )?;
+        let base_root_bank = RootBank::load_checked(base_root_bank_ai, program_id)?;
+
+        check!(
+            base_root_bank.node_banks.contains(base_node_bank_ai.key),
+            MangoErrorCode::InvalidNodeBank
+        )?;
+        let mut base_node_bank = NodeBank::load_mut_checked(base_node_bank_ai, program_id)?;
+        check_eq!(&base_node_bank.vault, base_vault_ai.key, MangoErrorCode::InvalidVault)?;
+
+        check_eq!(
+            &mango_group.tokens[QUOTE_INDEX].root_bank,
+            quote_root_bank_ai.key,
+            MangoErrorCode::InvalidRootBank
+        )?;
+        let quote_root_bank = RootBank::load_checked(quote_root_bank_ai, program_id)?;
+
+        check!(
+            quote_root_bank.node_banks.contains(quote_node_bank_ai.key),
+            MangoErrorCode::InvalidNodeBank
+        )?;
+        let mut quote_node_bank = NodeBank::load_mut_checked(quote_node_bank_ai, program_id)?;
+        check_eq!(&quote_node_bank.vault, quote_vault_ai.key, MangoErrorCode::InvalidVault)?;
+
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+
+        let liqee_active_assets = UserActiveAssets::new(&mango_group, &liqee_ma, vec![]);
+
+        mango_cache.check_valid(&mango_group, &liqee_active_assets, now_ts)?;
+        mango_cache.check_not_halted(&mango_group, &liqee_active_assets)?;
+
+        let mut health_cache = HealthCache::new_for_liquidation(liqee_active_assets);
+        health_cache.init_vals(&mango_group, &mango_cache, &liqee_ma, liqee_open_orders_ais)?;
+        let init_health = health_cache.get_health(&mango_group, HealthType::Init);
+        let maint_health = health_cache.get_health(&mango_group, HealthType::Maint);
+
+        // Can only force cancel on an account already being liquidated
+        if liqee_ma.being_liquidated {
+            if init_health > ZERO_I80F48 {
+                liqee_ma.being_liquidated = false;
+                msg!("Account init_health above zero.");
+                return Ok(());
+            }
+        } else if maint_health >= ZERO_I80F48 {
+            return Err(throw_err!(MangoErrorCode::NotLiquidatable));
+        } else {
+            liqee_ma.being_liquidated = true;
+        }
+
+        // Cancel orders up to the limit
+        let open_orders_ai = &liqee_open_orders_ais[market_index];
+        let signers_seeds = gen_signer_seeds(&mango_group.signer_nonce, mango_group_ai.key);
+        invoke_cancel_orders(
+            open_orders_ai,
+            dex_prog_ai,
+            spot_market_ai,
+            bids_ai,
+            asks_ai,
+            signer_ai,
+            dex_event_queue_ai,
+            &[&signers_seeds],
+            limit,
+        )?;
+
+        let (pre_base, pre_quote) = {
+            let open_orders = load_open_orders(open_orders_ai)?;
+            (
+                open_orders.native_coin_free,
+                open_orders.native_pc_free + open_orders.referrer_rebates_accrued,
+            )
+        };
+
+        if pre_base == 0 && pre_quote == 0 {
+            // margin basket may be in an invalid state; correct it before returning
+            let open_orders = load_open_orders(open_orders_ai)?;
+            liqee_ma.update_basket(market_index, &open_orders)?;
+            return Ok(());
+        }
+
+        // Settle funds released by canceling open orders
+        // TODO OPT add a new ForceSettleFunds to save compute in this instruction
+        invoke_settle_funds(
+            dex_prog_ai,
+            spot_market_ai,
+            open_orders_ai,
+            signer_ai,
+            dex_base_ai,
+            dex_quote_ai,
+            base_vault_ai,
+            quote_vault_ai,
+            dex_signer_ai,
+            token_prog_ai,
+            &[&signers_seeds],
+        )?;
+
+        let (post_base, post_quote) = {
+            let open_orders = load_open_orders(open_orders_ai)?;
+            liqee_ma.update_basket(market_index, &open_orders)?;
+            mango_emit_stack::<_, 256>(OpenOrdersBalanceLog {
+                mango_group: *mango_group_ai.key,
+                mango_account: *liqee_mango_account_ai.key,
+                market_index: market_index as u64,
+                base_total: open_orders.native_coin_total,
+                base_free: open_orders.native_coin_free,
+                quote_total: open_orders.native_pc_total,
+                quote_free: open_orders.native_pc_free,
+                referrer_rebates_accrued: open_orders.referrer_rebates_accrued,
+            });
+
+            (
+                open_orders.native_coin_free,
+                open_orders.native_pc_free + open_orders.referrer_rebates_accrued,
+            )
+        };
+
+        check!(post_base <= pre_base, MangoErrorCode::Default)?;
+        check!(post_quote <= pre_quote, MangoErrorCode::Default)?;
+
+        // Update balances from settling funds
+        let base_change = I80F48::from_num(pre_base - post_base);
+        let quote_change = I80F48::from_num(pre_quote - post_quote);
+
+        checked_change_net(
+            &mango_cache.root_bank_cache[market_index],
+            &mut base_node_bank,
+            &mut liqee_ma,
+            liqee_mango_account_ai.key,
+            market_index,
+            base_change,
+        )?;
+        checked_change_net(
+            &mango_cache.root_bank_cache[QUOTE_INDEX],
+            &mut quote_node_bank,
+            &mut liqee_ma,
+            liqee_mango_account_ai.key,
+            QUOTE_INDEX,
+            quote_change,
+        )
+    }
+
+    #[inline(never)]
+    fn force_cancel_perp_orders(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        limit: u8,
+    ) -> MangoResult<()> {
+        const NUM_FIXED: usize = 6;
+        let (fixed_ais, liqee_open_orders_ais, iceberg_orders_ais) =
+            array_refs![accounts, NUM_FIXED, MAX_PAIRS; ..;];
+
+        let [
+            mango_group_ai,         // read
+            mango_cache_ai,         // read
+            perp_market_ai,         // read
+            bids_ai,                // write
+            asks_ai,                // write
+            liqee_mango_account_ai, // write
+        ] = fixed_ais;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+
+        let mut liqee_ma =
+            MangoAccount::load_mut_checked(liqee_mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(!liqee_ma.is_bankrupt, MangoErrorCode::Bankrupt)?;
+        liqee_ma.check_open_orders(&mango_group, liqee_open_orders_ais)?;
+
+        let perp_market = PerpMarket::load_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+        let market_index = mango_group.find_perp_market_index(perp_market_ai.key).unwrap();
+        let perp_market_info = &mango_group.perp_markets[market_index];
+        check!(!perp_market_info.is_empty(), MangoErrorCode::InvalidMarket)?;
+
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+
+        let liqee_active_assets = UserActiveAssets::new(&mango_group, &liqee_ma, vec![]);
+
+        mango_cache.check_valid(&mango_group, &liqee_active_assets, now_ts)?;
+        mango_cache.check_not_halted(&mango_group, &liqee_active_assets)?;
+
+        let mut health_cache = HealthCache::new_for_liquidation(liqee_active_assets);
+        health_cache.init_vals(&mango_group, &mango_cache, &liqee_ma, liqee_open_orders_ais)?;
+        let init_health = health_cache.get_health(&mango_group, HealthType::Init);
+        let maint_health = health_cache.get_health(&mango_group, HealthType::Maint);
+
+        if liqee_ma.being_liquidated {
+            if init_health > ZERO_I80F48 {
+                liqee_ma.being_liquidated = false;
+                msg!("Account init_health above zero.");
+                return Ok(());
+            }
+        } else if maint_health >= ZERO_I80F48 {
+            msg!(
+                "maint health {} init health {}",
+                maint_health.to_num::<f64>(),
+                init_health.to_num::<f64>()
+            );
+            return Err(throw_err!(MangoErrorCode::NotLiquidatable));
+        } else {
+            liqee_ma.being_liquidated = true;
+        }
+
+        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+        let orders_before = liqee_ma.orders;
+        book.cancel_all(&mut liqee_ma, market_index, limit)?;
+        let canceled_order_ids = liqee_ma.removed_order_ids(&orders_before);
+        cancel_iceberg_orders(
+            program_id,
+            iceberg_orders_ais.first(),
+            &mut liqee_ma,
+            liqee_mango_account_ai.key,
+            |o| o.market_index as usize == market_index && canceled_order_ids.contains(&o.order_id),
+        )
+    }
+
+    #[inline(never)]
+    /// Liquidator takes some of borrows at token at `liab_index` and receives some deposits from
+    /// the token at `asset_index`
+    /// Requires: `liab_index != asset_index`
+    fn liquidate_token_and_token(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        max_liab_transfer: I80F48,
+    ) -> MangoResult<()> {
+        // parameter checks
+        check!(max_liab_transfer.is_positive(), MangoErrorCode::InvalidParam)?;
+
+        const NUM_FIXED: usize = 9;
+        let accounts = array_ref![accounts, 0, NUM_FIXED + 2 * MAX_PAIRS];
+        let (fixed_ais, liqee_open_orders_ais, liqor_open_orders_ais) =
+            array_refs![accounts, NUM_FIXED, MAX_PAIRS, MAX_PAIRS];
+
+        let [
+            mango_group_ai,         // read
+            mango_cache_ai,         // read
+            liqee_mango_account_ai, // write
+            liqor_mango_account_ai, // write
+            liqor_ai,               // read, signer
+            asset_root_bank_ai,     // read
+            asset_node_bank_ai,     // write
+            liab_root_bank_ai,      // read
+            liab_node_bank_ai,      // write
+        ] = fixed_ais;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+        let mut liqee_ma =
+            MangoAccount::load_mut_checked(liqee_mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(!liqee_ma.is_bankrupt, MangoErrorCode::Bankrupt)?;
+        liqee_ma.check_open_orders(&mango_group, liqee_open_orders_ais)?;
+
+        let mut liqor_ma =
+            MangoAccount::load_mut_checked(liqor_mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(
+            &liqor_ma.owner == liqor_ai.key || &liqor_ma.delegate == liqor_ai.key,
+            MangoErrorCode::InvalidOwner
+        )?;
+        check!(liqor_ai.is_signer, MangoErrorCode::InvalidSignerKey)?;
+        check!(!liqor_ma.is_bankrupt, MangoErrorCode::Bankrupt)?;
+        liqor_ma.check_open_orders(&mango_group, liqor_open_orders_ais)?;
+
+        let asset_root_bank = RootBank::load_checked(asset_root_bank_ai, program_id)?;
+        let asset_index = mango_group.find_root_bank_index(asset_root_bank_ai.key).unwrap();
+        let mut asset_node_bank = NodeBank::load_mut_checked(asset_node_bank_ai, program_id)?;
+        check!(
+            asset_root_bank.node_banks.contains(asset_node_bank_ai.key),
+            MangoErrorCode::InvalidNodeBank
+        )?;
+
+        let liab_root_bank = RootBank::load_checked(liab_root_bank_ai, program_id)?;
+        let liab_index = mango_group.find_root_bank_index(liab_root_bank_ai.key).unwrap();
+        let mut liab_node_bank = NodeBank::load_mut_checked(liab_node_bank_ai, program_id)?;
+        check!(liab_root_bank.node_banks.contains(liab_node_bank_ai.key), MangoErrorCode::Default)?;
+        check!(asset_index != liab_index, MangoErrorCode::InvalidParam)?;
 
         let now_ts = Clock::get()?.unix_timestamp as u64;
         let liqee_active_assets = UserActiveAssets::new(&mango_group, &liqee_ma, vec![]);
@@ -3551,6 +4598,7 @@ impl Processor {
             &UserActiveAssets::merge(&liqee_active_assets, &liqor_active_assets),
             now_ts,
         )?;
+        mango_cache.check_not_halted(&mango_group, &liqee_active_assets)?;
 
         // Make sure orders are cancelled for perps and check orders
         for i in 0..mango_group.num_oracles {
@@ -3559,7 +4607,7 @@ impl Processor {
             }
         }
 
-        let mut health_cache = HealthCache::new(liqee_active_assets);
+        let mut health_cache = HealthCache::new_for_liquidation(liqee_active_assets);
         health_cache.init_vals(&mango_group, &mango_cache, &liqee_ma, liqee_open_orders_ais)?;
         let init_health = health_cache.get_health(&mango_group, HealthType::Init);
         let maint_health = health_cache.get_health(&mango_group, HealthType::Maint);
@@ -3769,6 +4817,7 @@ impl Processor {
             &UserActiveAssets::merge(&liqee_active_assets, &liqor_active_assets),
             now_ts,
         )?;
+        mango_cache.check_not_halted(&mango_group, &liqee_active_assets)?;
 
         // Make sure orders are cancelled for perps and check orders
         for i in 0..mango_group.num_oracles {
@@ -3777,7 +4826,7 @@ impl Processor {
             }
         }
 
-        let mut health_cache = HealthCache::new(liqee_active_assets);
+        let mut health_cache = HealthCache::new_for_liquidation(liqee_active_assets);
         health_cache.init_vals(&mango_group, &mango_cache, &liqee_ma, liqee_open_orders_ais)?;
         let init_health = health_cache.get_health(&mango_group, HealthType::Init);
         let maint_health = health_cache.get_health(&mango_group, HealthType::Maint);
@@ -4091,6 +5140,7 @@ impl Processor {
             &UserActiveAssets::merge(&liqee_active_assets, &liqor_active_assets),
             now_ts,
         )?;
+        mango_cache.check_not_halted(&mango_group, &liqee_active_assets)?;
         liqee_ma.perp_accounts[market_index].settle_funding(cache);
         liqor_ma.perp_accounts[market_index].settle_funding(cache);
 
@@ -4101,7 +5151,7 @@ impl Processor {
             }
         }
 
-        let mut health_cache = HealthCache::new(liqee_active_assets);
+        let mut health_cache = HealthCache::new_for_liquidation(liqee_active_assets);
         health_cache.init_vals(&mango_group, &mango_cache, &liqee_ma, liqee_open_orders_ais)?;
         let init_health = health_cache.get_health(&mango_group, HealthType::Init);
         let maint_health = health_cache.get_health(&mango_group, HealthType::Maint);
@@ -4124,13 +5174,15 @@ impl Processor {
         let liqee_perp_account = &mut liqee_ma.perp_accounts[market_index];
         let liqor_perp_account = &mut liqor_ma.perp_accounts[market_index];
 
-        let price = mango_cache.price_cache[market_index].price;
+        // Transfer at the price the liqee's health was computed with
+        let price = health_cache.get_perp_price(&mango_group, &mango_cache, market_index);
         let lot_price = price * I80F48::from_num(pmi.base_lot_size);
         let (base_transfer, quote_transfer) = if liqee_perp_account.base_position > 0 {
             check!(base_transfer_request > 0, MangoErrorCode::InvalidParam)?;
 
-            let health_per_lot =
-                lot_price * (ONE_I80F48 - pmi.init_asset_weight - pmi.liquidation_fee);
+            // Weighted like health so a shocked market frees up the right amount per lot
+            let health_per_lot = lot_price * (ONE_I80F48 - pmi.liquidation_fee)
+                - pmi.get_weighted_base_val(lot_price, HealthType::Init);
             let max_transfer = -init_health / health_per_lot;
             let max_transfer: i64 = max_transfer.checked_ceil().unwrap().checked_to_num().unwrap();
 
@@ -4146,8 +5198,8 @@ impl Processor {
             // We know it liqee_perp_account.base_position < 0
             check!(base_transfer_request < 0, MangoErrorCode::InvalidParam)?;
 
-            let health_per_lot =
-                lot_price * (ONE_I80F48 - pmi.init_liab_weight + pmi.liquidation_fee);
+            let health_per_lot = lot_price * (ONE_I80F48 + pmi.liquidation_fee)
+                + pmi.get_weighted_base_val(-lot_price, HealthType::Init);
             let max_transfer = -init_health / health_per_lot;
             let max_transfer: i64 = max_transfer.checked_floor().unwrap().checked_to_num().unwrap();
 
@@ -4160,6 +5212,11 @@ impl Processor {
             (base_transfer, quote_transfer)
         };
 
+        // The liqee's position shrinks by as much as the liqor's may grow, so open interest
+        // doesn't increase; only the liqor's position needs checking
+        let liqor_base = liqor_perp_account.base_position;
+        perp_market.check_max_position(liqor_base, liqor_base + base_transfer)?;
+
         liqee_perp_account.change_base_position(&mut perp_market, -base_transfer);
         liqor_perp_account.change_base_position(&mut perp_market, base_transfer);
 
@@ -4228,6 +5285,172 @@ impl Processor {
         Ok(())
     }
 
+    #[inline(never)]
+    /// Close the perp position of an account with negative equity against profitable accounts
+    /// on the other side at the liqee's bankruptcy price, when the insurance fund can't cover
+    /// the loss on this market. The ranking is computed off chain, so only the admin may run it.
+    fn auto_deleverage(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult<()> {
+        const NUM_FIXED: usize = 6;
+        let (fixed_ais, liqee_open_orders_ais, counterparty_ais) =
+            array_refs![accounts, NUM_FIXED, MAX_PAIRS; ..;];
+
+        let [
+            mango_group_ai,         // read
+            mango_cache_ai,         // read
+            perp_market_ai,         // write
+            insurance_vault_ai,     // read
+            liqee_mango_account_ai, // write
+            admin_ai,               // read, signer
+        ] = fixed_ais;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+
+        check!(
+            insurance_vault_ai.key == &mango_group.insurance_vault,
+            MangoErrorCode::InvalidVault
+        )?;
+        let insurance_vault = Account::unpack(&insurance_vault_ai.try_borrow_data()?)?;
+
+        let mut liqee_ma =
+            MangoAccount::load_mut_checked(liqee_mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(!liqee_ma.is_bankrupt, MangoErrorCode::Bankrupt)?;
+        liqee_ma.check_open_orders(&mango_group, liqee_open_orders_ais)?;
+
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+        let market_index = mango_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
+        let pmi = &mango_group.perp_markets[market_index];
+        let cache = &mango_cache.perp_market_cache[market_index];
+
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let liqee_active_assets = UserActiveAssets::new(&mango_group, &liqee_ma, vec![]);
+        mango_cache.check_valid(&mango_group, &liqee_active_assets, now_ts)?;
+        mango_cache.check_not_halted(&mango_group, &liqee_active_assets)?;
+        liqee_ma.perp_accounts[market_index].settle_funding(cache);
+
+        // Make sure orders are cancelled for perps before deleveraging
+        for i in 0..mango_group.num_oracles {
+            if liqee_active_assets.perps[i] {
+                check!(liqee_ma.perp_accounts[i].has_no_open_orders(), MangoErrorCode::Default)?;
+            }
+        }
+
+        let mut health_cache = HealthCache::new(liqee_active_assets);
+        health_cache.init_vals(&mango_group, &mango_cache, &liqee_ma, liqee_open_orders_ais)?;
+        let equity = health_cache.get_health(&mango_group, HealthType::Equity);
+        check!(equity.is_negative(), MangoErrorCode::NotLiquidatable)?;
+        liqee_ma.being_liquidated = true;
+
+        let liqee_base = liqee_ma.perp_accounts[market_index].base_position;
+        check!(liqee_base != 0, MangoErrorCode::InvalidParam)?;
+        let price = pmi.settled_or(mango_cache.price_cache[market_index].price);
+
+        // Counterparties only make up what was lost on this market, and only when the
+        // insurance fund can't
+        let liqee_pnl =
+            liqee_ma.perp_accounts[market_index].unrealized_pnl(pmi.base_lot_size, price);
+        let loss = (-equity).min(-liqee_pnl);
+        check!(loss.is_positive(), MangoErrorCode::InvalidParam)?;
+        check!(
+            I80F48::from_num(insurance_vault.amount) < loss,
+            MangoErrorCode::InsuranceVaultNotEmpty
+        )?;
+        let bankruptcy_price =
+            liqee_ma.perp_accounts[market_index].bankruptcy_price(pmi.base_lot_size, price, loss);
+        // What a counterparty gives up per lot compared to closing at the oracle price
+        let lot_loss = (price - bankruptcy_price).abs() * I80F48::from_num(pmi.base_lot_size);
+
+        let mut remaining = liqee_base.abs();
+        let mut prev_score: Option<I80F48> = None;
+        let mut counterparty_ais = counterparty_ais;
+        while remaining != 0 && !counterparty_ais.is_empty() {
+            let (counterparty_ai, mut counterparty_ma, _) = load_adl_counterparty(
+                program_id,
+                &mango_group,
+                mango_group_ai.key,
+                &mango_cache,
+                market_index,
+                &[liqee_mango_account_ai.key],
+                &mut counterparty_ais,
+                &mut prev_score,
+                now_ts,
+            )?;
+
+            // Only positions on the other side
+            let counterparty_pa = &mut counterparty_ma.perp_accounts[market_index];
+            check!(
+                counterparty_pa.base_position.signum() == -liqee_base.signum(),
+                MangoErrorCode::InvalidAdlCounterparty
+            )?;
+
+            // The counterparty gives up at most its unrealized pnl
+            let pnl = counterparty_pa.unrealized_pnl(pmi.base_lot_size, price);
+            let max_lots = if lot_loss.is_zero() {
+                remaining
+            } else {
+                pnl.checked_div(lot_loss)
+                    .and_then(|lots| lots.checked_floor())
+                    .and_then(|lots| lots.checked_to_num::<i64>())
+                    .unwrap_or(i64::MAX)
+            };
+            let lots = remaining.min(counterparty_pa.base_position.abs()).min(max_lots);
+            if lots == 0 {
+                continue;
+            }
+
+            let base_transfer = -liqee_base.signum() * lots;
+            let quote_transfer =
+                I80F48::from_num(-base_transfer * pmi.base_lot_size) * bankruptcy_price;
+            let liqee_pa = &mut liqee_ma.perp_accounts[market_index];
+            liqee_pa.change_base_position(&mut perp_market, base_transfer);
+            counterparty_pa.change_base_position(&mut perp_market, -base_transfer);
+            counterparty_pa.transfer_quote_position(liqee_pa, quote_transfer);
+            remaining -= lots;
+
+            mango_emit_heap!(AutoDeleverageLog {
+                mango_group: *mango_group_ai.key,
+                liqee: *liqee_mango_account_ai.key,
+                counterparty: *counterparty_ai.key,
+                market_index: market_index as u64,
+                bankruptcy_price: bankruptcy_price.to_bits(),
+                base_transfer,
+                quote_transfer: quote_transfer.to_bits()
+            });
+            emit_perp_balances(
+                *mango_group_ai.key,
+                *counterparty_ai.key,
+                market_index as u64,
+                &counterparty_ma.perp_accounts[market_index],
+                cache,
+            );
+        }
+
+        health_cache.update_perp_val(&mango_group, &mango_cache, &liqee_ma, market_index)?;
+        let liqee_maint_health = health_cache.get_health(&mango_group, HealthType::Maint);
+        if liqee_maint_health < ZERO_I80F48 {
+            liqee_ma.is_bankrupt =
+                liqee_ma.check_enter_bankruptcy(&mango_group, liqee_open_orders_ais);
+        } else {
+            let liqee_init_health = health_cache.get_health(&mango_group, HealthType::Init);
+            liqee_ma.being_liquidated = liqee_init_health < NEG_ONE_I80F48;
+        }
+
+        emit_perp_balances(
+            *mango_group_ai.key,
+            *liqee_mango_account_ai.key,
+            market_index as u64,
+            &liqee_ma.perp_accounts[market_index],
+            cache,
+        );
+
+        Ok(())
+    }
+
     #[inline(never)]
     /// Claim insurance fund and then socialize loss
     fn resolve_perp_bankruptcy(
@@ -4239,7 +5462,8 @@ impl Processor {
         // First check the account is bankrupt
         // Determine the value of the liab transfer
         // Check if insurance fund has enough (given the fees)
-        // If insurance fund does not have enough, start the socialize loss function
+        // If insurance fund does not have enough, deleverage profitable accounts if the admin
+        // picked them in ADL queue order, then start the socialize loss function
 
         // TODO - since liquidation fee is 0 for USDC, what's the incentive for someone to call this?
         //  just add 1bp fee
@@ -4249,8 +5473,8 @@ impl Processor {
         check!(max_liab_transfer.is_positive(), MangoErrorCode::InvalidParam)?;
 
         const NUM_FIXED: usize = 12;
-        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
-        let (fixed_ais, liqor_open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
+        let (fixed_ais, liqor_open_orders_ais, adl_ais) =
+            array_refs![accounts, NUM_FIXED, MAX_PAIRS; ..;];
 
         let [
             mango_group_ai,         // read
@@ -4362,7 +5586,7 @@ impl Processor {
         // and if quote position is still negative
         let socialized_loss =
             if liab_transfer_u64 == insurance_vault.amount && quote_position.is_negative() {
-                // insurance fund empty so socialize loss
+                // insurance fund empty so deleverage, then socialize loss
                 check!(
                     &mango_group.perp_markets[liab_index].perp_market == perp_market_ai.key,
                     MangoErrorCode::InvalidMarket
@@ -4370,6 +5594,34 @@ impl Processor {
                 let mut perp_market =
                     PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
 
+                // The ADL queue is ranked off chain, so only the admin may pick counterparties.
+                // Without the admin's signature the whole loss is socialized.
+                let counterparty_ais: &[AccountInfo] = match adl_ais.split_first() {
+                    Some((admin_ai, counterparty_ais))
+                        if admin_ai.is_signer && admin_ai.key == &mango_group.admin =>
+                    {
+                        counterparty_ais
+                    }
+                    _ => &[],
+                };
+                if !counterparty_ais.is_empty() {
+                    deleverage_bankrupt_account(
+                        program_id,
+                        &mango_group,
+                        mango_group_ai.key,
+                        &mango_cache,
+                        &mut perp_market,
+                        liab_index,
+                        &mut liqee_ma,
+                        liqee_mango_account_ai.key,
+                        &mut liqor_ma,
+                        liqor_mango_account_ai.key,
+                        liqor_open_orders_ais,
+                        counterparty_ais,
+                        now_ts,
+                    )?;
+                }
+
                 perp_market.socialize_loss(
                     &mut liqee_ma.perp_accounts[liab_index],
                     &mut mango_cache.perp_market_cache[liab_index],
@@ -4706,6 +5958,8 @@ impl Processor {
 
         perp_market_cache.check_valid(&mango_group, now_ts)?;
 
+        // Makers whose iceberg order slice may have left the book
+        let mut iceberg_makers: Vec<Pubkey> = vec![];
         for _ in 0..limit {
             let event = match event_queue.peek_front() {
                 None => break,
@@ -4733,6 +5987,12 @@ impl Processor {
                         let pre_mngo = ma.perp_accounts[market_index].mngo_accrued;
                         ma.execute_maker(market_index, &mut perp_market, perp_market_cache, fill)?;
                         ma.execute_taker(market_index, &mut perp_market, perp_market_cache, fill)?;
+                        if fill.maker_out
+                            && ma.has_iceberg_orders
+                            && !iceberg_makers.contains(&fill.maker)
+                        {
+                            iceberg_makers.push(fill.maker);
+                        }
                         mango_emit_stack::<_, 512>(MngoAccrualLog {
                             mango_group: *mango_group_ai.key,
                             mango_account: fill.maker,
@@ -4785,6 +6045,12 @@ impl Processor {
                             perp_market_cache,
                             fill,
                         )?;
+                        if fill.maker_out
+                            && maker.has_iceberg_orders
+                            && !iceberg_makers.contains(&fill.maker)
+                        {
+                            iceberg_makers.push(fill.maker);
+                        }
                         mango_emit_stack::<_, 512>(MngoAccrualLog {
                             mango_group: *mango_group_ai.key,
                             mango_account: fill.maker,
@@ -4824,6 +6090,9 @@ impl Processor {
                     };
 
                     ma.remove_order(out.slot as usize, out.quantity)?;
+                    if ma.has_iceberg_orders && !iceberg_makers.contains(&out.owner) {
+                        iceberg_makers.push(out.owner);
+                    }
                 }
                 EventType::Liquidate => {
                     // This is purely for record keeping. Can be removed if program logs are superior
@@ -4833,6 +6102,59 @@ impl Processor {
             // consume this event
             event_queue.pop_front().map_err(|_| throw!())?;
         }
+
+        // Post the next slice of the makers' iceberg orders. This needs the bids, asks and
+        // each maker's IcebergOrders PDA; if any is missing the slice waits for a later
+        // ConsumeEvents or RefillIcebergOrders.
+        if iceberg_makers.is_empty() {
+            return Ok(());
+        }
+        let price_cache = &mango_cache.price_cache[market_index];
+        if price_cache.check_valid(&mango_group, market_index, now_ts).is_err()
+            || price_cache.is_halted()
+        {
+            return Ok(());
+        }
+        let find_ai = |pk: &Pubkey| mango_account_ais.iter().find(|ai| ai.key == pk);
+        let (bids_ai, asks_ai) = match (find_ai(&perp_market.bids), find_ai(&perp_market.asks)) {
+            (Some(bids_ai), Some(asks_ai)) => (bids_ai, asks_ai),
+            _ => return Ok(()),
+        };
+        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+        for maker_pk in iceberg_makers.iter() {
+            let mut iceberg_orders = match mango_account_ais
+                .iter()
+                .filter(|ai| ai.owner == program_id && ai.data_len() == size_of::<IcebergOrders>())
+                .find_map(|ai| IcebergOrders::load_mut_checked(ai, program_id, maker_pk).ok())
+            {
+                None => continue,
+                Some(iceberg_orders) => iceberg_orders,
+            };
+            let mut maker = MangoAccount::load_mut_checked(
+                find_ai(maker_pk).unwrap(),
+                program_id,
+                mango_group_ai.key,
+            )?;
+            if maker.being_liquidated || maker.is_bankrupt {
+                continue;
+            }
+            post_iceberg_slices(
+                program_id,
+                &mango_group,
+                mango_group_ai.key,
+                &mango_cache,
+                &mut book,
+                &mut event_queue,
+                &mut perp_market,
+                &mut maker,
+                maker_pk,
+                &mut iceberg_orders,
+                market_index,
+                true,
+                now_ts,
+                u8::MAX,
+            )?;
+        }
         Ok(())
     }
 
@@ -4878,6 +6200,7 @@ impl Processor {
             market_index: market_index as u64,
             long_funding: perp_market.long_funding.to_bits(),
             short_funding: perp_market.short_funding.to_bits(),
+            funding_period: perp_market.funding_period,
         });
 
         Ok(())
@@ -5550,6 +6873,8 @@ impl Processor {
                     quantity,
                     i64::MAX, // no limit on quote quantity
                     order.order_type,
+                    SelfTradeBehavior::AllowSelfTrade,
+                    0,
                     0,
                     order.client_order_id,
                     now_ts,
@@ -5557,743 +6882,1267 @@ impl Processor {
                     u8::MAX,
                 )?;
 
-                // TODO OPT - unnecessary, remove after testing
-                health_cache.update_perp_val(
-                    &mango_group,
-                    &mango_cache,
-                    &mango_account,
-                    market_index,
-                )?;
-                let post_health = health_cache.get_health(&mango_group, HealthType::Init);
-                let pa = &mango_account.perp_accounts[market_index];
-                check!(
-                    sim_post_health == post_health
-                        && taker_base == pa.taker_base
-                        && taker_quote == pa.taker_quote
-                        && bids_quantity == pa.bids_quantity
-                        && asks_quantity == pa.asks_quantity,
-                    MangoErrorCode::MathError
+                // TODO OPT - unnecessary, remove after testing
+                health_cache.update_perp_val(
+                    &mango_group,
+                    &mango_cache,
+                    &mango_account,
+                    market_index,
+                )?;
+                let post_health = health_cache.get_health(&mango_group, HealthType::Init);
+                let pa = &mango_account.perp_accounts[market_index];
+                check!(
+                    sim_post_health == post_health
+                        && taker_base == pa.taker_base
+                        && taker_quote == pa.taker_quote
+                        && bids_quantity == pa.bids_quantity
+                        && asks_quantity == pa.asks_quantity,
+                    MangoErrorCode::MathError
+                )?;
+            } else {
+                // normally this would be an InsufficientFunds error but we want to remove the AO and persist changes
+                msg!("Failed to place perp order due to insufficient funds")
+            }
+        }
+
+        order.is_active = false;
+        program_transfer_lamports(advanced_orders_ai, agent_ai, ADVANCED_ORDER_FEE)
+    }
+
+    /// Create a MangoAccount PDA and initialize it
+    #[inline(never)]
+    fn create_mango_account(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        account_num: u64,
+    ) -> MangoResult {
+        const NUM_FIXED: usize = 4;
+        let fixed_accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,         // write
+            mango_account_ai,       // write
+            owner_ai,               // read (write if no payer passed) & signer
+            system_prog_ai,         // read
+        ] = fixed_accounts;
+        let payer_ai = if accounts.len() > NUM_FIXED {
+            &accounts[NUM_FIXED] // write & signer
+        } else {
+            owner_ai
+        };
+        check!(
+            system_prog_ai.key == &solana_program::system_program::id(),
+            MangoErrorCode::InvalidProgramId
+        )?;
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check!(payer_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+
+        let mut mango_group = MangoGroup::load_mut_checked(mango_group_ai, program_id)?;
+        check!(
+            mango_group.num_mango_accounts < mango_group.max_mango_accounts,
+            MangoErrorCode::MaxAccountsReached
+        )?;
+        let rent = Rent::get()?;
+
+        let mango_account_seeds: &[&[u8]] =
+            &[&mango_group_ai.key.as_ref(), &owner_ai.key.as_ref(), &account_num.to_le_bytes()];
+        seed_and_create_pda(
+            program_id,
+            payer_ai,
+            &rent,
+            size_of::<MangoAccount>(),
+            program_id,
+            system_prog_ai,
+            mango_account_ai,
+            mango_account_seeds,
+            &[],
+        )?;
+        let mut mango_account: RefMut<MangoAccount> = MangoAccount::load_mut(mango_account_ai)?;
+        check!(!mango_account.meta_data.is_initialized, MangoErrorCode::InvalidAccountState)?;
+
+        mango_account.mango_group = *mango_group_ai.key;
+        mango_account.owner = *owner_ai.key;
+        mango_account.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
+        mango_account.meta_data = MetaData::new(DataType::MangoAccount, 1, true);
+
+        mango_group.num_mango_accounts += 1;
+
+        mango_emit_heap!(CreateMangoAccountLog {
+            mango_group: *mango_group_ai.key,
+            mango_account: *mango_account_ai.key,
+            owner: *owner_ai.key
+        });
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn update_margin_basket(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult {
+        const NUM_FIXED: usize = 2;
+        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
+        let (fixed_ais, open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
+
+        let [
+            mango_group_ai,         // read
+            mango_account_ai,       // write
+        ] = fixed_ais;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        let mut mango_account =
+            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+
+        for i in 0..mango_group.num_oracles {
+            check_eq!(
+                open_orders_ais[i].key,
+                &mango_account.spot_open_orders[i],
+                MangoErrorCode::InvalidOpenOrdersAccount
+            )?;
+
+            if mango_account.spot_open_orders[i] != Pubkey::default() {
+                check_open_orders(
+                    &open_orders_ais[i],
+                    &mango_group.signer_key,
+                    &mango_group.dex_program_id,
                 )?;
-            } else {
-                // normally this would be an InsufficientFunds error but we want to remove the AO and persist changes
-                msg!("Failed to place perp order due to insufficient funds")
+                let open_orders = load_open_orders(&open_orders_ais[i])?;
+                mango_account.update_basket(i, &open_orders)?;
             }
         }
 
-        order.is_active = false;
-        program_transfer_lamports(advanced_orders_ai, agent_ai, ADVANCED_ORDER_FEE)
+        Ok(())
+    }
+    #[inline(never)]
+    /// Change the maximum number of MangoAccounts.v1 allowed
+    fn change_max_mango_accounts(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        max_mango_accounts: u32,
+    ) -> MangoResult {
+        const NUM_FIXED: usize = 2;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai, // write
+            admin_ai        // read, signer
+        ] = accounts;
+
+        let mut mango_group = MangoGroup::load_mut_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+
+        mango_group.max_mango_accounts = max_mango_accounts;
+        Ok(())
     }
 
-    /// Create a MangoAccount PDA and initialize it
     #[inline(never)]
-    fn create_mango_account(
+    /// Change the maximum number of MangoAccounts.v1 allowed
+    fn change_dont_square(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        account_num: u64,
+        dont_square: bool,
     ) -> MangoResult {
+        const NUM_FIXED: usize = 2;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai, // write
+            admin_ai        // read, signer
+        ] = accounts;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+
+        msg!(
+            "DontSquare({}) is deprecated; set the index params with ChangePerpMarketParams2",
+            dont_square
+        );
+        Err(throw_err!(MangoErrorCode::InvalidParam))
+    }
+
+    #[inline(never)]
+    /// Grow a MangoGroup created before PerpMarketInfo had the index, risk and price band params
+    /// and move its data into the current layout
+    fn upgrade_mango_group(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai, // write
+            admin_ai,       // write, signer
+            system_prog_ai, // read
+        ] = accounts;
+        check_eq!(mango_group_ai.owner, program_id, MangoErrorCode::InvalidOwner)?;
+        check_eq!(mango_group_ai.data_len(), MangoGroup::V0_SIZE, MangoErrorCode::InvalidAccount)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(
+            system_prog_ai.key,
+            &solana_program::system_program::id(),
+            MangoErrorCode::InvalidProgramId
+        )?;
+
+        let rent = Rent::get()?;
+        resize_account(admin_ai, &rent, size_of::<MangoGroup>(), system_prog_ai, mango_group_ai)?;
+        MangoGroup::upgrade_v0_layout(&mut mango_group_ai.try_borrow_mut_data()?)?;
+
+        // The admin is only readable once the data is in the new layout; failing reverts it all
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Grow a PerpMarket created before the funding, premium, expiry and limit fields were added
+    /// and set them so funding keeps working as before
+    fn upgrade_perp_market(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult {
         const NUM_FIXED: usize = 4;
-        let fixed_accounts = array_ref![accounts, 0, NUM_FIXED];
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
         let [
-            mango_group_ai,         // write
+            mango_group_ai, // read
+            perp_market_ai, // write
+            admin_ai,       // write, signer
+            system_prog_ai, // read
+        ] = accounts;
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+        check_eq!(
+            system_prog_ai.key,
+            &solana_program::system_program::id(),
+            MangoErrorCode::InvalidProgramId
+        )?;
+        let market_index = mango_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
+
+        check_eq!(perp_market_ai.owner, program_id, MangoErrorCode::InvalidOwner)?;
+        check_eq!(perp_market_ai.data_len(), PerpMarket::V0_SIZE, MangoErrorCode::InvalidAccount)?;
+        let rent = Rent::get()?;
+        resize_account(admin_ai, &rent, size_of::<PerpMarket>(), system_prog_ai, perp_market_ai)?;
+
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+        perp_market.init_v0_upgrade(market_index);
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Grow a MangoCache created before PriceCache had the EMA and circuit breaker state and
+    /// move its data into the current layout
+    fn upgrade_mango_cache(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult {
+        const NUM_FIXED: usize = 4;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai, // read
+            mango_cache_ai, // write
+            admin_ai,       // write, signer
+            system_prog_ai, // read
+        ] = accounts;
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+        check_eq!(
+            system_prog_ai.key,
+            &solana_program::system_program::id(),
+            MangoErrorCode::InvalidProgramId
+        )?;
+        check_eq!(mango_cache_ai.key, &mango_group.mango_cache, MangoErrorCode::InvalidCache)?;
+        check_eq!(mango_cache_ai.owner, program_id, MangoErrorCode::InvalidOwner)?;
+        check_eq!(mango_cache_ai.data_len(), MangoCache::V0_SIZE, MangoErrorCode::InvalidAccount)?;
+
+        let rent = Rent::get()?;
+        resize_account(admin_ai, &rent, size_of::<MangoCache>(), system_prog_ai, mango_cache_ai)?;
+        MangoCache::upgrade_v0_layout(&mut mango_cache_ai.try_borrow_mut_data()?)?;
+        Ok(())
+    }
+
+    /// Create a DustAccount PDA and initialize it
+    #[inline(never)]
+    fn create_dust_account(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult {
+        const NUM_FIXED: usize = 4;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,         // read
             mango_account_ai,       // write
-            owner_ai,               // read (write if no payer passed) & signer
+            payer_ai,               // write & signer
             system_prog_ai,         // read
-        ] = fixed_accounts;
-        let payer_ai = if accounts.len() > NUM_FIXED {
-            &accounts[NUM_FIXED] // write & signer
-        } else {
-            owner_ai
-        };
+        ] = accounts;
         check!(
             system_prog_ai.key == &solana_program::system_program::id(),
             MangoErrorCode::InvalidProgramId
         )?;
-        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
         check!(payer_ai.is_signer, MangoErrorCode::SignerNecessary)?;
 
-        let mut mango_group = MangoGroup::load_mut_checked(mango_group_ai, program_id)?;
-        check!(
-            mango_group.num_mango_accounts < mango_group.max_mango_accounts,
-            MangoErrorCode::MaxAccountsReached
-        )?;
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
         let rent = Rent::get()?;
 
-        let mango_account_seeds: &[&[u8]] =
-            &[&mango_group_ai.key.as_ref(), &owner_ai.key.as_ref(), &account_num.to_le_bytes()];
+        let mango_account_seeds: &[&[u8]] = &[&mango_group_ai.key.as_ref(), b"DustAccount"];
         seed_and_create_pda(
             program_id,
             payer_ai,
             &rent,
             size_of::<MangoAccount>(),
             program_id,
-            system_prog_ai,
-            mango_account_ai,
-            mango_account_seeds,
-            &[],
+            system_prog_ai,
+            mango_account_ai,
+            mango_account_seeds,
+            &[],
+        )?;
+        let mut mango_account: RefMut<MangoAccount> = MangoAccount::load_mut(mango_account_ai)?;
+        check!(!mango_account.meta_data.is_initialized, MangoErrorCode::InvalidAccountState)?;
+
+        mango_account.mango_group = *mango_group_ai.key;
+        mango_account.owner = mango_group.admin;
+        mango_account.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
+        mango_account.meta_data = MetaData::new(DataType::MangoAccount, 0, true);
+        mango_account.not_upgradable = true;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn upgrade_mango_account_v0_v1(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,   // write
+            mango_account_ai, // write
+            owner_ai          // signer
+        ] = accounts;
+
+        let mut mango_group = MangoGroup::load_mut_checked(mango_group_ai, program_id)?;
+        let mut mango_account =
+            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check!(
+            &mango_account.owner == owner_ai.key || &mango_account.delegate == owner_ai.key,
+            MangoErrorCode::InvalidOwner
+        )?;
+        check_eq!(mango_account.meta_data.version, 0, MangoErrorCode::InvalidAccountState)?;
+        check!(!mango_account.not_upgradable, MangoErrorCode::InvalidAccountState)?;
+        check!(
+            mango_group.num_mango_accounts < mango_group.max_mango_accounts,
+            MangoErrorCode::MaxAccountsReached
+        )?;
+
+        mango_group.num_mango_accounts += 1;
+        mango_account.meta_data.version = 1;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn cancel_perp_orders_side(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        side: Side,
+        limit: u8,
+    ) -> MangoResult {
+        const NUM_FIXED: usize = 6;
+        let (fixed_ais, iceberg_orders_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            mango_group_ai,     // read
+            mango_account_ai,   // write
+            owner_ai,           // read, signer
+            perp_market_ai,     // write
+            bids_ai,            // write
+            asks_ai,            // write
+        ] = fixed_ais;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+
+        let mut mango_account =
+            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(!mango_account.is_bankrupt, MangoErrorCode::Bankrupt)?;
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check!(
+            &mango_account.owner == owner_ai.key || &mango_account.delegate == owner_ai.key,
+            MangoErrorCode::InvalidOwner
+        )?;
+
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+
+        let market_index = mango_group.find_perp_market_index(perp_market_ai.key).unwrap();
+
+        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+        let mngo_start = mango_account.perp_accounts[market_index].mngo_accrued;
+        let orders_before = mango_account.orders;
+
+        if perp_market.meta_data.version == 0 {
+            return Err(throw_err!(MangoErrorCode::InvalidParam));
+        } else {
+            let (all_order_ids, canceled_order_ids) = book.cancel_all_side_with_size_incentives(
+                &mut mango_account,
+                &mut perp_market,
+                market_index,
+                side,
+                limit,
+            )?;
+            mango_emit_heap!(CancelAllPerpOrdersLog {
+                mango_group: *mango_group_ai.key,
+                mango_account: *mango_account_ai.key,
+                market_index: market_index as u64,
+                all_order_ids,
+                canceled_order_ids
+            });
+        }
+        let canceled_order_ids = mango_account.removed_order_ids(&orders_before);
+        cancel_iceberg_orders(
+            program_id,
+            iceberg_orders_ais.first(),
+            &mut mango_account,
+            mango_account_ai.key,
+            |o| o.market_index as usize == market_index && canceled_order_ids.contains(&o.order_id),
         )?;
-        let mut mango_account: RefMut<MangoAccount> = MangoAccount::load_mut(mango_account_ai)?;
-        check!(!mango_account.meta_data.is_initialized, MangoErrorCode::InvalidAccountState)?;
-
-        mango_account.mango_group = *mango_group_ai.key;
-        mango_account.owner = *owner_ai.key;
-        mango_account.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
-        mango_account.meta_data = MetaData::new(DataType::MangoAccount, 1, true);
 
-        mango_group.num_mango_accounts += 1;
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let index_price = perp_market.last_index_price;
+        perp_market.update_premium(&book, index_price, now_ts);
 
-        mango_emit_heap!(CreateMangoAccountLog {
+        mango_emit_heap!(MngoAccrualLog {
             mango_group: *mango_group_ai.key,
             mango_account: *mango_account_ai.key,
-            owner: *owner_ai.key
+            market_index: market_index as u64,
+            mngo_accrual: mango_account.perp_accounts[market_index].mngo_accrued - mngo_start
         });
-
         Ok(())
     }
 
     #[inline(never)]
-    fn update_margin_basket(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult {
-        const NUM_FIXED: usize = 2;
-        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
-        let (fixed_ais, open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
-
+    fn set_delegate(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult {
+        const NUM_FIXED: usize = 4;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
         let [
-            mango_group_ai,         // read
-            mango_account_ai,       // write
-        ] = fixed_ais;
+            mango_group_ai,                   // read
+            mango_account_ai,                 // write
+            owner_ai,                         // read, signer
+            delegate_ai,                      // read
+        ] = accounts;
 
-        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
         let mut mango_account =
             MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
 
-        for i in 0..mango_group.num_oracles {
-            check_eq!(
-                open_orders_ais[i].key,
-                &mango_account.spot_open_orders[i],
-                MangoErrorCode::InvalidOpenOrdersAccount
-            )?;
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check!(&mango_account.owner == owner_ai.key, MangoErrorCode::InvalidOwner)?;
+        check!(&mango_account.delegate != delegate_ai.key, MangoErrorCode::InvalidAccount)?;
 
-            if mango_account.spot_open_orders[i] != Pubkey::default() {
-                check_open_orders(
-                    &open_orders_ais[i],
-                    &mango_group.signer_key,
-                    &mango_group.dex_program_id,
-                )?;
-                let open_orders = load_open_orders(&open_orders_ais[i])?;
-                mango_account.update_basket(i, &open_orders)?;
-            }
-        }
+        mango_account.delegate = *delegate_ai.key;
 
         Ok(())
     }
+
     #[inline(never)]
-    /// Change the maximum number of MangoAccounts.v1 allowed
-    fn change_max_mango_accounts(
+    fn change_spot_market_params(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        max_mango_accounts: u32,
+        maint_leverage: Option<I80F48>,
+        init_leverage: Option<I80F48>,
+        liquidation_fee: Option<I80F48>,
+        optimal_util: Option<I80F48>,
+        optimal_rate: Option<I80F48>,
+        max_rate: Option<I80F48>,
+        version: Option<u8>,
     ) -> MangoResult {
-        const NUM_FIXED: usize = 2;
+        const NUM_FIXED: usize = 4;
         let accounts = array_ref![accounts, 0, NUM_FIXED];
+
         let [
-            mango_group_ai, // write
-            admin_ai        // read, signer
+        mango_group_ai, // write
+        spot_market_ai, // write
+        root_bank_ai,   // write
+        admin_ai        // read, signer
         ] = accounts;
 
         let mut mango_group = MangoGroup::load_mut_checked(mango_group_ai, program_id)?;
-        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
         check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
 
-        mango_group.max_mango_accounts = max_mango_accounts;
+        let market_index = mango_group
+            .find_spot_market_index(spot_market_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
+
+        // checks rootbank is part of the group
+        let _root_bank_index = mango_group
+            .find_root_bank_index(root_bank_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidRootBank))?;
+
+        let mut root_bank = RootBank::load_mut_checked(&root_bank_ai, program_id)?;
+        let mut info = &mut mango_group.spot_markets[market_index];
+
+        // Unwrap params. Default to current state if Option is None
+        let (init_asset_weight, init_liab_weight) = init_leverage
+            .map_or((info.init_asset_weight, info.init_liab_weight), |x| get_leverage_weights(x));
+        let (maint_asset_weight, maint_liab_weight) = maint_leverage
+            .map_or((info.maint_asset_weight, info.maint_liab_weight), |x| get_leverage_weights(x));
+
+        let liquidation_fee = liquidation_fee.unwrap_or(info.liquidation_fee);
+        let optimal_util = optimal_util.unwrap_or(root_bank.optimal_util);
+        let optimal_rate = optimal_rate.unwrap_or(root_bank.optimal_rate);
+        let max_rate = max_rate.unwrap_or(root_bank.max_rate);
+        let version = version.unwrap_or(root_bank.meta_data.version);
+
+        // params check
+        check!(init_asset_weight > ZERO_I80F48, MangoErrorCode::InvalidParam)?;
+        check!(maint_asset_weight > init_asset_weight, MangoErrorCode::InvalidParam)?;
+        // maint leverage may only increase to prevent unforeseen liquidations
+        check!(maint_asset_weight >= info.maint_asset_weight, MangoErrorCode::InvalidParam)?;
+
+        // set the params on the RootBank
+        root_bank.set_rate_params(optimal_util, optimal_rate, max_rate)?;
+
+        // set the params on MangoGroup SpotMarketInfo
+        info.liquidation_fee = liquidation_fee;
+        info.maint_asset_weight = maint_asset_weight;
+        info.init_asset_weight = init_asset_weight;
+        info.maint_liab_weight = maint_liab_weight;
+        info.init_liab_weight = init_liab_weight;
+
+        check!(version == 0, MangoErrorCode::InvalidParam)?;
+
+        root_bank.meta_data.version = version;
         Ok(())
     }
 
+    /// Set the `ref_surcharge_centibps`, `ref_share_centibps` and `ref_mngo_required` on `MangoGroup`
     #[inline(never)]
-    /// Change the maximum number of MangoAccounts.v1 allowed
-    fn change_dont_square(
+    fn change_referral_fee_params(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        dont_square: bool,
+        ref_surcharge_centibps: u32,
+        ref_share_centibps: u32,
+        ref_mngo_required: u64,
     ) -> MangoResult {
+        check!(ref_surcharge_centibps >= ref_share_centibps, MangoErrorCode::InvalidParam)?;
+
         const NUM_FIXED: usize = 2;
         let accounts = array_ref![accounts, 0, NUM_FIXED];
+
         let [
             mango_group_ai, // write
             admin_ai        // read, signer
         ] = accounts;
 
         let mut mango_group = MangoGroup::load_mut_checked(mango_group_ai, program_id)?;
-        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
         check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        msg!("old referral fee params: ref_surcharge_centibps: {} ref_share_centibps: {} ref_mngo_required: {}", mango_group.ref_surcharge_centibps, mango_group.ref_share_centibps, mango_group.ref_mngo_required);
+
+        // TODO - when this goes out, if there are any events on the EventQueue fee logging will be messed up
+
+        mango_group.ref_surcharge_centibps = ref_surcharge_centibps;
+        mango_group.ref_share_centibps = ref_share_centibps;
+        mango_group.ref_mngo_required = ref_mngo_required;
 
-        mango_group.dont_square = dont_square;
+        msg!("new referral fee params: ref_surcharge_centibps: {} ref_share_centibps: {} ref_mngo_required: {}", ref_surcharge_centibps, ref_share_centibps, ref_mngo_required);
         Ok(())
     }
 
-    /// Create a DustAccount PDA and initialize it
     #[inline(never)]
-    fn create_dust_account(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult {
-        const NUM_FIXED: usize = 4;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
+    /// Store the referrer's MangoAccount pubkey on the Referrer account
+    /// It will create the Referrer account as a PDA of user's MangoAccount if it doesn't exist
+    /// This is primarily useful for the UI; the referrer address stored here is not necessarily
+    /// who earns the ref fees.
+    fn set_referrer_memory(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult {
+        const NUM_FIXED: usize = 7;
         let [
-            mango_group_ai,         // read
-            mango_account_ai,       // write
-            payer_ai,               // write & signer
-            system_prog_ai,         // read
-        ] = accounts;
+            mango_group_ai,             // read
+            mango_account_ai,           // read
+            owner_ai,                   // signer
+            referrer_memory_ai,         // write
+            referrer_mango_account_ai,  // read
+            payer_ai,                   // write, signer
+            system_prog_ai,             // read
+        ] = array_ref![accounts, 0, NUM_FIXED];
         check!(
             system_prog_ai.key == &solana_program::system_program::id(),
             MangoErrorCode::InvalidProgramId
         )?;
-        check!(payer_ai.is_signer, MangoErrorCode::SignerNecessary)?;
 
-        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
-        let rent = Rent::get()?;
+        let _ = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        let mango_account =
+            MangoAccount::load_checked(mango_account_ai, program_id, &mango_group_ai.key)?;
+        check!(
+            &mango_account.owner == owner_ai.key || &mango_account.delegate == owner_ai.key,
+            MangoErrorCode::InvalidOwner
+        )?;
+        check!(owner_ai.is_signer, MangoErrorCode::InvalidSignerKey)?;
 
-        let mango_account_seeds: &[&[u8]] = &[&mango_group_ai.key.as_ref(), b"DustAccount"];
+        let _ =
+            MangoAccount::load_checked(referrer_mango_account_ai, program_id, mango_group_ai.key)?;
+
+        if referrer_memory_ai.data_is_empty() {
+            // initialize it if it's not initialized yet
+            let referrer_seeds: &[&[u8]] = &[&mango_account_ai.key.as_ref(), b"ReferrerMemory"];
+            seed_and_create_pda(
+                program_id,
+                payer_ai,
+                &Rent::get()?,
+                size_of::<ReferrerMemory>(),
+                program_id,
+                system_prog_ai,
+                referrer_memory_ai,
+                referrer_seeds,
+                &[],
+            )?;
+            ReferrerMemory::init(referrer_memory_ai, program_id, referrer_mango_account_ai)
+        } else {
+            // otherwise just set referrer pubkey
+            let mut referrer_memory =
+                ReferrerMemory::load_mut_checked(referrer_memory_ai, program_id)?;
+            referrer_memory.referrer_mango_account = *referrer_mango_account_ai.key;
+            Ok(())
+        }
+    }
+
+    /// Associate the referrer's MangoAccount with a human readable `referrer_id` which can be used
+    /// in a ref link
+    /// Create the `ReferrerIdRecord` PDA; if it already exists throw error
+    #[inline(never)]
+    fn register_referrer_id(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        referrer_id: [u8; INFO_LEN],
+    ) -> MangoResult {
+        const NUM_FIXED: usize = 5;
+        let [
+            mango_group_ai,             // read
+            referrer_mango_account_ai,  // read
+            referrer_id_record_ai,      // write
+            payer_ai,                   // write, signer
+            system_prog_ai,             // read
+        ] = array_ref![accounts, 0, NUM_FIXED];
+        check!(
+            system_prog_ai.key == &solana_program::system_program::id(),
+            MangoErrorCode::InvalidProgramId
+        )?;
+
+        let _ = MangoGroup::load_checked(mango_group_ai, program_id)?;
+
+        let _ =
+            MangoAccount::load_checked(referrer_mango_account_ai, program_id, mango_group_ai.key)?;
+
+        // referrer_id_record must be empty; cannot be transferred
+        check!(referrer_id_record_ai.data_is_empty(), MangoErrorCode::InvalidAccount)?;
+        let referrer_record_seeds: &[&[u8]] =
+            &[&mango_group_ai.key.as_ref(), b"ReferrerIdRecord", &referrer_id];
         seed_and_create_pda(
             program_id,
             payer_ai,
-            &rent,
-            size_of::<MangoAccount>(),
+            &Rent::get()?,
+            size_of::<ReferrerIdRecord>(),
             program_id,
             system_prog_ai,
-            mango_account_ai,
-            mango_account_seeds,
+            referrer_id_record_ai,
+            referrer_record_seeds,
             &[],
         )?;
-        let mut mango_account: RefMut<MangoAccount> = MangoAccount::load_mut(mango_account_ai)?;
-        check!(!mango_account.meta_data.is_initialized, MangoErrorCode::InvalidAccountState)?;
-
-        mango_account.mango_group = *mango_group_ai.key;
-        mango_account.owner = mango_group.admin;
-        mango_account.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
-        mango_account.meta_data = MetaData::new(DataType::MangoAccount, 0, true);
-        mango_account.not_upgradable = true;
-
-        Ok(())
-    }
-
-    #[inline(never)]
-    fn upgrade_mango_account_v0_v1(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult {
-        const NUM_FIXED: usize = 3;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
-        let [
-            mango_group_ai,   // write
-            mango_account_ai, // write
-            owner_ai          // signer
-        ] = accounts;
-
-        let mut mango_group = MangoGroup::load_mut_checked(mango_group_ai, program_id)?;
-        let mut mango_account =
-            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
-
-        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
-        check!(
-            &mango_account.owner == owner_ai.key || &mango_account.delegate == owner_ai.key,
-            MangoErrorCode::InvalidOwner
-        )?;
-        check_eq!(mango_account.meta_data.version, 0, MangoErrorCode::InvalidAccountState)?;
-        check!(!mango_account.not_upgradable, MangoErrorCode::InvalidAccountState)?;
-        check!(
-            mango_group.num_mango_accounts < mango_group.max_mango_accounts,
-            MangoErrorCode::MaxAccountsReached
-        )?;
 
-        mango_group.num_mango_accounts += 1;
-        mango_account.meta_data.version = 1;
-
-        Ok(())
+        ReferrerIdRecord::init(
+            referrer_id_record_ai,
+            program_id,
+            referrer_mango_account_ai,
+            referrer_id,
+        )
     }
 
     #[inline(never)]
-    fn cancel_perp_orders_side(
+    fn cancel_all_spot_orders(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        side: Side,
         limit: u8,
     ) -> MangoResult {
-        const NUM_FIXED: usize = 6;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        const NUM_FIXED: usize = 21;
+
         let [
-            mango_group_ai,     // read
-            mango_account_ai,   // write
-            owner_ai,           // read, signer
-            perp_market_ai,     // write
-            bids_ai,            // write
-            asks_ai,            // write
-        ] = accounts;
+            mango_group_ai,         // read
+            mango_cache_ai,         // read
+            mango_account_ai,       // write
+            owner_ai,               // read, signer
+            base_root_bank_ai,      // read
+            base_node_bank_ai,      // write
+            base_vault_ai,          // write
+            quote_root_bank_ai,     // read
+            quote_node_bank_ai,     // write
+            quote_vault_ai,         // write
+
+            spot_market_ai,         // write
+            bids_ai,                // write
+            asks_ai,                // write
+            open_orders_ai,         // write
+            signer_ai,              // read
+            dex_event_queue_ai,     // write
+            dex_base_ai,            // write
+            dex_quote_ai,           // write
+            dex_signer_ai,          // read
+            dex_prog_ai,            // read
+            token_prog_ai,          // read
+        ] = array_ref![accounts, 0, NUM_FIXED];
+        check_eq!(token_prog_ai.key, &spl_token::ID, MangoErrorCode::InvalidProgramId)?;
 
         let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        check_eq!(dex_prog_ai.key, &mango_group.dex_program_id, MangoErrorCode::InvalidProgramId)?;
+        check!(signer_ai.key == &mango_group.signer_key, MangoErrorCode::InvalidSignerKey)?;
 
         let mut mango_account =
             MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
-        check!(!mango_account.is_bankrupt, MangoErrorCode::Bankrupt)?;
-        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
         check!(
             &mango_account.owner == owner_ai.key || &mango_account.delegate == owner_ai.key,
             MangoErrorCode::InvalidOwner
         )?;
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
 
-        let mut perp_market =
-            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+        let market_index = mango_group
+            .find_spot_market_index(spot_market_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
 
-        let market_index = mango_group.find_perp_market_index(perp_market_ai.key).unwrap();
+        check_open_orders(open_orders_ai, &mango_group.signer_key, &mango_group.dex_program_id)?;
 
-        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
-        let mngo_start = mango_account.perp_accounts[market_index].mngo_accrued;
+        check_eq!(
+            &mango_account.spot_open_orders[market_index],
+            open_orders_ai.key,
+            MangoErrorCode::InvalidOpenOrdersAccount
+        )?;
 
-        if perp_market.meta_data.version == 0 {
-            return Err(throw_err!(MangoErrorCode::InvalidParam));
-        } else {
-            let (all_order_ids, canceled_order_ids) = book.cancel_all_side_with_size_incentives(
-                &mut mango_account,
-                &mut perp_market,
-                market_index,
-                side,
-                limit,
-            )?;
-            mango_emit_heap!(CancelAllPerpOrdersLog {
+        let signer_seeds = gen_signer_seeds(&mango_group.signer_nonce, mango_group_ai.key);
+        invoke_cancel_orders(
+            open_orders_ai,
+            dex_prog_ai,
+            spot_market_ai,
+            bids_ai,
+            asks_ai,
+            signer_ai,
+            dex_event_queue_ai,
+            &[&signer_seeds],
+            limit,
+        )?;
+
+        let (pre_base, pre_quote) = {
+            let open_orders = load_open_orders(open_orders_ai)?;
+            (
+                open_orders.native_coin_free,
+                open_orders.native_pc_free + open_orders.referrer_rebates_accrued,
+            )
+        };
+
+        if pre_base == 0 && pre_quote == 0 {
+            // margin basket may be in an invalid state; correct it before returning
+            let open_orders = load_open_orders(open_orders_ai)?;
+            mango_account.update_basket(market_index, &open_orders)?;
+            return Ok(());
+        }
+
+        // Settle funds released by canceling open orders
+        invoke_settle_funds(
+            dex_prog_ai,
+            spot_market_ai,
+            open_orders_ai,
+            signer_ai,
+            dex_base_ai,
+            dex_quote_ai,
+            base_vault_ai,
+            quote_vault_ai,
+            dex_signer_ai,
+            token_prog_ai,
+            &[&signer_seeds],
+        )?;
+
+        let (post_base, post_quote) = {
+            let open_orders = load_open_orders(open_orders_ai)?;
+            mango_account.update_basket(market_index, &open_orders)?;
+            mango_emit_stack::<_, 256>(OpenOrdersBalanceLog {
                 mango_group: *mango_group_ai.key,
                 mango_account: *mango_account_ai.key,
                 market_index: market_index as u64,
-                all_order_ids,
-                canceled_order_ids
+                base_total: open_orders.native_coin_total,
+                base_free: open_orders.native_coin_free,
+                quote_total: open_orders.native_pc_total,
+                quote_free: open_orders.native_pc_free,
+                referrer_rebates_accrued: open_orders.referrer_rebates_accrued,
             });
-        }
 
-        mango_emit_heap!(MngoAccrualLog {
-            mango_group: *mango_group_ai.key,
-            mango_account: *mango_account_ai.key,
-            market_index: market_index as u64,
-            mngo_accrual: mango_account.perp_accounts[market_index].mngo_accrued - mngo_start
-        });
-        Ok(())
-    }
+            (
+                open_orders.native_coin_free,
+                open_orders.native_pc_free + open_orders.referrer_rebates_accrued,
+            )
+        };
 
-    #[inline(never)]
-    fn set_delegate(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult {
-        const NUM_FIXED: usize = 4;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
-        let [
-            mango_group_ai,                   // read
-            mango_account_ai,                 // write
-            owner_ai,                         // read, signer
-            delegate_ai,                      // read
-        ] = accounts;
+        check!(post_base <= pre_base, MangoErrorCode::Default)?;
+        check!(post_quote <= pre_quote, MangoErrorCode::Default)?;
 
-        let mut mango_account =
-            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        // Update balances from settling funds
+        let base_change = I80F48::from_num(pre_base - post_base);
+        let quote_change = I80F48::from_num(pre_quote - post_quote);
 
-        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
-        check!(&mango_account.owner == owner_ai.key, MangoErrorCode::InvalidOwner)?;
-        check!(&mango_account.delegate != delegate_ai.key, MangoErrorCode::InvalidAccount)?;
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+        let clock = Clock::get()?;
+        let now_ts = clock.unix_timestamp as u64;
 
-        mango_account.delegate = *delegate_ai.key;
+        mango_cache.root_bank_cache[market_index].check_valid(&mango_group, now_ts)?;
+        mango_cache.root_bank_cache[QUOTE_INDEX].check_valid(&mango_group, now_ts)?;
+
+        check_eq!(
+            &mango_group.tokens[market_index].root_bank,
+            base_root_bank_ai.key,
+            MangoErrorCode::InvalidRootBank
+        )?;
+        let base_root_bank = RootBank::load_checked(base_root_bank_ai, program_id)?;
+
+        check!(
+            base_root_bank.node_banks.contains(base_node_bank_ai.key),
+            MangoErrorCode::InvalidNodeBank
+        )?;
+        let mut base_node_bank = NodeBank::load_mut_checked(base_node_bank_ai, program_id)?;
+        check_eq!(&base_node_bank.vault, base_vault_ai.key, MangoErrorCode::InvalidVault)?;
+
+        check_eq!(
+            &mango_group.tokens[QUOTE_INDEX].root_bank,
+            quote_root_bank_ai.key,
+            MangoErrorCode::InvalidRootBank
+        )?;
+        let quote_root_bank = RootBank::load_checked(quote_root_bank_ai, program_id)?;
+
+        check!(
+            quote_root_bank.node_banks.contains(quote_node_bank_ai.key),
+            MangoErrorCode::InvalidNodeBank
+        )?;
+        let mut quote_node_bank = NodeBank::load_mut_checked(quote_node_bank_ai, program_id)?;
+        check_eq!(&quote_node_bank.vault, quote_vault_ai.key, MangoErrorCode::InvalidVault)?;
 
+        checked_change_net(
+            &mango_cache.root_bank_cache[market_index],
+            &mut base_node_bank,
+            &mut mango_account,
+            mango_account_ai.key,
+            market_index,
+            base_change,
+        )?;
+        checked_change_net(
+            &mango_cache.root_bank_cache[QUOTE_INDEX],
+            &mut quote_node_bank,
+            &mut mango_account,
+            mango_account_ai.key,
+            QUOTE_INDEX,
+            quote_change,
+        )?;
         Ok(())
     }
 
     #[inline(never)]
-    fn change_spot_market_params(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        maint_leverage: Option<I80F48>,
-        init_leverage: Option<I80F48>,
-        liquidation_fee: Option<I80F48>,
-        optimal_util: Option<I80F48>,
-        optimal_rate: Option<I80F48>,
-        max_rate: Option<I80F48>,
-        version: Option<u8>,
-    ) -> MangoResult {
-        const NUM_FIXED: usize = 4;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
-
-        let [
-        mango_group_ai, // write
-        spot_market_ai, // write
-        root_bank_ai,   // write
-        admin_ai        // read, signer
-        ] = accounts;
-
-        let mut mango_group = MangoGroup::load_mut_checked(mango_group_ai, program_id)?;
-        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
-        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
-
-        let market_index = mango_group
-            .find_spot_market_index(spot_market_ai.key)
-            .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
+    fn init_otc_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult {
+        const NUM_FIXED: usize = 6;
 
-        // checks rootbank is part of the group
-        let _root_bank_index = mango_group
-            .find_root_bank_index(root_bank_ai.key)
-            .ok_or(throw_err!(MangoErrorCode::InvalidRootBank))?;
+        let [otc_orders_pda_ai, mango_group_ai, creator_mango_account_ai, otc_order_owner_ai, rent_ai, system_program_ai] =
+            array_ref![accounts, 0, NUM_FIXED];
 
-        let mut root_bank = RootBank::load_mut_checked(&root_bank_ai, program_id)?;
-        let mut info = &mut mango_group.spot_markets[market_index];
+        // Unpack accounts state
+        let _mango_group_state = MangoGroup::load_checked(mango_group_ai, program_id)?;
 
-        // Unwrap params. Default to current state if Option is None
-        let (init_asset_weight, init_liab_weight) = init_leverage
-            .map_or((info.init_asset_weight, info.init_liab_weight), |x| get_leverage_weights(x));
-        let (maint_asset_weight, maint_liab_weight) = maint_leverage
-            .map_or((info.maint_asset_weight, info.maint_liab_weight), |x| get_leverage_weights(x));
+        let creator_mango_account_state =
+            MangoAccount::load_checked(creator_mango_account_ai, program_id, mango_group_ai.key)?;
 
-        let liquidation_fee = liquidation_fee.unwrap_or(info.liquidation_fee);
-        let optimal_util = optimal_util.unwrap_or(root_bank.optimal_util);
-        let optimal_rate = optimal_rate.unwrap_or(root_bank.optimal_rate);
-        let max_rate = max_rate.unwrap_or(root_bank.max_rate);
-        let version = version.unwrap_or(root_bank.meta_data.version);
+        // Check accounts
+        check!(otc_order_owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(
+            otc_order_owner_ai.key,
+            &creator_mango_account_state.owner,
+            MangoErrorCode::InvalidAccount
+        )?;
+        check_eq!(
+            rent_ai.key,
+            &solana_program::sysvar::rent::id(),
+            MangoErrorCode::InvalidAccount
+        )?;
+        check_eq!(
+            system_program_ai.key,
+            &solana_program::system_program::id(),
+            MangoErrorCode::InvalidProgramId
+        )?;
 
-        // params check
-        check!(init_asset_weight > ZERO_I80F48, MangoErrorCode::InvalidParam)?;
-        check!(maint_asset_weight > init_asset_weight, MangoErrorCode::InvalidParam)?;
-        // maint leverage may only increase to prevent unforeseen liquidations
-        check!(maint_asset_weight >= info.maint_asset_weight, MangoErrorCode::InvalidParam)?;
+        let (otc_orders_pda, bump_seed) = Pubkey::find_program_address(
+            &[OTC_ORDERS_PREFIX.as_bytes(), creator_mango_account_ai.key.as_ref()],
+            program_id,
+        );
+        check_eq!(&otc_orders_pda, otc_orders_pda_ai.key, MangoErrorCode::InvalidProgramId)?;
 
-        // set the params on the RootBank
-        root_bank.set_rate_params(optimal_util, optimal_rate, max_rate)?;
+        let rent = Rent::get()?;
 
-        // set the params on MangoGroup SpotMarketInfo
-        info.liquidation_fee = liquidation_fee;
-        info.maint_asset_weight = maint_asset_weight;
-        info.init_asset_weight = init_asset_weight;
-        info.maint_liab_weight = maint_liab_weight;
-        info.init_liab_weight = init_liab_weight;
+        create_pda_account(
+            otc_order_owner_ai,
+            &rent,
+            size_of::<OtcOrders>(),
+            program_id,
+            system_program_ai,
+            otc_orders_pda_ai,
+            &[OTC_ORDERS_PREFIX.as_bytes(), creator_mango_account_ai.key.as_ref(), &[bump_seed]],
+            &[],
+        )?;
 
-        check!(version == 0, MangoErrorCode::InvalidParam)?;
+        let _ = OtcOrders::load_and_init(
+            otc_orders_pda_ai,
+            program_id,
+            &rent,
+            creator_mango_account_ai.key,
+            bump_seed,
+        )?;
 
-        root_bank.meta_data.version = version;
         Ok(())
     }
 
-    /// Set the `ref_surcharge_centibps`, `ref_share_centibps` and `ref_mngo_required` on `MangoGroup`
     #[inline(never)]
-    fn change_referral_fee_params(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        ref_surcharge_centibps: u32,
-        ref_share_centibps: u32,
-        ref_mngo_required: u64,
-    ) -> MangoResult {
-        check!(ref_surcharge_centibps >= ref_share_centibps, MangoErrorCode::InvalidParam)?;
-
-        const NUM_FIXED: usize = 2;
+    fn init_iceberg_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult {
+        const NUM_FIXED: usize = 6;
         let accounts = array_ref![accounts, 0, NUM_FIXED];
-
         let [
-            mango_group_ai, // write
-            admin_ai        // read, signer
+            iceberg_orders_ai,  // write
+            mango_group_ai,     // read
+            mango_account_ai,   // read
+            owner_ai,           // write, signer
+            rent_ai,            // read
+            system_prog_ai,     // read
         ] = accounts;
 
-        let mut mango_group = MangoGroup::load_mut_checked(mango_group_ai, program_id)?;
-        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
-        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
-        msg!("old referral fee params: ref_surcharge_centibps: {} ref_share_centibps: {} ref_mngo_required: {}", mango_group.ref_surcharge_centibps, mango_group.ref_share_centibps, mango_group.ref_mngo_required);
-
-        // TODO - when this goes out, if there are any events on the EventQueue fee logging will be messed up
-
-        mango_group.ref_surcharge_centibps = ref_surcharge_centibps;
-        mango_group.ref_share_centibps = ref_share_centibps;
-        mango_group.ref_mngo_required = ref_mngo_required;
-
-        msg!("new referral fee params: ref_surcharge_centibps: {} ref_share_centibps: {} ref_mngo_required: {}", ref_surcharge_centibps, ref_share_centibps, ref_mngo_required);
-        Ok(())
-    }
+        let _mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        let mango_account =
+            MangoAccount::load_checked(mango_account_ai, program_id, mango_group_ai.key)?;
 
-    #[inline(never)]
-    /// Store the referrer's MangoAccount pubkey on the Referrer account
-    /// It will create the Referrer account as a PDA of user's MangoAccount if it doesn't exist
-    /// This is primarily useful for the UI; the referrer address stored here is not necessarily
-    /// who earns the ref fees.
-    fn set_referrer_memory(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult {
-        const NUM_FIXED: usize = 7;
-        let [
-            mango_group_ai,             // read
-            mango_account_ai,           // read
-            owner_ai,                   // signer
-            referrer_memory_ai,         // write
-            referrer_mango_account_ai,  // read
-            payer_ai,                   // write, signer
-            system_prog_ai,             // read
-        ] = array_ref![accounts, 0, NUM_FIXED];
-        check!(
-            system_prog_ai.key == &solana_program::system_program::id(),
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(owner_ai.key, &mango_account.owner, MangoErrorCode::InvalidOwner)?;
+        check_eq!(
+            rent_ai.key,
+            &solana_program::sysvar::rent::id(),
+            MangoErrorCode::InvalidAccount
+        )?;
+        check_eq!(
+            system_prog_ai.key,
+            &solana_program::system_program::id(),
             MangoErrorCode::InvalidProgramId
         )?;
 
-        let _ = MangoGroup::load_checked(mango_group_ai, program_id)?;
-        let mango_account =
-            MangoAccount::load_checked(mango_account_ai, program_id, &mango_group_ai.key)?;
-        check!(
-            &mango_account.owner == owner_ai.key || &mango_account.delegate == owner_ai.key,
-            MangoErrorCode::InvalidOwner
+        let (pda_address, bump_seed) = Pubkey::find_program_address(
+            &[ICEBERG_ORDERS_PREFIX.as_bytes(), mango_account_ai.key.as_ref()],
+            program_id,
+        );
+        check_eq!(&pda_address, iceberg_orders_ai.key, MangoErrorCode::InvalidAccount)?;
+
+        let rent = Rent::get()?;
+        create_pda_account(
+            owner_ai,
+            &rent,
+            size_of::<IcebergOrders>(),
+            program_id,
+            system_prog_ai,
+            iceberg_orders_ai,
+            &[ICEBERG_ORDERS_PREFIX.as_bytes(), mango_account_ai.key.as_ref(), &[bump_seed]],
+            &[],
         )?;
-        check!(owner_ai.is_signer, MangoErrorCode::InvalidSignerKey)?;
 
-        let _ =
-            MangoAccount::load_checked(referrer_mango_account_ai, program_id, mango_group_ai.key)?;
+        let _ = IcebergOrders::load_and_init(
+            iceberg_orders_ai,
+            program_id,
+            &rent,
+            mango_account_ai.key,
+        )?;
 
-        if referrer_memory_ai.data_is_empty() {
-            // initialize it if it's not initialized yet
-            let referrer_seeds: &[&[u8]] = &[&mango_account_ai.key.as_ref(), b"ReferrerMemory"];
-            seed_and_create_pda(
-                program_id,
-                payer_ai,
-                &Rent::get()?,
-                size_of::<ReferrerMemory>(),
-                program_id,
-                system_prog_ai,
-                referrer_memory_ai,
-                referrer_seeds,
-                &[],
-            )?;
-            ReferrerMemory::init(referrer_memory_ai, program_id, referrer_mango_account_ai)
-        } else {
-            // otherwise just set referrer pubkey
-            let mut referrer_memory =
-                ReferrerMemory::load_mut_checked(referrer_memory_ai, program_id)?;
-            referrer_memory.referrer_mango_account = *referrer_mango_account_ai.key;
-            Ok(())
-        }
+        Ok(())
     }
 
-    /// Associate the referrer's MangoAccount with a human readable `referrer_id` which can be used
-    /// in a ref link
-    /// Create the `ReferrerIdRecord` PDA; if it already exists throw error
     #[inline(never)]
-    fn register_referrer_id(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        referrer_id: [u8; INFO_LEN],
-    ) -> MangoResult {
-        const NUM_FIXED: usize = 5;
+    fn init_heartbeat(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult {
+        const NUM_FIXED: usize = 6;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
         let [
-            mango_group_ai,             // read
-            referrer_mango_account_ai,  // read
-            referrer_id_record_ai,      // write
-            payer_ai,                   // write, signer
-            system_prog_ai,             // read
-        ] = array_ref![accounts, 0, NUM_FIXED];
-        check!(
-            system_prog_ai.key == &solana_program::system_program::id(),
+            heartbeat_ai,       // write
+            mango_group_ai,     // read
+            mango_account_ai,   // read
+            owner_ai,           // write, signer
+            rent_ai,            // read
+            system_prog_ai,     // read
+        ] = accounts;
+
+        let _mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        let mango_account =
+            MangoAccount::load_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(owner_ai.key, &mango_account.owner, MangoErrorCode::InvalidOwner)?;
+        check_eq!(
+            rent_ai.key,
+            &solana_program::sysvar::rent::id(),
+            MangoErrorCode::InvalidAccount
+        )?;
+        check_eq!(
+            system_prog_ai.key,
+            &solana_program::system_program::id(),
             MangoErrorCode::InvalidProgramId
         )?;
 
-        let _ = MangoGroup::load_checked(mango_group_ai, program_id)?;
-
-        let _ =
-            MangoAccount::load_checked(referrer_mango_account_ai, program_id, mango_group_ai.key)?;
-
-        // referrer_id_record must be empty; cannot be transferred
-        check!(referrer_id_record_ai.data_is_empty(), MangoErrorCode::InvalidAccount)?;
-        let referrer_record_seeds: &[&[u8]] =
-            &[&mango_group_ai.key.as_ref(), b"ReferrerIdRecord", &referrer_id];
-        seed_and_create_pda(
+        let (pda_address, bump_seed) = Pubkey::find_program_address(
+            &[HEARTBEAT_PREFIX.as_bytes(), mango_account_ai.key.as_ref()],
             program_id,
-            payer_ai,
-            &Rent::get()?,
-            size_of::<ReferrerIdRecord>(),
+        );
+        check_eq!(&pda_address, heartbeat_ai.key, MangoErrorCode::InvalidAccount)?;
+
+        let rent = Rent::get()?;
+        create_pda_account(
+            owner_ai,
+            &rent,
+            size_of::<Heartbeat>(),
             program_id,
             system_prog_ai,
-            referrer_id_record_ai,
-            referrer_record_seeds,
+            heartbeat_ai,
+            &[HEARTBEAT_PREFIX.as_bytes(), mango_account_ai.key.as_ref(), &[bump_seed]],
             &[],
         )?;
 
-        ReferrerIdRecord::init(
-            referrer_id_record_ai,
-            program_id,
-            referrer_mango_account_ai,
-            referrer_id,
-        )
+        let _ = Heartbeat::load_and_init(heartbeat_ai, program_id, &rent, mango_account_ai.key)?;
+
+        Ok(())
     }
 
     #[inline(never)]
-    fn cancel_all_spot_orders(
+    /// Owner or delegate moves the heartbeat deadline; 0 disarms it
+    fn set_heartbeat(program_id: &Pubkey, accounts: &[AccountInfo], deadline: u64) -> MangoResult {
+        const NUM_FIXED: usize = 4;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            mango_account_ai,   // read
+            owner_ai,           // read, signer
+            heartbeat_ai,       // write
+        ] = accounts;
+
+        let _mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        let mango_account =
+            MangoAccount::load_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check!(
+            &mango_account.owner == owner_ai.key || &mango_account.delegate == owner_ai.key,
+            MangoErrorCode::InvalidOwner
+        )?;
+
+        let mut heartbeat =
+            Heartbeat::load_mut_checked(heartbeat_ai, program_id, mango_account_ai.key)?;
+        heartbeat.deadline = deadline;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Permissionless crank cancelling the perp orders of a MangoAccount whose heartbeat
+    /// deadline has passed, including the hidden rest of its iceberg orders
+    fn expire_heartbeat_orders(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
         limit: u8,
     ) -> MangoResult {
-        const NUM_FIXED: usize = 21;
-
+        const NUM_FIXED: usize = 4;
+        let (fixed_ais, perp_market_ais) = array_refs![accounts, NUM_FIXED; ..;];
         let [
-            mango_group_ai,         // read
-            mango_cache_ai,         // read
-            mango_account_ai,       // write
-            owner_ai,               // read, signer
-            base_root_bank_ai,      // read
-            base_node_bank_ai,      // write
-            base_vault_ai,          // write
-            quote_root_bank_ai,     // read
-            quote_node_bank_ai,     // write
-            quote_vault_ai,         // write
-
-            spot_market_ai,         // write
-            bids_ai,                // write
-            asks_ai,                // write
-            open_orders_ai,         // write
-            signer_ai,              // read
-            dex_event_queue_ai,     // write
-            dex_base_ai,            // write
-            dex_quote_ai,           // write
-            dex_signer_ai,          // read
-            dex_prog_ai,            // read
-            token_prog_ai,          // read
-        ] = array_ref![accounts, 0, NUM_FIXED];
-        check_eq!(token_prog_ai.key, &spl_token::ID, MangoErrorCode::InvalidProgramId)?;
+            mango_group_ai,     // read
+            mango_account_ai,   // write
+            heartbeat_ai,       // read
+            iceberg_orders_ai,  // write
+        ] = fixed_ais;
+        check!(perp_market_ais.len() % 3 == 0, MangoErrorCode::InvalidAccount)?;
 
         let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
-        check_eq!(dex_prog_ai.key, &mango_group.dex_program_id, MangoErrorCode::InvalidProgramId)?;
-        check!(signer_ai.key == &mango_group.signer_key, MangoErrorCode::InvalidSignerKey)?;
-
         let mut mango_account =
             MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
-        check!(
-            &mango_account.owner == owner_ai.key || &mango_account.delegate == owner_ai.key,
-            MangoErrorCode::InvalidOwner
-        )?;
-        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
-
-        let market_index = mango_group
-            .find_spot_market_index(spot_market_ai.key)
-            .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
 
-        check_open_orders(open_orders_ai, &mango_group.signer_key, &mango_group.dex_program_id)?;
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let heartbeat = Heartbeat::load_checked(heartbeat_ai, program_id, mango_account_ai.key)?;
+        check!(heartbeat.is_expired(now_ts), MangoErrorCode::HeartbeatNotExpired)?;
 
-        check_eq!(
-            &mango_account.spot_open_orders[market_index],
-            open_orders_ai.key,
-            MangoErrorCode::InvalidOpenOrdersAccount
-        )?;
+        for market_ais in perp_market_ais.chunks(3) {
+            let [perp_market_ai, bids_ai, asks_ai] = array_ref![market_ais, 0, 3];
 
-        let signer_seeds = gen_signer_seeds(&mango_group.signer_nonce, mango_group_ai.key);
-        invoke_cancel_orders(
-            open_orders_ai,
-            dex_prog_ai,
-            spot_market_ai,
-            bids_ai,
-            asks_ai,
-            signer_ai,
-            dex_event_queue_ai,
-            &[&signer_seeds],
-            limit,
-        )?;
+            let mut perp_market =
+                PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+            let market_index = mango_group
+                .find_perp_market_index(perp_market_ai.key)
+                .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
 
-        let (pre_base, pre_quote) = {
-            let open_orders = load_open_orders(open_orders_ai)?;
-            (
-                open_orders.native_coin_free,
-                open_orders.native_pc_free + open_orders.referrer_rebates_accrued,
-            )
-        };
+            let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+            let mngo_start = mango_account.perp_accounts[market_index].mngo_accrued;
 
-        if pre_base == 0 && pre_quote == 0 {
-            // margin basket may be in an invalid state; correct it before returning
-            let open_orders = load_open_orders(open_orders_ai)?;
-            mango_account.update_basket(market_index, &open_orders)?;
-            return Ok(());
-        }
+            if perp_market.meta_data.version == 0 {
+                book.cancel_all_with_price_incentives(
+                    &mut mango_account,
+                    &mut perp_market,
+                    market_index,
+                    limit,
+                )?;
+            } else {
+                let (all_order_ids, canceled_order_ids) = book.cancel_all_with_size_incentives(
+                    &mut mango_account,
+                    &mut perp_market,
+                    market_index,
+                    limit,
+                )?;
+                mango_emit_heap!(CancelAllPerpOrdersLog {
+                    mango_group: *mango_group_ai.key,
+                    mango_account: *mango_account_ai.key,
+                    market_index: market_index as u64,
+                    all_order_ids,
+                    canceled_order_ids
+                });
+            }
+            cancel_iceberg_orders(
+                program_id,
+                Some(iceberg_orders_ai),
+                &mut mango_account,
+                mango_account_ai.key,
+                |o| o.market_index as usize == market_index,
+            )?;
 
-        // Settle funds released by canceling open orders
-        invoke_settle_funds(
-            dex_prog_ai,
-            spot_market_ai,
-            open_orders_ai,
-            signer_ai,
-            dex_base_ai,
-            dex_quote_ai,
-            base_vault_ai,
-            quote_vault_ai,
-            dex_signer_ai,
-            token_prog_ai,
-            &[&signer_seeds],
-        )?;
+            let index_price = perp_market.last_index_price;
+            perp_market.update_premium(&book, index_price, now_ts);
 
-        let (post_base, post_quote) = {
-            let open_orders = load_open_orders(open_orders_ai)?;
-            mango_account.update_basket(market_index, &open_orders)?;
-            mango_emit_stack::<_, 256>(OpenOrdersBalanceLog {
+            mango_emit_heap!(MngoAccrualLog {
                 mango_group: *mango_group_ai.key,
                 mango_account: *mango_account_ai.key,
                 market_index: market_index as u64,
-                base_total: open_orders.native_coin_total,
-                base_free: open_orders.native_coin_free,
-                quote_total: open_orders.native_pc_total,
-                quote_free: open_orders.native_pc_free,
-                referrer_rebates_accrued: open_orders.referrer_rebates_accrued,
+                mngo_accrual: mango_account.perp_accounts[market_index].mngo_accrued - mngo_start
             });
+        }
 
-            (
-                open_orders.native_coin_free,
-                open_orders.native_pc_free + open_orders.referrer_rebates_accrued,
-            )
-        };
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn create_perp_otc_order(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        price: i64,
+        size: u64,
+        expires: UnixTimestamp,
+        side: Side,
+    ) -> MangoResult {
+        const NUM_FIXED: usize = 8;
+
+        let [otc_orders_pda_ai, mango_group_ai, creator_mango_account_ai, counterparty_wallet_ai, perp_market_ai, otc_order_owner_ai, clock_ai, system_program_ai] =
+            array_ref![accounts, 0, NUM_FIXED];
+
+        // Unpack accounts state
+        let mango_group_state = MangoGroup::load_checked(mango_group_ai, program_id)?;
+
+        let creator_mango_account_state =
+            MangoAccount::load_checked(creator_mango_account_ai, program_id, mango_group_ai.key)?;
+
+        let _perp_market_state =
+            PerpMarket::load_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+
+        let mut otc_orders = OtcOrders::load_mut_checked(otc_orders_pda_ai, program_id)?;
+
+        // Check accounts
+        check!(otc_order_owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check!(
+            otc_order_owner_ai.key != counterparty_wallet_ai.key,
+            MangoErrorCode::InvalidAccount
+        )?;
+        check_eq!(
+            otc_order_owner_ai.key,
+            &creator_mango_account_state.owner,
+            MangoErrorCode::InvalidAccount
+        )?;
+        check_eq!(
+            creator_mango_account_ai.key,
+            &otc_orders.creator_account,
+            MangoErrorCode::InvalidAccount
+        )?;
+        check_eq!(
+            clock_ai.key,
+            &solana_program::sysvar::clock::id(),
+            MangoErrorCode::InvalidAccount
+        )?;
+        check_eq!(
+            system_program_ai.key,
+            &solana_program::system_program::id(),
+            MangoErrorCode::InvalidProgramId
+        )?;
+
+        let (otc_orders_pda, _) = Pubkey::find_program_address(
+            &[OTC_ORDERS_PREFIX.as_bytes(), creator_mango_account_ai.key.as_ref()],
+            program_id,
+        );
+        check_eq!(&otc_orders_pda, otc_orders_pda_ai.key, MangoErrorCode::InvalidProgramId)?;
+
+        let clock = Clock::get()?;
+
+        check!(clock.unix_timestamp < expires, MangoErrorCode::InvalidTimeArgument)?;
+
+        let perp_account_index = mango_group_state
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
+
+        // Add perp OTC order
+        let perp_otc_order = PerpOtcOrder::new(
+            side,
+            price,
+            size,
+            clock.unix_timestamp,
+            expires,
+            counterparty_wallet_ai.key.clone(),
+            perp_market_ai.key.clone(),
+            perp_account_index,
+        );
+
+        otc_orders.add_perp_order(perp_otc_order)?;
 
-        check!(post_base <= pre_base, MangoErrorCode::Default)?;
-        check!(post_quote <= pre_quote, MangoErrorCode::Default)?;
+        Ok(())
+    }
 
-        // Update balances from settling funds
-        let base_change = I80F48::from_num(pre_base - post_base);
-        let quote_change = I80F48::from_num(pre_quote - post_quote);
+    #[inline(never)]
+    fn cancel_perp_otc_order(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        order_id: usize,
+    ) -> MangoResult {
+        const NUM_FIXED: usize = 5;
 
-        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
-        let clock = Clock::get()?;
-        let now_ts = clock.unix_timestamp as u64;
+        let [otc_orders_pda_ai, mango_group_ai, creator_mango_account_ai, otc_order_owner_ai, system_program_ai] =
+            array_ref![accounts, 0, NUM_FIXED];
 
-        mango_cache.root_bank_cache[market_index].check_valid(&mango_group, now_ts)?;
-        mango_cache.root_bank_cache[QUOTE_INDEX].check_valid(&mango_group, now_ts)?;
+        // Unpack accounts state
+        let _mango_group_state = MangoGroup::load_checked(mango_group_ai, program_id)?;
+
+        let creator_mango_account_state =
+            MangoAccount::load_checked(creator_mango_account_ai, program_id, mango_group_ai.key)?;
+
+        let mut otc_orders = OtcOrders::load_mut_checked(otc_orders_pda_ai, program_id)?;
 
+        // Check accounts
+        check!(otc_order_owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
         check_eq!(
-            &mango_group.tokens[market_index].root_bank,
-            base_root_bank_ai.key,
-            MangoErrorCode::InvalidRootBank
+            otc_order_owner_ai.key,
+            &creator_mango_account_state.owner,
+            MangoErrorCode::InvalidAccount
         )?;
-        let base_root_bank = RootBank::load_checked(base_root_bank_ai, program_id)?;
-
-        check!(
-            base_root_bank.node_banks.contains(base_node_bank_ai.key),
-            MangoErrorCode::InvalidNodeBank
+        check_eq!(
+            creator_mango_account_ai.key,
+            &otc_orders.creator_account,
+            MangoErrorCode::InvalidAccount
         )?;
-        let mut base_node_bank = NodeBank::load_mut_checked(base_node_bank_ai, program_id)?;
-        check_eq!(&base_node_bank.vault, base_vault_ai.key, MangoErrorCode::InvalidVault)?;
-
         check_eq!(
-            &mango_group.tokens[QUOTE_INDEX].root_bank,
-            quote_root_bank_ai.key,
-            MangoErrorCode::InvalidRootBank
+            system_program_ai.key,
+            &solana_program::system_program::id(),
+            MangoErrorCode::InvalidProgramId
         )?;
-        let quote_root_bank = RootBank::load_checked(quote_root_bank_ai, program_id)?;
 
-        check!(
-            quote_root_bank.node_banks.contains(quote_node_bank_ai.key),
-            MangoErrorCode::InvalidNodeBank
-        )?;
-        let mut quote_node_bank = NodeBank::load_mut_checked(quote_node_bank_ai, program_id)?;
-        check_eq!(&quote_node_bank.vault, quote_vault_ai.key, MangoErrorCode::InvalidVault)?;
+        let (otc_orders_pda, _) = Pubkey::find_program_address(
+            &[OTC_ORDERS_PREFIX.as_bytes(), creator_mango_account_ai.key.as_ref()],
+            program_id,
+        );
+        check_eq!(&otc_orders_pda, otc_orders_pda_ai.key, MangoErrorCode::InvalidProgramId)?;
+
+        otc_orders.cancel_perp_order_by_index(order_id)?;
 
-        checked_change_net(
-            &mango_cache.root_bank_cache[market_index],
-            &mut base_node_bank,
-            &mut mango_account,
-            mango_account_ai.key,
-            market_index,
-            base_change,
-        )?;
-        checked_change_net(
-            &mango_cache.root_bank_cache[QUOTE_INDEX],
-            &mut quote_node_bank,
-            &mut mango_account,
-            mango_account_ai.key,
-            QUOTE_INDEX,
-            quote_change,
-        )?;
         Ok(())
     }
 
     #[inline(never)]
-    fn init_otc_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult {
-        const NUM_FIXED: usize = 6;
+    fn delete_all_perp_otc_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult {
+        const NUM_FIXED: usize = 5;
 
-        let [otc_orders_pda_ai, mango_group_ai, creator_mango_account_ai, otc_order_owner_ai, rent_ai, system_program_ai] =
+        let [otc_orders_pda_ai, mango_group_ai, creator_mango_account_ai, otc_order_owner_ai, system_program_ai] =
             array_ref![accounts, 0, NUM_FIXED];
 
         // Unpack accounts state
@@ -6302,6 +8151,8 @@ impl Processor {
         let creator_mango_account_state =
             MangoAccount::load_checked(creator_mango_account_ai, program_id, mango_group_ai.key)?;
 
+        let mut otc_orders = OtcOrders::load_mut_checked(otc_orders_pda_ai, program_id)?;
+
         // Check accounts
         check!(otc_order_owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
         check_eq!(
@@ -6310,8 +8161,8 @@ impl Processor {
             MangoErrorCode::InvalidAccount
         )?;
         check_eq!(
-            rent_ai.key,
-            &solana_program::sysvar::rent::id(),
+            creator_mango_account_ai.key,
+            &otc_orders.creator_account,
             MangoErrorCode::InvalidAccount
         )?;
         check_eq!(
@@ -6320,67 +8171,82 @@ impl Processor {
             MangoErrorCode::InvalidProgramId
         )?;
 
-        let (otc_orders_pda, bump_seed) = Pubkey::find_program_address(
+        let (otc_orders_pda, _) = Pubkey::find_program_address(
             &[OTC_ORDERS_PREFIX.as_bytes(), creator_mango_account_ai.key.as_ref()],
             program_id,
         );
         check_eq!(&otc_orders_pda, otc_orders_pda_ai.key, MangoErrorCode::InvalidProgramId)?;
 
-        let rent = Rent::get()?;
-
-        create_pda_account(
-            otc_order_owner_ai,
-            &rent,
-            size_of::<OtcOrders>(),
-            program_id,
-            system_program_ai,
-            otc_orders_pda_ai,
-            &[OTC_ORDERS_PREFIX.as_bytes(), creator_mango_account_ai.key.as_ref(), &[bump_seed]],
-            &[],
-        )?;
-
-        let _ = OtcOrders::load_and_init(
-            otc_orders_pda_ai,
-            program_id,
-            &rent,
-            creator_mango_account_ai.key,
-            bump_seed,
-        )?;
+        otc_orders.delete_all_perp_orders()?;
 
         Ok(())
     }
 
     #[inline(never)]
-    fn create_perp_otc_order(
+    fn cancel_spot_otc_order(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        price: i64,
-        size: u64,
-        expires: UnixTimestamp,
-        side: Side,
+        order_id: usize,
     ) -> MangoResult {
-        const NUM_FIXED: usize = 8;
+        const NUM_FIXED: usize = 5;
 
-        let [otc_orders_pda_ai, mango_group_ai, creator_mango_account_ai, counterparty_wallet_ai, perp_market_ai, otc_order_owner_ai, clock_ai, system_program_ai] =
+        let [otc_orders_pda_ai, mango_group_ai, creator_mango_account_ai, otc_order_owner_ai, system_program_ai] =
             array_ref![accounts, 0, NUM_FIXED];
 
         // Unpack accounts state
-        let mango_group_state = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        let _mango_group_state = MangoGroup::load_checked(mango_group_ai, program_id)?;
 
         let creator_mango_account_state =
             MangoAccount::load_checked(creator_mango_account_ai, program_id, mango_group_ai.key)?;
 
-        let _perp_market_state =
-            PerpMarket::load_checked(perp_market_ai, program_id, mango_group_ai.key)?;
-
         let mut otc_orders = OtcOrders::load_mut_checked(otc_orders_pda_ai, program_id)?;
 
         // Check accounts
         check!(otc_order_owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
-        check!(
-            otc_order_owner_ai.key != counterparty_wallet_ai.key,
+        check_eq!(
+            otc_order_owner_ai.key,
+            &creator_mango_account_state.owner,
+            MangoErrorCode::InvalidAccount
+        )?;
+        check_eq!(
+            creator_mango_account_ai.key,
+            &otc_orders.creator_account,
             MangoErrorCode::InvalidAccount
         )?;
+        check_eq!(
+            system_program_ai.key,
+            &solana_program::system_program::id(),
+            MangoErrorCode::InvalidProgramId
+        )?;
+
+        let (otc_orders_pda, _) = Pubkey::find_program_address(
+            &[OTC_ORDERS_PREFIX.as_bytes(), creator_mango_account_ai.key.as_ref()],
+            program_id,
+        );
+        check_eq!(&otc_orders_pda, otc_orders_pda_ai.key, MangoErrorCode::InvalidProgramId)?;
+
+        otc_orders.cancel_spot_order_by_index(order_id)?;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn delete_all_spot_otc_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult {
+        const NUM_FIXED: usize = 5;
+
+        let [otc_orders_pda_ai, mango_group_ai, creator_mango_account_ai, otc_order_owner_ai, system_program_ai] =
+            array_ref![accounts, 0, NUM_FIXED];
+
+        // Unpack accounts state
+        let _mango_group_state = MangoGroup::load_checked(mango_group_ai, program_id)?;
+
+        let creator_mango_account_state =
+            MangoAccount::load_checked(creator_mango_account_ai, program_id, mango_group_ai.key)?;
+
+        let mut otc_orders = OtcOrders::load_mut_checked(otc_orders_pda_ai, program_id)?;
+
+        // Check accounts
+        check!(otc_order_owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
         check_eq!(
             otc_order_owner_ai.key,
             &creator_mango_account_state.owner,
@@ -6392,8 +8258,83 @@ impl Processor {
             MangoErrorCode::InvalidAccount
         )?;
         check_eq!(
-            clock_ai.key,
-            &solana_program::sysvar::clock::id(),
+            system_program_ai.key,
+            &solana_program::system_program::id(),
+            MangoErrorCode::InvalidProgramId
+        )?;
+
+        let (otc_orders_pda, _) = Pubkey::find_program_address(
+            &[OTC_ORDERS_PREFIX.as_bytes(), creator_mango_account_ai.key.as_ref()],
+            program_id,
+        );
+        check_eq!(&otc_orders_pda, otc_orders_pda_ai.key, MangoErrorCode::InvalidProgramId)?;
+
+        otc_orders.delete_all_spot_orders()?;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn take_perp_otc_order(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        order_id: usize,
+        open_orders_count: usize,
+    ) -> MangoResult {
+        const NUM_FIXED: usize = 9;
+
+        let [otc_orders_pda_ai, mango_group_ai, counterparty_mango_account_ai, creator_mango_account_ai, owner_ai, perp_market_ai, mango_cache_ai, _clock_ai, system_program_ai] =
+            array_ref![accounts, 0, NUM_FIXED];
+
+        let open_orders_ais = &accounts[NUM_FIXED..];
+        let (packed_open_orders_counterparty_ais, packed_open_orders_creator_ais): (
+            &[AccountInfo],
+            &[AccountInfo],
+        ) = if open_orders_ais.is_empty() {
+            (&[], &[])
+        } else {
+            let packed_open_orders_counterparty_ais =
+                if open_orders_count == 0 { &[] } else { &open_orders_ais[..open_orders_count] };
+
+            let packed_open_orders_creator_ais = &open_orders_ais[open_orders_count..];
+
+            (packed_open_orders_counterparty_ais, packed_open_orders_creator_ais)
+        };
+
+        // Unpack accounts state
+        let mango_group_state = MangoGroup::load_checked(mango_group_ai, program_id)?;
+
+        let mut creator_mango_account_state = MangoAccount::load_mut_checked(
+            creator_mango_account_ai,
+            program_id,
+            mango_group_ai.key,
+        )?;
+
+        let mut counterparty_mango_account_state = MangoAccount::load_mut_checked(
+            counterparty_mango_account_ai,
+            program_id,
+            mango_group_ai.key,
+        )?;
+
+        let mango_cache_state =
+            MangoCache::load_checked(mango_cache_ai, program_id, &mango_group_state)?;
+
+        let mut perp_market_state =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+
+        let mut otc_orders = OtcOrders::load_mut_checked(otc_orders_pda_ai, program_id)?;
+
+        // Check accounts
+        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check!(!counterparty_mango_account_state.is_bankrupt, MangoErrorCode::Bankrupt)?;
+        check_eq!(
+            owner_ai.key,
+            &counterparty_mango_account_state.owner,
+            MangoErrorCode::InvalidAccount
+        )?;
+        check_eq!(
+            creator_mango_account_ai.key,
+            &otc_orders.creator_account,
             MangoErrorCode::InvalidAccount
         )?;
         check_eq!(
@@ -6409,482 +8350,676 @@ impl Processor {
         check_eq!(&otc_orders_pda, otc_orders_pda_ai.key, MangoErrorCode::InvalidProgramId)?;
 
         let clock = Clock::get()?;
+        check!(
+            !perp_market_state.is_expired(clock.unix_timestamp as u64),
+            MangoErrorCode::PerpMarketExpired
+        )?;
 
-        check!(clock.unix_timestamp < expires, MangoErrorCode::InvalidTimeArgument)?;
+        let order = otc_orders.get_mut_perp_order(order_id)?;
+        check_eq!(&order.counterparty_wallet, owner_ai.key, MangoErrorCode::InvalidAccount)?;
+        check_eq!(&order.perp_market, perp_market_ai.key, MangoErrorCode::InvalidAccount)?;
+        check!(order.expires > clock.unix_timestamp, MangoErrorCode::OtcOrderExpired)?;
+        check_eq!(order.status, OtcOrderStatus::Created, MangoErrorCode::InvalidOtcOrderStatus)?;
+        order.status = OtcOrderStatus::Filled;
 
-        let perp_account_index = mango_group_state
-            .find_perp_market_index(perp_market_ai.key)
-            .ok_or(throw_err!(MangoErrorCode::InvalidMarket))?;
+        let perp_market_cache = &mango_cache_state.perp_market_cache[order.perp_account_index];
 
-        // Add perp OTC order
-        let perp_otc_order = PerpOtcOrder::new(
-            side,
-            price,
-            size,
-            clock.unix_timestamp,
-            expires,
-            counterparty_wallet_ai.key.clone(),
-            perp_market_ai.key.clone(),
-            perp_account_index,
+        let counterparty_open_orders_ais = counterparty_mango_account_state
+            .checked_unpack_open_orders(&mango_group_state, packed_open_orders_counterparty_ais)?;
+        let counterparty_open_orders_accounts =
+            load_open_orders_accounts(&counterparty_open_orders_ais)?;
+
+        let creator_open_orders_ais = creator_mango_account_state
+            .checked_unpack_open_orders(&mango_group_state, packed_open_orders_creator_ais)?;
+        let creator_open_orders_accounts = load_open_orders_accounts(&creator_open_orders_ais)?;
+
+        // Main logic
+        let active_assets_counterparty = UserActiveAssets::new(
+            &mango_group_state,
+            &counterparty_mango_account_state,
+            vec![(AssetType::Perp, order.perp_account_index)],
         );
 
-        otc_orders.add_perp_order(perp_otc_order)?;
+        let active_assets_creator = UserActiveAssets::new(
+            &mango_group_state,
+            &creator_mango_account_state,
+            vec![(AssetType::Perp, order.perp_account_index)],
+        );
 
-        Ok(())
-    }
+        mango_cache_state.check_valid(
+            &mango_group_state,
+            &active_assets_counterparty,
+            clock.unix_timestamp as u64,
+        )?;
+        mango_cache_state.check_valid(
+            &mango_group_state,
+            &active_assets_creator,
+            clock.unix_timestamp as u64,
+        )?;
 
-    #[inline(never)]
-    fn cancel_perp_otc_order(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        order_id: usize,
-    ) -> MangoResult {
-        const NUM_FIXED: usize = 5;
+        let mut health_cache_counterparty = HealthCache::new(active_assets_counterparty);
+        let mut health_cache_creator = HealthCache::new(active_assets_creator);
 
-        let [otc_orders_pda_ai, mango_group_ai, creator_mango_account_ai, otc_order_owner_ai, system_program_ai] =
-            array_ref![accounts, 0, NUM_FIXED];
+        health_cache_counterparty.init_vals_with_orders_vec(
+            &mango_group_state,
+            &mango_cache_state,
+            &counterparty_mango_account_state,
+            &counterparty_open_orders_accounts,
+        )?;
+        health_cache_creator.init_vals_with_orders_vec(
+            &mango_group_state,
+            &mango_cache_state,
+            &creator_mango_account_state,
+            &creator_open_orders_accounts,
+        )?;
 
-        // Unpack accounts state
-        let _mango_group_state = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        let pre_health_counterparty =
+            health_cache_counterparty.get_health(&mango_group_state, HealthType::Init);
+        let pre_health_creator =
+            health_cache_creator.get_health(&mango_group_state, HealthType::Init);
 
-        let creator_mango_account_state =
-            MangoAccount::load_checked(creator_mango_account_ai, program_id, mango_group_ai.key)?;
+        // Update the being_liquidated flag
+        if counterparty_mango_account_state.being_liquidated {
+            if pre_health_counterparty >= ZERO_I80F48 {
+                counterparty_mango_account_state.being_liquidated = false;
+            } else {
+                return Err(throw_err!(MangoErrorCode::BeingLiquidated));
+            }
+        }
 
-        let mut otc_orders = OtcOrders::load_mut_checked(otc_orders_pda_ai, program_id)?;
+        // Update the being_liquidated flag
+        if creator_mango_account_state.being_liquidated {
+            if pre_health_creator >= ZERO_I80F48 {
+                creator_mango_account_state.being_liquidated = false;
+            } else {
+                return Err(throw_err!(MangoErrorCode::BeingLiquidated));
+            }
+        }
 
-        // Check accounts
-        check!(otc_order_owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
-        check_eq!(
-            otc_order_owner_ai.key,
-            &creator_mango_account_state.owner,
-            MangoErrorCode::InvalidAccount
-        )?;
-        check_eq!(
-            creator_mango_account_ai.key,
-            &otc_orders.creator_account,
-            MangoErrorCode::InvalidAccount
+        // This means health must only go up
+        let health_up_only_counterparty = pre_health_counterparty < ZERO_I80F48;
+
+        // This means health must only go up
+        let health_up_only_creator = pre_health_creator < ZERO_I80F48;
+
+        let match_qty: i64 =
+            order.size.try_into().map_err(|_| throw_err!(MangoErrorCode::MathError))?;
+        let match_quote =
+            match_qty.checked_mul(order.price).ok_or(throw_err!(MangoErrorCode::MathError))?;
+
+        // Negative `match_qty`, positive `match_quote` for selling account
+        // Positive `match_qty`, negative `match_quote` for buying account
+
+        let (match_qty_creator, match_quote_creator) = if order.creator_side == Side::Bid {
+            (match_qty, -match_quote)
+        } else {
+            (-match_qty, match_quote)
+        };
+
+        let counterparty_base =
+            counterparty_mango_account_state.perp_accounts[order.perp_account_index].base_position;
+        let creator_base =
+            creator_mango_account_state.perp_accounts[order.perp_account_index].base_position;
+        perp_market_state
+            .check_max_position(counterparty_base, counterparty_base - match_qty_creator)?;
+        perp_market_state.check_max_position(creator_base, creator_base + match_qty_creator)?;
+        perp_market_state.check_max_open_interest(
+            (counterparty_base - match_qty_creator).abs() - counterparty_base.abs()
+                + (creator_base + match_qty_creator).abs()
+                - creator_base.abs(),
         )?;
-        check_eq!(
-            system_program_ai.key,
-            &solana_program::system_program::id(),
-            MangoErrorCode::InvalidProgramId
+
+        counterparty_mango_account_state.perp_accounts[order.perp_account_index]
+            .add_taker_trade(match_qty_creator * -1, match_quote_creator * -1);
+        creator_mango_account_state.perp_accounts[order.perp_account_index]
+            .add_taker_trade(match_qty_creator, match_quote_creator);
+
+        // Rebase counterparty position
+        let counterparty_fill = FillEvent::new(
+            if order.creator_side == Side::Ask { Side::Bid } else { Side::Ask },
+            0,
+            false,
+            0,
+            0,
+            creator_mango_account_state.owner,
+            0,
+            0,
+            I80F48!(0),
+            0,
+            0,
+            *owner_ai.key,
+            0,
+            0,
+            I80F48!(0),
+            order.price,
+            match_qty,
+            0,
+        );
+        counterparty_mango_account_state.execute_taker(
+            order.perp_account_index,
+            &mut perp_market_state,
+            perp_market_cache,
+            &counterparty_fill,
         )?;
 
-        let (otc_orders_pda, _) = Pubkey::find_program_address(
-            &[OTC_ORDERS_PREFIX.as_bytes(), creator_mango_account_ai.key.as_ref()],
-            program_id,
+        // Rebase creator position
+        let creator_fill = FillEvent::new(
+            order.creator_side,
+            0,
+            false,
+            0,
+            0,
+            *owner_ai.key,
+            0,
+            0,
+            I80F48!(0),
+            0,
+            0,
+            creator_mango_account_state.owner,
+            0,
+            0,
+            I80F48!(0),
+            order.price,
+            match_qty,
+            0,
         );
-        check_eq!(&otc_orders_pda, otc_orders_pda_ai.key, MangoErrorCode::InvalidProgramId)?;
+        creator_mango_account_state.execute_taker(
+            order.perp_account_index,
+            &mut perp_market_state,
+            perp_market_cache,
+            &creator_fill,
+        )?;
+
+        health_cache_counterparty.update_perp_val(
+            &mango_group_state,
+            &mango_cache_state,
+            &counterparty_mango_account_state,
+            order.perp_account_index,
+        )?;
+        health_cache_creator.update_perp_val(
+            &mango_group_state,
+            &mango_cache_state,
+            &creator_mango_account_state,
+            order.perp_account_index,
+        )?;
+
+        let post_health_counterparty =
+            health_cache_counterparty.get_health(&mango_group_state, HealthType::Init);
+        let post_health_creator =
+            health_cache_creator.get_health(&mango_group_state, HealthType::Init);
+
+        check!(
+            post_health_counterparty >= ZERO_I80F48
+                || (health_up_only_counterparty
+                    && post_health_counterparty >= pre_health_counterparty),
+            MangoErrorCode::InsufficientFunds
+        )?;
+        check!(
+            post_health_creator >= ZERO_I80F48
+                || (health_up_only_creator && post_health_creator >= pre_health_creator),
+            MangoErrorCode::InsufficientFunds
+        )
+    }
+
+    #[inline(never)]
+    /// Change the impact quantity and funding clamp used in update_funding
+    fn change_perp_market_funding_params(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        impact_quantity: Option<i64>,
+        max_funding: Option<I80F48>,
+        min_funding: Option<I80F48>,
+    ) -> MangoResult {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai, // read
+            perp_market_ai, // write
+            admin_ai        // read, signer
+        ] = accounts;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+        check!(
+            mango_group.find_perp_market_index(perp_market_ai.key).is_some(),
+            MangoErrorCode::InvalidMarket
+        )?;
+
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+
+        let impact_quantity = impact_quantity.unwrap_or(perp_market.impact_quantity);
+        let max_funding = max_funding.unwrap_or(perp_market.max_funding);
+        let min_funding = min_funding.unwrap_or(perp_market.min_funding);
 
-        otc_orders.cancel_perp_order_by_index(order_id)?;
+        check!(impact_quantity > 0, MangoErrorCode::InvalidParam)?;
+        check!(!max_funding.is_negative(), MangoErrorCode::InvalidParam)?;
+        check!(!min_funding.is_positive(), MangoErrorCode::InvalidParam)?;
+
+        perp_market.impact_quantity = impact_quantity;
+        perp_market.max_funding = max_funding;
+        perp_market.min_funding = min_funding;
 
         Ok(())
     }
 
     #[inline(never)]
-    fn delete_all_perp_otc_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult {
-        const NUM_FIXED: usize = 5;
-
-        let [otc_orders_pda_ai, mango_group_ai, creator_mango_account_ai, otc_order_owner_ai, system_program_ai] =
-            array_ref![accounts, 0, NUM_FIXED];
-
-        // Unpack accounts state
-        let _mango_group_state = MangoGroup::load_checked(mango_group_ai, program_id)?;
+    /// Change the hard caps on position size and open interest of a perp market
+    fn change_perp_market_position_limits(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        max_position_lots: Option<i64>,
+        max_open_interest: Option<i64>,
+    ) -> MangoResult {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai, // read
+            perp_market_ai, // write
+            admin_ai        // read, signer
+        ] = accounts;
 
-        let creator_mango_account_state =
-            MangoAccount::load_checked(creator_mango_account_ai, program_id, mango_group_ai.key)?;
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+        check!(
+            mango_group.find_perp_market_index(perp_market_ai.key).is_some(),
+            MangoErrorCode::InvalidMarket
+        )?;
 
-        let mut otc_orders = OtcOrders::load_mut_checked(otc_orders_pda_ai, program_id)?;
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
 
-        // Check accounts
-        check!(otc_order_owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
-        check_eq!(
-            otc_order_owner_ai.key,
-            &creator_mango_account_state.owner,
-            MangoErrorCode::InvalidAccount
-        )?;
-        check_eq!(
-            creator_mango_account_ai.key,
-            &otc_orders.creator_account,
-            MangoErrorCode::InvalidAccount
-        )?;
-        check_eq!(
-            system_program_ai.key,
-            &solana_program::system_program::id(),
-            MangoErrorCode::InvalidProgramId
-        )?;
+        let max_position_lots = max_position_lots.unwrap_or(perp_market.max_position_lots);
+        let max_open_interest = max_open_interest.unwrap_or(perp_market.max_open_interest);
 
-        let (otc_orders_pda, _) = Pubkey::find_program_address(
-            &[OTC_ORDERS_PREFIX.as_bytes(), creator_mango_account_ai.key.as_ref()],
-            program_id,
-        );
-        check_eq!(&otc_orders_pda, otc_orders_pda_ai.key, MangoErrorCode::InvalidProgramId)?;
+        check!(max_position_lots >= 0, MangoErrorCode::InvalidParam)?;
+        check!(max_open_interest >= 0, MangoErrorCode::InvalidParam)?;
 
-        otc_orders.delete_all_perp_orders()?;
+        perp_market.max_position_lots = max_position_lots;
+        perp_market.max_open_interest = max_open_interest;
 
         Ok(())
     }
 
     #[inline(never)]
-    fn cancel_spot_otc_order(
+    /// Change the limits applied to the oracle price and cached price at an oracle index
+    fn change_oracle_limits(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        order_id: usize,
+        max_conf_bps: Option<u16>,
+        max_oracle_age_slots: Option<u16>,
+        price_valid_interval: Option<u16>,
+        max_price_move_bps: Option<u16>,
     ) -> MangoResult {
-        const NUM_FIXED: usize = 5;
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai, // write
+            oracle_ai,      // read
+            admin_ai        // read, signer
+        ] = accounts;
 
-        let [otc_orders_pda_ai, mango_group_ai, creator_mango_account_ai, otc_order_owner_ai, system_program_ai] =
-            array_ref![accounts, 0, NUM_FIXED];
+        let mut mango_group = MangoGroup::load_mut_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+        let oracle_index = mango_group
+            .find_oracle_index(oracle_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidParam))?;
+
+        let info = &mut mango_group.tokens[oracle_index];
+        if let Some(max_conf_bps) = max_conf_bps {
+            check!(max_conf_bps <= 10_000, MangoErrorCode::InvalidParam)?;
+            info.max_conf_bps = max_conf_bps;
+        }
+        if let Some(max_oracle_age_slots) = max_oracle_age_slots {
+            info.max_oracle_age_slots = max_oracle_age_slots;
+        }
+        if let Some(price_valid_interval) = price_valid_interval {
+            info.price_valid_interval = price_valid_interval;
+        }
+        if let Some(max_price_move_bps) = max_price_move_bps {
+            mango_group.perp_markets[oracle_index].max_price_move_bps = max_price_move_bps;
+        }
 
-        // Unpack accounts state
-        let _mango_group_state = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        Ok(())
+    }
 
-        let creator_mango_account_state =
-            MangoAccount::load_checked(creator_mango_account_ai, program_id, mango_group_ai.key)?;
+    #[inline(never)]
+    /// Accept the price that tripped the circuit breaker at an oracle index and clear the halt
+    fn clear_price_halt(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult {
+        const NUM_FIXED: usize = 4;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai, // read
+            mango_cache_ai, // write
+            oracle_ai,      // read
+            admin_ai        // read, signer
+        ] = accounts;
 
-        let mut otc_orders = OtcOrders::load_mut_checked(otc_orders_pda_ai, program_id)?;
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+        let mut mango_cache =
+            MangoCache::load_mut_checked(mango_cache_ai, program_id, &mango_group)?;
+        let oracle_index = mango_group
+            .find_oracle_index(oracle_ai.key)
+            .ok_or(throw_err!(MangoErrorCode::InvalidParam))?;
 
-        // Check accounts
-        check!(otc_order_owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
-        check_eq!(
-            otc_order_owner_ai.key,
-            &creator_mango_account_state.owner,
-            MangoErrorCode::InvalidAccount
-        )?;
-        check_eq!(
-            creator_mango_account_ai.key,
-            &otc_orders.creator_account,
-            MangoErrorCode::InvalidAccount
-        )?;
-        check_eq!(
-            system_program_ai.key,
-            &solana_program::system_program::id(),
-            MangoErrorCode::InvalidProgramId
-        )?;
+        let price_cache = &mut mango_cache.price_cache[oracle_index];
+        check!(price_cache.is_halted(), MangoErrorCode::InvalidParam)?;
 
-        let (otc_orders_pda, _) = Pubkey::find_program_address(
-            &[OTC_ORDERS_PREFIX.as_bytes(), creator_mango_account_ai.key.as_ref()],
-            program_id,
-        );
-        check_eq!(&otc_orders_pda, otc_orders_pda_ai.key, MangoErrorCode::InvalidProgramId)?;
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let price = price_cache.halted_price;
+        price_cache.halted_price = ZERO_I80F48;
+        price_cache.halt_ts = 0;
+        price_cache.update(price, now_ts, mango_group.perp_markets[oracle_index].ema_period);
 
-        otc_orders.cancel_spot_order_by_index(order_id)?;
+        mango_emit_heap!(CachePricesLog {
+            mango_group: *mango_group_ai.key,
+            oracle_indexes: vec![oracle_index as u64],
+            oracle_prices: vec![price.to_bits()]
+        });
 
         Ok(())
     }
 
     #[inline(never)]
-    fn delete_all_spot_otc_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult {
-        const NUM_FIXED: usize = 5;
-
-        let [otc_orders_pda_ai, mango_group_ai, creator_mango_account_ai, otc_order_owner_ai, system_program_ai] =
-            array_ref![accounts, 0, NUM_FIXED];
-
-        // Unpack accounts state
-        let _mango_group_state = MangoGroup::load_checked(mango_group_ai, program_id)?;
-
-        let creator_mango_account_state =
-            MangoAccount::load_checked(creator_mango_account_ai, program_id, mango_group_ai.key)?;
-
-        let mut otc_orders = OtcOrders::load_mut_checked(otc_orders_pda_ai, program_id)?;
+    /// Initialize a DerivedOracle over two existing oracles and add it to the MangoGroup
+    fn add_derived_oracle(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        base_index: usize,
+        base_exponent: u8,
+        quote_index: usize,
+        quote_exponent: u8,
+        multiplier: I80F48,
+    ) -> MangoResult {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai, // write
+            oracle_ai,      // write
+            admin_ai        // read, signer
+        ] = accounts;
 
-        // Check accounts
-        check!(otc_order_owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
-        check_eq!(
-            otc_order_owner_ai.key,
-            &creator_mango_account_state.owner,
-            MangoErrorCode::InvalidAccount
-        )?;
-        check_eq!(
-            creator_mango_account_ai.key,
-            &otc_orders.creator_account,
-            MangoErrorCode::InvalidAccount
+        let mut mango_group = MangoGroup::load_mut_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+        check!(mango_group.num_oracles < MAX_PAIRS, MangoErrorCode::InvalidParam)?;
+        check!(base_index < mango_group.num_oracles, MangoErrorCode::InvalidParam)?;
+        check!(quote_index < mango_group.num_oracles, MangoErrorCode::InvalidParam)?;
+        check!(base_index != quote_index, MangoErrorCode::InvalidParam)?;
+        check!(
+            base_exponent > 0 && base_exponent <= MAX_INDEX_EXPONENT,
+            MangoErrorCode::InvalidParam
         )?;
-        check_eq!(
-            system_program_ai.key,
-            &solana_program::system_program::id(),
-            MangoErrorCode::InvalidProgramId
+        check!(
+            quote_exponent > 0 && quote_exponent <= MAX_INDEX_EXPONENT,
+            MangoErrorCode::InvalidParam
         )?;
+        check!(multiplier.is_positive(), MangoErrorCode::InvalidParam)?;
 
-        let (otc_orders_pda, _) = Pubkey::find_program_address(
-            &[OTC_ORDERS_PREFIX.as_bytes(), creator_mango_account_ai.key.as_ref()],
-            program_id,
-        );
-        check_eq!(&otc_orders_pda, otc_orders_pda_ai.key, MangoErrorCode::InvalidProgramId)?;
+        let rent = Rent::get()?;
+        let mut oracle = DerivedOracle::load_and_init(oracle_ai, program_id, &rent)?;
+        oracle.base_index = base_index as u64;
+        oracle.base_exponent = base_exponent;
+        oracle.quote_index = quote_index as u64;
+        oracle.quote_exponent = quote_exponent;
+        oracle.multiplier = multiplier;
 
-        otc_orders.delete_all_spot_orders()?;
+        let oracle_index = mango_group.num_oracles;
+        mango_group.oracles[oracle_index] = *oracle_ai.key;
+        mango_group.num_oracles += 1;
 
         Ok(())
     }
 
     #[inline(never)]
-    fn take_perp_otc_order(
+    /// Initialize a CompositeOracle over the passed in source oracles and add it to the MangoGroup
+    fn add_composite_oracle(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        order_id: usize,
-        open_orders_count: usize,
+        min_quorum: u8,
+        weights: [u16; MAX_COMPOSITE_SOURCES],
     ) -> MangoResult {
-        const NUM_FIXED: usize = 9;
-
-        let [otc_orders_pda_ai, mango_group_ai, counterparty_mango_account_ai, creator_mango_account_ai, owner_ai, perp_market_ai, mango_cache_ai, _clock_ai, system_program_ai] =
-            array_ref![accounts, 0, NUM_FIXED];
-
-        let open_orders_ais = &accounts[NUM_FIXED..];
-        let (packed_open_orders_counterparty_ais, packed_open_orders_creator_ais): (
-            &[AccountInfo],
-            &[AccountInfo],
-        ) = if open_orders_ais.is_empty() {
-            (&[], &[])
-        } else {
-            let packed_open_orders_counterparty_ais =
-                if open_orders_count == 0 { &[] } else { &open_orders_ais[..open_orders_count] };
-
-            let packed_open_orders_creator_ais = &open_orders_ais[open_orders_count..];
-
-            (packed_open_orders_counterparty_ais, packed_open_orders_creator_ais)
-        };
-
-        // Unpack accounts state
-        let mango_group_state = MangoGroup::load_checked(mango_group_ai, program_id)?;
-
-        let mut creator_mango_account_state = MangoAccount::load_mut_checked(
-            creator_mango_account_ai,
-            program_id,
-            mango_group_ai.key,
-        )?;
-
-        let mut counterparty_mango_account_state = MangoAccount::load_mut_checked(
-            counterparty_mango_account_ai,
-            program_id,
-            mango_group_ai.key,
-        )?;
-
-        let mango_cache_state =
-            MangoCache::load_checked(mango_cache_ai, program_id, &mango_group_state)?;
-
-        let mut perp_market_state =
-            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+        const NUM_FIXED: usize = 3;
+        let (fixed_ais, source_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            mango_group_ai, // write
+            oracle_ai,      // write
+            admin_ai        // read, signer
+        ] = fixed_ais;
 
-        let mut otc_orders = OtcOrders::load_mut_checked(otc_orders_pda_ai, program_id)?;
+        let mut mango_group = MangoGroup::load_mut_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+        check!(mango_group.num_oracles < MAX_PAIRS, MangoErrorCode::InvalidParam)?;
 
-        // Check accounts
-        check!(owner_ai.is_signer, MangoErrorCode::SignerNecessary)?;
-        check!(!counterparty_mango_account_state.is_bankrupt, MangoErrorCode::Bankrupt)?;
-        check_eq!(
-            owner_ai.key,
-            &counterparty_mango_account_state.owner,
-            MangoErrorCode::InvalidAccount
-        )?;
-        check_eq!(
-            creator_mango_account_ai.key,
-            &otc_orders.creator_account,
-            MangoErrorCode::InvalidAccount
+        let num_sources = source_ais.len();
+        check!(
+            num_sources > 0 && num_sources <= MAX_COMPOSITE_SOURCES,
+            MangoErrorCode::InvalidParam
         )?;
-        check_eq!(
-            system_program_ai.key,
-            &solana_program::system_program::id(),
-            MangoErrorCode::InvalidProgramId
+        check!(
+            min_quorum > 0 && (min_quorum as usize) <= num_sources,
+            MangoErrorCode::InvalidParam
         )?;
 
-        let (otc_orders_pda, _) = Pubkey::find_program_address(
-            &[OTC_ORDERS_PREFIX.as_bytes(), creator_mango_account_ai.key.as_ref()],
-            program_id,
-        );
-        check_eq!(&otc_orders_pda, otc_orders_pda_ai.key, MangoErrorCode::InvalidProgramId)?;
+        let rent = Rent::get()?;
+        let mut oracle = CompositeOracle::load_and_init(oracle_ai, program_id, &rent)?;
+        for (i, source_ai) in source_ais.iter().enumerate() {
+            match determine_oracle_type(source_ai) {
+                OracleType::Pyth
+                | OracleType::Switchboard
+                | OracleType::SwitchboardV2
+                | OracleType::Stub => {}
+                OracleType::PythPull => {
+                    PythPriceUpdate::load_checked(source_ai)?;
+                }
+                _ => return Err(throw_err!(MangoErrorCode::InvalidOracleType)),
+            }
+            check!(weights[i] > 0, MangoErrorCode::InvalidParam)?;
+            oracle.sources[i] = *source_ai.key;
+            oracle.weights[i] = weights[i];
+        }
+        oracle.num_sources = num_sources as u8;
+        oracle.min_quorum = min_quorum;
 
-        let clock = Clock::get()?;
+        let oracle_index = mango_group.num_oracles;
+        mango_group.oracles[oracle_index] = *oracle_ai.key;
+        mango_group.num_oracles += 1;
 
-        let order = otc_orders.get_mut_perp_order(order_id)?;
-        check_eq!(&order.counterparty_wallet, owner_ai.key, MangoErrorCode::InvalidAccount)?;
-        check_eq!(&order.perp_market, perp_market_ai.key, MangoErrorCode::InvalidAccount)?;
-        check!(order.expires > clock.unix_timestamp, MangoErrorCode::OtcOrderExpired)?;
-        check_eq!(order.status, OtcOrderStatus::Created, MangoErrorCode::InvalidOtcOrderStatus)?;
-        order.status = OtcOrderStatus::Filled;
+        Ok(())
+    }
 
-        let perp_market_cache = &mango_cache_state.perp_market_cache[order.perp_account_index];
+    #[inline(never)]
+    /// Initialize a RealizedVolOracle on another oracle and add it to the MangoGroup
+    fn add_realized_vol_oracle(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        source_index: usize,
+        window: u64,
+        min_sample_interval: u64,
+    ) -> MangoResult {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai, // write
+            oracle_ai,      // write
+            admin_ai        // read, signer
+        ] = accounts;
 
-        let counterparty_open_orders_ais = counterparty_mango_account_state
-            .checked_unpack_open_orders(&mango_group_state, packed_open_orders_counterparty_ais)?;
-        let counterparty_open_orders_accounts =
-            load_open_orders_accounts(&counterparty_open_orders_ais)?;
+        let mut mango_group = MangoGroup::load_mut_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+        check!(source_index < mango_group.num_oracles, MangoErrorCode::InvalidParam)?;
+        check!(mango_group.num_oracles < MAX_PAIRS, MangoErrorCode::InvalidParam)?;
+        check!(window > 0, MangoErrorCode::InvalidParam)?;
 
-        let creator_open_orders_ais = creator_mango_account_state
-            .checked_unpack_open_orders(&mango_group_state, packed_open_orders_creator_ais)?;
-        let creator_open_orders_accounts = load_open_orders_accounts(&creator_open_orders_ais)?;
+        let rent = Rent::get()?;
+        RealizedVolOracle::load_and_init(
+            oracle_ai,
+            program_id,
+            &rent,
+            source_index,
+            window,
+            min_sample_interval,
+        )?;
 
-        // Main logic
-        let active_assets_counterparty = UserActiveAssets::new(
-            &mango_group_state,
-            &counterparty_mango_account_state,
-            vec![(AssetType::Perp, order.perp_account_index)],
-        );
+        let oracle_index = mango_group.num_oracles;
+        mango_group.oracles[oracle_index] = *oracle_ai.key;
+        mango_group.num_oracles += 1;
 
-        let active_assets_creator = UserActiveAssets::new(
-            &mango_group_state,
-            &creator_mango_account_state,
-            vec![(AssetType::Perp, order.perp_account_index)],
-        );
+        Ok(())
+    }
 
-        mango_cache_state.check_valid(
-            &mango_group_state,
-            &active_assets_counterparty,
-            clock.unix_timestamp as u64,
-        )?;
-        mango_cache_state.check_valid(
-            &mango_group_state,
-            &active_assets_creator,
-            clock.unix_timestamp as u64,
+    #[inline(never)]
+    /// Turn a perp market into a dated future expiring at `expiry`, or back into a perpetual if
+    /// `expiry` is 0. Only possible before the market settles, so the expiry of a market whose
+    /// settlement window had too few samples can be moved out.
+    fn set_perp_market_expiry(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        expiry: u64,
+        settlement_window: u64,
+    ) -> MangoResult {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai, // read
+            perp_market_ai, // write
+            admin_ai        // read, signer
+        ] = accounts;
+
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        check!(admin_ai.is_signer, MangoErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &mango_group.admin, MangoErrorCode::InvalidAdminKey)?;
+        check!(
+            mango_group.find_perp_market_index(perp_market_ai.key).is_some(),
+            MangoErrorCode::InvalidMarket
         )?;
 
-        let mut health_cache_counterparty = HealthCache::new(active_assets_counterparty);
-        let mut health_cache_creator = HealthCache::new(active_assets_creator);
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
 
-        health_cache_counterparty.init_vals_with_orders_vec(
-            &mango_group_state,
-            &mango_cache_state,
-            &counterparty_mango_account_state,
-            &counterparty_open_orders_accounts,
-        )?;
-        health_cache_creator.init_vals_with_orders_vec(
-            &mango_group_state,
-            &mango_cache_state,
-            &creator_mango_account_state,
-            &creator_open_orders_accounts,
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        check!(!perp_market.is_settled(), MangoErrorCode::PerpMarketExpired)?;
+        check!(expiry == 0 || expiry > now_ts, MangoErrorCode::InvalidParam)?;
+        // at least a second between the samples
+        check!(
+            expiry == 0 || settlement_window >= MIN_SETTLEMENT_SAMPLES,
+            MangoErrorCode::InvalidParam
         )?;
 
-        let pre_health_counterparty =
-            health_cache_counterparty.get_health(&mango_group_state, HealthType::Init);
-        let pre_health_creator =
-            health_cache_creator.get_health(&mango_group_state, HealthType::Init);
+        perp_market.expiry = expiry;
+        perp_market.settlement_window = settlement_window;
+        perp_market.reset_settlement_samples();
 
-        // Update the being_liquidated flag
-        if counterparty_mango_account_state.being_liquidated {
-            if pre_health_counterparty >= ZERO_I80F48 {
-                counterparty_mango_account_state.being_liquidated = false;
-            } else {
-                return Err(throw_err!(MangoErrorCode::BeingLiquidated));
-            }
-        }
+        Ok(())
+    }
 
-        // Update the being_liquidated flag
-        if creator_mango_account_state.being_liquidated {
-            if pre_health_creator >= ZERO_I80F48 {
-                creator_mango_account_state.being_liquidated = false;
-            } else {
-                return Err(throw_err!(MangoErrorCode::BeingLiquidated));
-            }
-        }
+    #[inline(never)]
+    /// Sample the cached index price into the settlement TWAP of a dated perp market. The first
+    /// call at or after expiry sets the settlement price, in the PerpMarket and the MangoGroup.
+    fn settle_expired_perp_market(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai, // write
+            mango_cache_ai, // read
+            perp_market_ai, // write
+        ] = accounts;
 
-        // This means health must only go up
-        let health_up_only_counterparty = pre_health_counterparty < ZERO_I80F48;
+        let mut mango_group = MangoGroup::load_mut_checked(mango_group_ai, program_id)?;
+        let mango_cache = MangoCache::load_checked(mango_cache_ai, program_id, &mango_group)?;
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+        let market_index = mango_group.find_perp_market_index(perp_market_ai.key).unwrap();
 
-        // This means health must only go up
-        let health_up_only_creator = pre_health_creator < ZERO_I80F48;
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        check!(perp_market.expiry != 0, MangoErrorCode::InvalidParam)?;
+        check!(!perp_market.is_settled(), MangoErrorCode::InvalidParam)?;
+        check!(
+            now_ts >= perp_market.expiry.saturating_sub(perp_market.settlement_window),
+            MangoErrorCode::InvalidParam
+        )?;
 
-        let match_qty: i64 =
-            order.size.try_into().map_err(|_| throw_err!(MangoErrorCode::MathError))?;
-        let match_quote =
-            match_qty.checked_mul(order.price).ok_or(throw_err!(MangoErrorCode::MathError))?;
+        let price_cache = &mango_cache.price_cache[market_index];
+        price_cache.check_valid(&mango_group, market_index, now_ts)?;
 
-        // Negative `match_qty`, positive `match_quote` for selling account
-        // Positive `match_qty`, negative `match_quote` for buying account
+        // Funding must be final before positions can be settled
+        if perp_market.is_expired(now_ts) && perp_market.last_updated < perp_market.expiry {
+            msg!("UpdateFunding must be called after expiry before settlement");
+            return Err(throw_err!(MangoErrorCode::InvalidParam));
+        }
 
-        let (match_qty_creator, match_quote_creator) = if order.creator_side == Side::Bid {
-            (match_qty, -match_quote)
-        } else {
-            (-match_qty, match_quote)
-        };
+        perp_market.sample_settlement_price(price_cache.price, now_ts)?;
 
-        counterparty_mango_account_state.perp_accounts[order.perp_account_index]
-            .add_taker_trade(match_qty_creator * -1, match_quote_creator * -1);
-        creator_mango_account_state.perp_accounts[order.perp_account_index]
-            .add_taker_trade(match_qty_creator, match_quote_creator);
+        if perp_market.is_settled() {
+            mango_group.perp_markets[market_index].settlement_price = perp_market.settlement_price;
+            mango_emit_heap!(SettleExpiredPerpMarketLog {
+                mango_group: *mango_group_ai.key,
+                market_index: market_index as u64,
+                settlement_price: perp_market.settlement_price.to_bits()
+            });
+        }
 
-        // Rebase counterparty position
-        let counterparty_fill = FillEvent::new(
-            if order.creator_side == Side::Ask { Side::Bid } else { Side::Ask },
-            0,
-            false,
-            0,
-            0,
-            creator_mango_account_state.owner,
-            0,
-            0,
-            I80F48!(0),
-            0,
-            0,
-            *owner_ai.key,
-            0,
-            0,
-            I80F48!(0),
-            order.price,
-            match_qty,
-            0,
-        );
-        counterparty_mango_account_state.execute_taker(
-            order.perp_account_index,
-            &mut perp_market_state,
-            perp_market_cache,
-            &counterparty_fill,
-        )?;
+        Ok(())
+    }
 
-        // Rebase creator position
-        let creator_fill = FillEvent::new(
-            order.creator_side,
-            0,
-            false,
-            0,
-            0,
-            *owner_ai.key,
-            0,
-            0,
-            I80F48!(0),
-            0,
-            0,
-            creator_mango_account_state.owner,
-            0,
-            0,
-            I80F48!(0),
-            order.price,
-            match_qty,
-            0,
-        );
-        creator_mango_account_state.execute_taker(
-            order.perp_account_index,
-            &mut perp_market_state,
-            perp_market_cache,
-            &creator_fill,
-        )?;
+    #[inline(never)]
+    /// Close the position of a MangoAccount in a settled perp market at the settlement price
+    fn settle_expired_position(program_id: &Pubkey, accounts: &[AccountInfo]) -> MangoResult {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            mango_group_ai,     // read
+            mango_account_ai,   // write
+            perp_market_ai,     // write
+        ] = accounts;
 
-        health_cache_counterparty.update_perp_val(
-            &mango_group_state,
-            &mango_cache_state,
-            &counterparty_mango_account_state,
-            order.perp_account_index,
-        )?;
-        health_cache_creator.update_perp_val(
-            &mango_group_state,
-            &mango_cache_state,
-            &creator_mango_account_state,
-            order.perp_account_index,
+        let mango_group = MangoGroup::load_checked(mango_group_ai, program_id)?;
+        let mut mango_account =
+            MangoAccount::load_mut_checked(mango_account_ai, program_id, mango_group_ai.key)?;
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, mango_group_ai.key)?;
+        let market_index = mango_group.find_perp_market_index(perp_market_ai.key).unwrap();
+        check!(perp_market.is_settled(), MangoErrorCode::PerpMarketNotSettled)?;
+
+        // Open orders and unprocessed fills must be cleared first
+        let pa = &mut mango_account.perp_accounts[market_index];
+        check!(
+            pa.bids_quantity == 0 && pa.asks_quantity == 0,
+            MangoErrorCode::InvalidAccountState
         )?;
+        check!(pa.taker_base == 0 && pa.taker_quote == 0, MangoErrorCode::InvalidAccountState)?;
+
+        // funding stopped accruing at expiry
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let perp_market_cache = PerpMarketCache {
+            long_funding: perp_market.long_funding,
+            short_funding: perp_market.short_funding,
+            last_update: now_ts,
+        };
+        pa.settle_funding(&perp_market_cache);
 
-        let post_health_counterparty =
-            health_cache_counterparty.get_health(&mango_group_state, HealthType::Init);
-        let post_health_creator =
-            health_cache_creator.get_health(&mango_group_state, HealthType::Init);
+        let base_change = -pa.base_position;
+        pa.quote_position -= I80F48::from_num(base_change)
+            * I80F48::from_num(perp_market.base_lot_size)
+            * perp_market.settlement_price;
+        pa.change_base_position(&mut perp_market, base_change);
 
-        check!(
-            post_health_counterparty >= ZERO_I80F48
-                || (health_up_only_counterparty
-                    && post_health_counterparty >= pre_health_counterparty),
-            MangoErrorCode::InsufficientFunds
-        )?;
-        check!(
-            post_health_creator >= ZERO_I80F48
-                || (health_up_only_creator && post_health_creator >= pre_health_creator),
-            MangoErrorCode::InsufficientFunds
-        )
+        emit_perp_balances(
+            *mango_group_ai.key,
+            *mango_account_ai.key,
+            market_index as u64,
+            pa,
+            &perp_market_cache,
+        );
+
+        Ok(())
     }
 
     pub fn process(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> MangoResult {
@@ -7251,6 +9386,10 @@ impl Processor {
                 version,
                 lm_size_shift,
                 base_decimals,
+                index_exponent,
+                underlying_oracle_index,
+                index_divisor,
+                funding_period,
             } => {
                 msg!("Mango: CreatePerpMarket");
                 Self::create_perp_market(
@@ -7271,6 +9410,10 @@ impl Processor {
                     version,
                     lm_size_shift,
                     base_decimals,
+                    index_exponent,
+                    underlying_oracle_index,
+                    index_divisor,
+                    funding_period,
                 )
             }
             MangoInstruction::ChangePerpMarketParams2 {
@@ -7286,6 +9429,17 @@ impl Processor {
                 exp,
                 version,
                 lm_size_shift,
+                index_exponent,
+                underlying_oracle_index,
+                index_divisor,
+                funding_period,
+                maint_shock_bps,
+                init_shock_bps,
+                ema_period,
+                liquidation_use_ema,
+                funding_use_ema,
+                price_band_bps,
+                price_band_clamp,
             } => {
                 msg!("Mango: ChangePerpMarketParams2");
                 Self::change_perp_market_params2(
@@ -7303,6 +9457,17 @@ impl Processor {
                     exp,
                     version,
                     lm_size_shift,
+                    index_exponent,
+                    underlying_oracle_index,
+                    index_divisor,
+                    funding_period,
+                    maint_shock_bps,
+                    init_shock_bps,
+                    ema_period,
+                    liquidation_use_ema,
+                    funding_use_ema,
+                    price_band_bps,
+                    price_band_clamp,
                 )
             }
             MangoInstruction::UpdateMarginBasket => {
@@ -7388,6 +9553,8 @@ impl Processor {
                 reduce_only,
                 limit,
                 expiry_type,
+                self_trade_behavior,
+                display_quantity,
             } => {
                 msg!("Mango: PlacePerpOrder2 client_order_id={}", client_order_id);
                 Self::place_perp_order2(
@@ -7403,6 +9570,8 @@ impl Processor {
                     expiry_timestamp,
                     limit,
                     expiry_type,
+                    self_trade_behavior,
+                    display_quantity,
                 )
             }
             MangoInstruction::CancelAllSpotOrders { limit } => {
@@ -7441,6 +9610,199 @@ impl Processor {
                 msg!("Mango: TakePerpOtcOrder");
                 Self::take_perp_otc_order(program_id, accounts, order_id, open_orders_count)
             }
+            MangoInstruction::ChangePerpMarketFundingParams {
+                impact_quantity,
+                max_funding,
+                min_funding,
+            } => {
+                msg!("Mango: ChangePerpMarketFundingParams");
+                Self::change_perp_market_funding_params(
+                    program_id,
+                    accounts,
+                    impact_quantity,
+                    max_funding,
+                    min_funding,
+                )
+            }
+            MangoInstruction::AddRealizedVolOracle {
+                source_index,
+                window,
+                min_sample_interval,
+            } => {
+                msg!("Mango: AddRealizedVolOracle");
+                Self::add_realized_vol_oracle(
+                    program_id,
+                    accounts,
+                    source_index,
+                    window,
+                    min_sample_interval,
+                )
+            }
+            MangoInstruction::SetPerpMarketExpiry { expiry, settlement_window } => {
+                msg!("Mango: SetPerpMarketExpiry");
+                Self::set_perp_market_expiry(program_id, accounts, expiry, settlement_window)
+            }
+            MangoInstruction::SettleExpiredPerpMarket => {
+                msg!("Mango: SettleExpiredPerpMarket");
+                Self::settle_expired_perp_market(program_id, accounts)
+            }
+            MangoInstruction::SettleExpiredPosition => {
+                msg!("Mango: SettleExpiredPosition");
+                Self::settle_expired_position(program_id, accounts)
+            }
+            MangoInstruction::AddCompositeOracle { min_quorum, weights } => {
+                msg!("Mango: AddCompositeOracle");
+                Self::add_composite_oracle(program_id, accounts, min_quorum, weights)
+            }
+            MangoInstruction::ChangeOracleLimits {
+                max_conf_bps,
+                max_oracle_age_slots,
+                price_valid_interval,
+                max_price_move_bps,
+            } => {
+                msg!("Mango: ChangeOracleLimits");
+                Self::change_oracle_limits(
+                    program_id,
+                    accounts,
+                    max_conf_bps,
+                    max_oracle_age_slots,
+                    price_valid_interval,
+                    max_price_move_bps,
+                )
+            }
+            MangoInstruction::ClearPriceHalt => {
+                msg!("Mango: ClearPriceHalt");
+                Self::clear_price_halt(program_id, accounts)
+            }
+            MangoInstruction::AddDerivedOracle {
+                base_index,
+                base_exponent,
+                quote_index,
+                quote_exponent,
+                multiplier,
+            } => {
+                msg!("Mango: AddDerivedOracle");
+                Self::add_derived_oracle(
+                    program_id,
+                    accounts,
+                    base_index,
+                    base_exponent,
+                    quote_index,
+                    quote_exponent,
+                    multiplier,
+                )
+            }
+            MangoInstruction::EditPerpOrder { order_id, new_price, new_quantity, limit } => {
+                msg!("Mango: EditPerpOrder");
+                Self::edit_perp_order(
+                    program_id,
+                    accounts,
+                    order_id,
+                    new_price,
+                    new_quantity,
+                    limit,
+                )
+            }
+            MangoInstruction::BatchPerpOrders {
+                limit,
+                cancel_order_ids,
+                cancel_client_order_ids,
+                orders,
+            } => {
+                msg!("Mango: BatchPerpOrders");
+                Self::batch_perp_orders(
+                    program_id,
+                    accounts,
+                    limit,
+                    cancel_order_ids,
+                    cancel_client_order_ids,
+                    orders,
+                )
+            }
+            MangoInstruction::InitIcebergOrders => {
+                msg!("Mango: InitIcebergOrders");
+                Self::init_iceberg_orders(program_id, accounts)
+            }
+            MangoInstruction::RefillIcebergOrders { limit } => {
+                msg!("Mango: RefillIcebergOrders");
+                Self::refill_iceberg_orders(program_id, accounts, limit)
+            }
+            MangoInstruction::CancelIcebergOrder { index } => {
+                msg!("Mango: CancelIcebergOrder");
+                Self::cancel_iceberg_order(program_id, accounts, index)
+            }
+            MangoInstruction::PlacePerpPeggedOrder {
+                price_offset,
+                peg_limit,
+                max_base_quantity,
+                max_quote_quantity,
+                client_order_id,
+                expiry_timestamp,
+                side,
+                order_type,
+                reduce_only,
+                limit,
+                expiry_type,
+                self_trade_behavior,
+            } => {
+                msg!("Mango: PlacePerpPeggedOrder client_order_id={}", client_order_id);
+                Self::place_perp_pegged_order(
+                    program_id,
+                    accounts,
+                    side,
+                    price_offset,
+                    peg_limit,
+                    max_base_quantity,
+                    max_quote_quantity,
+                    client_order_id,
+                    order_type,
+                    reduce_only,
+                    expiry_timestamp,
+                    limit,
+                    expiry_type,
+                    self_trade_behavior,
+                )
+            }
+            MangoInstruction::InitHeartbeat => {
+                msg!("Mango: InitHeartbeat");
+                Self::init_heartbeat(program_id, accounts)
+            }
+            MangoInstruction::SetHeartbeat { deadline } => {
+                msg!("Mango: SetHeartbeat deadline={}", deadline);
+                Self::set_heartbeat(program_id, accounts, deadline)
+            }
+            MangoInstruction::ExpireHeartbeatOrders { limit } => {
+                msg!("Mango: ExpireHeartbeatOrders");
+                Self::expire_heartbeat_orders(program_id, accounts, limit)
+            }
+            MangoInstruction::ChangePerpMarketPositionLimits {
+                max_position_lots,
+                max_open_interest,
+            } => {
+                msg!("Mango: ChangePerpMarketPositionLimits");
+                Self::change_perp_market_position_limits(
+                    program_id,
+                    accounts,
+                    max_position_lots,
+                    max_open_interest,
+                )
+            }
+            MangoInstruction::AutoDeleverage => {
+                msg!("Mango: AutoDeleverage");
+                Self::auto_deleverage(program_id, accounts)
+            }
+            MangoInstruction::UpgradeMangoGroup => {
+                msg!("Mango: UpgradeMangoGroup");
+                Self::upgrade_mango_group(program_id, accounts)
+            }
+            MangoInstruction::UpgradePerpMarket => {
+                msg!("Mango: UpgradePerpMarket");
+                Self::upgrade_perp_market(program_id, accounts)
+            }
+            MangoInstruction::UpgradeMangoCache => {
+                msg!("Mango: UpgradeMangoCache");
+                Self::upgrade_mango_cache(program_id, accounts)
+            }
         }
     }
 }
@@ -7589,100 +9951,239 @@ fn invoke_transfer<'a>(
     solana_program::program::invoke_signed(&transfer_instruction, &accs, signers_seeds)
 }
 
+/// Price of a DerivedOracle from the cached prices of its sources, which must be valid
+pub fn read_derived_oracle(
+    program_id: &Pubkey,
+    mango_group: &MangoGroup,
+    mango_cache: &MangoCache,
+    oracle_ai: &AccountInfo,
+    now_ts: u64,
+) -> MangoResult<I80F48> {
+    let oracle = DerivedOracle::load_checked(oracle_ai, program_id)?;
+    let base_index = oracle.base_index as usize;
+    let quote_index = oracle.quote_index as usize;
+    for i in [base_index, quote_index] {
+        let price_cache = &mango_cache.price_cache[i];
+        price_cache.check_valid(mango_group, i, now_ts)?;
+        check!(!price_cache.is_halted(), MangoErrorCode::PriceHalted)?;
+    }
+
+    oracle
+        .compute_price(
+            mango_cache.price_cache[base_index].price,
+            mango_cache.price_cache[quote_index].price,
+        )
+        .ok_or(throw_err!(MangoErrorCode::InvalidOraclePrice))
+}
+
+/// `source_ais` are searched for the sources of a CompositeOracle
 pub fn read_oracle(
     mango_group: &MangoGroup,
     token_index: usize,
     oracle_ai: &AccountInfo,
+    source_ais: &[AccountInfo],
+    limits: &OracleLimits,
 ) -> MangoResult<I80F48> {
     let quote_decimals = mango_group.tokens[QUOTE_INDEX].decimals as i32;
     let base_decimals = mango_group.tokens[token_index].decimals as i32;
 
     let oracle_type = determine_oracle_type(oracle_ai);
 
-    let price = pow_i80f48(
-        match oracle_type {
-            OracleType::Pyth => {
-                let oracle_data = oracle_ai.try_borrow_data()?;
-                let price_account = pyth_client::load_price(&oracle_data).unwrap();
-                let mut value = I80F48::from_num(price_account.agg.price);
-                let conf = I80F48::from_num(price_account.agg.conf).checked_div(value).unwrap();
-
-                if token_index == 0 {
-                    value = pow_i80f48(value, 2) / 1000000;
-                }
+    let price = match oracle_type {
+        OracleType::Pyth => {
+            let oracle_data = oracle_ai.try_borrow_data()?;
+            let price_account = pyth_client::load_price(&oracle_data).unwrap();
+            let value = I80F48::from_num(price_account.agg.price);
+            let conf = I80F48::from_num(price_account.agg.conf).checked_div(value).unwrap();
+
+            // Filter out bad prices
+            let slot = Clock::get()?.slot;
+            if price_account.agg.status != PriceStatus::Trading {
+                msg!("Pyth status invalid: {}", price_account.agg.status as u8);
+                return Err(throw_err!(MangoErrorCode::InvalidOraclePrice));
+            } else if limits.is_stale(slot, price_account.valid_slot) {
+                msg!(
+                    "Pyth price stale; oracle index: {} valid slot: {}",
+                    token_index,
+                    price_account.valid_slot
+                );
+                return Err(throw_err!(MangoErrorCode::InvalidOraclePrice));
+            } else if limits.is_conf_too_high(conf, PYTH_CONF_FILTER) {
+                msg!(
+                    "Pyth conf interval too high; oracle index: {} value: {} conf: {}",
+                    token_index,
+                    value.to_num::<f64>(),
+                    conf.to_num::<f64>()
+                );
+                return Err(throw_err!(MangoErrorCode::InvalidOraclePrice));
+            }
 
-                // Filter out bad prices
-                if price_account.agg.status != PriceStatus::Trading {
-                    msg!("Pyth status invalid: {}", price_account.agg.status as u8);
-                    return Err(throw_err!(MangoErrorCode::InvalidOraclePrice));
-                } else if conf > PYTH_CONF_FILTER {
-                    msg!(
-                        "Pyth conf interval too high; oracle index: {} value: {} conf: {}",
-                        token_index,
-                        value.to_num::<f64>(),
-                        conf.to_num::<f64>()
-                    );
-                    return Err(throw_err!(MangoErrorCode::InvalidOraclePrice));
-                }
+            let decimals = quote_decimals
+                .checked_add(price_account.expo)
+                .unwrap()
+                .checked_sub(base_decimals)
+                .unwrap();
 
-                let decimals = quote_decimals
-                    .checked_add(price_account.expo)
-                    .unwrap()
-                    .checked_sub(base_decimals)
-                    .unwrap();
+            let decimal_adj = I80F48::from_num(10u64.pow(decimals.abs() as u32));
+            if decimals < 0 {
+                value.checked_div(decimal_adj).unwrap()
+            } else {
+                value.checked_mul(decimal_adj).unwrap()
+            }
+        }
+        OracleType::PythPull => {
+            let update = PythPriceUpdate::load(oracle_ai)?;
+            let value = I80F48::from_num(update.price);
+            let clock = Clock::get()?;
+
+            // Filter out bad prices
+            if !value.is_positive() {
+                msg!("Pyth price update invalid; oracle index: {}", token_index);
+                return Err(throw_err!(MangoErrorCode::InvalidOraclePrice));
+            } else if clock.unix_timestamp - update.publish_time > PYTH_PULL_MAX_STALENESS
+                || limits.is_stale(clock.slot, update.posted_slot)
+            {
+                msg!(
+                    "Pyth price update stale; oracle index: {} publish time: {}",
+                    token_index,
+                    update.publish_time
+                );
+                return Err(throw_err!(MangoErrorCode::InvalidOraclePrice));
+            }
+            let conf = I80F48::from_num(update.conf).checked_div(value).unwrap();
+            if limits.is_conf_too_high(conf, PYTH_CONF_FILTER) {
+                msg!(
+                    "Pyth conf interval too high; oracle index: {} value: {} conf: {}",
+                    token_index,
+                    value.to_num::<f64>(),
+                    conf.to_num::<f64>()
+                );
+                return Err(throw_err!(MangoErrorCode::InvalidOraclePrice));
+            }
 
+            let decimals = quote_decimals
+                .checked_add(update.exponent)
+                .unwrap()
+                .checked_sub(base_decimals)
+                .unwrap();
+            let decimal_adj = I80F48::from_num(10u64.pow(decimals.abs() as u32));
+            if decimals < 0 {
+                value.checked_div(decimal_adj).unwrap()
+            } else {
+                value.checked_mul(decimal_adj).unwrap()
+            }
+        }
+        OracleType::Stub => {
+            let oracle = StubOracle::load(oracle_ai)?;
+            I80F48::from_num(oracle.price)
+        }
+        OracleType::RealizedVol => {
+            // annualized volatility in percent, as quote per base in ui units
+            let oracle = RealizedVolOracle::load(oracle_ai)?;
+            let value = oracle.annualized_vol().checked_mul(I80F48::from_num(100)).unwrap();
+
+            let decimals = quote_decimals.checked_sub(base_decimals).unwrap();
+            let decimal_adj = I80F48::from_num(10u64.pow(decimals.abs() as u32));
+            if decimals < 0 {
+                value.checked_div(decimal_adj).unwrap()
+            } else {
+                value.checked_mul(decimal_adj).unwrap()
+            }
+        }
+        OracleType::Switchboard => {
+            msg!("switchboard");
+            let result =
+                FastRoundResultAccountData::deserialize(&oracle_ai.try_borrow_data()?).unwrap();
+            let value = I80F48::from_num(result.result.result);
+            // let value = get_switchboard_value(oracle_ai).unwrap();
+
+            let decimals = quote_decimals.checked_sub(base_decimals).unwrap();
+            if decimals < 0 {
                 let decimal_adj = I80F48::from_num(10u64.pow(decimals.abs() as u32));
-                if decimals < 0 {
-                    value.checked_div(decimal_adj).unwrap()
-                } else {
-                    value.checked_mul(decimal_adj).unwrap()
-                }
+                value.checked_div(decimal_adj).unwrap()
+            } else if decimals > 0 {
+                let decimal_adj = I80F48::from_num(10u64.pow(decimals.abs() as u32));
+                value.checked_mul(decimal_adj).unwrap()
+            } else {
+                value
+            }
+        }
+        OracleType::SwitchboardV2 => {
+            let feed = AggregatorAccountData::new(oracle_ai)
+                .map_err(|_| throw_err!(MangoErrorCode::InvalidOracleType))?;
+            let round = &feed.latest_confirmed_round;
+            let value = switchboard_decimal_to_i80f48(&round.result)
+                .ok_or(throw_err!(MangoErrorCode::InvalidOraclePrice))?;
+            let std_deviation = switchboard_decimal_to_i80f48(&round.std_deviation)
+                .ok_or(throw_err!(MangoErrorCode::InvalidOraclePrice))?;
+            let clock = Clock::get()?;
+            let stale = if limits.max_age_slots > 0 {
+                limits.is_stale(clock.slot, round.round_open_slot)
+            } else {
+                clock.unix_timestamp - round.round_open_timestamp > SWITCHBOARD_V2_MAX_STALENESS
+            };
+
+            // Filter out bad prices
+            if round.num_success < feed.min_oracle_results || !value.is_positive() {
+                msg!("Switchboard V2 round invalid; oracle index: {}", token_index);
+                return Err(throw_err!(MangoErrorCode::InvalidOraclePrice));
+            } else if stale {
+                msg!(
+                    "Switchboard V2 price stale; oracle index: {} round open: {}",
+                    token_index,
+                    round.round_open_timestamp
+                );
+                return Err(throw_err!(MangoErrorCode::InvalidOraclePrice));
+            } else if limits.is_conf_too_high(std_deviation / value, SWITCHBOARD_V2_CONF_FILTER) {
+                msg!(
+                    "Switchboard V2 std deviation too high; oracle index: {} value: {} std: {}",
+                    token_index,
+                    value.to_num::<f64>(),
+                    std_deviation.to_num::<f64>()
+                );
+                return Err(throw_err!(MangoErrorCode::InvalidOraclePrice));
             }
-            OracleType::Stub => {
-                let oracle = StubOracle::load(oracle_ai)?;
-                I80F48::from_num(oracle.price)
+
+            // the feed reports ui prices
+            let decimals = quote_decimals.checked_sub(base_decimals).unwrap();
+            let decimal_adj = I80F48::from_num(10u64.pow(decimals.abs() as u32));
+            if decimals < 0 {
+                value.checked_div(decimal_adj).unwrap()
+            } else {
+                value.checked_mul(decimal_adj).unwrap()
             }
-            OracleType::Switchboard => {
-                msg!("switchboard");
-                let result =
-                    FastRoundResultAccountData::deserialize(&oracle_ai.try_borrow_data()?).unwrap();
-                let mut value = I80F48::from_num(result.result.result);
-                // let value = get_switchboard_value(oracle_ai).unwrap();
-                if token_index == 0 {
-                    value = pow_i80f48(value, 2) / 1000000;
+        }
+        OracleType::Composite => {
+            let composite = CompositeOracle::load(oracle_ai)?;
+            let mut prices = Vec::with_capacity(composite.num_sources as usize);
+            for i in 0..composite.num_sources as usize {
+                let source_ai = match source_ais.iter().find(|ai| ai.key == &composite.sources[i]) {
+                    Some(ai) => ai,
+                    None => continue,
+                };
+                match read_oracle(mango_group, token_index, source_ai, &[], limits) {
+                    Ok(price) => prices.push((price, composite.weights[i])),
+                    Err(_) => msg!("Composite oracle source {} invalid", i),
                 }
+            }
 
-                let decimals = quote_decimals.checked_sub(base_decimals).unwrap();
-                if decimals < 0 {
-                    let decimal_adj = I80F48::from_num(10u64.pow(decimals.abs() as u32));
-                    value.checked_div(decimal_adj).unwrap()
-                } else if decimals > 0 {
-                    let decimal_adj = I80F48::from_num(10u64.pow(decimals.abs() as u32));
-                    value.checked_mul(decimal_adj).unwrap()
-                } else {
-                    value
+            match composite.aggregate(&mut prices) {
+                Some(price) => price,
+                None => {
+                    msg!(
+                        "Composite oracle quorum not met; oracle index: {} valid sources: {}",
+                        token_index,
+                        prices.len()
+                    );
+                    return Err(throw_err!(MangoErrorCode::InvalidOraclePrice));
                 }
             }
-            // OracleType::SwitchboardV2 => {
-            //     msg!("switchboard V2");
-            //     let mut value = get_switchboard_value(oracle_ai).unwrap();
-
-            //     let decimals = quote_decimals.checked_sub(base_decimals).unwrap();
-
-            //     if decimals < 0 {
-            //         let decimal_adj = I80F48::from_num(10u64.pow(decimals.abs() as u32));
-            //         value.checked_div(decimal_adj).unwrap()
-            //     } else if decimals > 0 {
-            //         let decimal_adj = I80F48::from_num(10u64.pow(decimals.abs() as u32));
-            //         value.checked_mul(decimal_adj).unwrap()
-            //     } else {
-            //         value
-            //     }
-            // }
-            OracleType::Unknown => return Err(throw_err!(MangoErrorCode::InvalidOracleType)),
-        },
-        1 as u8,
-    );
+        }
+        // computed from the MangoCache by read_derived_oracle
+        OracleType::Derived | OracleType::Unknown => {
+            return Err(throw_err!(MangoErrorCode::InvalidOracleType))
+        }
+    };
 
     Ok(price)
 }
@@ -8185,6 +10686,21 @@ fn create_pda_account<'a>(
     }
 }
 
+/// Grow a program owned account to `space` bytes, topping up its rent exemption from `funder`
+fn resize_account<'a>(
+    funder: &AccountInfo<'a>,
+    rent: &Rent,
+    space: usize,
+    system_program: &AccountInfo<'a>,
+    account: &AccountInfo<'a>,
+) -> ProgramResult {
+    let required_lamports = rent.minimum_balance(space).saturating_sub(account.lamports());
+    if required_lamports > 0 {
+        invoke_transfer_lamports(funder, account, system_program, required_lamports, &[])?;
+    }
+    account.realloc(space, true)
+}
+
 /// Transfer lamports from a src account owned by the currently executing program id
 fn program_transfer_lamports(
     src_ai: &AccountInfo,
@@ -8214,6 +10730,304 @@ fn cancel_all_advanced_orders<'a>(
     program_transfer_lamports(advanced_orders_ai, agent_ai, total_fee)
 }
 
+/// Post the next slice of each iceberg order on `market_index` whose visible slice is gone.
+/// With `post_only` the slices are posted as PostOnly and skipped unless `Book::can_post`
+/// holds and the EventQueue has room, so that ConsumeEvents can't fail on them.
+fn post_iceberg_slices(
+    program_id: &Pubkey,
+    mango_group: &MangoGroup,
+    mango_group_pk: &Pubkey,
+    mango_cache: &MangoCache,
+    book: &mut Book,
+    event_queue: &mut EventQueue,
+    perp_market: &mut PerpMarket,
+    mango_account: &mut MangoAccount,
+    mango_account_pk: &Pubkey,
+    iceberg_orders: &mut IcebergOrders,
+    market_index: usize,
+    post_only: bool,
+    now_ts: u64,
+    limit: u8,
+) -> MangoResult {
+    let oracle_price = mango_cache.get_price(market_index);
+    for order in iceberg_orders.orders.iter_mut() {
+        if !order.is_active
+            || order.market_index as usize != market_index
+            || mango_account.find_order_side(market_index, order.order_id).is_some()
+        {
+            continue;
+        }
+
+        let quantity = order.display_quantity.min(order.hidden_quantity);
+        let order_type = if post_only {
+            let can_post = event_queue.buf.len() - event_queue.len() >= MAX_POST_ONLY_EVENTS
+                && book.can_post(
+                    perp_market,
+                    &mango_group.perp_markets[market_index],
+                    mango_account,
+                    market_index,
+                    order.side,
+                    order.price,
+                    quantity,
+                    oracle_price,
+                    now_ts,
+                );
+            if !can_post {
+                continue;
+            }
+            OrderType::PostOnly
+        } else {
+            order.order_type
+        };
+
+        // DecrementTake self-trades shrink our own orders on the other side instead of
+        // adding to taker_base, but use up the slice all the same
+        let side = order.side;
+        let taken_so_far = |pa: &PerpAccount| match side {
+            Side::Bid => pa.taker_base - pa.asks_quantity,
+            Side::Ask => -pa.taker_base - pa.bids_quantity,
+        };
+        let taken_before = taken_so_far(&mango_account.perp_accounts[market_index]);
+        let posted = book.new_order(
+            program_id,
+            mango_group,
+            mango_group_pk,
+            mango_cache,
+            event_queue,
+            perp_market,
+            oracle_price,
+            mango_account,
+            mango_account_pk,
+            market_index,
+            order.side,
+            order.price,
+            quantity,
+            i64::MAX,
+            order_type,
+            SelfTradeBehavior::DecrementTake,
+            0,
+            0,
+            order.client_order_id,
+            now_ts,
+            None,
+            limit,
+        )?;
+
+        // Whatever was neither matched nor posted (e.g. a rejected PostOnly slice)
+        // stays hidden and is retried on the next refill
+        let taken = taken_so_far(&mango_account.perp_accounts[market_index]) - taken_before;
+        let posted_quantity = match posted {
+            Some((order_id, _)) => {
+                order.order_id = order_id;
+                match side {
+                    Side::Bid => book.bids.find_by_key(order_id),
+                    Side::Ask => book.asks.find_by_key(order_id),
+                }
+                .map_or(0, |leaf| leaf.quantity)
+            }
+            None => 0,
+        };
+        order.hidden_quantity -= posted_quantity + taken;
+        if order.hidden_quantity == 0 {
+            order.is_active = false;
+        }
+    }
+    mango_account.has_iceberg_orders = iceberg_orders.has_active_orders();
+    Ok(())
+}
+
+/// Drop the hidden rest of the iceberg orders whose visible slice was canceled, so that it
+/// isn't posted again. Without the IcebergOrders PDA the hidden rest stays: ConsumeEvents
+/// doesn't see a canceled slice, but RefillIcebergOrders posts the next one.
+fn cancel_iceberg_orders(
+    program_id: &Pubkey,
+    iceberg_orders_ai: Option<&AccountInfo>,
+    mango_account: &mut MangoAccount,
+    mango_account_pk: &Pubkey,
+    canceled: impl Fn(&IcebergOrder) -> bool,
+) -> MangoResult {
+    let iceberg_orders_ai = match iceberg_orders_ai {
+        Some(ai) if mango_account.has_iceberg_orders => ai,
+        _ => return Ok(()),
+    };
+    let mut iceberg_orders =
+        IcebergOrders::load_mut_checked(iceberg_orders_ai, program_id, mango_account_pk)?;
+    iceberg_orders.cancel_orders(canceled);
+    mango_account.has_iceberg_orders = iceberg_orders.has_active_orders();
+    Ok(())
+}
+
+/// Make up the negative quote position a bankrupt liqee has left on `market_index` by
+/// deleveraging the ADL counterparties, all on one side of the market. Each counterparty gives up
+/// at most its unrealized pnl, down to its maintenance health, spread over its lots: just enough
+/// of its position is force-closed at the bankruptcy price that makes up the loss. The liqee no
+/// longer has a position to close them against, so the liqor takes the lots over at the price
+/// the position is valued at and must stay above init health.
+fn deleverage_bankrupt_account(
+    program_id: &Pubkey,
+    mango_group: &MangoGroup,
+    mango_group_pk: &Pubkey,
+    mango_cache: &MangoCache,
+    perp_market: &mut PerpMarket,
+    market_index: usize,
+    liqee_ma: &mut MangoAccount,
+    liqee_pk: &Pubkey,
+    liqor_ma: &mut MangoAccount,
+    liqor_pk: &Pubkey,
+    liqor_open_orders_ais: &[AccountInfo; MAX_PAIRS],
+    mut counterparty_ais: &[AccountInfo],
+    now_ts: u64,
+) -> MangoResult {
+    let pmi = &mango_group.perp_markets[market_index];
+    let cache = &mango_cache.perp_market_cache[market_index];
+    let price = pmi.settled_or(mango_cache.price_cache[market_index].price);
+    liqor_ma.perp_accounts[market_index].settle_funding(cache);
+
+    let mut side = 0; // sign of the counterparties' positions
+    let mut prev_score: Option<I80F48> = None;
+    while liqee_ma.perp_accounts[market_index].quote_position.is_negative()
+        && !counterparty_ais.is_empty()
+    {
+        let (counterparty_ai, mut counterparty_ma, counterparty_health_cache) =
+            load_adl_counterparty(
+                program_id,
+                mango_group,
+                mango_group_pk,
+                mango_cache,
+                market_index,
+                &[liqee_pk, liqor_pk],
+                &mut counterparty_ais,
+                &mut prev_score,
+                now_ts,
+            )?;
+        let maint_health = counterparty_health_cache.get_health(mango_group, HealthType::Maint);
+        let counterparty_pa = &mut counterparty_ma.perp_accounts[market_index];
+        let counterparty_lots = counterparty_pa.base_position.abs();
+        if side == 0 {
+            side = counterparty_pa.base_position.signum();
+        }
+        check!(
+            counterparty_pa.base_position.signum() == side,
+            MangoErrorCode::InvalidAdlCounterparty
+        )?;
+
+        let max_loss = counterparty_pa.unrealized_pnl(pmi.base_lot_size, price).min(maint_health);
+        if !max_loss.is_positive() {
+            continue;
+        }
+        let liqee_pa = &mut liqee_ma.perp_accounts[market_index];
+        let loss = (-liqee_pa.quote_position).min(max_loss);
+        let lots = (loss * I80F48::from_num(counterparty_lots) / max_loss)
+            .checked_ceil()
+            .and_then(|lots| lots.checked_to_num::<i64>())
+            .ok_or(math_err!())?
+            .min(counterparty_lots);
+
+        // The counterparty sells (or buys back) `lots` at the bankruptcy price, the liqor takes
+        // them at `price` and the difference goes to the liqee
+        let base_transfer = side * lots;
+        let base_value = I80F48::from_num(base_transfer * pmi.base_lot_size);
+        let bankruptcy_price = price - loss / base_value;
+        let liqor_pa = &mut liqor_ma.perp_accounts[market_index];
+        let liqor_base = liqor_pa.base_position;
+        perp_market.check_max_position(liqor_base, liqor_base + base_transfer)?;
+        counterparty_pa.change_base_position(perp_market, -base_transfer);
+        liqor_pa.change_base_position(perp_market, base_transfer);
+        liqor_pa.transfer_quote_position(counterparty_pa, base_value * price);
+        counterparty_pa.transfer_quote_position(liqee_pa, loss);
+
+        mango_emit_heap!(AutoDeleverageLog {
+            mango_group: *mango_group_pk,
+            liqee: *liqee_pk,
+            counterparty: *counterparty_ai.key,
+            market_index: market_index as u64,
+            bankruptcy_price: bankruptcy_price.to_bits(),
+            base_transfer,
+            quote_transfer: (-base_value * bankruptcy_price).to_bits()
+        });
+        emit_perp_balances(
+            *mango_group_pk,
+            *counterparty_ai.key,
+            market_index as u64,
+            &counterparty_ma.perp_accounts[market_index],
+            cache,
+        );
+    }
+
+    let liqor_active_assets =
+        UserActiveAssets::new(mango_group, liqor_ma, vec![(AssetType::Perp, market_index)]);
+    let mut liqor_health_cache = HealthCache::new(liqor_active_assets);
+    liqor_health_cache.init_vals(mango_group, mango_cache, liqor_ma, liqor_open_orders_ais)?;
+    let liqor_health = liqor_health_cache.get_health(mango_group, HealthType::Init);
+    check!(liqor_health >= ZERO_I80F48, MangoErrorCode::InsufficientFunds)
+}
+
+/// Load the next auto-deleveraging counterparty from `counterparty_ais`, where each
+/// MangoAccount is followed by the open orders of its margin basket, and settle its funding.
+/// Counterparties must have a profitable position and come in descending order of
+/// PerpAccount::adl_score, with positions valued like AutoDeleverage does, at the settlement
+/// price once the market settled. Only the order among the accounts passed is checked: whether
+/// a higher ranked account was left out can't be seen on chain, which is why only the admin may
+/// pass counterparties. Returns the account with its HealthCache.
+fn load_adl_counterparty<'a, 'b>(
+    program_id: &Pubkey,
+    mango_group: &MangoGroup,
+    mango_group_pk: &Pubkey,
+    mango_cache: &MangoCache,
+    market_index: usize,
+    excluded_pks: &[&Pubkey],
+    counterparty_ais: &mut &'a [AccountInfo<'b>],
+    prev_score: &mut Option<I80F48>,
+    now_ts: u64,
+) -> MangoResult<(&'a AccountInfo<'b>, RefMut<'a, MangoAccount>, HealthCache)> {
+    let all_ais: &'a [AccountInfo<'b>] = counterparty_ais;
+    let (counterparty_ai, rest) =
+        all_ais.split_first().ok_or(throw_err!(MangoErrorCode::InvalidAccount))?;
+    check!(!excluded_pks.contains(&counterparty_ai.key), MangoErrorCode::InvalidAdlCounterparty)?;
+    let mut counterparty_ma =
+        MangoAccount::load_mut_checked(counterparty_ai, program_id, mango_group_pk)?;
+    check!(!counterparty_ma.is_bankrupt, MangoErrorCode::Bankrupt)?;
+
+    let num_open_orders = counterparty_ma.num_in_margin_basket as usize;
+    check!(rest.len() >= num_open_orders, MangoErrorCode::InvalidOpenOrdersAccount)?;
+    let (packed_open_orders_ais, rest) = rest.split_at(num_open_orders);
+    *counterparty_ais = rest;
+
+    let open_orders_ais =
+        counterparty_ma.checked_unpack_open_orders(mango_group, packed_open_orders_ais)?;
+    let open_orders_accounts = load_open_orders_accounts(&open_orders_ais)?;
+
+    let active_assets = UserActiveAssets::new(mango_group, &counterparty_ma, vec![]);
+    mango_cache.check_valid(mango_group, &active_assets, now_ts)?;
+    counterparty_ma.perp_accounts[market_index]
+        .settle_funding(&mango_cache.perp_market_cache[market_index]);
+
+    let mut health_cache = HealthCache::new(active_assets);
+    health_cache.init_vals_with_orders_vec(
+        mango_group,
+        mango_cache,
+        &counterparty_ma,
+        &open_orders_accounts,
+    )?;
+    let equity = health_cache.get_health(mango_group, HealthType::Equity);
+
+    let score = counterparty_ma.perp_accounts[market_index]
+        .adl_score(
+            mango_group.perp_markets[market_index].base_lot_size,
+            mango_group.perp_markets[market_index]
+                .settled_or(mango_cache.price_cache[market_index].price),
+            equity,
+        )
+        .ok_or(throw_err!(MangoErrorCode::InvalidAdlCounterparty))?;
+    check!(
+        prev_score.map_or(true, |prev_score| score <= prev_score),
+        MangoErrorCode::InvalidAdlCounterparty
+    )?;
+    *prev_score = Some(score);
+
+    Ok((counterparty_ai, counterparty_ma, health_cache))
+}
+
 // Returns asset_weight and liab_weight
 pub fn get_leverage_weights(leverage: I80F48) -> (I80F48, I80F48) {
     (
@@ -27,11 +27,12 @@ use mango_macro::{Loadable, Pod, TriviallyTransmutable};
 
 use crate::error::{check_assert, MangoError, MangoErrorCode, MangoResult, SourceFileId};
 use crate::ids::mngo_token;
-use crate::matching::{Book, LeafNode, OrderType, Side};
+use crate::matching::{is_oracle_pegged_key, Book, LeafNode, OrderType, Side};
+use crate::oracle::OracleLimits;
 use crate::queue::{EventQueue, EventType, FillEvent};
 use crate::utils::{
     compute_interest_rate, invert_side, pow_i80f48, remove_slop_mut, split_open_orders,
-    MAX_PERP_OTC_ORDERS, MAX_SPOT_OTC_ORDERS,
+    MAX_ICEBERG_ORDERS, MAX_PERP_OTC_ORDERS, MAX_SPOT_OTC_ORDERS,
 };
 
 pub const MAX_TOKENS: usize = 16; // Just changed
@@ -44,6 +45,7 @@ pub const NEG_ONE_I80F48: I80F48 = I80F48!(-1);
 pub const DAY: I80F48 = I80F48!(86400);
 pub const WEEK: I80F48 = I80F48!(604800);
 pub const YEAR: I80F48 = I80F48!(31536000);
+pub const LN_2: I80F48 = I80F48!(0.693147180559945309417232121458);
 pub const SERUM_TAKER_FEE: I80F48 = I80F48!(0.00000); //2.4 bp Serum Taker Fee
 
 pub const DUST_THRESHOLD: I80F48 = I80F48!(0.001); // TODO make this part of MangoGroup state
@@ -55,7 +57,17 @@ pub const FREE_ORDER_SLOT: u8 = u8::MAX;
 pub const MAX_NUM_IN_MARGIN_BASKET: u8 = 9;
 pub const INDEX_START: I80F48 = I80F48!(1_000_000);
 pub const PYTH_CONF_FILTER: I80F48 = I80F48!(0.10); // filter out pyth prices with conf > 10% of price
+pub const SWITCHBOARD_V2_CONF_FILTER: I80F48 = I80F48!(0.10); // same for std deviation of the round
+pub const SWITCHBOARD_V2_MAX_STALENESS: i64 = 300; // seconds since the last confirmed round opened
+pub const PYTH_PULL_MAX_STALENESS: i64 = 60; // seconds since the pyth price update was published
 pub const CENTIBPS_PER_UNIT: I80F48 = I80F48!(1_000_000);
+pub const MAX_INDEX_EXPONENT: u8 = 4; // max power of the underlying used as a perp index
+pub const PERP_MARKET_INFO_V0_SIZE: usize = 160; // before the index, risk and price band params
+pub const PRICE_CACHE_V0_SIZE: usize = 24; // before the EMA and circuit breaker state
+pub const DEFAULT_IMPACT_QUANTITY: i64 = 100; // base lots
+pub const DEFAULT_MAX_FUNDING: I80F48 = I80F48!(0.05);
+pub const DEFAULT_MIN_FUNDING: I80F48 = I80F48!(-0.05);
+pub const MIN_SETTLEMENT_SAMPLES: u64 = 10; // index samples a dated perp needs before settling
 
 declare_check_assert_macros!(SourceFileId::State);
 
@@ -90,6 +102,8 @@ pub enum DataType {
     ReferrerMemory,
     ReferrerIdRecord,
     OtcOrders,
+    IcebergOrders,
+    Heartbeat,
 }
 
 const NUM_HEALTHS: usize = 3;
@@ -147,7 +161,12 @@ pub struct TokenInfo {
     pub mint: Pubkey,
     pub root_bank: Pubkey,
     pub decimals: u8,
-    pub padding: [u8; 7],
+    pub padding: u8,
+
+    // limits for the oracle price at this index; 0 uses the defaults
+    pub max_conf_bps: u16, // max confidence interval (or std deviation) over price
+    pub max_oracle_age_slots: u16, // max slots since the oracle price was published
+    pub price_valid_interval: u16, // seconds a cached price stays valid
 }
 
 impl TokenInfo {
@@ -186,12 +205,109 @@ pub struct PerpMarketInfo {
     pub taker_fee: I80F48,
     pub base_lot_size: i64,  // The lot size of the underlying
     pub quote_lot_size: i64, // min tick
+
+    // index price = price(oracles[underlying_oracle_index]) ^ index_exponent / index_divisor
+    pub index_divisor: I80F48,
+    pub underlying_oracle_index: u8,
+    pub index_exponent: u8, // 1 for linear perps, 2 for squared perps etc.
+
+    // if non-zero, maint/init health revalue the position at the underlying price +/- this many
+    // bps through index_exponent and use the worst case instead of the asset/liab weights
+    pub maint_shock_bps: u16,
+    pub init_shock_bps: u16,
+
+    // time constant in seconds of the EMA of the cached price; 0 keeps it equal to the price.
+    // Liquidations and funding use the EMA instead of the price if the flag is set
+    pub ema_period: u16,
+    pub liquidation_use_ema: bool,
+    pub funding_use_ema: bool,
+
+    // if non-zero, CachePrices halts the price cached at this index instead of moving it by more
+    // than this many bps per valid_interval. Applies whether or not there is a perp market
+    pub max_price_move_bps: u16,
+
+    // if non-zero, bids above oracle * (1 + price_band_bps / 10_000) and asks below
+    // oracle * (1 - price_band_bps / 10_000) are rejected, or clamped to the band edge if
+    // price_band_clamp is set. Market orders are always clamped
+    pub price_band_bps: u16,
+    pub price_band_clamp: bool,
+    pub padding: [u8; 1],
+
+    // copy of PerpMarket::settlement_price once a dated perp market settles; positions are valued
+    // at it instead of the oracle price from then on
+    pub settlement_price: I80F48,
 }
 
 impl PerpMarketInfo {
     pub fn is_empty(&self) -> bool {
         self.perp_market == Pubkey::default()
     }
+
+    /// Price positions are valued at: the settlement price once the market settled, else `price`
+    pub fn settled_or(&self, price: I80F48) -> I80F48 {
+        if self.settlement_price.is_positive() {
+            self.settlement_price
+        } else {
+            price
+        }
+    }
+
+    /// Highest bid or lowest ask price in quote lots per base lot the price band allows,
+    /// None if the band is disabled
+    pub fn price_band_limit(&self, side: Side, oracle_price_lots: i64) -> Option<i64> {
+        if self.price_band_bps == 0 {
+            return None;
+        }
+        let oracle_price_lots = oracle_price_lots as i128;
+        let band_bps = self.price_band_bps as i128;
+        Some(match side {
+            Side::Bid => (oracle_price_lots * (10_000 + band_bps) / 10_000).min(i64::MAX as i128),
+            Side::Ask => {
+                let min_price = oracle_price_lots * (10_000 - band_bps);
+                ((min_price + 9_999) / 10_000).max(1)
+            }
+        } as i64)
+    }
+
+    /// Returns the oracle index, exponent and divisor used to compute the index price cached
+    /// at `market_index`. Oracles without index params are cached as is.
+    pub fn index_params(&self, market_index: usize) -> (usize, u8, I80F48) {
+        if self.index_exponent == 0 {
+            (market_index, 1, ONE_I80F48)
+        } else {
+            (self.underlying_oracle_index as usize, self.index_exponent, self.index_divisor)
+        }
+    }
+
+    /// Weighted value of the unweighted base value of a perp position for the given health type
+    pub fn get_weighted_base_val(&self, base: I80F48, health_type: HealthType) -> I80F48 {
+        // the value of a settled position can't move anymore
+        if self.settlement_price.is_positive() {
+            return base;
+        }
+        let (asset_weight, liab_weight, shock_bps) = match health_type {
+            HealthType::Maint => {
+                (self.maint_asset_weight, self.maint_liab_weight, self.maint_shock_bps)
+            }
+            HealthType::Init => {
+                (self.init_asset_weight, self.init_liab_weight, self.init_shock_bps)
+            }
+            HealthType::Equity => (ONE_I80F48, ONE_I80F48, 0),
+        };
+
+        if shock_bps > 0 {
+            // The index moves by (1 +/- shock)^exponent when the underlying moves by +/- shock
+            let shock = I80F48::from_num(shock_bps) / I80F48::from_num(10_000);
+            let exponent = self.index_exponent.max(1);
+            let up = pow_i80f48(ONE_I80F48 + shock, exponent);
+            let down = pow_i80f48(ONE_I80F48 - shock, exponent);
+            (base * up).min(base * down)
+        } else if base.is_negative() {
+            base * liab_weight
+        } else {
+            base * asset_weight
+        }
+    }
 }
 
 #[derive(Copy, Clone, Pod, Loadable)]
@@ -225,11 +341,53 @@ pub struct MangoGroup {
     pub ref_surcharge_centibps: u32, // 100
     pub ref_share_centibps: u32,     // 80 (must be less than surcharge)
     pub ref_mngo_required: u64,
-    pub dont_square: bool,
-    pub padding: [u8; 7], // padding used for future expansions
+    pub dont_square: bool, // DEPRECATED - see PerpMarketInfo::index_exponent
+    pub padding: [u8; 7],  // padding used for future expansions
 }
 
 impl MangoGroup {
+    /// Size of MangoGroup accounts created with the smaller PerpMarketInfo
+    pub const V0_SIZE: usize = size_of::<MangoGroup>()
+        - MAX_PAIRS * (size_of::<PerpMarketInfo>() - PERP_MARKET_INFO_V0_SIZE);
+
+    /// Move the data of a MangoGroup in the V0 layout, already resized to the current size, into
+    /// the current layout. The new PerpMarketInfo fields start disabled, except that oracle 0
+    /// keeps being cached as oracle 1 squared unless dont_square was set.
+    pub fn upgrade_v0_layout(data: &mut [u8]) -> MangoResult {
+        check_eq!(data.len(), size_of::<MangoGroup>(), MangoErrorCode::InvalidAccount)?;
+        let info_size = size_of::<PerpMarketInfo>();
+        let perp_markets_start = size_of::<MetaData>()
+            + size_of::<usize>()
+            + size_of::<[TokenInfo; MAX_TOKENS]>()
+            + size_of::<[SpotMarketInfo; MAX_PAIRS]>();
+
+        // Everything after perp_markets moves back; then each PerpMarketInfo, last one first
+        let old_tail_start = perp_markets_start + MAX_PAIRS * PERP_MARKET_INFO_V0_SIZE;
+        let new_tail_start = perp_markets_start + MAX_PAIRS * info_size;
+        data.copy_within(old_tail_start..Self::V0_SIZE, new_tail_start);
+        for i in (0..MAX_PAIRS).rev() {
+            let old_start = perp_markets_start + i * PERP_MARKET_INFO_V0_SIZE;
+            let new_start = perp_markets_start + i * info_size;
+            data.copy_within(old_start..old_start + PERP_MARKET_INFO_V0_SIZE, new_start);
+            data[new_start + PERP_MARKET_INFO_V0_SIZE..new_start + info_size].fill(0);
+        }
+
+        let mango_group: &mut MangoGroup = from_bytes_mut(data);
+        for i in 0..MAX_PAIRS {
+            let info = &mut mango_group.perp_markets[i];
+            info.index_divisor = ONE_I80F48;
+            info.underlying_oracle_index = i as u8;
+            info.index_exponent = 1;
+        }
+        if !mango_group.dont_square && mango_group.num_oracles > 1 {
+            let info = &mut mango_group.perp_markets[0];
+            info.underlying_oracle_index = 1;
+            info.index_exponent = 2;
+            info.index_divisor = I80F48!(1_000_000);
+        }
+        Ok(())
+    }
+
     pub fn load_mut_checked<'a>(
         account: &'a AccountInfo,
         program_id: &Pubkey,
@@ -263,6 +421,24 @@ impl MangoGroup {
         Ok(mango_group)
     }
 
+    /// Limits applied when reading the oracle price cached at `oracle_index`. A configured
+    /// max_conf_bps of a perp with a derived index (see PerpMarketInfo::index_params) applies to
+    /// the index, whose confidence interval is the underlying's times the index exponent
+    pub fn oracle_limits(&self, oracle_index: usize) -> OracleLimits {
+        let info = &self.tokens[oracle_index];
+        let (_, exponent, _) = self.perp_markets[oracle_index].index_params(oracle_index);
+        let max_conf = if info.max_conf_bps == 0 {
+            None
+        } else {
+            Some(
+                I80F48::from_num(info.max_conf_bps)
+                    / I80F48::from_num(10_000)
+                    / I80F48::from_num(exponent),
+            )
+        };
+        OracleLimits { max_conf, max_age_slots: info.max_oracle_age_slots as u64 }
+    }
+
     pub fn find_oracle_index(&self, oracle_pk: &Pubkey) -> Option<usize> {
         self.oracles.iter().position(|pk| pk == oracle_pk) // TODO OPT profile
     }
@@ -605,15 +781,83 @@ impl NodeBank {
 pub struct PriceCache {
     pub price: I80F48, // unit is interpreted as how many quote native tokens for 1 base native token
     pub last_update: u64,
+    pub ema_price: I80F48, // exponential moving average of price; see PerpMarketInfo::ema_period
+
+    // non-zero if the circuit breaker rejected this price; see PerpMarketInfo::max_price_move_bps
+    pub halted_price: I80F48,
+    pub halt_ts: u64,
 }
 
 impl PriceCache {
-    pub fn check_valid(&self, mango_group: &MangoGroup, now_ts: u64) -> MangoResult<()> {
+    /// Set a new price and fold it into the EMA, weighted by the time since the last update so
+    /// cranking CachePrices more often doesn't speed it up
+    pub fn update(&mut self, price: I80F48, now_ts: u64, ema_period: u16) {
+        self.ema_price = if ema_period == 0 || !self.ema_price.is_positive() {
+            price
+        } else {
+            // linear approximation of 1 - e^(-dt / period), capped at a full replacement
+            let elapsed = I80F48::from_num(now_ts.saturating_sub(self.last_update));
+            let alpha = min(ONE_I80F48, elapsed / I80F48::from_num(ema_period));
+            self.ema_price + alpha * (price - self.ema_price)
+        };
+        self.price = price;
+        self.last_update = now_ts;
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted_price.is_positive()
+    }
+
+    /// Circuit breaker: returns false and halts if the price moved more than `max_move_bps` per
+    /// `valid_interval` since the last update. The halt clears on a price close to the cached
+    /// price, or on a price at a later time close to the one that caused the halt
+    pub fn check_price_move(
+        &mut self,
+        price: I80F48,
+        now_ts: u64,
+        max_move_bps: u16,
+        valid_interval: u64,
+    ) -> bool {
+        if max_move_bps == 0 || !self.price.is_positive() {
+            self.halted_price = ZERO_I80F48;
+            self.halt_ts = 0;
+            return true;
+        }
+
+        let intervals = max(1, now_ts.saturating_sub(self.last_update) / max(1, valid_interval));
+        let max_move =
+            I80F48::from_num((max_move_bps as u64).saturating_mul(intervals)) / I80F48!(10_000);
+        let within = |reference: I80F48| ((price - reference) / reference).abs() <= max_move;
+
+        let confirms_halt = self.is_halted() && within(self.halted_price);
+        if within(self.price) || (confirms_halt && now_ts > self.halt_ts) {
+            self.halted_price = ZERO_I80F48;
+            self.halt_ts = 0;
+            true
+        } else {
+            if !confirms_halt {
+                self.halted_price = price;
+                self.halt_ts = now_ts;
+            }
+            false
+        }
+    }
+
+    pub fn check_valid(
+        &self,
+        mango_group: &MangoGroup,
+        token_index: usize,
+        now_ts: u64,
+    ) -> MangoResult<()> {
         // Hack: explicitly double valid_interval as a quick fix to make Mango
         // less likely to become unusable when solana reliability goes bad.
-        // There's currently no instruction to change the valid_interval.
+        // Tokens can override it with ChangeOracleLimits.
+        let valid_interval = match mango_group.tokens[token_index].price_valid_interval {
+            0 => 2 * mango_group.valid_interval,
+            interval => interval as u64,
+        };
         check!(
-            self.last_update >= now_ts - (2 * mango_group.valid_interval),
+            self.last_update >= now_ts.saturating_sub(valid_interval),
             MangoErrorCode::InvalidPriceCache
         )
     }
@@ -664,6 +908,35 @@ pub struct MangoCache {
 }
 
 impl MangoCache {
+    /// Size of MangoCache accounts created with the smaller PriceCache
+    pub const V0_SIZE: usize =
+        size_of::<MangoCache>() - MAX_PAIRS * (size_of::<PriceCache>() - PRICE_CACHE_V0_SIZE);
+
+    /// Move the data of a MangoCache in the V0 layout, already resized to the current size, into
+    /// the current layout. The EMA starts at the cached price and no price is halted.
+    pub fn upgrade_v0_layout(data: &mut [u8]) -> MangoResult {
+        check_eq!(data.len(), size_of::<MangoCache>(), MangoErrorCode::InvalidAccount)?;
+        let entry_size = size_of::<PriceCache>();
+        let price_cache_start = size_of::<MetaData>();
+
+        // Everything after price_cache moves back; then each PriceCache, last one first
+        let old_tail_start = price_cache_start + MAX_PAIRS * PRICE_CACHE_V0_SIZE;
+        let new_tail_start = price_cache_start + MAX_PAIRS * entry_size;
+        data.copy_within(old_tail_start..Self::V0_SIZE, new_tail_start);
+        for i in (0..MAX_PAIRS).rev() {
+            let old_start = price_cache_start + i * PRICE_CACHE_V0_SIZE;
+            let new_start = price_cache_start + i * entry_size;
+            data.copy_within(old_start..old_start + PRICE_CACHE_V0_SIZE, new_start);
+            data[new_start + PRICE_CACHE_V0_SIZE..new_start + entry_size].fill(0);
+        }
+
+        let mango_cache: &mut MangoCache = from_bytes_mut(data);
+        for price_cache in mango_cache.price_cache.iter_mut() {
+            price_cache.ema_price = price_cache.price;
+        }
+        Ok(())
+    }
+
     pub fn load_mut_checked<'a>(
         account: &'a AccountInfo,
         program_id: &Pubkey,
@@ -713,7 +986,7 @@ impl MangoCache {
     ) -> MangoResult<()> {
         for i in 0..mango_group.num_oracles {
             if active_assets.spot[i] || active_assets.perps[i] {
-                self.price_cache[i].check_valid(&mango_group, now_ts)?;
+                self.price_cache[i].check_valid(&mango_group, i, now_ts)?;
             }
 
             if active_assets.spot[i] {
@@ -727,6 +1000,20 @@ impl MangoCache {
         self.root_bank_cache[QUOTE_INDEX].check_valid(&mango_group, now_ts)
     }
 
+    /// Fail if the circuit breaker halted the price of any of the active assets
+    pub fn check_not_halted(
+        &self,
+        mango_group: &MangoGroup,
+        active_assets: &UserActiveAssets,
+    ) -> MangoResult<()> {
+        for i in 0..mango_group.num_oracles {
+            if active_assets.spot[i] || active_assets.perps[i] {
+                check!(!self.price_cache[i].is_halted(), MangoErrorCode::PriceHalted)?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn get_price(&self, i: usize) -> I80F48 {
         if i == QUOTE_INDEX {
             ONE_I80F48
@@ -792,6 +1079,9 @@ pub struct HealthCache {
 
     /// This will be zero until update_health is called for the first time
     health: [Option<I80F48>; NUM_HEALTHS],
+
+    /// Value perps with the EMA price on markets where liquidation_use_ema is set
+    use_ema: bool,
 }
 
 impl HealthCache {
@@ -802,9 +1092,32 @@ impl HealthCache {
             perp: vec![(ZERO_I80F48, ZERO_I80F48); MAX_PAIRS],
             quote: ZERO_I80F48,
             health: [None; NUM_HEALTHS],
+            use_ema: false,
         }
     }
 
+    /// HealthCache for the liqee of a liquidation, so one bad oracle tick can't liquidate
+    /// perp positions on markets that smooth their price
+    pub fn new_for_liquidation(active_assets: UserActiveAssets) -> Self {
+        Self { use_ema: true, ..Self::new(active_assets) }
+    }
+
+    /// Price the perp positions of market i are valued at in this HealthCache
+    pub fn get_perp_price(
+        &self,
+        mango_group: &MangoGroup,
+        mango_cache: &MangoCache,
+        i: usize,
+    ) -> I80F48 {
+        let pmi = &mango_group.perp_markets[i];
+        let price_cache = &mango_cache.price_cache[i];
+        pmi.settled_or(if self.use_ema && pmi.liquidation_use_ema {
+            price_cache.ema_price
+        } else {
+            price_cache.price
+        })
+    }
+
     pub fn init_vals(
         &mut self,
         mango_group: &MangoGroup,
@@ -831,7 +1144,7 @@ impl HealthCache {
                 self.perp[i] = mango_account.perp_accounts[i].get_val(
                     &mango_group.perp_markets[i],
                     &mango_cache.perp_market_cache[i],
-                    mango_cache.price_cache[i].price,
+                    self.get_perp_price(mango_group, mango_cache, i),
                 )?;
             }
         }
@@ -861,7 +1174,7 @@ impl HealthCache {
                 self.perp[i] = mango_account.perp_accounts[i].get_val(
                     &mango_group.perp_markets[i],
                     &mango_cache.perp_market_cache[i],
-                    mango_cache.price_cache[i].price,
+                    self.get_perp_price(mango_group, mango_cache, i),
                 )?;
             }
         }
@@ -918,22 +1231,16 @@ impl HealthCache {
                     let spot_market_info = &mango_group.spot_markets[i];
                     let perp_market_info = &mango_group.perp_markets[i];
 
-                    let (spot_asset_weight, spot_liab_weight, perp_asset_weight, perp_liab_weight) =
-                        match health_type {
-                            HealthType::Maint => (
-                                spot_market_info.maint_asset_weight,
-                                spot_market_info.maint_liab_weight,
-                                perp_market_info.maint_asset_weight,
-                                perp_market_info.maint_liab_weight,
-                            ),
-                            HealthType::Init => (
-                                spot_market_info.init_asset_weight,
-                                spot_market_info.init_liab_weight,
-                                perp_market_info.init_asset_weight,
-                                perp_market_info.init_liab_weight,
-                            ),
-                            HealthType::Equity => (ONE_I80F48, ONE_I80F48, ONE_I80F48, ONE_I80F48),
-                        };
+                    let (spot_asset_weight, spot_liab_weight) = match health_type {
+                        HealthType::Maint => (
+                            spot_market_info.maint_asset_weight,
+                            spot_market_info.maint_liab_weight,
+                        ),
+                        HealthType::Init => {
+                            (spot_market_info.init_asset_weight, spot_market_info.init_liab_weight)
+                        }
+                        HealthType::Equity => (ONE_I80F48, ONE_I80F48),
+                    };
 
                     if self.active_assets.spot[i] {
                         let (base, quote) = self.spot[i];
@@ -946,11 +1253,7 @@ impl HealthCache {
 
                     if self.active_assets.perps[i] {
                         let (base, quote) = self.perp[i];
-                        if base.is_negative() {
-                            health += base * perp_liab_weight + quote;
-                        } else {
-                            health += base * perp_asset_weight + quote
-                        }
+                        health += perp_market_info.get_weighted_base_val(base, health_type) + quote;
                     }
                 }
 
@@ -976,22 +1279,15 @@ impl HealthCache {
             let spot_market_info = &mango_group.spot_markets[i];
             let perp_market_info = &mango_group.perp_markets[i];
 
-            let (spot_asset_weight, spot_liab_weight, perp_asset_weight, perp_liab_weight) =
-                match health_type {
-                    HealthType::Maint => (
-                        spot_market_info.maint_asset_weight,
-                        spot_market_info.maint_liab_weight,
-                        perp_market_info.maint_asset_weight,
-                        perp_market_info.maint_liab_weight,
-                    ),
-                    HealthType::Init => (
-                        spot_market_info.init_asset_weight,
-                        spot_market_info.init_liab_weight,
-                        perp_market_info.init_asset_weight,
-                        perp_market_info.init_liab_weight,
-                    ),
-                    HealthType::Equity => (ONE_I80F48, ONE_I80F48, ONE_I80F48, ONE_I80F48),
-                };
+            let (spot_asset_weight, spot_liab_weight) = match health_type {
+                HealthType::Maint => {
+                    (spot_market_info.maint_asset_weight, spot_market_info.maint_liab_weight)
+                }
+                HealthType::Init => {
+                    (spot_market_info.init_asset_weight, spot_market_info.init_liab_weight)
+                }
+                HealthType::Equity => (ONE_I80F48, ONE_I80F48),
+            };
 
             if self.active_assets.spot[i] {
                 let (base, quote) = self.spot[i];
@@ -1014,10 +1310,11 @@ impl HealthCache {
                 } else {
                     assets += quote;
                 }
-                if base.is_negative() {
-                    liabilities -= base * perp_liab_weight;
+                let weighted_base = perp_market_info.get_weighted_base_val(base, health_type);
+                if weighted_base.is_negative() {
+                    liabilities -= weighted_base;
                 } else {
-                    assets += base * perp_asset_weight;
+                    assets += weighted_base;
                 }
             }
         }
@@ -1130,7 +1427,7 @@ impl HealthCache {
         let (base, quote) = mango_account.perp_accounts[market_index].sim_get_val(
             info,
             &mango_cache.perp_market_cache[market_index],
-            mango_cache.price_cache[market_index].price,
+            self.get_perp_price(mango_group, mango_cache, market_index),
             taker_base,
             taker_quote,
             bids_quantity,
@@ -1140,24 +1437,9 @@ impl HealthCache {
         let (prev_base, prev_quote) = self.perp[market_index];
         let pmi = &mango_group.perp_markets[market_index];
 
-        let (asset_weight, liab_weight) = match health_type {
-            HealthType::Maint => (pmi.maint_asset_weight, pmi.maint_liab_weight),
-            HealthType::Init => (pmi.init_asset_weight, pmi.init_liab_weight),
-            HealthType::Equity => (ONE_I80F48, ONE_I80F48),
-        };
-
         // Get health from val
-        let prev_perp_health = if prev_base.is_negative() {
-            prev_base * liab_weight + prev_quote
-        } else {
-            prev_base * asset_weight + prev_quote
-        };
-
-        let curr_perp_health = if base.is_negative() {
-            base * liab_weight + quote
-        } else {
-            base * asset_weight + quote
-        };
+        let prev_perp_health = pmi.get_weighted_base_val(prev_base, health_type) + prev_quote;
+        let curr_perp_health = pmi.get_weighted_base_val(base, health_type) + quote;
 
         let h = self.health[health_type as usize].ok_or(throw!())?;
 
@@ -1194,7 +1476,7 @@ impl HealthCache {
         let (base, quote) = mango_account.perp_accounts[market_index].get_val(
             &mango_group.perp_markets[market_index],
             &mango_cache.perp_market_cache[market_index],
-            mango_cache.price_cache[market_index].price,
+            self.get_perp_price(mango_group, mango_cache, market_index),
         )?;
 
         let (prev_base, prev_quote) = self.perp[market_index];
@@ -1204,24 +1486,10 @@ impl HealthCache {
                 let health_type: HealthType = HealthType::try_from_primitive(i).unwrap();
                 let pmi = &mango_group.perp_markets[market_index];
 
-                let (asset_weight, liab_weight) = match health_type {
-                    HealthType::Maint => (pmi.maint_asset_weight, pmi.maint_liab_weight),
-                    HealthType::Init => (pmi.init_asset_weight, pmi.init_liab_weight),
-                    HealthType::Equity => (ONE_I80F48, ONE_I80F48),
-                };
-
                 // Get health from val
-                let prev_perp_health = if prev_base.is_negative() {
-                    prev_base * liab_weight + prev_quote
-                } else {
-                    prev_base * asset_weight + prev_quote
-                };
-
-                let curr_perp_health = if base.is_negative() {
-                    base * liab_weight + quote
-                } else {
-                    base * asset_weight + quote
-                };
+                let prev_perp_health =
+                    pmi.get_weighted_base_val(prev_base, health_type) + prev_quote;
+                let curr_perp_health = pmi.get_weighted_base_val(base, health_type) + quote;
 
                 self.health[i] = Some(h + curr_perp_health - prev_perp_health);
             }
@@ -1275,11 +1543,15 @@ pub struct MangoAccount {
     // Alternative authority/signer of transactions for a mango account
     pub delegate: Pubkey,
 
+    /// Whether the IcebergOrders PDA has active orders; if so, instructions that cancel perp
+    /// orders need it to drop the hidden rest of canceled iceberg orders
+    pub has_iceberg_orders: bool,
+
     /// padding for expansions
     /// Note: future expansion can also be just done via isolated PDAs
     /// which can be computed independently and dont need to be linked from
     /// this account
-    pub padding: [u8; 5],
+    pub padding: [u8; 4],
 }
 
 impl MangoAccount {
@@ -1685,8 +1957,10 @@ impl MangoAccount {
         }
         pa.quote_position = pa.quote_position.checked_add(quote - fees).unwrap();
 
-        // if versions don't match, no LM
-        if perp_market.meta_data.version == fill.version {
+        // if versions don't match, no LM; oracle pegged orders get none either
+        if perp_market.meta_data.version == fill.version
+            && !is_oracle_pegged_key(fill.maker_order_id)
+        {
             if fill.version == 0 {
                 pa.apply_price_incentives(
                     perp_market,
@@ -1748,6 +2022,11 @@ impl MangoAccount {
         None
     }
 
+    /// Ids of the orders in `orders_before`, an earlier copy of `orders`, that are gone now
+    pub fn removed_order_ids(&self, orders_before: &[i128; MAX_PERP_OPEN_ORDERS]) -> Vec<i128> {
+        orders_before.iter().filter(|&&id| id != 0 && !self.orders.contains(&id)).copied().collect()
+    }
+
     /// Calculate the max native units to withdraw
     pub fn max_withdrawable(
         &self,
@@ -1820,6 +2099,43 @@ pub struct PerpAccount {
 }
 
 impl PerpAccount {
+    /// Position in base lots if all orders on `side` were filled: long for bids, short for asks.
+    /// Negative if the orders can't even close the opposite position.
+    pub fn exposure(&self, side: Side) -> i64 {
+        let base_position = self.base_position + self.taker_base;
+        match side {
+            Side::Bid => base_position + self.bids_quantity,
+            Side::Ask => self.asks_quantity - base_position,
+        }
+    }
+
+    /// Value of the position at `price` net of the quote paid for it. Funding must be settled
+    /// before calling this.
+    pub fn unrealized_pnl(&self, base_lot_size: i64, price: I80F48) -> I80F48 {
+        self.quote_position + I80F48::from_num(self.base_position * base_lot_size) * price
+    }
+
+    /// Unrealized pnl times leverage, which ranks accounts in the auto-deleveraging queue.
+    /// None unless the position is profitable at `price` and `equity` is positive.
+    pub fn adl_score(&self, base_lot_size: i64, price: I80F48, equity: I80F48) -> Option<I80F48> {
+        if self.base_position == 0 || !equity.is_positive() {
+            return None;
+        }
+        let pnl = self.unrealized_pnl(base_lot_size, price);
+        if !pnl.is_positive() {
+            return None;
+        }
+        let notional = I80F48::from_num(self.base_position * base_lot_size).abs() * price;
+        pnl.checked_mul(notional)?.checked_div(equity)
+    }
+
+    /// Price at which closing the whole position makes up `loss` compared to closing it at
+    /// `price`. Never negative; any loss beyond that is left for the bankruptcy process.
+    pub fn bankruptcy_price(&self, base_lot_size: i64, price: I80F48, loss: I80F48) -> I80F48 {
+        let base = I80F48::from_num(self.base_position * base_lot_size);
+        (price + loss / base).max(ZERO_I80F48)
+    }
+
     /// Add taker trade after it has been matched but before it has been process on EventQueue
     pub fn add_taker_trade(&mut self, base_change: i64, quote_change: i64) {
         // TODO make checked? estimate chances of overflow here
@@ -2143,6 +2459,33 @@ pub struct PerpMarket {
 
     // mngo_vault holds mango tokens to be disbursed as liquidity incentives for this perp market
     pub mngo_vault: Pubkey,
+
+    pub funding_period: u64, // seconds over which the book premium is paid out as funding
+
+    pub impact_quantity: i64, // base lots used to compute the impact bid/ask for funding
+    pub max_funding: I80F48,  // clamp on the book premium used for funding
+    pub min_funding: I80F48,
+
+    // time weighted book premium since last_updated; see update_premium
+    pub premium_accumulator: I80F48, // sum of premium * seconds
+    pub last_premium: I80F48,        // premium of the book after the last change
+    pub last_index_price: I80F48,    // index price last_premium was computed against
+    pub last_premium_update: u64,
+
+    // dated futures; expiry is 0 for perpetual markets
+    pub expiry: u64,                   // no new orders at or after this timestamp
+    pub settlement_window: u64,        // seconds before expiry over which the index is averaged
+    pub settlement_price_sum: I80F48,  // sum of sampled index price * seconds it was the latest
+    pub settlement_price: I80F48,      // zero until SettleExpiredPerpMarket finalizes it
+    pub settlement_last_price: I80F48, // index price of the latest sample
+    pub settlement_last_sample: u64,
+    pub settlement_first_sample: u64,
+    pub settlement_num_samples: u64,
+
+    // caps in base lots regardless of health; 0 disables. open_interest counts both sides and
+    // its cap is soft, see check_order_limits
+    pub max_position_lots: i64,
+    pub max_open_interest: i64,
 }
 
 impl PerpMarket {
@@ -2165,6 +2508,7 @@ impl PerpMarket {
         exp: u8,
         version: u8,
         lm_size_shift: u8, // right shift the depth number to prevent overflow
+        funding_period: u64,
     ) -> MangoResult<RefMut<'a, Self>> {
         let mut state = Self::load_mut(account)?;
         check!(account.owner == program_id, MangoErrorCode::InvalidOwner)?;
@@ -2186,6 +2530,10 @@ impl PerpMarket {
         state.event_queue = *event_queue_ai.key;
         state.quote_lot_size = quote_lot_size;
         state.base_lot_size = base_lot_size;
+        state.funding_period = funding_period;
+        state.impact_quantity = DEFAULT_IMPACT_QUANTITY;
+        state.max_funding = DEFAULT_MAX_FUNDING;
+        state.min_funding = DEFAULT_MIN_FUNDING;
 
         let vault = Account::unpack(&mngo_vault_ai.try_borrow_data()?)?;
         check!(vault.owner == mango_group.signer_key, MangoErrorCode::InvalidOwner)?;
@@ -2198,6 +2546,7 @@ impl PerpMarket {
         let clock = Clock::get()?;
         let period_start = clock.unix_timestamp as u64;
         state.last_updated = period_start;
+        state.last_premium_update = period_start;
 
         state.liquidity_mining_info = LiquidityMiningInfo {
             rate,
@@ -2240,6 +2589,21 @@ impl PerpMarket {
         Ok(state)
     }
 
+    /// Size of PerpMarket accounts created before the funding, premium, expiry and limit fields
+    pub const V0_SIZE: usize = 320;
+
+    /// Set the fields after V0_SIZE of an upgraded PerpMarket so funding works as it did before:
+    /// one week funding period for market index 0 and one day otherwise, the default impact
+    /// quantity and funding clamps. Expiry and position limits stay disabled.
+    pub fn init_v0_upgrade(&mut self, market_index: usize) {
+        let funding_period = if market_index == 0 { WEEK } else { DAY };
+        self.funding_period = funding_period.to_num();
+        self.impact_quantity = DEFAULT_IMPACT_QUANTITY;
+        self.max_funding = DEFAULT_MAX_FUNDING;
+        self.min_funding = DEFAULT_MIN_FUNDING;
+        self.last_premium_update = self.last_updated;
+    }
+
     pub fn gen_order_id(&mut self, side: Side, price: i64) -> i128 {
         self.seq_num += 1;
 
@@ -2250,6 +2614,18 @@ impl PerpMarket {
         }
     }
 
+    /// Order id of an oracle pegged order; see `matching::is_oracle_pegged_key`
+    pub fn gen_oracle_pegged_order_id(&mut self, side: Side, price_offset: i64) -> i128 {
+        self.seq_num += 1;
+
+        let upper = (((price_offset as u64) ^ (1 << 63)) as u128) << 64;
+        let lower = match side {
+            Side::Bid => !self.seq_num,
+            Side::Ask => self.seq_num,
+        } ^ (1 << 62);
+        (upper | lower as u128) as i128
+    }
+
     /// Use current order book price and index price to update the instantaneous funding
     pub fn update_funding(
         &mut self,
@@ -2261,36 +2637,30 @@ impl PerpMarket {
     ) -> MangoResult {
         // Get the index price from cache, ensure it's not outdated
         let price_cache = &mango_cache.price_cache[market_index];
-        price_cache.check_valid(&mango_group, now_ts)?;
-
-        let index_price = price_cache.price;
-        // hard-coded for now because there's no convenient place to put this; also creates breaking
-        // change if we make this a parameter
-        const IMPACT_QUANTITY: i64 = 100;
+        price_cache.check_valid(&mango_group, market_index, now_ts)?;
 
-        // Get current book price & compare it to index price
-        let bid = book.get_impact_price(Side::Bid, IMPACT_QUANTITY, now_ts);
-        let ask = book.get_impact_price(Side::Ask, IMPACT_QUANTITY, now_ts);
+        // No funding is paid after a dated future expires
+        let now_ts = if self.expiry != 0 { now_ts.min(self.expiry) } else { now_ts };
 
-        const MAX_FUNDING: I80F48 = I80F48!(0.05);
-        const MIN_FUNDING: I80F48 = I80F48!(-0.05);
+        let index_price = if mango_group.perp_markets[market_index].funding_use_ema {
+            price_cache.ema_price
+        } else {
+            price_cache.price
+        };
 
-        let diff = match (bid, ask) {
-            (Some(bid), Some(ask)) => {
-                // calculate mid-market rate
-                let book_price = self.lot_to_native_price((bid + ask) / 2);
-                (book_price / index_price - ONE_I80F48).clamp(MIN_FUNDING, MAX_FUNDING)
-            }
-            (Some(_bid), None) => MAX_FUNDING,
-            (None, Some(_ask)) => MIN_FUNDING,
-            (None, None) => ZERO_I80F48,
+        // Use the time weighted average of the book premium since the last update so an order
+        // placed right before this crank can't move funding much
+        self.update_premium(book, index_price, now_ts);
+        let elapsed = I80F48::from_num(now_ts - self.last_updated);
+        let diff = if elapsed.is_positive() {
+            (self.premium_accumulator / elapsed).clamp(self.min_funding, self.max_funding)
+        } else {
+            ZERO_I80F48
         };
+        self.premium_accumulator = ZERO_I80F48;
 
         // TODO TEST consider what happens if time_factor is very small. Can funding_delta == 0 when diff != 0?
-
-        // DAI: Use 7-day funding for power perps.
-        let funding_period = if market_index == 0 { WEEK } else { DAY };
-        let time_factor = I80F48::from_num(now_ts - self.last_updated) / funding_period;
+        let time_factor = elapsed / I80F48::from_num(self.funding_period);
 
         let funding_delta: I80F48 = index_price
             .checked_mul(diff)
@@ -2308,6 +2678,144 @@ impl PerpMarket {
         Ok(())
     }
 
+    pub fn is_expired(&self, now_ts: u64) -> bool {
+        self.expiry != 0 && now_ts >= self.expiry
+    }
+
+    /// Fails if a position in base lots grows from `position_start` to `position` beyond
+    /// max_position_lots. Positions that don't grow are always fine, even above the cap.
+    pub fn check_max_position(&self, position_start: i64, position: i64) -> MangoResult {
+        if self.max_position_lots == 0 || position.abs() <= position_start.abs() {
+            return Ok(());
+        }
+        check!(position.abs() <= self.max_position_lots, MangoErrorCode::MaxPositionExceeded)
+    }
+
+    /// Fails if open interest would grow by `increase` base lots beyond max_open_interest
+    pub fn check_max_open_interest(&self, increase: i64) -> MangoResult {
+        if self.max_open_interest == 0 || increase <= 0 {
+            return Ok(());
+        }
+        check!(
+            self.open_interest + increase <= self.max_open_interest,
+            MangoErrorCode::MaxOpenInterestExceeded
+        )
+    }
+
+    /// Check an order that moved the account's exposure on `side` from `exposure_start` and
+    /// its taker_base from `taker_base_start` against both limits. The position cap counts the
+    /// resting quantity too, open interest only the matched fills. The makers' accounts aren't
+    /// loaded, so their side of each fill is counted as opening.
+    ///
+    /// The open interest cap is soft: fills only reach open_interest once ConsumeEvents
+    /// processes them, which can't reject fills that already matched, so fills still waiting
+    /// on the EventQueue are not counted.
+    pub fn check_order_limits(
+        &self,
+        pa: &PerpAccount,
+        side: Side,
+        exposure_start: i64,
+        taker_base_start: i64,
+    ) -> MangoResult {
+        self.check_max_position(exposure_start.max(0), pa.exposure(side).max(0))?;
+
+        let fills = pa.taker_base - taker_base_start;
+        let taker_start = pa.base_position + taker_base_start;
+        let taker_increase = (taker_start + fills).abs() - taker_start.abs();
+        self.check_max_open_interest(taker_increase + fills.abs())
+    }
+
+    pub fn is_settled(&self) -> bool {
+        self.settlement_price.is_positive()
+    }
+
+    /// Seconds that must pass between two samples of the settlement TWAP
+    pub fn settlement_sample_interval(&self) -> u64 {
+        self.settlement_window / MIN_SETTLEMENT_SAMPLES
+    }
+
+    /// Add a sample of the index price to the settlement TWAP. Each sampled price is weighted by
+    /// the seconds until the next sample or expiry, and samples must be settlement_sample_interval
+    /// apart. The first call at or after expiry sets the settlement price to the average since the
+    /// first sample, which fails if the window had fewer than MIN_SETTLEMENT_SAMPLES samples.
+    pub fn sample_settlement_price(&mut self, index_price: I80F48, now_ts: u64) -> MangoResult {
+        let expired = self.is_expired(now_ts);
+        if expired {
+            check!(
+                self.settlement_num_samples >= MIN_SETTLEMENT_SAMPLES,
+                MangoErrorCode::NotEnoughSettlementSamples
+            )?;
+        } else if self.settlement_num_samples > 0 {
+            check!(
+                now_ts >= self.settlement_last_sample + self.settlement_sample_interval(),
+                MangoErrorCode::InvalidParam
+            )?;
+        }
+
+        // The previous sample holds until now
+        let end = now_ts.min(self.expiry);
+        if self.settlement_num_samples > 0 {
+            self.settlement_price_sum +=
+                self.settlement_last_price * I80F48::from_num(end - self.settlement_last_sample);
+        } else {
+            self.settlement_first_sample = end;
+        }
+        self.settlement_last_sample = end;
+
+        if expired {
+            self.settlement_price = self.settlement_price_sum
+                / I80F48::from_num(self.expiry - self.settlement_first_sample);
+        } else {
+            self.settlement_last_price = index_price;
+            self.settlement_num_samples += 1;
+        }
+        Ok(())
+    }
+
+    /// Clear the settlement TWAP, e.g. when the expiry moves
+    pub fn reset_settlement_samples(&mut self) {
+        self.settlement_price_sum = ZERO_I80F48;
+        self.settlement_last_price = ZERO_I80F48;
+        self.settlement_last_sample = 0;
+        self.settlement_first_sample = 0;
+        self.settlement_num_samples = 0;
+    }
+
+    /// Premium of the impact mid price over the index price, clamped to the funding limits
+    pub fn get_book_premium(&self, book: &Book, index_price: I80F48, now_ts: u64) -> I80F48 {
+        if !index_price.is_positive() {
+            return ZERO_I80F48;
+        }
+
+        // Get current book price & compare it to index price
+        let index_price_lots = self.native_price_to_lot(index_price);
+        let bid = book.get_impact_price(Side::Bid, self.impact_quantity, index_price_lots, now_ts);
+        let ask = book.get_impact_price(Side::Ask, self.impact_quantity, index_price_lots, now_ts);
+
+        match (bid, ask) {
+            (Some(bid), Some(ask)) => {
+                // calculate mid-market rate
+                let book_price = self.lot_to_native_price((bid + ask) / 2);
+                (book_price / index_price - ONE_I80F48).clamp(self.min_funding, self.max_funding)
+            }
+            (Some(_bid), None) => self.max_funding,
+            (None, Some(_ask)) => self.min_funding,
+            (None, None) => ZERO_I80F48,
+        }
+    }
+
+    /// Accrue the last recorded premium for the time it was in effect, then record the premium
+    /// of the current book. Must be called after every change to the book.
+    pub fn update_premium(&mut self, book: &Book, index_price: I80F48, now_ts: u64) {
+        if now_ts > self.last_premium_update {
+            let elapsed = I80F48::from_num(now_ts - self.last_premium_update);
+            self.premium_accumulator += self.last_premium * elapsed;
+            self.last_premium_update = now_ts;
+        }
+        self.last_index_price = index_price;
+        self.last_premium = self.get_book_premium(book, index_price, now_ts);
+    }
+
     /// Convert from the price stored on the book to the price used in value calculations
     pub fn lot_to_native_price(&self, price: i64) -> I80F48 {
         I80F48::from_num(price)
@@ -2317,7 +2825,19 @@ impl PerpMarket {
             .unwrap()
     }
 
-    /// Socialize the loss in this account across all longs and shorts
+    /// Native price converted to quote lots per base lot, rounded down
+    pub fn native_price_to_lot(&self, price: I80F48) -> i64 {
+        price
+            .checked_mul(I80F48::from_num(self.base_lot_size))
+            .unwrap()
+            .checked_div(I80F48::from_num(self.quote_lot_size))
+            .unwrap()
+            .checked_to_num()
+            .unwrap_or(i64::MAX)
+    }
+
+    /// Socialize the loss in this account across all longs and shorts. With an empty insurance
+    /// fund, ResolvePerpBankruptcy takes the loss from the ADL queue first; this spreads the rest.
     pub fn socialize_loss(
         &mut self,
         account: &mut PerpAccount,
@@ -2693,11 +3213,181 @@ impl OtcOrders {
     }
 }
 
+/// A perp order of which only `display_quantity` rests on the book at a time
+#[derive(Copy, Clone, Pod)]
+#[repr(C)]
+pub struct IcebergOrder {
+    pub is_active: bool,
+    pub market_index: u8,
+    pub side: Side,
+    pub order_type: OrderType,
+    pub padding0: [u8; 4],
+    pub client_order_id: u64,
+    pub price: i64,
+    pub display_quantity: i64,
+    pub hidden_quantity: i64, // not yet posted to the book
+    pub padding1: [u8; 8],
+
+    /// Id of the visible slice; once it leaves the MangoAccount the next slice can be posted
+    pub order_id: i128,
+}
+
+/// PDA with seeds `[ICEBERG_ORDERS_PREFIX, mango_account]` tracking the hidden remainder
+/// of a MangoAccount's iceberg orders. Hidden quantity is not part of bids_quantity or
+/// asks_quantity and so doesn't count towards health until a slice of it is posted, which
+/// the health check at placement already covered.
+#[derive(Copy, Clone, Pod, Loadable)]
+#[repr(C)]
+pub struct IcebergOrders {
+    pub meta_data: MetaData,
+    pub mango_account: Pubkey,
+    pub padding: [u8; 8],
+    pub orders: [IcebergOrder; MAX_ICEBERG_ORDERS],
+}
+
+impl IcebergOrders {
+    pub fn load_and_init<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        rent: &Rent,
+        mango_account: &Pubkey,
+    ) -> MangoResult<RefMut<'a, Self>> {
+        let mut state: RefMut<Self> = Self::load_mut(account)?;
+
+        check!(account.owner == program_id, MangoErrorCode::InvalidOwner)?;
+        check!(
+            rent.is_exempt(account.lamports(), size_of::<Self>()),
+            MangoErrorCode::AccountNotRentExempt
+        )?;
+        check!(!state.meta_data.is_initialized, MangoErrorCode::InvalidAccountState)?;
+
+        state.meta_data = MetaData::new(DataType::IcebergOrders, 0, true);
+        state.mango_account = *mango_account;
+
+        Ok(state)
+    }
+
+    pub fn load_mut_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        mango_account_pk: &Pubkey,
+    ) -> MangoResult<RefMut<'a, Self>> {
+        check_eq!(account.owner, program_id, MangoErrorCode::InvalidOwner)?;
+        let state = Self::load_mut(account)?;
+        check!(state.meta_data.is_initialized, MangoErrorCode::InvalidAccountState)?;
+        check!(
+            state.meta_data.data_type == DataType::IcebergOrders as u8,
+            MangoErrorCode::InvalidAccountState
+        )?;
+        check!(&state.mango_account == mango_account_pk, MangoErrorCode::InvalidAccountState)?;
+        Ok(state)
+    }
+
+    /// Store a new iceberg order in the first free slot
+    pub fn add_order(&mut self, order: IcebergOrder) -> MangoResult {
+        let slot = self
+            .orders
+            .iter()
+            .position(|o| !o.is_active)
+            .ok_or(throw_err!(MangoErrorCode::TooManyOpenOrders))?;
+        self.orders[slot] = order;
+        Ok(())
+    }
+
+    /// Drop the hidden rest of the active iceberg orders for which `canceled` is true
+    pub fn cancel_orders(&mut self, canceled: impl Fn(&IcebergOrder) -> bool) {
+        for order in self.orders.iter_mut() {
+            if order.is_active && canceled(order) {
+                order.is_active = false;
+                order.hidden_quantity = 0;
+            }
+        }
+    }
+
+    pub fn has_active_orders(&self) -> bool {
+        self.orders.iter().any(|o| o.is_active)
+    }
+}
+
+/// PDA with seeds `[HEARTBEAT_PREFIX, mango_account]` acting as a dead-man's switch: once
+/// `deadline` has passed anyone can cancel the MangoAccount's perp orders.
+#[derive(Copy, Clone, Pod, Loadable)]
+#[repr(C)]
+pub struct Heartbeat {
+    pub meta_data: MetaData,
+    pub mango_account: Pubkey,
+    /// Unix timestamp, 0 if the switch is disarmed
+    pub deadline: u64,
+}
+
+impl Heartbeat {
+    pub fn load_and_init<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        rent: &Rent,
+        mango_account: &Pubkey,
+    ) -> MangoResult<RefMut<'a, Self>> {
+        let mut state: RefMut<Self> = Self::load_mut(account)?;
+
+        check!(account.owner == program_id, MangoErrorCode::InvalidOwner)?;
+        check!(
+            rent.is_exempt(account.lamports(), size_of::<Self>()),
+            MangoErrorCode::AccountNotRentExempt
+        )?;
+        check!(!state.meta_data.is_initialized, MangoErrorCode::InvalidAccountState)?;
+
+        state.meta_data = MetaData::new(DataType::Heartbeat, 0, true);
+        state.mango_account = *mango_account;
+        state.deadline = 0;
+
+        Ok(state)
+    }
+
+    pub fn load_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        mango_account_pk: &Pubkey,
+    ) -> MangoResult<Ref<'a, Self>> {
+        check_eq!(account.owner, program_id, MangoErrorCode::InvalidOwner)?;
+        let state = Self::load(account)?;
+        state.check(mango_account_pk)?;
+        Ok(state)
+    }
+
+    pub fn load_mut_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        mango_account_pk: &Pubkey,
+    ) -> MangoResult<RefMut<'a, Self>> {
+        check_eq!(account.owner, program_id, MangoErrorCode::InvalidOwner)?;
+        let state = Self::load_mut(account)?;
+        state.check(mango_account_pk)?;
+        Ok(state)
+    }
+
+    fn check(&self, mango_account_pk: &Pubkey) -> MangoResult {
+        check!(self.meta_data.is_initialized, MangoErrorCode::InvalidAccountState)?;
+        check!(
+            self.meta_data.data_type == DataType::Heartbeat as u8,
+            MangoErrorCode::InvalidAccountState
+        )?;
+        check!(&self.mango_account == mango_account_pk, MangoErrorCode::InvalidAccountState)
+    }
+
+    pub fn is_expired(&self, now_ts: u64) -> bool {
+        self.deadline != 0 && now_ts > self.deadline
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::{
-        mem, DataType, MetaData, OtcOrderStatus, OtcOrders, PerpOtcOrder, Pubkey, Side,
-        SpotOtcOrder, I80F48, MAX_PERP_OTC_ORDERS, MAX_SPOT_OTC_ORDERS,
+        mem, DataType, HealthType, Heartbeat, IcebergOrder, IcebergOrders, MangoCache, MangoGroup,
+        MetaData, OrderType, OtcOrderStatus, OtcOrders, PerpAccount, PerpMarket, PerpMarketInfo,
+        PerpOtcOrder, PriceCache, Pubkey, Side, SpotOtcOrder, DEFAULT_MAX_FUNDING, I80F48,
+        MAX_ICEBERG_ORDERS, MAX_PAIRS, MAX_PERP_OTC_ORDERS, MAX_SPOT_OTC_ORDERS,
+        MIN_SETTLEMENT_SAMPLES, ONE_I80F48, PERP_MARKET_INFO_V0_SIZE, PRICE_CACHE_V0_SIZE,
+        ZERO_I80F48,
     };
     use solana_program::system_program;
 
@@ -3208,6 +3898,364 @@ mod tests {
         assert_eq!(otc_orders.spot_orders_len, 1);
         assert_eq!(otc_orders.spot_orders[0].status, OtcOrderStatus::Canceled);
     }
+
+    #[test]
+    pub fn success_shocked_perp_health() {
+        fn assert_close(a: I80F48, b: f64) {
+            assert!((a - I80F48::from_num(b)).abs() < I80F48::from_num(0.000001));
+        }
+
+        let mut info: PerpMarketInfo = unsafe { mem::zeroed() };
+        info.maint_asset_weight = I80F48::from_num(0.9);
+        info.maint_liab_weight = I80F48::from_num(1.1);
+        info.index_exponent = 2;
+        let long = I80F48::from_num(100);
+        let short = I80F48::from_num(-100);
+
+        // linear weights without a shock
+        assert_close(info.get_weighted_base_val(long, HealthType::Maint), 90.0);
+        assert_close(info.get_weighted_base_val(short, HealthType::Maint), -110.0);
+
+        // a 10% move in the underlying moves a squared index by -19% / +21%
+        info.maint_shock_bps = 1000;
+        assert_close(info.get_weighted_base_val(long, HealthType::Maint), 81.0);
+        assert_close(info.get_weighted_base_val(short, HealthType::Maint), -121.0);
+        assert_eq!(info.get_weighted_base_val(long, HealthType::Equity), long);
+    }
+
+    #[test]
+    pub fn success_price_cache_ema() {
+        let mut price_cache: PriceCache = unsafe { mem::zeroed() };
+
+        // the first price initializes the EMA
+        price_cache.update(I80F48::from_num(100), 1000, 600);
+        assert_eq!(price_cache.ema_price, I80F48::from_num(100));
+
+        // a single bad tick only moves the EMA by the fraction of the period that passed
+        price_cache.update(I80F48::from_num(40), 1150, 600);
+        assert_eq!(price_cache.price, I80F48::from_num(40));
+        assert_eq!(price_cache.ema_price, I80F48::from_num(85));
+
+        // no time passed so the EMA doesn't move
+        price_cache.update(I80F48::from_num(40), 1150, 600);
+        assert_eq!(price_cache.ema_price, I80F48::from_num(85));
+
+        // without an EMA period it tracks the price
+        price_cache.update(I80F48::from_num(40), 1151, 0);
+        assert_eq!(price_cache.ema_price, I80F48::from_num(40));
+    }
+
+    #[test]
+    pub fn price_cache_valid_interval() {
+        let mut mango_group: MangoGroup = unsafe { mem::zeroed() };
+        mango_group.valid_interval = 10;
+        let mut price_cache: PriceCache = unsafe { mem::zeroed() };
+        price_cache.update(I80F48::from_num(100), 1000, 0);
+
+        // twice the group's valid_interval by default
+        assert!(price_cache.check_valid(&mango_group, 0, 1020).is_ok());
+        assert!(price_cache.check_valid(&mango_group, 0, 1021).is_err());
+
+        // unless the token overrides it
+        mango_group.tokens[0].price_valid_interval = 60;
+        assert!(price_cache.check_valid(&mango_group, 0, 1060).is_ok());
+        assert!(price_cache.check_valid(&mango_group, 0, 1061).is_err());
+    }
+
+    #[test]
+    pub fn oracle_limits_from_group() {
+        let mut mango_group: MangoGroup = unsafe { mem::zeroed() };
+
+        // nothing configured
+        let limits = mango_group.oracle_limits(0);
+        assert_eq!(limits.max_conf, None);
+        assert_eq!(limits.max_age_slots, 0);
+
+        mango_group.tokens[0].max_conf_bps = 200;
+        mango_group.tokens[0].max_oracle_age_slots = 25;
+        let limits = mango_group.oracle_limits(0);
+        assert_eq!(limits.max_conf, Some(I80F48::from_num(200) / I80F48::from_num(10_000)));
+        assert_eq!(limits.max_age_slots, 25);
+
+        // the index of a squared perp has twice the relative confidence of its underlying
+        mango_group.perp_markets[0].index_exponent = 2;
+        mango_group.perp_markets[0].underlying_oracle_index = 1;
+        mango_group.perp_markets[0].index_divisor = ONE_I80F48;
+        let limits = mango_group.oracle_limits(0);
+        assert_eq!(
+            limits.max_conf,
+            Some(I80F48::from_num(200) / I80F48::from_num(10_000) / I80F48::from_num(2))
+        );
+    }
+
+    #[test]
+    pub fn success_price_circuit_breaker() {
+        let mut price_cache: PriceCache = unsafe { mem::zeroed() };
+        price_cache.update(I80F48::from_num(100), 1000, 0);
+
+        // 10% per 10 seconds
+        assert!(price_cache.check_price_move(I80F48::from_num(109), 1010, 1000, 10));
+        assert!(!price_cache.check_price_move(I80F48::from_num(150), 1010, 1000, 10));
+        assert!(price_cache.is_halted());
+
+        // the same reading can't confirm itself
+        assert!(!price_cache.check_price_move(I80F48::from_num(150), 1010, 1000, 10));
+        assert!(price_cache.is_halted());
+
+        // a later update close to the halted price confirms the move
+        assert!(price_cache.check_price_move(I80F48::from_num(151), 1011, 1000, 10));
+        assert!(!price_cache.is_halted());
+
+        // going back to the cached price clears the halt
+        assert!(!price_cache.check_price_move(I80F48::from_num(50), 1012, 1000, 10));
+        assert!(price_cache.check_price_move(I80F48::from_num(101), 1012, 1000, 10));
+        assert!(!price_cache.is_halted());
+
+        // more time since the last update allows a larger move
+        assert!(price_cache.check_price_move(I80F48::from_num(150), 1050, 1000, 10));
+    }
+
+    #[test]
+    pub fn iceberg_orders_reuse_free_slots() {
+        assert_eq!(mem::size_of::<IcebergOrder>(), 64);
+        let mut iceberg_orders: IcebergOrders = unsafe { mem::zeroed() };
+        let order = IcebergOrder {
+            is_active: true,
+            market_index: 1,
+            side: Side::Ask,
+            order_type: OrderType::PostOnly,
+            padding0: [0; 4],
+            client_order_id: 7,
+            price: 1000,
+            display_quantity: 5,
+            hidden_quantity: 45,
+            padding1: [0; 8],
+            order_id: 42,
+        };
+
+        for _ in 0..MAX_ICEBERG_ORDERS {
+            iceberg_orders.add_order(order).unwrap();
+        }
+        assert!(iceberg_orders.add_order(order).is_err());
+
+        iceberg_orders.orders[3].is_active = false;
+        iceberg_orders.add_order(IcebergOrder { client_order_id: 8, ..order }).unwrap();
+        assert_eq!(iceberg_orders.orders[3].client_order_id, 8);
+    }
+
+    #[test]
+    pub fn perp_price_band_limit() {
+        let mut info: PerpMarketInfo = unsafe { mem::zeroed() };
+        assert_eq!(info.price_band_limit(Side::Bid, 1000), None);
+
+        info.price_band_bps = 2000;
+        assert_eq!(info.price_band_limit(Side::Bid, 1000), Some(1200));
+        assert_eq!(info.price_band_limit(Side::Ask, 1000), Some(800));
+
+        // rounded towards the oracle price
+        assert_eq!(info.price_band_limit(Side::Bid, 1003), Some(1203));
+        assert_eq!(info.price_band_limit(Side::Ask, 1003), Some(803));
+        assert_eq!(info.price_band_limit(Side::Ask, 1), Some(1));
+
+        info.price_band_bps = 20_000;
+        assert_eq!(info.price_band_limit(Side::Ask, 1000), Some(1));
+        assert_eq!(info.price_band_limit(Side::Bid, i64::MAX), Some(i64::MAX));
+    }
+
+    #[test]
+    pub fn perp_position_limits() {
+        let mut perp_market: PerpMarket = unsafe { mem::zeroed() };
+        let mut pa: PerpAccount = unsafe { mem::zeroed() };
+        pa.base_position = 40;
+        pa.taker_base = 10;
+        pa.bids_quantity = 30;
+        pa.asks_quantity = 20;
+        assert_eq!(pa.exposure(Side::Bid), 80);
+        assert_eq!(pa.exposure(Side::Ask), -30);
+
+        // disabled
+        assert!(perp_market.check_order_limits(&pa, Side::Bid, 0, 0).is_ok());
+
+        perp_market.max_position_lots = 60;
+        assert!(perp_market.check_order_limits(&pa, Side::Bid, 50, 10).is_err());
+        assert!(perp_market.check_order_limits(&pa, Side::Ask, -60, 10).is_ok());
+        // shrinking a position above the cap is fine
+        assert!(perp_market.check_max_position(-100, -70).is_ok());
+        assert!(perp_market.check_max_position(-50, 70).is_err());
+
+        perp_market.max_position_lots = 0;
+        perp_market.max_open_interest = 1000;
+        perp_market.open_interest = 985;
+        // resting quantity doesn't count, only the 10 lots the order took, on both sides
+        assert!(perp_market.check_order_limits(&pa, Side::Bid, 0, 0).is_err());
+        assert!(perp_market.check_order_limits(&pa, Side::Bid, 0, 5).is_ok());
+        // a taker closing its position only grows open interest on the makers' side
+        pa.base_position = -40;
+        assert!(perp_market.check_order_limits(&pa, Side::Bid, 0, -10).is_ok());
+        assert!(perp_market.check_max_open_interest(-10).is_ok());
+    }
+
+    #[test]
+    pub fn perp_settlement_price() {
+        let mut perp_market: PerpMarket = unsafe { mem::zeroed() };
+        perp_market.expiry = 1000;
+        perp_market.settlement_window = 100;
+        assert_eq!(perp_market.settlement_sample_interval(), 10);
+
+        // each price holds until the next sample: 10 for 50 seconds, then 20 for 50 seconds
+        for i in 0..10 {
+            let price = I80F48::from_num(if i < 5 { 10 } else { 20 });
+            perp_market.sample_settlement_price(price, 900 + 10 * i).unwrap();
+        }
+        // too soon after the last sample
+        assert!(perp_market.sample_settlement_price(I80F48::from_num(100), 995).is_err());
+
+        let mut too_few_samples = perp_market;
+        too_few_samples.settlement_num_samples = MIN_SETTLEMENT_SAMPLES - 1;
+        assert!(too_few_samples.sample_settlement_price(I80F48::from_num(20), 1000).is_err());
+
+        // the price at expiry isn't sampled
+        perp_market.sample_settlement_price(I80F48::from_num(100), 1005).unwrap();
+        assert_eq!(perp_market.settlement_price, I80F48::from_num(15));
+        assert!(perp_market.is_settled());
+
+        // settled positions are valued at the settlement price without any weight
+        let mut info: PerpMarketInfo = unsafe { mem::zeroed() };
+        info.maint_asset_weight = I80F48::from_num(0.9);
+        info.maint_shock_bps = 1000;
+        assert_eq!(info.settled_or(I80F48::from_num(30)), I80F48::from_num(30));
+        info.settlement_price = perp_market.settlement_price;
+        assert_eq!(info.settled_or(I80F48::from_num(30)), I80F48::from_num(15));
+        let base = I80F48::from_num(150);
+        assert_eq!(info.get_weighted_base_val(base, HealthType::Maint), base);
+    }
+
+    #[test]
+    pub fn perp_adl_score() {
+        let price = I80F48::from_num(2);
+        let mut pa: PerpAccount = unsafe { mem::zeroed() };
+        pa.base_position = -50;
+        pa.quote_position = I80F48::from_num(1300);
+        assert_eq!(pa.unrealized_pnl(10, price), I80F48::from_num(300));
+        // pnl 300 on a notional of 1000 with equity 600
+        assert_eq!(pa.adl_score(10, price, I80F48::from_num(600)), Some(I80F48::from_num(500)));
+        assert_eq!(pa.adl_score(10, price, ZERO_I80F48), None);
+
+        pa.base_position = 50;
+        pa.quote_position = I80F48::from_num(-1200);
+        assert_eq!(pa.adl_score(10, price, I80F48::from_num(600)), None);
+
+        // closing a long above the oracle price or a short below it covers the loss
+        let loss = I80F48::from_num(125);
+        assert_eq!(pa.bankruptcy_price(10, price, loss), I80F48::from_num(2.25));
+        pa.base_position = -50;
+        assert_eq!(pa.bankruptcy_price(10, price, loss), I80F48::from_num(1.75));
+        assert_eq!(pa.bankruptcy_price(10, price, I80F48::from_num(2000)), ZERO_I80F48);
+    }
+
+    #[test]
+    pub fn upgrade_mango_group_v0_layout() {
+        let mut mango_group: MangoGroup = unsafe { mem::zeroed() };
+        mango_group.num_oracles = 2;
+        mango_group.perp_markets[1].base_lot_size = 10;
+        mango_group.perp_markets[MAX_PAIRS - 1].quote_lot_size = 7;
+        mango_group.admin = Pubkey::new_unique();
+
+        // Write the group out in the V0 layout
+        let bytes = bytemuck::bytes_of(&mango_group);
+        let start =
+            &mango_group.perp_markets as *const _ as usize - &mango_group as *const _ as usize;
+        let info_size = mem::size_of::<PerpMarketInfo>();
+        let mut v0 = bytes[..start].to_vec();
+        for i in 0..MAX_PAIRS {
+            let info_start = start + i * info_size;
+            v0.extend_from_slice(&bytes[info_start..info_start + PERP_MARKET_INFO_V0_SIZE]);
+        }
+        v0.extend_from_slice(&bytes[start + MAX_PAIRS * info_size..]);
+        assert_eq!(v0.len(), MangoGroup::V0_SIZE);
+
+        // u64 backing keeps the data aligned like account data
+        let mut buf = vec![0u64; mem::size_of::<MangoGroup>() / 8];
+        let data: &mut [u8] = bytemuck::cast_slice_mut(&mut buf);
+        data[..v0.len()].copy_from_slice(&v0);
+        MangoGroup::upgrade_v0_layout(data).unwrap();
+
+        let upgraded: &MangoGroup = bytemuck::from_bytes(data);
+        assert_eq!(upgraded.num_oracles, 2);
+        assert_eq!(upgraded.admin, mango_group.admin);
+        assert_eq!(upgraded.perp_markets[1].base_lot_size, 10);
+        assert_eq!(upgraded.perp_markets[MAX_PAIRS - 1].quote_lot_size, 7);
+        assert_eq!(upgraded.perp_markets[1].index_params(1), (1, 1, ONE_I80F48));
+        assert_eq!(upgraded.perp_markets[0].index_params(0), (1, 2, I80F48::from_num(1_000_000)));
+        assert_eq!(upgraded.perp_markets[0].max_price_move_bps, 0);
+    }
+
+    #[test]
+    pub fn upgrade_mango_cache_v0_layout() {
+        let mut mango_cache: MangoCache = unsafe { mem::zeroed() };
+        mango_cache.meta_data = MetaData::new(DataType::MangoCache, 0, true);
+        mango_cache.price_cache[0].price = I80F48::from_num(20);
+        mango_cache.price_cache[MAX_PAIRS - 1].last_update = 5;
+        mango_cache.root_bank_cache[0].deposit_index = I80F48::from_num(3);
+        mango_cache.perp_market_cache[MAX_PAIRS - 1].long_funding = I80F48::from_num(4);
+
+        // Write the cache out in the V0 layout
+        let bytes = bytemuck::bytes_of(&mango_cache);
+        let start = mem::size_of::<MetaData>();
+        let entry_size = mem::size_of::<PriceCache>();
+        let mut v0 = bytes[..start].to_vec();
+        for i in 0..MAX_PAIRS {
+            let entry_start = start + i * entry_size;
+            v0.extend_from_slice(&bytes[entry_start..entry_start + PRICE_CACHE_V0_SIZE]);
+        }
+        v0.extend_from_slice(&bytes[start + MAX_PAIRS * entry_size..]);
+        assert_eq!(v0.len(), MangoCache::V0_SIZE);
+
+        let mut buf = vec![0u64; mem::size_of::<MangoCache>() / 8];
+        let data: &mut [u8] = bytemuck::cast_slice_mut(&mut buf);
+        data[..v0.len()].copy_from_slice(&v0);
+        MangoCache::upgrade_v0_layout(data).unwrap();
+
+        let upgraded: &MangoCache = bytemuck::from_bytes(data);
+        assert_eq!(upgraded.meta_data.data_type, DataType::MangoCache as u8);
+        assert_eq!(upgraded.price_cache[0].price, I80F48::from_num(20));
+        assert_eq!(upgraded.price_cache[0].ema_price, I80F48::from_num(20));
+        assert!(!upgraded.price_cache[0].is_halted());
+        assert_eq!(upgraded.price_cache[MAX_PAIRS - 1].last_update, 5);
+        assert_eq!(upgraded.root_bank_cache[0].deposit_index, I80F48::from_num(3));
+        assert_eq!(upgraded.perp_market_cache[MAX_PAIRS - 1].long_funding, I80F48::from_num(4));
+    }
+
+    #[test]
+    pub fn upgrade_perp_market_v0() {
+        let mut perp_market: PerpMarket = unsafe { mem::zeroed() };
+        let v0_end =
+            &perp_market.funding_period as *const _ as usize - &perp_market as *const _ as usize;
+        assert_eq!(PerpMarket::V0_SIZE, v0_end);
+
+        perp_market.last_updated = 1000;
+        perp_market.init_v0_upgrade(0);
+        assert_eq!(perp_market.funding_period, 7 * 86400);
+        assert_eq!(perp_market.last_premium_update, 1000);
+        perp_market.init_v0_upgrade(3);
+        assert_eq!(perp_market.funding_period, 86400);
+        assert_eq!(perp_market.impact_quantity, 100);
+        assert_eq!(perp_market.max_funding, DEFAULT_MAX_FUNDING);
+        assert_eq!(perp_market.expiry, 0);
+        assert_eq!(perp_market.max_open_interest, 0);
+    }
+
+    #[test]
+    pub fn heartbeat_expiry() {
+        let mut heartbeat: Heartbeat = unsafe { mem::zeroed() };
+        // disarmed
+        assert!(!heartbeat.is_expired(u64::MAX));
+
+        heartbeat.deadline = 1000;
+        assert!(!heartbeat.is_expired(999));
+        assert!(!heartbeat.is_expired(1000));
+        assert!(heartbeat.is_expired(1001));
+    }
 }
 
 pub fn load_market_state<'a>(
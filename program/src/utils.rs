@@ -2,7 +2,7 @@ use bytemuck::{bytes_of, cast_slice_mut, from_bytes_mut, Contiguous, Pod};
 
 use crate::error::MangoResult;
 use crate::matching::Side;
-use crate::state::{RootBank, ONE_I80F48};
+use crate::state::{RootBank, LN_2, ONE_I80F48, ZERO_I80F48};
 use fixed::types::I80F48;
 use solana_program::account_info::AccountInfo;
 use solana_program::program_error::ProgramError;
@@ -13,6 +13,9 @@ use std::mem::size_of;
 pub const OTC_ORDERS_PREFIX: &str = "otc_orders";
 pub const MAX_PERP_OTC_ORDERS: usize = 10;
 pub const MAX_SPOT_OTC_ORDERS: usize = 10;
+pub const ICEBERG_ORDERS_PREFIX: &str = "iceberg_orders";
+pub const MAX_ICEBERG_ORDERS: usize = 8;
+pub const HEARTBEAT_PREFIX: &str = "heartbeat";
 
 pub mod serum_fees_mod {
     use solana_program::declare_id;
@@ -84,17 +87,73 @@ pub fn split_open_orders(
 }
 
 /// exponentiate by squaring; send in 1 / base if you want neg
-pub fn pow_i80f48(mut base: I80F48, mut exp: u8) -> I80F48 {
+pub fn pow_i80f48(base: I80F48, exp: u8) -> I80F48 {
+    checked_pow_i80f48(base, exp).unwrap()
+}
+
+/// exponentiate by squaring; None on overflow
+pub fn checked_pow_i80f48(mut base: I80F48, mut exp: u8) -> Option<I80F48> {
     let mut result = ONE_I80F48;
     loop {
         if exp & 1 == 1 {
-            result = result.checked_mul(base).unwrap();
+            result = result.checked_mul(base)?;
         }
         exp >>= 1;
         if exp == 0 {
-            break result;
+            break Some(result);
+        }
+        base = base.checked_mul(base)?;
+    }
+}
+
+/// Natural log of a positive number; returns zero for non-positive inputs
+pub fn ln_i80f48(x: I80F48) -> I80F48 {
+    if !x.is_positive() {
+        return ZERO_I80F48;
+    }
+
+    // reduce to x = m * 2^k with m in [1, 2)
+    let two = I80F48::from_num(2);
+    let mut m = x;
+    let mut k = 0i32;
+    while m >= two {
+        m /= two;
+        k += 1;
+    }
+    while m < ONE_I80F48 {
+        m *= two;
+        k -= 1;
+    }
+
+    // ln(m) = 2 * atanh(z) = 2 * (z + z^3 / 3 + z^5 / 5 + ...) with z = (m - 1) / (m + 1) <= 1/3
+    let z = (m - ONE_I80F48) / (m + ONE_I80F48);
+    let z2 = z * z;
+    let mut term = z;
+    let mut sum = ZERO_I80F48;
+    let mut n = 1u32;
+    while !term.is_zero() {
+        sum += term / I80F48::from_num(n);
+        term *= z2;
+        n += 2;
+    }
+
+    LN_2 * I80F48::from_num(k) + sum * two
+}
+
+/// Square root by Newton's method; returns zero for non-positive inputs
+pub fn sqrt_i80f48(x: I80F48) -> I80F48 {
+    if !x.is_positive() {
+        return ZERO_I80F48;
+    }
+
+    let two = I80F48::from_num(2);
+    let mut guess = if x > ONE_I80F48 { x } else { ONE_I80F48 };
+    loop {
+        let next = (guess + x / guess) / two;
+        if next >= guess {
+            break guess;
         }
-        base = base.checked_mul(base).unwrap();
+        guess = next;
     }
 }
 
@@ -160,3 +219,40 @@ pub fn interest_rate_curve_calculator(
         slope * utilization
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: I80F48, b: f64) {
+        let diff = (a - I80F48::from_num(b)).abs();
+        assert!(diff < I80F48::from_num(0.000000001), "{} != {}", a, b);
+    }
+
+    #[test]
+    fn ln_i80f48_values() {
+        assert_eq!(ln_i80f48(ONE_I80F48), ZERO_I80F48);
+        assert_close(ln_i80f48(I80F48::from_num(2)), std::f64::consts::LN_2);
+        assert_close(ln_i80f48(I80F48::from_num(0.5)), -std::f64::consts::LN_2);
+        assert_close(ln_i80f48(I80F48::from_num(std::f64::consts::E)), 1.0);
+        assert_close(ln_i80f48(I80F48::from_num(1.1)), 1.1f64.ln());
+        assert_close(ln_i80f48(I80F48::from_num(1_000_000)), 1_000_000f64.ln());
+        assert_close(ln_i80f48(I80F48::from_num(0.0001)), 0.0001f64.ln());
+
+        // not defined, so zero
+        assert_eq!(ln_i80f48(ZERO_I80F48), ZERO_I80F48);
+        assert_eq!(ln_i80f48(I80F48::from_num(-3)), ZERO_I80F48);
+    }
+
+    #[test]
+    fn sqrt_i80f48_values() {
+        assert_close(sqrt_i80f48(I80F48::from_num(4)), 2.0);
+        assert_close(sqrt_i80f48(I80F48::from_num(2)), std::f64::consts::SQRT_2);
+        assert_close(sqrt_i80f48(I80F48::from_num(0.25)), 0.5);
+        assert_close(sqrt_i80f48(I80F48::from_num(1e12)), 1e6);
+        assert_eq!(sqrt_i80f48(ONE_I80F48), ONE_I80F48);
+
+        assert_eq!(sqrt_i80f48(ZERO_I80F48), ZERO_I80F48);
+        assert_eq!(sqrt_i80f48(I80F48::from_num(-4)), ZERO_I80F48);
+    }
+}
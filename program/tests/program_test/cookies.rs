@@ -1,9 +1,7 @@
 use std::mem::size_of;
 use std::num::NonZeroU64;
 
-use anchor_lang::prelude::AccountMeta;
 use fixed::types::I80F48;
-use solana_program::instruction::Instruction;
 use solana_program::pubkey::Pubkey;
 use solana_sdk::signature::{Keypair, Signer};
 use solana_sdk::transport::TransportError;
@@ -79,40 +77,27 @@ impl MangoGroupCookie {
         let quote_optimal_rate = I80F48::from_num(0.06);
         let quote_max_rate = I80F48::from_num(1.5);
 
-        let accounts = vec![
-            AccountMeta::new(mango_group_pk, false),
-            AccountMeta::new_readonly(admin_pk, true),
-        ];
-
-        let instr = mango::instruction::MangoInstruction::DontSquare { dont_square: true };
-
-        let data = instr.pack();
-        let dont_square_ix = Instruction { program_id: mango_program_id, accounts, data };
-
-        let instructions = [
-            mango::instruction::init_mango_group(
-                &mango_program_id,
-                &mango_group_pk,
-                &signer_pk,
-                &admin_pk,
-                &quote_mint_pk,
-                &quote_vault_pk,
-                &quote_node_bank_pk,
-                &quote_root_bank_pk,
-                &dao_vault_pk,
-                &msrm_vault_pk,
-                &fees_vault_pk,
-                &mango_cache_pk,
-                &serum_program_id,
-                signer_nonce,
-                5,
-                quote_optimal_util,
-                quote_optimal_rate,
-                quote_max_rate,
-            )
-            .unwrap(),
-            dont_square_ix,
-        ];
+        let instructions = [mango::instruction::init_mango_group(
+            &mango_program_id,
+            &mango_group_pk,
+            &signer_pk,
+            &admin_pk,
+            &quote_mint_pk,
+            &quote_vault_pk,
+            &quote_node_bank_pk,
+            &quote_root_bank_pk,
+            &dao_vault_pk,
+            &msrm_vault_pk,
+            &fees_vault_pk,
+            &mango_cache_pk,
+            &serum_program_id,
+            signer_nonce,
+            5,
+            quote_optimal_util,
+            quote_optimal_rate,
+            quote_max_rate,
+        )
+        .unwrap()];
 
         test.process_transaction(&instructions, None).await.unwrap();
 
@@ -649,6 +634,7 @@ pub struct PlacePerpOptions {
     pub order_type: mango::matching::OrderType,
     pub limit: u8,
     pub expiry_timestamp: Option<u64>,
+    pub self_trade_behavior: mango::matching::SelfTradeBehavior,
 }
 
 impl Default for PlacePerpOptions {
@@ -659,6 +645,7 @@ impl Default for PlacePerpOptions {
             order_type: mango::matching::OrderType::Limit,
             limit: 10,
             expiry_timestamp: None,
+            self_trade_behavior: mango::matching::SelfTradeBehavior::DecrementTake,
         }
     }
 }
@@ -778,6 +765,7 @@ impl PerpMarketCookie {
             options.reduce_only,
             options.expiry_timestamp,
             options.limit,
+            options.self_trade_behavior,
         )
         .await;
 
@@ -1,6 +1,7 @@
 use std::borrow::Borrow;
 use std::mem::size_of;
 
+use anchor_lang::Discriminator;
 use bincode::deserialize;
 use fixed::types::I80F48;
 use serum_dex::instruction::NewOrderInstructionV3;
@@ -25,6 +26,7 @@ use solana_sdk::{
     transport::TransportError,
 };
 use spl_token::{state::*, *};
+use switchboard_v2::{AggregatorAccountData, SwitchboardDecimal};
 
 use mango::{entrypoint::*, ids::*, instruction::*, matching::*, oracle::*, state::*, utils::*};
 use mango_common::Loadable;
@@ -684,6 +686,73 @@ impl MangoProgramTest {
         self.process_transaction(&instructions, None).await.unwrap();
     }
 
+    #[allow(dead_code)]
+    /// Write a fully verified Pyth pull oracle price update (PriceUpdateV2) account
+    pub async fn set_pyth_price_update(
+        &mut self,
+        address: &Pubkey,
+        feed_id: [u8; 32],
+        price: i64,
+        exponent: i32,
+        publish_time: i64,
+    ) {
+        let posted_slot = self.get_clock().await.slot;
+        let mut data = Vec::new();
+        data.extend_from_slice(&PYTH_PRICE_UPDATE_DISCRIMINATOR);
+        data.extend_from_slice(&Pubkey::new_unique().to_bytes()); // write authority
+        data.push(1); // VerificationLevel::Full
+        data.extend_from_slice(&feed_id);
+        data.extend_from_slice(&price.to_le_bytes());
+        data.extend_from_slice(&0u64.to_le_bytes()); // conf
+        data.extend_from_slice(&exponent.to_le_bytes());
+        data.extend_from_slice(&publish_time.to_le_bytes());
+        data.extend_from_slice(&publish_time.to_le_bytes()); // prev publish time
+        data.extend_from_slice(&price.to_le_bytes()); // ema price
+        data.extend_from_slice(&0u64.to_le_bytes()); // ema conf
+        data.extend_from_slice(&posted_slot.to_le_bytes());
+        data.push(0); // accounts are sized for VerificationLevel::Partial
+
+        let account = solana_sdk::account::Account {
+            lamports: self.rent.minimum_balance(data.len()),
+            data,
+            owner: pyth_receiver_program::ID,
+            executable: false,
+            rent_epoch: 0,
+        };
+        self.context.set_account(address, &account.into());
+    }
+
+    #[allow(dead_code)]
+    /// Write a switchboard v2 aggregator whose latest confirmed round has one oracle response
+    pub async fn set_switchboard_v2_aggregator(
+        &mut self,
+        address: &Pubkey,
+        result: SwitchboardDecimal,
+        std_deviation: SwitchboardDecimal,
+        round_open_timestamp: i64,
+    ) {
+        let round_open_slot = self.get_clock().await.slot;
+        let mut aggregator: AggregatorAccountData = unsafe { std::mem::zeroed() };
+        aggregator.min_oracle_results = 1;
+        aggregator.latest_confirmed_round.num_success = 1;
+        aggregator.latest_confirmed_round.round_open_slot = round_open_slot;
+        aggregator.latest_confirmed_round.round_open_timestamp = round_open_timestamp;
+        aggregator.latest_confirmed_round.result = result;
+        aggregator.latest_confirmed_round.std_deviation = std_deviation;
+
+        let mut data = AggregatorAccountData::discriminator().to_vec();
+        data.extend_from_slice(bytemuck::bytes_of(&aggregator));
+
+        let account = solana_sdk::account::Account {
+            lamports: self.rent.minimum_balance(data.len()),
+            data,
+            owner: switchboard_v2_program::ID,
+            executable: false,
+            rent_epoch: 0,
+        };
+        self.context.set_account(address, &account.into());
+    }
+
     #[allow(dead_code)]
     pub fn base_size_number_to_lots(&mut self, mint: &MintCookie, quantity: f64) -> u64 {
         return ((quantity * mint.unit) / mint.base_lot) as u64;
@@ -798,6 +867,7 @@ impl MangoProgramTest {
         reduce_only: bool,
         expiry_timestamp: Option<u64>,
         limit: u8,
+        self_trade_behavior: SelfTradeBehavior,
     ) {
         let mango_program_id = self.mango_program_id;
         let mango_group = mango_group_cookie.mango_group;
@@ -837,6 +907,8 @@ impl MangoProgramTest {
             expiry_timestamp,
             limit,
             ExpiryType::Absolute,
+            self_trade_behavior,
+            0,
         )
         .unwrap()];
         self.process_transaction(&instructions, Some(&[&user])).await.unwrap();
@@ -0,0 +1,109 @@
+// Tests related to auto-deleveraging perp positions once the insurance fund can't cover losses
+mod program_test;
+
+use fixed::types::I80F48;
+use mango::{
+    error::MangoErrorCode, instruction::auto_deleverage, matching::Side, state::MangoAccount,
+};
+use program_test::assertions::*;
+use program_test::cookies::*;
+use program_test::scenarios::*;
+use program_test::*;
+use solana_program::pubkey::Pubkey;
+use solana_program_test::*;
+use solana_sdk::signature::{Keypair, Signer};
+
+#[tokio::test]
+/// A long with negative equity is closed against the profitable short at the price that makes
+/// up its loss, and only the admin may pick the counterparties
+async fn test_auto_deleverage() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig::default_two_mints();
+    let mut test = MangoProgramTest::start_new(&config).await;
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+    let mango_group = mango_group_cookie.mango_group;
+    let mango_group_pk = mango_group_cookie.address;
+    let mango_program_id = test.mango_program_id;
+    let admin_pk = test.get_payer_pk();
+
+    // General parameters
+    let bidder_user_index: usize = 0;
+    let asker_user_index: usize = 1;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 1.0;
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![
+        (bidder_user_index, test.quote_index, base_price),
+        (asker_user_index, mint_index, 1.0),
+    ];
+
+    // Matched Perp Orders
+    let matched_perp_orders = vec![vec![
+        (asker_user_index, mint_index, Side::Ask, base_size, base_price),
+        (bidder_user_index, mint_index, Side::Bid, base_size, base_price),
+    ]];
+
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    match_perp_order_scenario(&mut test, &mut mango_group_cookie, &matched_perp_orders).await;
+
+    // The price drop leaves the bidder with negative equity, which the empty insurance
+    // fund can't cover
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price / 150.0).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+
+    let bidder_pk = mango_group_cookie.mango_accounts[bidder_user_index].address;
+    let bidder_ma = mango_group_cookie.mango_accounts[bidder_user_index].mango_account;
+    let asker = Keypair::from_base58_string(&test.users[asker_user_index].to_base58_string());
+    let asker_pk = mango_group_cookie.mango_accounts[asker_user_index].address;
+    let asker_ma = mango_group_cookie.mango_accounts[asker_user_index].mango_account;
+    let asker_open_orders_pks: Vec<Pubkey> = asker_ma
+        .spot_open_orders
+        .iter()
+        .enumerate()
+        .filter_map(|(i, &pk)| if asker_ma.in_margin_basket[i] { Some(pk) } else { None })
+        .collect();
+    let adl_ix = |admin_pk: &Pubkey| {
+        auto_deleverage(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_group.mango_cache,
+            &mango_group.perp_markets[mint_index].perp_market,
+            &mango_group.insurance_vault,
+            &bidder_pk,
+            admin_pk,
+            &bidder_ma.spot_open_orders,
+            &[(asker_pk, asker_open_orders_pks.clone())],
+        )
+        .unwrap()
+    };
+
+    // === Act ===
+    // Step 1: Nobody but the admin may pick the counterparties
+    let error =
+        test.process_transaction(&[adl_ix(&asker.pubkey())], Some(&[&asker])).await.unwrap_err();
+    assert_eq!(get_error_code(error), Some(MangoErrorCode::InvalidAdminKey.into()));
+
+    // Step 2: The admin deleverages the bidder against the asker
+    test.process_transaction(&[adl_ix(&admin_pk)], None).await.unwrap();
+
+    // === Assert ===
+    // Both positions are closed and the bidder's quote deposit covers its perp loss exactly
+    let bidder_ma = test.load_account::<MangoAccount>(bidder_pk).await;
+    let asker_ma = test.load_account::<MangoAccount>(asker_pk).await;
+    assert_eq!(bidder_ma.perp_accounts[mint_index].base_position, 0);
+    assert_eq!(asker_ma.perp_accounts[mint_index].base_position, 0);
+    let quote_deposit = I80F48::from_num(base_price * test.quote_mint.unit);
+    assert_approx_eq!(
+        bidder_ma.perp_accounts[mint_index].quote_position,
+        -quote_deposit,
+        I80F48::from_num(1_000_000)
+    );
+    assert!(asker_ma.perp_accounts[mint_index].quote_position.is_positive());
+}
@@ -0,0 +1,138 @@
+// Tests related to dated perp markets with expiry and cash settlement
+mod program_test;
+
+use mango::{
+    error::MangoErrorCode,
+    instruction::{set_perp_market_expiry, settle_expired_perp_market, settle_expired_position},
+    matching::Side,
+    state::{MangoAccount, MangoGroup, PerpMarket, MIN_SETTLEMENT_SAMPLES},
+};
+use program_test::assertions::*;
+use program_test::cookies::*;
+use program_test::scenarios::*;
+use program_test::*;
+use solana_program_test::*;
+
+#[tokio::test]
+/// The settlement price is the average of index samples spread over the settlement window,
+/// and positions are closed at it after expiry
+async fn test_dated_perp_market_settlement() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig::default_two_mints();
+    let mut test = MangoProgramTest::start_new(&config).await;
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+    let mango_group = mango_group_cookie.mango_group;
+    let mango_group_pk = mango_group_cookie.address;
+    let mango_program_id = test.mango_program_id;
+    let admin_pk = test.get_payer_pk();
+
+    // General parameters
+    let bidder_user_index: usize = 0;
+    let asker_user_index: usize = 1;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 1.0;
+    let settlement_window: u64 = 20_000;
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![
+        (bidder_user_index, test.quote_index, base_price),
+        (asker_user_index, mint_index, 1.0),
+    ];
+
+    // Matched Perp Orders
+    let matched_perp_orders = vec![vec![
+        (asker_user_index, mint_index, Side::Ask, base_size, base_price),
+        (bidder_user_index, mint_index, Side::Bid, base_size, base_price),
+    ]];
+
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    match_perp_order_scenario(&mut test, &mut mango_group_cookie, &matched_perp_orders).await;
+
+    let perp_market_pk = mango_group_cookie.perp_markets[mint_index].address;
+    let bidder_pk = mango_group_cookie.mango_accounts[bidder_user_index].address;
+    let asker_pk = mango_group_cookie.mango_accounts[asker_user_index].address;
+    let sample_ix = settle_expired_perp_market(
+        &mango_program_id,
+        &mango_group_pk,
+        &mango_group.mango_cache,
+        &perp_market_pk,
+    )
+    .unwrap();
+
+    // The settlement window starts now
+    let expiry = test.get_clock().await.unix_timestamp as u64 + settlement_window;
+    test.process_transaction(
+        &[set_perp_market_expiry(
+            &mango_program_id,
+            &mango_group_pk,
+            &perp_market_pk,
+            &admin_pk,
+            expiry,
+            settlement_window,
+        )
+        .unwrap()],
+        None,
+    )
+    .await
+    .unwrap();
+
+    // === Act ===
+    // Step 1: Sample the index, but not twice within the sampling interval
+    mango_group_cookie.run_keeper(&mut test).await;
+    test.process_transaction(&[sample_ix.clone()], None).await.unwrap();
+    test.advance_clock().await;
+    let error = test.process_transaction(&[sample_ix.clone()], None).await.unwrap_err();
+    assert_eq!(get_error_code(error), Some(MangoErrorCode::InvalidParam.into()));
+
+    // Step 2: Sample the rest of the window
+    for _ in 1..MIN_SETTLEMENT_SAMPLES {
+        test.advance_clock_by_min_timespan(settlement_window / MIN_SETTLEMENT_SAMPLES).await;
+        mango_group_cookie.run_keeper(&mut test).await;
+        test.process_transaction(&[sample_ix.clone()], None).await.unwrap();
+    }
+
+    // Step 3: Settle the market after expiry, then both positions
+    test.advance_clock_past_timestamp(expiry as i64).await;
+    mango_group_cookie.run_keeper(&mut test).await;
+    test.process_transaction(&[sample_ix], None).await.unwrap();
+    test.process_transaction(
+        &[
+            settle_expired_position(
+                &mango_program_id,
+                &mango_group_pk,
+                &bidder_pk,
+                &perp_market_pk,
+            )
+            .unwrap(),
+            settle_expired_position(&mango_program_id, &mango_group_pk, &asker_pk, &perp_market_pk)
+                .unwrap(),
+        ],
+        None,
+    )
+    .await
+    .unwrap();
+
+    // === Assert ===
+    // The index never moved, so neither did the average
+    let perp_market = test.load_account::<PerpMarket>(perp_market_pk).await;
+    let mint = test.with_mint(mint_index);
+    let expected_price = test.with_oracle_price(&mint, base_price);
+    assert_eq!(perp_market.settlement_num_samples, MIN_SETTLEMENT_SAMPLES);
+    assert_approx_eq!(perp_market.settlement_price, expected_price);
+
+    // Health values positions at the settlement price from now on
+    let mango_group = test.load_account::<MangoGroup>(mango_group_pk).await;
+    assert_eq!(mango_group.perp_markets[mint_index].settlement_price, perp_market.settlement_price);
+
+    let bidder_ma = test.load_account::<MangoAccount>(bidder_pk).await;
+    let asker_ma = test.load_account::<MangoAccount>(asker_pk).await;
+    assert_eq!(bidder_ma.perp_accounts[mint_index].base_position, 0);
+    assert_eq!(asker_ma.perp_accounts[mint_index].base_position, 0);
+    assert_eq!(perp_market.open_interest, 0);
+}
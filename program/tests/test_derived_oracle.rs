@@ -0,0 +1,70 @@
+// Tests related to oracles derived from the cached prices of two other oracles
+mod program_test;
+
+use std::mem::size_of;
+
+use fixed::types::I80F48;
+use mango::{instruction::add_derived_oracle, oracle::DerivedOracle, state::MangoGroup};
+use program_test::cookies::*;
+use program_test::*;
+use solana_program_test::*;
+
+#[tokio::test]
+async fn test_derived_oracle() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig { num_mints: 3, ..MangoProgramTestConfig::default() };
+    let mut test = MangoProgramTest::start_new(&config).await;
+
+    let mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    let mango_group_pk = mango_group_cookie.address;
+    let mango_program_id = test.mango_program_id;
+    let admin_pk = test.get_payer_pk();
+    let oracle_pks = test.add_oracles_to_mango_group(&mango_group_pk).await;
+    let mango_group = test.load_account::<MangoGroup>(mango_group_pk).await;
+
+    // price of oracle 0 squared over the price of oracle 1, scaled by 1/4
+    let derived_pk = test.create_account(size_of::<DerivedOracle>(), &mango_program_id).await;
+    test.process_transaction(
+        &[add_derived_oracle(
+            &mango_program_id,
+            &mango_group_pk,
+            &derived_pk,
+            &admin_pk,
+            0,
+            2,
+            1,
+            1,
+            I80F48::from_num(0.25),
+        )
+        .unwrap()],
+        None,
+    )
+    .await
+    .unwrap();
+
+    // === Act ===
+    test.set_oracle(&mango_group, &mango_group_pk, &oracle_pks[0], I80F48::from_num(40)).await;
+    test.set_oracle(&mango_group, &mango_group_pk, &oracle_pks[1], I80F48::from_num(2)).await;
+    test.cache_all_prices(&mango_group, &mango_group_pk, &[derived_pk]).await;
+
+    // === Assert ===
+    let (_mango_cache_pk, mango_cache) = test.with_mango_cache(&mango_group).await;
+    assert_eq!(mango_cache.price_cache[2].price, I80F48::from_num(200));
+    let last_update = mango_cache.price_cache[2].last_update;
+
+    // Source prices that overflow the derived price leave the cached price as it was
+    test.advance_clock().await;
+    test.set_oracle(&mango_group, &mango_group_pk, &oracle_pks[0], I80F48::from_num(1e12)).await;
+    test.cache_all_prices(&mango_group, &mango_group_pk, &[derived_pk]).await;
+    let (_mango_cache_pk, mango_cache) = test.with_mango_cache(&mango_group).await;
+    assert_eq!(mango_cache.price_cache[2].price, I80F48::from_num(200));
+    assert_eq!(mango_cache.price_cache[2].last_update, last_update);
+
+    // So do source prices that are no longer valid
+    test.set_oracle(&mango_group, &mango_group_pk, &oracle_pks[0], I80F48::from_num(50)).await;
+    test.advance_clock_by_min_timespan(2 * mango_group.valid_interval + 1).await;
+    test.cache_all_prices(&mango_group, &mango_group_pk, &[derived_pk]).await;
+    let (_mango_cache_pk, mango_cache) = test.with_mango_cache(&mango_group).await;
+    assert_eq!(mango_cache.price_cache[2].price, I80F48::from_num(200));
+    assert_eq!(mango_cache.price_cache[2].last_update, last_update);
+}
@@ -0,0 +1,172 @@
+// Tests related to the heartbeat dead-man's switch
+mod program_test;
+
+use mango::{
+    error::MangoErrorCode,
+    instruction::{
+        expire_heartbeat_orders, init_heartbeat, init_iceberg_orders, place_perp_order2,
+        refill_iceberg_orders, set_heartbeat,
+    },
+    matching::{ExpiryType, OrderType, SelfTradeBehavior, Side},
+    state::{IcebergOrders, MangoAccount},
+    utils::{HEARTBEAT_PREFIX, ICEBERG_ORDERS_PREFIX},
+};
+use program_test::cookies::*;
+use program_test::scenarios::*;
+use program_test::*;
+use solana_program::pubkey::Pubkey;
+use solana_program_test::*;
+use solana_sdk::signature::{Keypair, Signer};
+
+#[tokio::test]
+/// Once the heartbeat deadline has passed anyone can cancel the account's perp orders,
+/// and the hidden rest of its iceberg orders can't be posted anymore
+async fn test_expire_heartbeat_orders() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig::default_two_mints();
+    let mut test = MangoProgramTest::start_new(&config).await;
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+    let mango_group = mango_group_cookie.mango_group;
+    let mango_group_pk = mango_group_cookie.address;
+    let mango_program_id = test.mango_program_id;
+
+    // General parameters
+    let asker_user_index: usize = 1;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 1.0;
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![(asker_user_index, mint_index, 100.0)];
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+
+    let mint = test.with_mint(mint_index);
+    let price_lots = test.price_number_to_lots(&mint, base_price) as i64;
+    let display_lots = test.base_size_number_to_lots(&mint, base_size) as i64;
+    let asker = Keypair::from_base58_string(&test.users[asker_user_index].to_base58_string());
+    let asker_pk = mango_group_cookie.mango_accounts[asker_user_index].address;
+    let asker_ma = mango_group_cookie.mango_accounts[asker_user_index].mango_account;
+    let perp_market_cookie = mango_group_cookie.perp_markets[mint_index];
+    let perp_market_pk = perp_market_cookie.address;
+    let perp_market = perp_market_cookie.perp_market;
+    let open_orders_pks: Vec<Pubkey> = asker_ma
+        .spot_open_orders
+        .iter()
+        .enumerate()
+        .filter_map(|(i, &pk)| if asker_ma.in_margin_basket[i] { Some(pk) } else { None })
+        .collect();
+    let (heartbeat_pk, _) = Pubkey::find_program_address(
+        &[HEARTBEAT_PREFIX.as_bytes(), asker_pk.as_ref()],
+        &mango_program_id,
+    );
+    let (iceberg_orders_pk, _) = Pubkey::find_program_address(
+        &[ICEBERG_ORDERS_PREFIX.as_bytes(), asker_pk.as_ref()],
+        &mango_program_id,
+    );
+    let expire_ix = expire_heartbeat_orders(
+        &mango_program_id,
+        &mango_group_pk,
+        &asker_pk,
+        &heartbeat_pk,
+        &[(perp_market_pk, perp_market.bids, perp_market.asks)],
+        20,
+    )
+    .unwrap();
+
+    // The asker places an iceberg ask of 3 with 1 on display and arms the heartbeat
+    mango_group_cookie.run_keeper(&mut test).await;
+    test.process_transaction(
+        &[
+            init_heartbeat(&mango_program_id, &mango_group_pk, &asker_pk, &asker.pubkey()).unwrap(),
+            init_iceberg_orders(&mango_program_id, &mango_group_pk, &asker_pk, &asker.pubkey())
+                .unwrap(),
+            place_perp_order2(
+                &mango_program_id,
+                &mango_group_pk,
+                &asker_pk,
+                &asker.pubkey(),
+                &mango_group.mango_cache,
+                &perp_market_pk,
+                &perp_market.bids,
+                &perp_market.asks,
+                &perp_market.event_queue,
+                None,
+                &open_orders_pks,
+                Side::Ask,
+                price_lots,
+                3 * display_lots,
+                i64::MAX,
+                0,
+                OrderType::Limit,
+                false,
+                None,
+                20,
+                ExpiryType::Absolute,
+                SelfTradeBehavior::DecrementTake,
+                display_lots,
+            )
+            .unwrap(),
+        ],
+        Some(&[&asker]),
+    )
+    .await
+    .unwrap();
+
+    // === Act ===
+    // Step 1: Nothing can be expired while the heartbeat is disarmed
+    let error = test.process_transaction(&[expire_ix.clone()], None).await.unwrap_err();
+    assert_eq!(get_error_code(error), Some(MangoErrorCode::HeartbeatNotExpired.into()));
+
+    // Step 2: A deadline in the past lets anyone expire the orders
+    test.process_transaction(
+        &[set_heartbeat(
+            &mango_program_id,
+            &mango_group_pk,
+            &asker_pk,
+            &asker.pubkey(),
+            &heartbeat_pk,
+            1,
+        )
+        .unwrap()],
+        Some(&[&asker]),
+    )
+    .await
+    .unwrap();
+    test.process_transaction(&[expire_ix], None).await.unwrap();
+
+    // Step 3: Refilling posts nothing
+    test.process_transaction(
+        &[refill_iceberg_orders(
+            &mango_program_id,
+            &mango_group_pk,
+            &asker_pk,
+            &asker.pubkey(),
+            &mango_group.mango_cache,
+            &perp_market_pk,
+            &perp_market.bids,
+            &perp_market.asks,
+            &perp_market.event_queue,
+            &iceberg_orders_pk,
+            &open_orders_pks,
+            20,
+        )
+        .unwrap()],
+        Some(&[&asker]),
+    )
+    .await
+    .unwrap();
+
+    // === Assert ===
+    let iceberg_orders = test.load_account::<IcebergOrders>(iceberg_orders_pk).await;
+    assert!(!iceberg_orders.orders[0].is_active);
+    assert_eq!(iceberg_orders.orders[0].hidden_quantity, 0);
+    let asker_ma = test.load_account::<MangoAccount>(asker_pk).await;
+    assert!(!asker_ma.has_iceberg_orders);
+    assert_eq!(asker_ma.perp_accounts[mint_index].asks_quantity, 0);
+    assert!(asker_ma.orders.iter().all(|&id| id == 0));
+}
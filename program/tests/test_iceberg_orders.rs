@@ -0,0 +1,505 @@
+// Tests related to iceberg perp orders
+mod program_test;
+
+use mango::{
+    error::MangoErrorCode,
+    instruction::{
+        cancel_perp_order, init_iceberg_orders, place_perp_order2, refill_iceberg_orders,
+    },
+    matching::{ExpiryType, OrderType, SelfTradeBehavior, Side},
+    state::{IcebergOrders, MangoAccount},
+    utils::ICEBERG_ORDERS_PREFIX,
+};
+use program_test::cookies::*;
+use program_test::scenarios::*;
+use program_test::*;
+use solana_program::{instruction::AccountMeta, pubkey::Pubkey};
+use solana_program_test::*;
+use solana_sdk::signature::{Keypair, Signer};
+
+#[tokio::test]
+/// Only display_quantity of an iceberg order rests on the book. Once a slice is filled the
+/// owner posts the next one with RefillIcebergOrders until the hidden rest is used up
+async fn test_iceberg_order_refill() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig::default_two_mints();
+    let mut test = MangoProgramTest::start_new(&config).await;
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+    let mango_group = mango_group_cookie.mango_group;
+    let mango_group_pk = mango_group_cookie.address;
+    let mango_program_id = test.mango_program_id;
+
+    // General parameters
+    let bidder_user_index: usize = 0;
+    let asker_user_index: usize = 1;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 1.0;
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![
+        (bidder_user_index, test.quote_index, 100.0 * base_price),
+        (asker_user_index, mint_index, 100.0),
+    ];
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+
+    let mint = test.with_mint(mint_index);
+    let price_lots = test.price_number_to_lots(&mint, base_price) as i64;
+    let display_lots = test.base_size_number_to_lots(&mint, base_size) as i64;
+    let bidder = Keypair::from_base58_string(&test.users[bidder_user_index].to_base58_string());
+    let bidder_pk = mango_group_cookie.mango_accounts[bidder_user_index].address;
+    let asker = Keypair::from_base58_string(&test.users[asker_user_index].to_base58_string());
+    let asker_pk = mango_group_cookie.mango_accounts[asker_user_index].address;
+    let asker_ma = mango_group_cookie.mango_accounts[asker_user_index].mango_account;
+    let mut perp_market_cookie = mango_group_cookie.perp_markets[mint_index];
+    let perp_market_pk = perp_market_cookie.address;
+    let perp_market = perp_market_cookie.perp_market;
+    let open_orders_pks: Vec<Pubkey> = asker_ma
+        .spot_open_orders
+        .iter()
+        .enumerate()
+        .filter_map(|(i, &pk)| if asker_ma.in_margin_basket[i] { Some(pk) } else { None })
+        .collect();
+    let (iceberg_orders_pk, _) = Pubkey::find_program_address(
+        &[ICEBERG_ORDERS_PREFIX.as_bytes(), asker_pk.as_ref()],
+        &mango_program_id,
+    );
+    let refill_ix = |owner_pk: &Pubkey, limit: u8| {
+        refill_iceberg_orders(
+            &mango_program_id,
+            &mango_group_pk,
+            &asker_pk,
+            owner_pk,
+            &mango_group.mango_cache,
+            &perp_market_pk,
+            &perp_market.bids,
+            &perp_market.asks,
+            &perp_market.event_queue,
+            &iceberg_orders_pk,
+            &open_orders_pks,
+            limit,
+        )
+        .unwrap()
+    };
+
+    // === Act ===
+    // Step 1: The asker places an iceberg ask of 3 with 1 on display
+    mango_group_cookie.run_keeper(&mut test).await;
+    test.process_transaction(
+        &[
+            init_iceberg_orders(&mango_program_id, &mango_group_pk, &asker_pk, &asker.pubkey())
+                .unwrap(),
+            place_perp_order2(
+                &mango_program_id,
+                &mango_group_pk,
+                &asker_pk,
+                &asker.pubkey(),
+                &mango_group.mango_cache,
+                &perp_market_pk,
+                &perp_market.bids,
+                &perp_market.asks,
+                &perp_market.event_queue,
+                None,
+                &open_orders_pks,
+                Side::Ask,
+                price_lots,
+                3 * display_lots,
+                i64::MAX,
+                0,
+                OrderType::Limit,
+                false,
+                None,
+                20,
+                ExpiryType::Absolute,
+                SelfTradeBehavior::DecrementTake,
+                display_lots,
+            )
+            .unwrap(),
+        ],
+        Some(&[&asker]),
+    )
+    .await
+    .unwrap();
+
+    let asker_ma = test.load_account::<MangoAccount>(asker_pk).await;
+    let iceberg_orders = test.load_account::<IcebergOrders>(iceberg_orders_pk).await;
+    assert_eq!(asker_ma.perp_accounts[mint_index].asks_quantity, display_lots);
+    assert!(iceberg_orders.orders[0].is_active);
+    assert_eq!(iceberg_orders.orders[0].hidden_quantity, 2 * display_lots);
+    assert_eq!(asker_ma.orders[0], iceberg_orders.orders[0].order_id);
+
+    // Step 2: The bidder takes the visible slice
+    for limit in [20, 21] {
+        perp_market_cookie
+            .place_order(
+                &mut test,
+                &mut mango_group_cookie,
+                bidder_user_index,
+                Side::Bid,
+                base_size,
+                base_price,
+                PlacePerpOptions::default(),
+            )
+            .await;
+        test.consume_perp_events(
+            &mango_group_cookie,
+            &perp_market_cookie,
+            &mut vec![bidder_pk, asker_pk],
+        )
+        .await;
+        mango_group_cookie.run_keeper(&mut test).await;
+        let asker_ma = test.load_account::<MangoAccount>(asker_pk).await;
+        assert_eq!(asker_ma.perp_accounts[mint_index].asks_quantity, 0);
+
+        // Step 3: Nobody but the owner can post the next slice
+        let error = test
+            .process_transaction(&[refill_ix(&bidder.pubkey(), limit)], Some(&[&bidder]))
+            .await
+            .unwrap_err();
+        assert_eq!(get_error_code(error), Some(MangoErrorCode::InvalidOwner.into()));
+
+        // Step 4: The owner does
+        test.process_transaction(&[refill_ix(&asker.pubkey(), limit)], Some(&[&asker]))
+            .await
+            .unwrap();
+        let asker_ma = test.load_account::<MangoAccount>(asker_pk).await;
+        let iceberg_orders = test.load_account::<IcebergOrders>(iceberg_orders_pk).await;
+        assert_eq!(asker_ma.perp_accounts[mint_index].asks_quantity, display_lots);
+        assert!(asker_ma.orders.contains(&iceberg_orders.orders[0].order_id));
+    }
+
+    // === Assert ===
+    // The last slice is on the book and nothing is hidden anymore
+    let iceberg_orders = test.load_account::<IcebergOrders>(iceberg_orders_pk).await;
+    assert!(!iceberg_orders.orders[0].is_active);
+    assert_eq!(iceberg_orders.orders[0].hidden_quantity, 0);
+    let asker_ma = test.load_account::<MangoAccount>(asker_pk).await;
+    assert_eq!(asker_ma.perp_accounts[mint_index].base_position, -2 * display_lots);
+}
+
+#[tokio::test]
+/// ConsumeEvents posts the next slice once it processes the fill of the visible one, if it is
+/// passed the bids, asks and the maker's IcebergOrders PDA
+async fn test_iceberg_order_consume_events() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig::default_two_mints();
+    let mut test = MangoProgramTest::start_new(&config).await;
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+    let mango_group = mango_group_cookie.mango_group;
+    let mango_group_pk = mango_group_cookie.address;
+    let mango_program_id = test.mango_program_id;
+
+    // General parameters
+    let bidder_user_index: usize = 0;
+    let asker_user_index: usize = 1;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 1.0;
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![
+        (bidder_user_index, test.quote_index, 100.0 * base_price),
+        (asker_user_index, mint_index, 100.0),
+    ];
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+
+    let mint = test.with_mint(mint_index);
+    let price_lots = test.price_number_to_lots(&mint, base_price) as i64;
+    let display_lots = test.base_size_number_to_lots(&mint, base_size) as i64;
+    let bidder_pk = mango_group_cookie.mango_accounts[bidder_user_index].address;
+    let asker = Keypair::from_base58_string(&test.users[asker_user_index].to_base58_string());
+    let asker_pk = mango_group_cookie.mango_accounts[asker_user_index].address;
+    let asker_ma = mango_group_cookie.mango_accounts[asker_user_index].mango_account;
+    let mut perp_market_cookie = mango_group_cookie.perp_markets[mint_index];
+    let perp_market_pk = perp_market_cookie.address;
+    let perp_market = perp_market_cookie.perp_market;
+    let open_orders_pks: Vec<Pubkey> = asker_ma
+        .spot_open_orders
+        .iter()
+        .enumerate()
+        .filter_map(|(i, &pk)| if asker_ma.in_margin_basket[i] { Some(pk) } else { None })
+        .collect();
+    let (iceberg_orders_pk, _) = Pubkey::find_program_address(
+        &[ICEBERG_ORDERS_PREFIX.as_bytes(), asker_pk.as_ref()],
+        &mango_program_id,
+    );
+
+    // The asker places an iceberg ask of 3 with 1 on display
+    mango_group_cookie.run_keeper(&mut test).await;
+    test.process_transaction(
+        &[
+            init_iceberg_orders(&mango_program_id, &mango_group_pk, &asker_pk, &asker.pubkey())
+                .unwrap(),
+            place_perp_order2(
+                &mango_program_id,
+                &mango_group_pk,
+                &asker_pk,
+                &asker.pubkey(),
+                &mango_group.mango_cache,
+                &perp_market_pk,
+                &perp_market.bids,
+                &perp_market.asks,
+                &perp_market.event_queue,
+                None,
+                &open_orders_pks,
+                Side::Ask,
+                price_lots,
+                3 * display_lots,
+                i64::MAX,
+                0,
+                OrderType::Limit,
+                false,
+                None,
+                20,
+                ExpiryType::Absolute,
+                SelfTradeBehavior::DecrementTake,
+                display_lots,
+            )
+            .unwrap(),
+        ],
+        Some(&[&asker]),
+    )
+    .await
+    .unwrap();
+
+    // === Act ===
+    // Step 1: Without the extra accounts ConsumeEvents leaves the slice to the owner
+    perp_market_cookie
+        .place_order(
+            &mut test,
+            &mut mango_group_cookie,
+            bidder_user_index,
+            Side::Bid,
+            base_size,
+            base_price,
+            PlacePerpOptions::default(),
+        )
+        .await;
+    mango_group_cookie.run_keeper(&mut test).await;
+    test.consume_perp_events(
+        &mango_group_cookie,
+        &perp_market_cookie,
+        &mut vec![bidder_pk, asker_pk],
+    )
+    .await;
+    let asker_ma = test.load_account::<MangoAccount>(asker_pk).await;
+    assert_eq!(asker_ma.perp_accounts[mint_index].asks_quantity, 0);
+
+    // Step 2: With them it posts the next slice after the following fill
+    test.process_transaction(
+        &[refill_iceberg_orders(
+            &mango_program_id,
+            &mango_group_pk,
+            &asker_pk,
+            &asker.pubkey(),
+            &mango_group.mango_cache,
+            &perp_market_pk,
+            &perp_market.bids,
+            &perp_market.asks,
+            &perp_market.event_queue,
+            &iceberg_orders_pk,
+            &open_orders_pks,
+            20,
+        )
+        .unwrap()],
+        Some(&[&asker]),
+    )
+    .await
+    .unwrap();
+    perp_market_cookie
+        .place_order(
+            &mut test,
+            &mut mango_group_cookie,
+            bidder_user_index,
+            Side::Bid,
+            base_size,
+            base_price,
+            PlacePerpOptions::default(),
+        )
+        .await;
+    mango_group_cookie.run_keeper(&mut test).await;
+    test.consume_perp_events(
+        &mango_group_cookie,
+        &perp_market_cookie,
+        &mut vec![bidder_pk, asker_pk, perp_market.bids, perp_market.asks, iceberg_orders_pk],
+    )
+    .await;
+
+    // === Assert ===
+    // The last slice rests on the book as a PostOnly order and nothing is hidden anymore
+    let asker_ma = test.load_account::<MangoAccount>(asker_pk).await;
+    let iceberg_orders = test.load_account::<IcebergOrders>(iceberg_orders_pk).await;
+    assert_eq!(asker_ma.perp_accounts[mint_index].base_position, -2 * display_lots);
+    assert_eq!(asker_ma.perp_accounts[mint_index].asks_quantity, display_lots);
+    assert!(asker_ma.orders.contains(&iceberg_orders.orders[0].order_id));
+    assert!(!iceberg_orders.orders[0].is_active);
+    assert_eq!(iceberg_orders.orders[0].hidden_quantity, 0);
+}
+
+#[tokio::test]
+/// Canceling the visible slice of an iceberg order with the IcebergOrders PDA drops the hidden
+/// rest, so a later refill doesn't post it again. Without the PDA the rest stays.
+async fn test_iceberg_order_cancel() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig::default_two_mints();
+    let mut test = MangoProgramTest::start_new(&config).await;
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+    let mango_group = mango_group_cookie.mango_group;
+    let mango_group_pk = mango_group_cookie.address;
+    let mango_program_id = test.mango_program_id;
+
+    // General parameters
+    let asker_user_index: usize = 1;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 1.0;
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![(asker_user_index, mint_index, 100.0)];
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+
+    let mint = test.with_mint(mint_index);
+    let price_lots = test.price_number_to_lots(&mint, base_price) as i64;
+    let display_lots = test.base_size_number_to_lots(&mint, base_size) as i64;
+    let asker = Keypair::from_base58_string(&test.users[asker_user_index].to_base58_string());
+    let asker_pk = mango_group_cookie.mango_accounts[asker_user_index].address;
+    let asker_ma = mango_group_cookie.mango_accounts[asker_user_index].mango_account;
+    let perp_market_cookie = mango_group_cookie.perp_markets[mint_index];
+    let perp_market_pk = perp_market_cookie.address;
+    let perp_market = perp_market_cookie.perp_market;
+    let open_orders_pks: Vec<Pubkey> = asker_ma
+        .spot_open_orders
+        .iter()
+        .enumerate()
+        .filter_map(|(i, &pk)| if asker_ma.in_margin_basket[i] { Some(pk) } else { None })
+        .collect();
+    let (iceberg_orders_pk, _) = Pubkey::find_program_address(
+        &[ICEBERG_ORDERS_PREFIX.as_bytes(), asker_pk.as_ref()],
+        &mango_program_id,
+    );
+
+    // The asker places an iceberg ask of 3 with 1 on display
+    mango_group_cookie.run_keeper(&mut test).await;
+    test.process_transaction(
+        &[
+            init_iceberg_orders(&mango_program_id, &mango_group_pk, &asker_pk, &asker.pubkey())
+                .unwrap(),
+            place_perp_order2(
+                &mango_program_id,
+                &mango_group_pk,
+                &asker_pk,
+                &asker.pubkey(),
+                &mango_group.mango_cache,
+                &perp_market_pk,
+                &perp_market.bids,
+                &perp_market.asks,
+                &perp_market.event_queue,
+                None,
+                &open_orders_pks,
+                Side::Ask,
+                price_lots,
+                3 * display_lots,
+                i64::MAX,
+                0,
+                OrderType::Limit,
+                false,
+                None,
+                20,
+                ExpiryType::Absolute,
+                SelfTradeBehavior::DecrementTake,
+                display_lots,
+            )
+            .unwrap(),
+        ],
+        Some(&[&asker]),
+    )
+    .await
+    .unwrap();
+    let asker_ma = test.load_account::<MangoAccount>(asker_pk).await;
+    assert!(asker_ma.has_iceberg_orders);
+    let cancel_ix = cancel_perp_order(
+        &mango_program_id,
+        &mango_group_pk,
+        &asker_pk,
+        &asker.pubkey(),
+        &perp_market_pk,
+        &perp_market.bids,
+        &perp_market.asks,
+        asker_ma.orders[0],
+        false,
+    )
+    .unwrap();
+
+    let refill_ix = refill_iceberg_orders(
+        &mango_program_id,
+        &mango_group_pk,
+        &asker_pk,
+        &asker.pubkey(),
+        &mango_group.mango_cache,
+        &perp_market_pk,
+        &perp_market.bids,
+        &perp_market.asks,
+        &perp_market.event_queue,
+        &iceberg_orders_pk,
+        &open_orders_pks,
+        20,
+    )
+    .unwrap();
+
+    // === Act ===
+    // Step 1: Without the IcebergOrders PDA the cancel keeps the hidden rest
+    test.process_transaction(&[cancel_ix], Some(&[&asker])).await.unwrap();
+    let iceberg_orders = test.load_account::<IcebergOrders>(iceberg_orders_pk).await;
+    assert!(iceberg_orders.orders[0].is_active);
+    assert_eq!(iceberg_orders.orders[0].hidden_quantity, 2 * display_lots);
+
+    // Step 2: So a refill posts the next slice
+    test.process_transaction(&[refill_ix.clone()], Some(&[&asker])).await.unwrap();
+    let asker_ma = test.load_account::<MangoAccount>(asker_pk).await;
+    let iceberg_orders = test.load_account::<IcebergOrders>(iceberg_orders_pk).await;
+    assert_eq!(asker_ma.perp_accounts[mint_index].asks_quantity, display_lots);
+    assert_eq!(iceberg_orders.orders[0].hidden_quantity, display_lots);
+
+    // Step 3: Cancel that slice with the PDA
+    let mut cancel_ix = cancel_perp_order(
+        &mango_program_id,
+        &mango_group_pk,
+        &asker_pk,
+        &asker.pubkey(),
+        &perp_market_pk,
+        &perp_market.bids,
+        &perp_market.asks,
+        iceberg_orders.orders[0].order_id,
+        false,
+    )
+    .unwrap();
+    cancel_ix.accounts.push(AccountMeta::new(iceberg_orders_pk, false));
+    test.process_transaction(&[cancel_ix], Some(&[&asker])).await.unwrap();
+
+    // Step 4: Refilling posts nothing
+    test.process_transaction(&[refill_ix], Some(&[&asker])).await.unwrap();
+
+    // === Assert ===
+    let iceberg_orders = test.load_account::<IcebergOrders>(iceberg_orders_pk).await;
+    assert!(!iceberg_orders.orders[0].is_active);
+    assert_eq!(iceberg_orders.orders[0].hidden_quantity, 0);
+    let asker_ma = test.load_account::<MangoAccount>(asker_pk).await;
+    assert!(!asker_ma.has_iceberg_orders);
+    assert_eq!(asker_ma.perp_accounts[mint_index].asks_quantity, 0);
+    assert!(asker_ma.orders.iter().all(|&id| id == 0));
+}
@@ -50,6 +50,48 @@ fn test_instruction_serialization() {
             order_type: OrderType::PostOnly,
             reduce_only: true,
         },
+        MangoInstruction::PlacePerpOrder2 {
+            price: 1200,
+            max_base_quantity: 5,
+            max_quote_quantity: i64::MAX,
+            client_order_id: 42,
+            expiry_timestamp: 30,
+            side: Side::Ask,
+            order_type: OrderType::PostOnlySlide,
+            reduce_only: true,
+            limit: 8,
+            expiry_type: ExpiryType::Relative,
+            self_trade_behavior: SelfTradeBehavior::CancelProvide,
+            display_quantity: 0,
+        },
+        MangoInstruction::PlacePerpOrder2 {
+            price: 1200,
+            max_base_quantity: 50,
+            max_quote_quantity: i64::MAX,
+            client_order_id: 43,
+            expiry_timestamp: 0,
+            side: Side::Bid,
+            order_type: OrderType::Limit,
+            reduce_only: false,
+            limit: 8,
+            expiry_type: ExpiryType::Absolute,
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+            display_quantity: 5,
+        },
+        MangoInstruction::PlacePerpOrder2 {
+            price: 1200,
+            max_base_quantity: 50,
+            max_quote_quantity: i64::MAX,
+            client_order_id: 44,
+            expiry_timestamp: 1_700_000_000,
+            side: Side::Ask,
+            order_type: OrderType::PostOnly,
+            reduce_only: false,
+            limit: 8,
+            expiry_type: ExpiryType::GoodTillDate,
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+            display_quantity: 0,
+        },
         MangoInstruction::CancelPerpOrderByClientId { client_order_id: 78, invalid_id_ok: true },
         MangoInstruction::CancelPerpOrder { order_id: 497894561564897, invalid_id_ok: true },
         MangoInstruction::ConsumeEvents { limit: 77 },
@@ -142,8 +184,168 @@ fn test_instruction_serialization() {
         },
         MangoInstruction::RemoveAdvancedOrder { order_index: 42 },
         MangoInstruction::ExecutePerpTriggerOrder { order_index: 249 },
+        MangoInstruction::CreatePerpMarket {
+            maint_leverage: I80F48::from_num(20.0),
+            init_leverage: I80F48::from_num(10.0),
+            liquidation_fee: I80F48::from_num(0.025),
+            maker_fee: I80F48::from_num(-0.0004),
+            taker_fee: I80F48::from_num(0.0005),
+            base_lot_size: 100,
+            quote_lot_size: 10,
+            rate: I80F48::from_num(0.03),
+            max_depth_bps: I80F48::from_num(200.0),
+            target_period_length: 3600,
+            mngo_per_period: 11400,
+            exp: 2,
+            version: 1,
+            lm_size_shift: 3,
+            base_decimals: 6,
+            index_exponent: 2,
+            underlying_oracle_index: 1,
+            index_divisor: I80F48::from_num(1_000_000),
+            funding_period: 86400,
+        },
+        MangoInstruction::ChangePerpMarketParams2 {
+            maint_leverage: None,
+            init_leverage: Some(I80F48::from_num(6156.33)),
+            liquidation_fee: None,
+            maker_fee: None,
+            taker_fee: Some(I80F48::from_num(999.73)),
+            rate: None,
+            max_depth_bps: None,
+            target_period_length: None,
+            mngo_per_period: Some(87985461),
+            exp: None,
+            version: Some(1),
+            lm_size_shift: None,
+            index_exponent: Some(3),
+            underlying_oracle_index: None,
+            index_divisor: Some(I80F48::from_num(1_000_000_000_000u64)),
+            funding_period: Some(604800),
+            maint_shock_bps: Some(1000),
+            init_shock_bps: None,
+            ema_period: Some(600),
+            liquidation_use_ema: Some(true),
+            funding_use_ema: None,
+            price_band_bps: Some(2000),
+            price_band_clamp: Some(false),
+        },
+        MangoInstruction::ChangePerpMarketFundingParams {
+            impact_quantity: Some(250),
+            max_funding: None,
+            min_funding: Some(I80F48::from_num(-0.02)),
+        },
+        MangoInstruction::AddRealizedVolOracle {
+            source_index: 1,
+            window: 604800,
+            min_sample_interval: 60,
+        },
+        MangoInstruction::SetPerpMarketExpiry { expiry: 1_700_000_000, settlement_window: 3600 },
+        MangoInstruction::SettleExpiredPerpMarket,
+        MangoInstruction::SettleExpiredPosition,
+        MangoInstruction::AddCompositeOracle { min_quorum: 2, weights: [3, 1, 1, 0, 0] },
+        MangoInstruction::ChangeOracleLimits {
+            max_conf_bps: Some(2000),
+            max_oracle_age_slots: None,
+            price_valid_interval: Some(30),
+            max_price_move_bps: Some(1500),
+        },
+        MangoInstruction::ClearPriceHalt,
+        MangoInstruction::AddDerivedOracle {
+            base_index: 1,
+            base_exponent: 2,
+            quote_index: 0,
+            quote_exponent: 1,
+            multiplier: I80F48::from_num(0.001),
+        },
+        MangoInstruction::EditPerpOrder {
+            order_id: 497894561564897,
+            new_price: 1200,
+            new_quantity: 5,
+            limit: 20,
+        },
+        MangoInstruction::BatchPerpOrders {
+            limit: 8,
+            cancel_order_ids: vec![497894561564897, -12],
+            cancel_client_order_ids: vec![],
+            orders: vec![
+                BatchPerpOrder {
+                    price: 1200,
+                    max_base_quantity: 5,
+                    client_order_id: 42,
+                    side: Side::Bid,
+                    order_type: OrderType::PostOnly,
+                    time_in_force: 0,
+                    self_trade_behavior: SelfTradeBehavior::CancelProvide,
+                },
+                BatchPerpOrder {
+                    price: 1300,
+                    max_base_quantity: 7,
+                    client_order_id: 43,
+                    side: Side::Ask,
+                    order_type: OrderType::Limit,
+                    time_in_force: 30,
+                    self_trade_behavior: SelfTradeBehavior::DecrementTake,
+                },
+            ],
+        },
+        MangoInstruction::InitIcebergOrders,
+        MangoInstruction::RefillIcebergOrders { limit: 20 },
+        MangoInstruction::CancelIcebergOrder { index: 7 },
+        MangoInstruction::PlacePerpPeggedOrder {
+            price_offset: -25,
+            peg_limit: 1150,
+            max_base_quantity: 12,
+            max_quote_quantity: i64::MAX,
+            client_order_id: 44,
+            expiry_timestamp: 60,
+            side: Side::Bid,
+            order_type: OrderType::PostOnly,
+            reduce_only: false,
+            limit: 8,
+            expiry_type: ExpiryType::Relative,
+            self_trade_behavior: SelfTradeBehavior::AbortTransaction,
+        },
+        MangoInstruction::InitHeartbeat,
+        MangoInstruction::SetHeartbeat { deadline: 1_700_000_000 },
+        MangoInstruction::ExpireHeartbeatOrders { limit: 20 },
+        MangoInstruction::ChangePerpMarketPositionLimits {
+            max_position_lots: Some(10_000),
+            max_open_interest: None,
+        },
+        MangoInstruction::AutoDeleverage,
+        MangoInstruction::UpgradeMangoGroup,
+        MangoInstruction::UpgradePerpMarket,
+        MangoInstruction::UpgradeMangoCache,
     ];
     for case in cases {
         assert!(MangoInstruction::unpack(&case.pack()).unwrap() == case);
     }
 }
+
+#[test]
+fn test_place_perp_order2_legacy_data() {
+    // Clients that predate self-trade prevention keep matching their own orders
+    let case = MangoInstruction::PlacePerpOrder2 {
+        price: 1200,
+        max_base_quantity: 5,
+        max_quote_quantity: i64::MAX,
+        client_order_id: 42,
+        expiry_timestamp: 30,
+        side: Side::Ask,
+        order_type: OrderType::Limit,
+        reduce_only: false,
+        limit: 8,
+        expiry_type: ExpiryType::Relative,
+        self_trade_behavior: SelfTradeBehavior::DecrementTake,
+        display_quantity: 0,
+    };
+    let data = case.pack();
+    match MangoInstruction::unpack(&data[..4 + 45]).unwrap() {
+        MangoInstruction::PlacePerpOrder2 { expiry_type, self_trade_behavior, .. } => {
+            assert_eq!(expiry_type, ExpiryType::Relative);
+            assert_eq!(self_trade_behavior, SelfTradeBehavior::AllowSelfTrade);
+        }
+        _ => panic!("unexpected instruction"),
+    }
+}
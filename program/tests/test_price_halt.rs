@@ -0,0 +1,223 @@
+// Tests related to the oracle circuit breaker
+mod program_test;
+
+use mango::{
+    error::MangoErrorCode,
+    instruction::{
+        change_oracle_limits, clear_price_halt, liquidate_perp_market, place_perp_order2, withdraw,
+    },
+    matching::{ExpiryType, OrderType, SelfTradeBehavior, Side},
+    utils::create_signer_key_and_nonce,
+};
+use program_test::cookies::*;
+use program_test::scenarios::*;
+use program_test::*;
+use solana_program::pubkey::Pubkey;
+use solana_program_test::*;
+use solana_sdk::signature::{Keypair, Signer};
+
+#[tokio::test]
+/// While the price of a market is halted, withdrawals, perp orders and liquidations of accounts
+/// with exposure to it fail until the halt is cleared
+async fn test_price_halt_gating() {
+    // === Arrange ===
+    let config =
+        MangoProgramTestConfig { num_users: 3, ..MangoProgramTestConfig::default_two_mints() };
+    let mut test = MangoProgramTest::start_new(&config).await;
+
+    let mut mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    mango_group_cookie.full_setup(&mut test, config.num_users, config.num_mints - 1).await;
+    let mango_group = mango_group_cookie.mango_group;
+    let mango_group_pk = mango_group_cookie.address;
+    let mango_program_id = test.mango_program_id;
+    let admin_pk = test.get_payer_pk();
+
+    // General parameters
+    let bidder_user_index: usize = 0;
+    let asker_user_index: usize = 1;
+    let liqor_user_index: usize = 2;
+    let mint_index: usize = 0;
+    let base_price: f64 = 10_000.0;
+    let base_size: f64 = 1.0;
+    let oracle_pk = mango_group.oracles[mint_index];
+    let halted_error: Option<u32> = Some(MangoErrorCode::PriceHalted.into());
+
+    // Set oracles
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price).await;
+
+    // Deposit amounts
+    let user_deposits = vec![
+        (bidder_user_index, test.quote_index, base_price),
+        (asker_user_index, mint_index, 1.0),
+        (liqor_user_index, test.quote_index, base_price),
+    ];
+
+    // Matched Perp Orders
+    let matched_perp_orders = vec![vec![
+        (asker_user_index, mint_index, Side::Ask, base_size, base_price),
+        (bidder_user_index, mint_index, Side::Bid, base_size, base_price),
+    ]];
+
+    deposit_scenario(&mut test, &mut mango_group_cookie, &user_deposits).await;
+    match_perp_order_scenario(&mut test, &mut mango_group_cookie, &matched_perp_orders).await;
+
+    // === Act ===
+    // Step 1: Allow 5% per valid_interval and move the oracle price by 10%
+    test.process_transaction(
+        &[change_oracle_limits(
+            &mango_program_id,
+            &mango_group_pk,
+            &oracle_pk,
+            &admin_pk,
+            None,
+            None,
+            None,
+            Some(500),
+        )
+        .unwrap()],
+        None,
+    )
+    .await
+    .unwrap();
+    mango_group_cookie.set_oracle(&mut test, mint_index, base_price * 0.9).await;
+    test.cache_all_prices(
+        &mango_group,
+        &mango_group_pk,
+        &mango_group.oracles[0..mango_group.num_oracles],
+    )
+    .await;
+
+    let mint = test.with_mint(mint_index);
+    let (_mango_cache_pk, mango_cache) = test.with_mango_cache(&mango_group).await;
+    assert!(mango_cache.price_cache[mint_index].is_halted());
+    assert_eq!(
+        mango_cache.price_cache[mint_index].price,
+        test.with_oracle_price(&mint, base_price)
+    );
+
+    // Step 2: The bidder can't withdraw quote while long the halted market
+    let bidder = Keypair::from_base58_string(&test.users[bidder_user_index].to_base58_string());
+    let bidder_pk = mango_group_cookie.mango_accounts[bidder_user_index].address;
+    let bidder_ma = mango_group_cookie.mango_accounts[bidder_user_index].mango_account;
+    let (signer_pk, _signer_nonce) =
+        create_signer_key_and_nonce(&mango_program_id, &mango_group_pk);
+    let (root_bank_pk, root_bank) = test.with_root_bank(&mango_group, test.quote_index).await;
+    let (node_bank_pk, node_bank) = test.with_node_bank(&root_bank, 0).await;
+    let user_token_account = test.with_user_token_account(bidder_user_index, test.quote_index);
+    let error = test
+        .process_transaction(
+            &[withdraw(
+                &mango_program_id,
+                &mango_group_pk,
+                &bidder_pk,
+                &bidder.pubkey(),
+                &mango_group.mango_cache,
+                &root_bank_pk,
+                &node_bank_pk,
+                &node_bank.vault,
+                &user_token_account,
+                &signer_pk,
+                &bidder_ma.spot_open_orders,
+                1,
+                false,
+            )
+            .unwrap()],
+            Some(&[&bidder]),
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(get_error_code(error), halted_error);
+
+    // Step 3: The asker can't place perp orders on the halted market
+    let asker = Keypair::from_base58_string(&test.users[asker_user_index].to_base58_string());
+    let asker_pk = mango_group_cookie.mango_accounts[asker_user_index].address;
+    let asker_ma = mango_group_cookie.mango_accounts[asker_user_index].mango_account;
+    let perp_market_cookie = mango_group_cookie.perp_markets[mint_index];
+    let perp_market = perp_market_cookie.perp_market;
+    let open_orders_pks: Vec<Pubkey> = asker_ma
+        .spot_open_orders
+        .iter()
+        .enumerate()
+        .filter_map(|(i, &pk)| if asker_ma.in_margin_basket[i] { Some(pk) } else { None })
+        .collect();
+    let error = test
+        .process_transaction(
+            &[place_perp_order2(
+                &mango_program_id,
+                &mango_group_pk,
+                &asker_pk,
+                &asker.pubkey(),
+                &mango_group.mango_cache,
+                &perp_market_cookie.address,
+                &perp_market.bids,
+                &perp_market.asks,
+                &perp_market.event_queue,
+                None,
+                &open_orders_pks,
+                Side::Ask,
+                test.price_number_to_lots(&mint, base_price) as i64,
+                test.base_size_number_to_lots(&mint, base_size) as i64,
+                i64::MAX,
+                0,
+                OrderType::Limit,
+                false,
+                None,
+                20,
+                ExpiryType::Absolute,
+                SelfTradeBehavior::DecrementTake,
+                0,
+            )
+            .unwrap()],
+            Some(&[&asker]),
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(get_error_code(error), halted_error);
+
+    // Step 4: Nobody can liquidate the bidder's position at the halted price
+    let liqor = Keypair::from_base58_string(&test.users[liqor_user_index].to_base58_string());
+    let liqor_pk = mango_group_cookie.mango_accounts[liqor_user_index].address;
+    let liqor_ma = mango_group_cookie.mango_accounts[liqor_user_index].mango_account;
+    let liquidate_ix = liquidate_perp_market(
+        &mango_program_id,
+        &mango_group_pk,
+        &mango_group.mango_cache,
+        &perp_market_cookie.address,
+        &perp_market.event_queue,
+        &bidder_pk,
+        &liqor_pk,
+        &liqor.pubkey(),
+        &bidder_ma.spot_open_orders,
+        &liqor_ma.spot_open_orders,
+        1,
+    )
+    .unwrap();
+    let error =
+        test.process_transaction(&[liquidate_ix.clone()], Some(&[&liqor])).await.unwrap_err();
+    assert_eq!(get_error_code(error), halted_error);
+
+    // Step 5: Once the admin accepts the new price, the healthy bidder just isn't liquidatable
+    test.process_transaction(
+        &[clear_price_halt(
+            &mango_program_id,
+            &mango_group_pk,
+            &mango_group.mango_cache,
+            &oracle_pk,
+            &admin_pk,
+        )
+        .unwrap()],
+        None,
+    )
+    .await
+    .unwrap();
+
+    // === Assert ===
+    let (_mango_cache_pk, mango_cache) = test.with_mango_cache(&mango_group).await;
+    assert!(!mango_cache.price_cache[mint_index].is_halted());
+    assert_eq!(
+        mango_cache.price_cache[mint_index].price,
+        test.with_oracle_price(&mint, base_price * 0.9)
+    );
+    let error = test.process_transaction(&[liquidate_ix], Some(&[&liqor])).await.unwrap_err();
+    assert_eq!(get_error_code(error), Some(MangoErrorCode::NotLiquidatable.into()));
+}
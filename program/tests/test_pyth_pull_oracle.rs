@@ -0,0 +1,79 @@
+// Tests related to Pyth pull oracle price update accounts
+mod program_test;
+
+use fixed::types::I80F48;
+use mango::{
+    instruction::add_oracle,
+    oracle::{PythPriceUpdate, PYTH_PUSH_ORACLE_SHARD},
+};
+use program_test::cookies::*;
+use program_test::*;
+use solana_program_test::*;
+
+#[tokio::test]
+async fn test_pyth_pull_oracle() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig::default_two_mints();
+    let mut test = MangoProgramTest::start_new(&config).await;
+
+    let mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    let mango_group = mango_group_cookie.mango_group;
+    let mango_group_pk = mango_group_cookie.address;
+    let mango_program_id = test.mango_program_id;
+    let admin_pk = test.get_payer_pk();
+
+    let feed_id = [7u8; 32];
+    let oracle_pk = PythPriceUpdate::feed_address(&feed_id, PYTH_PUSH_ORACLE_SHARD);
+    let publish_time = test.get_clock().await.unix_timestamp;
+    test.set_pyth_price_update(&oracle_pk, feed_id, 2_500_000, -6, publish_time).await;
+
+    // === Act ===
+    test.process_transaction(
+        &[add_oracle(&mango_program_id, &mango_group_pk, &oracle_pk, &admin_pk).unwrap()],
+        None,
+    )
+    .await
+    .unwrap();
+    test.cache_all_prices(&mango_group, &mango_group_pk, &[oracle_pk]).await;
+
+    // === Assert ===
+    // No spot market is listed so the base token has 0 decimals
+    let (_mango_cache_pk, mango_cache) = test.with_mango_cache(&mango_group).await;
+    let decimal_adj = I80F48::from_num(10u64.pow(test.quote_mint.decimals as u32 - 6));
+    assert_eq!(mango_cache.price_cache[0].price, I80F48::from_num(2_500_000) * decimal_adj);
+    let last_update = mango_cache.price_cache[0].last_update;
+
+    // Stale updates are not cached
+    test.advance_clock_past_timestamp(publish_time + 61).await;
+    test.cache_all_prices(&mango_group, &mango_group_pk, &[oracle_pk]).await;
+    let (_mango_cache_pk, mango_cache) = test.with_mango_cache(&mango_group).await;
+    assert_eq!(mango_cache.price_cache[0].last_update, last_update);
+}
+
+#[tokio::test]
+async fn test_pyth_pull_oracle_wrong_feed() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig::default_two_mints();
+    let mut test = MangoProgramTest::start_new(&config).await;
+
+    let mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    let mango_program_id = test.mango_program_id;
+    let admin_pk = test.get_payer_pk();
+
+    // The account isn't the price feed account of the feed it contains
+    let oracle_pk = PythPriceUpdate::feed_address(&[8u8; 32], PYTH_PUSH_ORACLE_SHARD);
+    let publish_time = test.get_clock().await.unix_timestamp;
+    test.set_pyth_price_update(&oracle_pk, [9u8; 32], 2_500_000, -6, publish_time).await;
+
+    // === Act ===
+    let result = test
+        .process_transaction(
+            &[add_oracle(&mango_program_id, &mango_group_cookie.address, &oracle_pk, &admin_pk)
+                .unwrap()],
+            None,
+        )
+        .await;
+
+    // === Assert ===
+    assert!(result.is_err());
+}
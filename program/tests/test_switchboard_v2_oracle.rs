@@ -0,0 +1,93 @@
+// Tests related to switchboard v2 aggregators as oracles
+mod program_test;
+
+use fixed::types::I80F48;
+use mango::{instruction::add_oracle, state::SWITCHBOARD_V2_MAX_STALENESS};
+use program_test::cookies::*;
+use program_test::*;
+use solana_program::pubkey::Pubkey;
+use solana_program_test::*;
+use switchboard_v2::SwitchboardDecimal;
+
+#[tokio::test]
+async fn test_switchboard_v2_oracle() {
+    // === Arrange ===
+    let config = MangoProgramTestConfig::default_two_mints();
+    let mut test = MangoProgramTest::start_new(&config).await;
+
+    let mango_group_cookie = MangoGroupCookie::default(&mut test).await;
+    let mango_group = mango_group_cookie.mango_group;
+    let mango_group_pk = mango_group_cookie.address;
+    let mango_program_id = test.mango_program_id;
+    let admin_pk = test.get_payer_pk();
+
+    let oracle_pk = Pubkey::new_unique();
+    let no_std_deviation = SwitchboardDecimal { mantissa: 0, scale: 0 };
+    let now = test.get_clock().await.unix_timestamp;
+    test.set_switchboard_v2_aggregator(
+        &oracle_pk,
+        SwitchboardDecimal { mantissa: 255, scale: 1 },
+        no_std_deviation,
+        now,
+    )
+    .await;
+
+    // === Act ===
+    test.process_transaction(
+        &[add_oracle(&mango_program_id, &mango_group_pk, &oracle_pk, &admin_pk).unwrap()],
+        None,
+    )
+    .await
+    .unwrap();
+    test.cache_all_prices(&mango_group, &mango_group_pk, &[oracle_pk]).await;
+
+    // === Assert ===
+    // No spot market is listed so the base token has 0 decimals and the ui price is scaled up
+    let decimal_adj = I80F48::from_num(10u64.pow(test.quote_mint.decimals as u32));
+    let expected_price = I80F48::from_num(25.5) * decimal_adj;
+    let (_mango_cache_pk, mango_cache) = test.with_mango_cache(&mango_group).await;
+    assert_eq!(mango_cache.price_cache[0].price, expected_price);
+    assert!(mango_cache.price_cache[0].last_update > 0);
+    let last_update = mango_cache.price_cache[0].last_update;
+
+    // Rounds with a std deviation above 10% of the result are not cached
+    test.advance_clock_by_min_timespan(1).await;
+    let now = test.get_clock().await.unix_timestamp;
+    test.set_switchboard_v2_aggregator(
+        &oracle_pk,
+        SwitchboardDecimal { mantissa: 30, scale: 0 },
+        SwitchboardDecimal { mantissa: 31, scale: 1 },
+        now,
+    )
+    .await;
+    test.cache_all_prices(&mango_group, &mango_group_pk, &[oracle_pk]).await;
+    let (_mango_cache_pk, mango_cache) = test.with_mango_cache(&mango_group).await;
+    assert_eq!(mango_cache.price_cache[0].price, expected_price);
+    assert_eq!(mango_cache.price_cache[0].last_update, last_update);
+
+    // Neither are rounds that opened too long ago
+    test.set_switchboard_v2_aggregator(
+        &oracle_pk,
+        SwitchboardDecimal { mantissa: 30, scale: 0 },
+        no_std_deviation,
+        now - SWITCHBOARD_V2_MAX_STALENESS - 1,
+    )
+    .await;
+    test.cache_all_prices(&mango_group, &mango_group_pk, &[oracle_pk]).await;
+    let (_mango_cache_pk, mango_cache) = test.with_mango_cache(&mango_group).await;
+    assert_eq!(mango_cache.price_cache[0].price, expected_price);
+    assert_eq!(mango_cache.price_cache[0].last_update, last_update);
+
+    // A fresh round with a std deviation of about 1% is
+    test.set_switchboard_v2_aggregator(
+        &oracle_pk,
+        SwitchboardDecimal { mantissa: 30, scale: 0 },
+        SwitchboardDecimal { mantissa: 29, scale: 2 },
+        now,
+    )
+    .await;
+    test.cache_all_prices(&mango_group, &mango_group_pk, &[oracle_pk]).await;
+    let (_mango_cache_pk, mango_cache) = test.with_mango_cache(&mango_group).await;
+    assert_eq!(mango_cache.price_cache[0].price, I80F48::from_num(30) * decimal_adj);
+    assert!(mango_cache.price_cache[0].last_update > last_update);
+}